@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tempfile::{tempdir, TempDir};
+use topazdb::{
+    iterators::{
+        loser_tree_iterator::LoserTreeIterator, merge_iterator::MergeIterator, StorageIterator,
+    },
+    opt::LsmOptions,
+    table::{SsTable, SsTableBuilder, SsTableIterator},
+};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:06}", idx * 5).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+fn num_of_keys() -> usize {
+    100000
+}
+
+/// Tables taking the keys in turn, every 10th key is in two tables.
+fn generate_ssts(num: usize) -> (TempDir, Vec<Arc<SsTable>>) {
+    let dir = tempdir().unwrap();
+    let mut builders: Vec<_> = (0..num)
+        .map(|_| SsTableBuilder::new(LsmOptions::default().into()))
+        .collect();
+    for idx in 0..num_of_keys() {
+        let (key, value) = (key_of(idx), value_of(idx));
+        builders[idx % num].add(&key, &value).unwrap();
+        if idx % 10 == 0 {
+            builders[(idx + 1) % num].add(&key, &value).unwrap();
+        }
+    }
+    let ssts = builders
+        .into_iter()
+        .enumerate()
+        .map(|(id, builder)| {
+            let path = dir.path().join(format!("{id}.sst"));
+            Arc::new(builder.build(id as u64, None, path).unwrap())
+        })
+        .collect();
+    (dir, ssts)
+}
+
+// the merge iterators take boxed iterators
+#[allow(clippy::vec_box)]
+fn iters(ssts: &[Arc<SsTable>]) -> Vec<Box<SsTableIterator>> {
+    ssts.iter()
+        .map(|sst| Box::new(SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap()))
+        .collect()
+}
+
+fn read_all(mut iter: impl StorageIterator) {
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, num_of_keys());
+}
+
+fn bench_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bench merge");
+    for num in [2, 8, 32] {
+        let (_dir, ssts) = generate_ssts(num);
+        group.bench_function(BenchmarkId::new("binary_heap", num), |b| {
+            b.iter(|| read_all(MergeIterator::create(iters(&ssts))))
+        });
+        group.bench_function(BenchmarkId::new("loser_tree", num), |b| {
+            b.iter(|| read_all(LoserTreeIterator::create(iters(&ssts))))
+        });
+    }
+}
+
+criterion_group!(benches, bench_merge);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+use std::ops::Bound;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tempfile::{tempdir, TempDir};
+use topazdb::{iterators::StorageIterator, lsm_storage::LsmStorage, opt::LsmOptions};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx * 5).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:010}", idx).into_bytes()
+}
+
+fn num_of_keys() -> usize {
+    10000
+}
+
+/// All keys are in a single sstable, `merged` adds a memtable holding a key in the range.
+fn generate_storage(merged: bool) -> (TempDir, LsmStorage) {
+    let dir = tempdir().unwrap();
+    let storage = LsmOptions::default().path(&dir).open().unwrap();
+    for idx in 0..num_of_keys() {
+        storage.put(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    if merged {
+        // the key doesn't exist, the scan results are the same
+        storage.delete(b"key_00001").unwrap();
+    }
+    (dir, storage)
+}
+
+fn scan_all(storage: &LsmStorage) {
+    let mut iter = storage
+        .scan(Bound::Included(b"key_"), Bound::Excluded(b"key_a"))
+        .unwrap();
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, num_of_keys());
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let (_dir, storage) = generate_storage(false);
+    c.bench_function("bench_scan_single_table", |b| b.iter(|| scan_all(&storage)));
+
+    let (_dir, storage) = generate_storage(true);
+    c.bench_function("bench_scan_merged", |b| b.iter(|| scan_all(&storage)));
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
@@ -1,13 +1,13 @@
 use std::{
     fs::{create_dir, remove_dir_all},
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use tempfile::tempdir;
-use topazdb::opt::LsmOptions;
+use topazdb::opt::{LsmOptions, WalSync};
 
 fn key_of(idx: usize) -> Vec<u8> {
     format!("key_{:03}", idx * 5).into_bytes()
@@ -175,6 +175,42 @@ fn bench_channel_write(c: &mut Criterion) {
     });
 }
 
+fn bench_wal_sync_tmpfs(c: &mut Criterion) {
+    let kvs = generate_kvs()
+        .into_iter()
+        .map(|x| (Bytes::from(x.0), Bytes::from(x.1)))
+        .collect::<Vec<_>>();
+    let mut g = c.benchmark_group("bench write");
+    for (name, wal_sync) in [
+        ("always", WalSync::Always),
+        ("every_100", WalSync::EveryN(100)),
+        (
+            "interval_10ms",
+            WalSync::Interval(Duration::from_millis(10)),
+        ),
+    ] {
+        let dir = tempdir().unwrap();
+        let mut opts = LsmOptions::default().path(dir.path()).wal_sync(wal_sync);
+        opts.memtable_size = 4096 * 1000;
+        opts.block_size = 4096;
+        let storage = Arc::new(opts.open().unwrap());
+        g.bench_function(BenchmarkId::new("tmpfs_4_writers", name), |b| {
+            b.iter(|| {
+                std::thread::scope(|s| {
+                    for kvs in kvs.chunks(kvs.len() / 4) {
+                        let storage = &storage;
+                        s.spawn(move || {
+                            for entry in kvs {
+                                storage.put(&entry.0, &entry.1).unwrap();
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+}
+
 fn create_test_dir(_: &mut Criterion) {
     create_dir(DIR).unwrap();
 }
@@ -197,5 +233,6 @@ criterion_group!(
     bench_write_tmpfs,
     bench_batch_write_tmpfs,
     bench_channel_write_tmpfs,
+    bench_wal_sync_tmpfs,
 );
 criterion_main!(benches);
@@ -0,0 +1,100 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    lsm_storage::{bound_to_bytes, LsmStorage},
+    opt::LsmOptions,
+};
+
+/// Entries buffered by a scan before the stream is polled.
+const SCAN_BUFFER: usize = 64;
+
+/// A stream of the entries of a scan, an error ends the stream.
+pub type ScanStream = ReceiverStream<Result<(Bytes, Bytes)>>;
+
+/// An async handle of `LsmStorage`, whose operations run in the blocking thread pool of tokio so
+/// they don't block the async runtime. Clones share the same storage.
+#[derive(Clone)]
+pub struct AsyncLsmStorage {
+    storage: Arc<LsmStorage>,
+}
+
+impl AsyncLsmStorage {
+    pub fn new(storage: LsmStorage) -> Self {
+        Self {
+            storage: Arc::new(storage),
+        }
+    }
+
+    pub async fn open(opts: LsmOptions) -> Result<Self> {
+        let storage = tokio::task::spawn_blocking(move || LsmStorage::open(opts)).await??;
+        Ok(Self::new(storage))
+    }
+
+    /// Get the blocking storage.
+    pub fn storage(&self) -> &LsmStorage {
+        &self.storage
+    }
+
+    async fn spawn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LsmStorage) -> Result<T> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || f(&storage)).await?
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let key = Bytes::copy_from_slice(key);
+        self.spawn(move |storage| storage.get(&key)).await
+    }
+
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key, value) = (Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+        self.spawn(move |storage| storage.put(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<()> {
+        let key = Bytes::copy_from_slice(key);
+        self.spawn(move |storage| storage.delete(&key)).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.spawn(|storage| storage.sync()).await
+    }
+
+    /// Scan a range of keys. The iterator runs in the blocking thread pool, and stops once the
+    /// stream is dropped.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> ScanStream {
+        let (lower, upper) = (bound_to_bytes(lower), bound_to_bytes(upper));
+        let storage = self.storage.clone();
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let lower = lower.as_ref().map(|key| &key[..]);
+            let upper = upper.as_ref().map(|key| &key[..]);
+            let iter = match storage.scan(lower, upper) {
+                Ok(iter) => iter,
+                Err(e) => return tx.blocking_send(Err(e)),
+            };
+            for entry in iter {
+                tx.blocking_send(entry)?;
+            }
+            Ok(())
+        });
+        ReceiverStream::new(rx)
+    }
+
+    /// Flush all memtables and stop background tasks like `LsmStorage::close`. It fails if any
+    /// clone of the handle or scan is still alive.
+    pub async fn close(self) -> Result<()> {
+        let storage = Arc::try_unwrap(self.storage)
+            .map_err(|_| anyhow::anyhow!("the storage is still in use"))?;
+        tokio::task::spawn_blocking(move || storage.close()).await?
+    }
+}
@@ -6,21 +6,98 @@ use anyhow::Ok;
 use anyhow::Result;
 pub use builder::BlockBuilder;
 pub use builder::Entry;
+pub use builder::{split_expire_at, ValueType, LONG_VALUE};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 pub use iterator::BlockIterator;
+use std::sync::Arc;
 
-use crate::checksum;
+use crate::checksum::{ChecksumType, CHECKSUM_SIZE};
+use crate::opt::{BytewiseComparator, Comparator};
 
 pub use self::compress::CompressOptions;
 
 pub const SIZEOF_U16: usize = std::mem::size_of::<u16>();
 
+/// Set in the number of offsets if entries are tagged with their value types.
+const TAGGED: u16 = 1 << 15;
+/// Set in the number of offsets if keys are prefix compressed, offsets are restart points.
+const PREFIX_COMPRESSED: u16 = 1 << 14;
+/// Set in the number of offsets if the checksum is xxh3 instead of crc32. Only prefix compressed
+/// blocks have it, older formats may use the bit for the number of offsets.
+const XXHASH3: u16 = 1 << 13;
+/// Set in the number of offsets if lengths of prefix compressed entries are varints. Older prefix
+/// compressed blocks never reach 4096 restart points, whose offsets are below 64KB.
+const VARINT: u16 = 1 << 12;
+
+/// The encoding of entries in a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Format {
+    /// |key_len(u16)|key|value_len(u16)|value|, written before value types were tagged. An empty
+    /// value is a tombstone.
+    #[default]
+    Untagged,
+    /// |key_len(u16)|key|value_type(u8)|value_len(u16)|value|
+    Tagged,
+    /// |shared_len(u16)|unshared_len(u16)|unshared key|value_type(u8)|value_len(u16)|value|, the
+    /// key shares `shared_len` bytes with the previous key, which is 0 at restart points.
+    PrefixCompressed,
+    /// Same as `PrefixCompressed` but the lengths are varints, so values may exceed 64KB.
+    Varint,
+}
+
+/// Append `value` as a LEB128 varint.
+fn put_varint(buf: &mut impl BufMut, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Read a LEB128 varint, the bits that don't fit in usize are dropped.
+fn get_varint(buf: &mut &[u8]) -> usize {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as usize).checked_shl(shift).unwrap_or(0);
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Get the encoded length of `value` as a varint.
+fn varint_len(value: usize) -> usize {
+    (usize::BITS - value.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Block {
     data: Bytes,
+    // offsets of restart points whose keys are stored in full, every entry is a restart point
+    // unless keys are prefix compressed
     offsets: Vec<u16>,
+    // shared prefix of all keys, which is stripped from the entries
+    prefix: Bytes,
+    format: Format,
+    // order of the keys, None if they are compared as bytes
+    comparator: Option<Arc<dyn Comparator>>,
+}
+
+/// An entry decoded from a block.
+struct EntryRef<'a> {
+    /// Length of the prefix shared with the previous key.
+    shared: usize,
+    unshared_key: &'a [u8],
+    value_type: ValueType,
+    /// The encoded value, see [`split_expire_at`] for `ValueType::PutWithExpiry`.
+    value: &'a [u8],
+    /// Offset of the next entry.
+    next: usize,
 }
 
 impl Block {
@@ -28,30 +105,75 @@ impl Block {
         SIZEOF_U16 + SIZEOF_U16 * self.offsets.len() + self.data.len()
     }
 
-    pub fn encode(&self, compress_option: CompressOptions) -> Result<Bytes> {
+    /// Encode the block with a checksum of `checksum_type` and compress it with
+    /// `compress_option` at `compress_level`, see `compress::encode` for the accepted levels.
+    pub fn encode(
+        &self,
+        compress_option: CompressOptions,
+        compress_level: i32,
+        checksum_type: ChecksumType,
+    ) -> Result<Bytes> {
         let num_element = self.offsets.len();
         let mut buf = BytesMut::with_capacity(self.uncompress_size());
         // |num_element|offsets|data| is easier to decode than |data|offsets|num_element|
-        buf.put_u16(num_element as u16);
+        let mut flags = match self.format {
+            Format::Untagged => 0,
+            Format::Tagged => TAGGED,
+            Format::PrefixCompressed => TAGGED | PREFIX_COMPRESSED,
+            Format::Varint => TAGGED | PREFIX_COMPRESSED | VARINT,
+        };
+        match (self.format, checksum_type) {
+            (_, ChecksumType::Crc32) => {}
+            (Format::PrefixCompressed | Format::Varint, ChecksumType::XxHash3) => flags |= XXHASH3,
+            (format, checksum_type) => {
+                return Err(anyhow::anyhow!(
+                    "{format:?} block doesn't support {checksum_type:?} checksum"
+                ))
+            }
+        }
+        buf.put_u16(num_element as u16 | flags);
         for &offset in &self.offsets {
             buf.put_u16(offset);
         }
         buf.put(self.data.clone());
 
-        let checksum = checksum::calculate_checksum(&buf);
+        let checksum = checksum_type.calculate(&buf);
         buf.put_u32(checksum);
-        compress::encode(&buf, compress_option)
+        compress::encode(&buf, compress_option, compress_level)
     }
 
     pub fn decode(data: &[u8]) -> Result<Self> {
         let mut data = compress::decode(data)?;
+        if data.len() < SIZEOF_U16 + CHECKSUM_SIZE {
+            return Err(anyhow::anyhow!("block is too small: {} bytes", data.len()));
+        }
 
-        let mut buf = data.split_to(data.len() - 4);
+        let mut buf = data.split_to(data.len() - CHECKSUM_SIZE);
 
+        // the header is checked by the checksum it selects
+        let num_element = (&buf[..]).get_u16();
+        let format = match (num_element & TAGGED, num_element & PREFIX_COMPRESSED) {
+            (0, 0) => Format::Untagged,
+            (_, 0) => Format::Tagged,
+            _ if num_element & VARINT != 0 => Format::Varint,
+            _ => Format::PrefixCompressed,
+        };
+        let mut mask = TAGGED | PREFIX_COMPRESSED;
+        let mut checksum_type = ChecksumType::Crc32;
+        if matches!(format, Format::PrefixCompressed | Format::Varint) {
+            mask |= XXHASH3 | VARINT;
+            if num_element & XXHASH3 != 0 {
+                checksum_type = ChecksumType::XxHash3;
+            }
+        }
         let checksum = data.get_u32();
-        checksum::verify_checksum(&buf, checksum)?;
+        checksum_type.verify(&buf, checksum)?;
 
-        let num_element = buf.get_u16() as usize;
+        buf.advance(SIZEOF_U16);
+        let num_element = (num_element & !mask) as usize;
+        if buf.len() < num_element * SIZEOF_U16 {
+            return Err(anyhow::anyhow!("block offsets are truncated"));
+        }
 
         let mut offsets = Vec::with_capacity(num_element);
         for _ in 0..num_element {
@@ -61,8 +183,89 @@ impl Block {
         Ok(Self {
             data: buf.freeze(),
             offsets,
+            prefix: Bytes::new(),
+            format,
+            comparator: None,
         })
     }
+
+    /// Decode the entry at `offset`.
+    fn entry_at(&self, offset: usize) -> EntryRef<'_> {
+        let mut buf = &self.data[offset..];
+        if self.format == Format::Varint {
+            return self.varint_entry_at(buf);
+        }
+        let shared = match self.format {
+            Format::PrefixCompressed => buf.get_u16() as usize,
+            _ => 0,
+        };
+        let klen = buf.get_u16() as usize;
+        let unshared_key = &buf[..klen];
+        buf.advance(klen);
+        let tag = match self.format {
+            Format::Untagged => None,
+            _ => Some(buf.get_u8()),
+        };
+        let vlen = buf.get_u16() as usize;
+        let value = &buf[..vlen];
+        let value_type = match tag {
+            // an empty value was the tombstone before entries are tagged
+            None if value.is_empty() => ValueType::Delete,
+            None => ValueType::Put,
+            Some(tag) => ValueType::try_from(tag).unwrap_or(ValueType::Put),
+        };
+        EntryRef {
+            shared,
+            unshared_key,
+            value_type,
+            value,
+            next: self.data.len() - buf.len() + vlen,
+        }
+    }
+
+    /// Decode the entry of `Format::Varint` at the start of `buf`.
+    fn varint_entry_at<'a>(&'a self, mut buf: &'a [u8]) -> EntryRef<'a> {
+        let shared = get_varint(&mut buf);
+        let klen = get_varint(&mut buf);
+        let unshared_key = &buf[..klen];
+        buf.advance(klen);
+        let tag = buf.get_u8();
+        let vlen = get_varint(&mut buf);
+        EntryRef {
+            shared,
+            unshared_key,
+            value_type: ValueType::try_from(tag).unwrap_or(ValueType::Put),
+            value: &buf[..vlen],
+            next: self.data.len() - buf.len() + vlen,
+        }
+    }
+
+    /// Set the prefix stripped from the keys of this block, it is not encoded.
+    pub fn with_prefix(mut self, prefix: Bytes) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Set the order of the keys of this block, they are compared as bytes by default.
+    pub fn with_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = Some(comparator).filter(|comparator| !comparator.is_bytewise());
+        self
+    }
+
+    /// Get the order of the keys.
+    pub fn comparator(&self) -> &dyn Comparator {
+        self.comparator.as_deref().unwrap_or(&BytewiseComparator)
+    }
+
+    /// Creates an iterator over the block and seek to the first entry.
+    pub fn iter(self: Arc<Self>) -> BlockIterator {
+        BlockIterator::create_and_seek_to_first(self)
+    }
+
+    /// Decode an encoded block buffer and iterate on it.
+    pub fn decode_and_iter(data: &[u8]) -> Result<BlockIterator> {
+        Ok(Arc::new(Self::decode(data)?).iter())
+    }
 }
 
 #[cfg(test)]
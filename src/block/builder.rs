@@ -1,48 +1,126 @@
-use super::{Block, SIZEOF_U16};
+use super::{put_varint, varint_len, Block, Format, SIZEOF_U16, VARINT};
+use anyhow::{anyhow, Result};
 use bytes::{BufMut, Bytes, BytesMut};
 
+/// Default number of entries between restart points, whose keys are stored in full.
+const DEFAULT_RESTART_INTERVAL: usize = 16;
+/// The number of restart points is stored below the format flags of a block.
+const MAX_RESTARTS: usize = VARINT as usize - 1;
+
 /// Builds a block.
 #[derive(Debug)]
 pub struct BlockBuilder {
     target_size: usize,
+    restart_interval: usize,
     data: BytesMut,
+    // offsets of restart points
     offsets: Vec<u16>,
-    size: usize,
+    num_entries: usize,
+    first_key: Bytes,
+    last_key: Bytes,
 }
 
 impl BlockBuilder {
     /// Creates a new block builder.
     pub fn new(target_size: usize) -> Self {
+        Self::with_restart_interval(target_size, DEFAULT_RESTART_INTERVAL)
+    }
+
+    /// Creates a new block builder which stores every `restart_interval`th key in full. Seeks
+    /// scan fewer entries from a restart point with a smaller interval, while other keys are
+    /// smaller with a larger one.
+    pub fn with_restart_interval(target_size: usize, restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart interval must be positive");
         Self {
             target_size,
+            restart_interval,
             data: BytesMut::new(),
             offsets: Vec::new(),
-            size: 0,
+            num_entries: 0,
+            first_key: Bytes::new(),
+            last_key: Bytes::new(),
         }
     }
 
-    /// Adds a key-value pair to the block. Returns false when the block is full.
-    #[must_use]
-    pub fn add(&mut self, key: &[u8], value: &[u8]) -> bool {
-        assert!(!key.is_empty(), "key must not be empty");
+    /// Adds a key-value pair to the block. Returns false when the block is full, the first entry
+    /// is always added. An empty key is an error.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.add_entry(Entry::new(key, value))
+    }
+
+    /// Adds a key-value pair which expires at `expire_at` to the block. Returns false when the
+    /// block is full.
+    pub fn add_with_expiry(&mut self, key: &[u8], value: &[u8], expire_at: u64) -> Result<bool> {
+        self.add_entry(Entry::with_expiry(key, value, expire_at))
+    }
+
+    /// Adds a tombstone of a deleted key to the block. Returns false when the block is full.
+    pub fn add_tombstone(&mut self, key: &[u8]) -> Result<bool> {
+        self.add_entry(Entry::tombstone(key))
+    }
 
-        let entry = Entry::new(key, value);
-        let encode_len = entry.encode_len();
+    /// Adds the encoded operands merged into a key to the block, see [`crate::merge`]. Returns
+    /// false when the block is full.
+    pub fn add_merge(&mut self, key: &[u8], operands: &[u8]) -> Result<bool> {
+        self.add_entry(Entry::merge(key, operands))
+    }
 
-        if encode_len + self.size + SIZEOF_U16 > self.target_size {
-            return false;
+    fn add_entry(&mut self, entry: Entry) -> Result<bool> {
+        if entry.key.is_empty() {
+            return Err(anyhow!("key must not be empty"));
         }
 
-        self.data.put(entry.encode());
-        self.offsets.push(self.size as u16);
-        self.size += encode_len;
+        let restart = self.num_entries.is_multiple_of(self.restart_interval);
+        let shared = match restart {
+            true => 0,
+            false => shared_len(&self.last_key, &entry.key),
+        };
+        let encode_len =
+            varint_len(shared) + varint_len(entry.key.len() - shared) + entry.key.len() - shared
+                + 1
+                + varint_len(entry.value.len())
+                + entry.value.len();
+        let offsets_len = SIZEOF_U16 * (self.offsets.len() + restart as usize);
+        // restart offsets are u16, a restart point past them starts the next block
+        let offset = u16::try_from(self.data.len());
+
+        if !self.is_empty()
+            && (self.data.len() + encode_len + offsets_len > self.target_size
+                || restart && (self.offsets.len() == MAX_RESTARTS || offset.is_err()))
+        {
+            return Ok(false);
+        }
+
+        if restart {
+            let offset =
+                offset.map_err(|_| anyhow!("restart offset {} overflows u16", self.data.len()))?;
+            self.offsets.push(offset);
+        }
+        put_entry(
+            &mut self.data,
+            shared,
+            &entry.key[shared..],
+            entry.value_type,
+            &entry.value,
+        );
+        if self.is_empty() {
+            self.first_key = entry.key.clone();
+        }
+        self.last_key = entry.key;
+        self.num_entries += 1;
 
-        true
+        Ok(true)
     }
 
     /// Check if there is no key-value pair in the block.
     pub fn is_empty(&self) -> bool {
-        self.size == 0
+        self.num_entries == 0
+    }
+
+    /// Returns the longest prefix shared by all keys in the block.
+    pub fn common_prefix(&self) -> &[u8] {
+        // keys are sorted, so it's the common prefix of the first and the last key
+        &self.first_key[..shared_len(&self.first_key, &self.last_key)]
     }
 
     /// Finalize the block.
@@ -52,12 +130,123 @@ impl BlockBuilder {
         Block {
             data: self.data.freeze(),
             offsets: self.offsets,
+            prefix: Bytes::new(),
+            format: Format::Varint,
+            comparator: None,
+        }
+    }
+
+    /// Finalize the block, stripping the first `prefix_len` bytes of every key.
+    ///
+    /// `prefix_len` must not exceed the length of [`BlockBuilder::common_prefix`].
+    pub fn build_strip_prefix(self, prefix_len: usize) -> Result<Block> {
+        assert!(prefix_len <= self.common_prefix().len());
+        if prefix_len == 0 {
+            return Ok(self.build());
+        }
+
+        let block = self.build();
+        let mut data = BytesMut::with_capacity(block.data.len());
+        let mut offsets = Vec::with_capacity(block.offsets.len());
+        let mut offset = 0;
+        while offset < block.data.len() {
+            let entry = block.entry_at(offset);
+            // other keys share at least the prefix with the previous keys
+            let (shared, unshared_key) = match entry.shared {
+                0 => {
+                    let offset = u16::try_from(data.len())
+                        .map_err(|_| anyhow!("restart offset {} overflows u16", data.len()))?;
+                    offsets.push(offset);
+                    (0, &entry.unshared_key[prefix_len..])
+                }
+                shared => (shared - prefix_len, entry.unshared_key),
+            };
+            put_entry(
+                &mut data,
+                shared,
+                unshared_key,
+                entry.value_type as u8,
+                entry.value,
+            );
+            offset = entry.next;
         }
+
+        Ok(Block {
+            data: data.freeze(),
+            offsets,
+            prefix: Bytes::new(),
+            format: Format::Varint,
+            comparator: None,
+        })
+    }
+}
+
+/// Get the length of the common prefix of `a` and `b`.
+fn shared_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+// |shared_len(varint)|unshared_len(varint)|unshared key|value_type(u8)|value_len(varint)|value|
+fn put_entry(buf: &mut BytesMut, shared: usize, unshared_key: &[u8], value_type: u8, value: &[u8]) {
+    put_varint(buf, shared);
+    put_varint(buf, unshared_key.len());
+    buf.put(unshared_key);
+    buf.put_u8(value_type);
+    put_varint(buf, value.len());
+    buf.put(value);
+}
+
+/// The type of an entry, a deleted key is kept as a tombstone until the last level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ValueType {
+    Put = 0,
+    Delete = 1,
+    /// The value ends with its expiry time, see [`split_expire_at`].
+    PutWithExpiry = 2,
+    /// The value is a list of merge operands, which are combined with an older value of the key.
+    Merge = 3,
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ValueType::Put),
+            1 => Ok(ValueType::Delete),
+            2 => Ok(ValueType::PutWithExpiry),
+            3 => Ok(ValueType::Merge),
+            _ => Err(anyhow::anyhow!("invalid value type {value}")),
+        }
+    }
+}
+
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Set in the value type of an encoded [`Entry`] if the value length is a u32 instead of a u16,
+/// which is how entries were encoded before.
+pub const LONG_VALUE: u8 = 1 << 7;
+
+const SIZEOF_EXPIRE_AT: usize = std::mem::size_of::<u64>();
+
+/// Split an encoded value of `ValueType::PutWithExpiry` into the value and its expiry time in
+/// milliseconds since the UNIX epoch, which is a little-endian u64 suffix.
+///
+/// A value too short to have the suffix is corrupted, it's taken as already expired.
+pub fn split_expire_at(value: &[u8]) -> (&[u8], u64) {
+    match value.len().checked_sub(SIZEOF_EXPIRE_AT) {
+        Some(len) => {
+            let (value, suffix) = value.split_at(len);
+            (value, u64::from_le_bytes(suffix.try_into().unwrap()))
+        }
+        None => (value, 0),
     }
 }
 
 pub struct Entry {
     key: Bytes,
+    value_type: u8,
     value: Bytes,
 }
 
@@ -65,22 +254,54 @@ impl Entry {
     pub fn new(key: &[u8], value: &[u8]) -> Self {
         Entry {
             key: Bytes::copy_from_slice(key),
+            value_type: ValueType::Put as u8,
             value: Bytes::copy_from_slice(value),
         }
     }
 
-    pub fn encode(self) -> Bytes {
-        let klen = self.key.len();
-        let vlen = self.value.len();
-        let mut buf = BytesMut::with_capacity(SIZEOF_U16 + klen + SIZEOF_U16 + vlen);
-        buf.put_u16(klen as u16);
+    pub fn with_expiry(key: &[u8], value: &[u8], expire_at: u64) -> Self {
+        let mut encoded = BytesMut::with_capacity(value.len() + SIZEOF_EXPIRE_AT);
+        encoded.put(value);
+        encoded.put_u64_le(expire_at);
+        Entry {
+            key: Bytes::copy_from_slice(key),
+            value_type: ValueType::PutWithExpiry as u8,
+            value: encoded.freeze(),
+        }
+    }
+
+    pub fn tombstone(key: &[u8]) -> Self {
+        Entry {
+            key: Bytes::copy_from_slice(key),
+            value_type: ValueType::Delete as u8,
+            value: Bytes::new(),
+        }
+    }
+
+    pub fn merge(key: &[u8], operands: &[u8]) -> Self {
+        Entry {
+            key: Bytes::copy_from_slice(key),
+            value_type: ValueType::Merge as u8,
+            value: Bytes::copy_from_slice(operands),
+        }
+    }
+
+    // |key_len(u16)|key|value_type(u8) | LONG_VALUE|value_len(u32)|value|
+    pub fn encode(self) -> Result<Bytes> {
+        let key_len = u16::try_from(self.key.len())
+            .map_err(|_| anyhow!("key length {} overflows u16", self.key.len()))?;
+        let value_len = u32::try_from(self.value.len())
+            .map_err(|_| anyhow!("value length {} overflows u32", self.value.len()))?;
+        let mut buf = BytesMut::with_capacity(self.encode_len());
+        buf.put_u16(key_len);
         buf.put(self.key);
-        buf.put_u16(vlen as u16);
+        buf.put_u8(self.value_type | LONG_VALUE);
+        buf.put_u32(value_len);
         buf.put(self.value);
-        buf.freeze()
+        Ok(buf.freeze())
     }
 
     pub fn encode_len(&self) -> usize {
-        SIZEOF_U16 + self.key.len() + SIZEOF_U16 + self.value.len()
+        SIZEOF_U16 + self.key.len() + 1 + SIZEOF_U32 + self.value.len()
     }
 }
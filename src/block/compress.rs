@@ -27,11 +27,17 @@ Found 2 outliers among 100 measurements (2.00%)
 
 // may support more compression methods?
 #[derive(PartialEq, Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum CompressOptions {
     Unkown = 0,
     Uncompress = 1,
     Snappy = 2,
     Lz4 = 3,
+    Zstd = 4,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -44,6 +50,7 @@ impl fmt::Display for CompressOptions {
 impl From<u8> for CompressOptions {
     fn from(value: u8) -> Self {
         match value {
+            4 => CompressOptions::Zstd,
             3 => CompressOptions::Lz4,
             2 => CompressOptions::Snappy,
             1 => CompressOptions::Uncompress,
@@ -59,6 +66,7 @@ impl From<CompressOptions> for u8 {
             CompressOptions::Uncompress => 1,
             CompressOptions::Snappy => 2,
             CompressOptions::Lz4 => 3,
+            CompressOptions::Zstd => 4,
         }
     }
 }
@@ -70,16 +78,37 @@ fn snappy_encode(data: &[u8]) -> Result<Bytes> {
     Ok(data.into())
 }
 
-fn lz4_encode(data: &[u8]) -> Result<Bytes> {
-    let mut data = lz4::block::compress(data, None, true)?;
+fn lz4_encode(data: &[u8], level: i32) -> Result<Bytes> {
+    let mode = match level {
+        0 => None,
+        level if level > 0 => Some(lz4::block::CompressionMode::HIGHCOMPRESSION(level)),
+        level => Some(lz4::block::CompressionMode::FAST(-level)),
+    };
+    let mut data = lz4::block::compress(data, mode, true)?;
     data.push(CompressOptions::Lz4.into());
     Ok(data.into())
 }
 
+fn zstd_encode(data: &[u8], level: i32) -> Result<Bytes> {
+    let mut data = zstd::bulk::compress(data, level)?;
+    data.push(CompressOptions::Zstd.into());
+    Ok(data.into())
+}
+
+fn zstd_decode(data: &[u8]) -> Result<BytesMut> {
+    let uncompressed = zstd::stream::decode_all(data)?;
+    Ok(BytesMut::from(uncompressed.as_slice()))
+}
+
 /// return compressed data
 ///
+/// `level` 0 is the codec's default, otherwise:
+/// - Lz4: 1..=12 uses the high compression mode, < 0 uses the fast mode with acceleration -level
+/// - Zstd: 1..=22, < 0 for the fast levels
+/// - Snappy and Uncompress ignore it
+///
 /// Error: buf is too big or too small or Unkown compress option
-pub fn encode(data: &[u8], opt: CompressOptions) -> Result<Bytes> {
+pub fn encode(data: &[u8], opt: CompressOptions, level: i32) -> Result<Bytes> {
     match opt {
         CompressOptions::Unkown => Err(anyhow::anyhow!("unkown compress option")),
         CompressOptions::Uncompress => {
@@ -88,7 +117,8 @@ pub fn encode(data: &[u8], opt: CompressOptions) -> Result<Bytes> {
             Ok(buf.freeze())
         }
         CompressOptions::Snappy => snappy_encode(data),
-        CompressOptions::Lz4 => lz4_encode(data),
+        CompressOptions::Lz4 => lz4_encode(data, level),
+        CompressOptions::Zstd => zstd_encode(data, level),
     }
 }
 
@@ -109,12 +139,14 @@ pub fn decode(data: &[u8]) -> Result<BytesMut> {
             let uncompressed = lz4::block::decompress(data, None)?;
             Ok(BytesMut::from(uncompressed.as_slice()))
         }
+        CompressOptions::Zstd => zstd_decode(data),
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::block::{compress::CompressOptions, BlockBuilder};
+    use crate::checksum::ChecksumType;
 
     use super::{decode, encode};
 
@@ -128,7 +160,7 @@ mod test {
     #[test]
     fn test_empty_data() {
         let str = b"";
-        assert!(encode(str, CompressOptions::Unkown).is_err());
+        assert!(encode(str, CompressOptions::Unkown, 0).is_err());
         assert!(decode(str).is_err());
     }
 
@@ -136,16 +168,21 @@ mod test {
     fn test_snappy() {
         let mut builder = BlockBuilder::new(2048);
         for i in 0..100 {
-            if !builder.add(
-                format!("key_{}", i).as_bytes(),
-                format!("value_{}", i).as_bytes(),
-            ) {
+            if !builder
+                .add(
+                    format!("key_{}", i).as_bytes(),
+                    format!("value_{}", i).as_bytes(),
+                )
+                .unwrap()
+            {
                 break;
             }
         }
         let block = builder.build();
         let uncompress_size = block.uncompress_size();
-        let compressed = block.encode(CompressOptions::Snappy).unwrap();
+        let compressed = block
+            .encode(CompressOptions::Snappy, 0, ChecksumType::Crc32)
+            .unwrap();
         println!(
             "uncompress_size: {uncompress_size}, snappy: {}",
             compressed.len()
@@ -157,16 +194,21 @@ mod test {
     fn test_lz4() {
         let mut builder = BlockBuilder::new(2048);
         for i in 0..100 {
-            if !builder.add(
-                format!("key_{}", i).as_bytes(),
-                format!("value_{}", i).as_bytes(),
-            ) {
+            if !builder
+                .add(
+                    format!("key_{}", i).as_bytes(),
+                    format!("value_{}", i).as_bytes(),
+                )
+                .unwrap()
+            {
                 break;
             }
         }
         let block = builder.build();
         let uncompress_size = block.uncompress_size();
-        let compressed = block.encode(CompressOptions::Lz4).unwrap();
+        let compressed = block
+            .encode(CompressOptions::Lz4, 0, ChecksumType::Crc32)
+            .unwrap();
         println!(
             "uncompress_size: {uncompress_size}, lz4: {}",
             compressed.len()
@@ -174,10 +216,36 @@ mod test {
         assert!(uncompress_size - compressed.len() > uncompress_size / 10)
     }
 
+    #[test]
+    fn test_zstd() {
+        let mut builder = BlockBuilder::new(2048);
+        for i in 0..100 {
+            if !builder
+                .add(
+                    format!("key_{}", i).as_bytes(),
+                    format!("value_{}", i).as_bytes(),
+                )
+                .unwrap()
+            {
+                break;
+            }
+        }
+        let block = builder.build();
+        let uncompress_size = block.uncompress_size();
+        let compressed = block
+            .encode(CompressOptions::Zstd, 0, ChecksumType::Crc32)
+            .unwrap();
+        println!(
+            "uncompress_size: {uncompress_size}, zstd: {}",
+            compressed.len()
+        );
+        assert!(uncompress_size - compressed.len() > uncompress_size / 10)
+    }
+
     #[test]
     fn test_compress_and_uncompress_snap() {
         let str = b"a simple string";
-        let compressed = encode(str, CompressOptions::Snappy).unwrap();
+        let compressed = encode(str, CompressOptions::Snappy, 0).unwrap();
         let uncompressed = decode(&compressed).unwrap();
         assert_eq!(str[..], uncompressed);
     }
@@ -185,8 +253,49 @@ mod test {
     #[test]
     fn test_compress_and_uncompress_lz4() {
         let str = b"a simple string";
-        let compressed = encode(str, CompressOptions::Lz4).unwrap();
+        let compressed = encode(str, CompressOptions::Lz4, 0).unwrap();
+        let uncompressed = decode(&compressed).unwrap();
+        assert_eq!(str[..], uncompressed);
+    }
+
+    #[test]
+    fn test_compress_and_uncompress_zstd() {
+        let str = b"a simple string";
+        let compressed = encode(str, CompressOptions::Zstd, 0).unwrap();
         let uncompressed = decode(&compressed).unwrap();
         assert_eq!(str[..], uncompressed);
     }
+
+    #[test]
+    fn test_compress_level() {
+        let mut builder = BlockBuilder::new(4096);
+        for i in 0..200 {
+            if !builder
+                .add(
+                    format!("key_{}", i).as_bytes(),
+                    format!("value_{}", i % 7).repeat(3).as_bytes(),
+                )
+                .unwrap()
+            {
+                break;
+            }
+        }
+        let block = builder.build();
+        for (opt, low, high) in [
+            (CompressOptions::Lz4, -10, 12),
+            (CompressOptions::Lz4, 0, 12),
+            (CompressOptions::Zstd, 1, 19),
+            (CompressOptions::Zstd, 0, 19),
+        ] {
+            let low = block.encode(opt, low, ChecksumType::Crc32).unwrap();
+            let high = block.encode(opt, high, ChecksumType::Crc32).unwrap();
+            assert!(
+                high.len() <= low.len(),
+                "{opt}: {} > {}",
+                high.len(),
+                low.len()
+            );
+            assert_eq!(decode(&low).unwrap(), decode(&high).unwrap());
+        }
+    }
 }
@@ -1,8 +1,9 @@
+use std::cmp::Ordering;
 use std::sync::Arc;
 
-use bytes::Buf;
-
-use super::Block;
+use super::{split_expire_at, Block, ValueType};
+use crate::opt::Comparator;
+use crate::util::is_expired;
 
 /// Iterates on a block.
 #[derive(Debug)]
@@ -10,7 +11,15 @@ pub struct BlockIterator {
     block: Arc<Block>,
     key: Vec<u8>,
     value: Vec<u8>,
-    idx: usize,
+    tombstone: bool,
+    merge: bool,
+    expire_at: Option<u64>,
+    // index of the restart point at or before the current entry
+    restart: usize,
+    // offset of the current entry
+    offset: usize,
+    // offset of the next entry
+    next: usize,
 }
 
 impl BlockIterator {
@@ -19,7 +28,12 @@ impl BlockIterator {
             block,
             key: Vec::new(),
             value: Vec::new(),
-            idx: 0,
+            tombstone: false,
+            merge: false,
+            expire_at: None,
+            restart: 0,
+            offset: 0,
+            next: 0,
         }
     }
 
@@ -36,6 +50,20 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the last entry.
+    pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_last();
+        iter
+    }
+
+    /// Creates a block iterator and seek to the last key that <= `key`.
+    pub fn create_and_seek_to_key_rev(block: Arc<Block>, key: &[u8]) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_to_key_rev(key);
+        iter
+    }
+
     /// Returns the key of the current entry.
     pub fn key(&self) -> &[u8] {
         &self.key
@@ -46,6 +74,23 @@ impl BlockIterator {
         &self.value
     }
 
+    /// Returns true if the current entry is a tombstone of a deleted key, or its value has
+    /// expired.
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone || self.expire_at.is_some_and(is_expired)
+    }
+
+    /// Returns true if the current value is a list of merge operands.
+    pub fn is_merge(&self) -> bool {
+        self.merge
+    }
+
+    /// Returns the expiry time of the current value in milliseconds since the UNIX epoch, None if
+    /// it never expires.
+    pub fn expire_at(&self) -> Option<u64> {
+        self.expire_at
+    }
+
     /// Returns true if the iterator is valid.
     pub fn is_valid(&self) -> bool {
         !self.key.is_empty()
@@ -53,58 +98,145 @@ impl BlockIterator {
 
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
+        self.seek_to_restart(0);
     }
 
+    /// Seeks to the last key in the block.
     pub fn seek_to_last(&mut self) {
-        self.seek_to(self.block.offsets.len() - 1);
+        self.seek_to_restart(self.block.offsets.len().saturating_sub(1));
+        while self.is_valid() && self.next < self.block.data.len() {
+            self.decode_next();
+        }
     }
 
-    fn seek_to(&mut self, idx: usize) {
+    fn invalidate(&mut self) {
         self.key.clear();
         self.value.clear();
+        self.offset = self.block.data.len();
+        self.next = self.block.data.len();
+    }
 
+    fn seek_to_restart(&mut self, idx: usize) {
         if idx >= self.block.offsets.len() {
-            self.idx = self.block.offsets.len();
-            return;
+            return self.invalidate();
         }
+        self.restart = idx;
+        self.next = self.block.offsets[idx] as usize;
+        self.decode_next();
+    }
 
-        self.idx = idx;
-
-        let offset = self.block.offsets[idx] as usize;
-        let mut buf = &self.block.data[offset..];
-
-        let klen = buf.get_u16() as usize;
-        self.key = buf[..klen].to_vec();
-        buf.advance(klen);
+    /// Decode the entry at `self.next`, whose key is built on the current key.
+    fn decode_next(&mut self) {
+        if self.next >= self.block.data.len() {
+            return self.invalidate();
+        }
+        let block = self.block.clone();
+        let entry = block.entry_at(self.next);
+        if entry.shared == 0 {
+            self.key.clear();
+            self.key.extend_from_slice(&block.prefix);
+        } else {
+            self.key.truncate(block.prefix.len() + entry.shared);
+        }
+        self.key.extend_from_slice(entry.unshared_key);
+        self.value.clear();
+        self.tombstone = entry.value_type == ValueType::Delete;
+        self.merge = entry.value_type == ValueType::Merge;
+        self.expire_at = None;
+        match entry.value_type {
+            ValueType::PutWithExpiry => {
+                let (value, expire_at) = split_expire_at(entry.value);
+                self.value.extend_from_slice(value);
+                self.expire_at = Some(expire_at);
+            }
+            _ => self.value.extend_from_slice(entry.value),
+        }
 
-        let vlen = buf.get_u16() as usize;
-        self.value = buf[..vlen].to_vec();
+        if block
+            .offsets
+            .get(self.restart + 1)
+            .map(|&offset| offset as usize)
+            == Some(self.next)
+        {
+            self.restart += 1;
+        }
+        self.offset = self.next;
+        self.next = entry.next;
     }
 
     /// Move to the next key in the block.
     pub fn next(&mut self) {
-        self.seek_to(self.idx + 1);
+        if !self.is_valid() {
+            return;
+        }
+        self.decode_next();
+    }
+
+    /// Move to the previous key in the block.
+    pub fn prev(&mut self) {
+        if !self.is_valid() {
+            return self.invalidate();
+        }
+        // keys are rebuilt from the restart point before the previous entry
+        let target = self.offset;
+        let mut restart = self.restart;
+        if self.block.offsets[restart] as usize == target {
+            if restart == 0 {
+                return self.invalidate();
+            }
+            restart -= 1;
+        }
+        self.seek_to_restart(restart);
+        while self.next < target {
+            self.decode_next();
+        }
+    }
+
+    /// Seek to the last key that <= `key`.
+    pub fn seek_to_key_rev(&mut self, key: &[u8]) {
+        self.seek_to_key(key);
+        if !self.is_valid() {
+            return self.seek_to_last();
+        }
+        if self.block.comparator().compare(&self.key, key).is_gt() {
+            self.prev();
+        }
     }
 
     /// Seek to the first key that >= `key`.
     pub fn seek_to_key(&mut self, key: &[u8]) {
+        // binary search the last restart point whose key < `key`, then scan from it
+        let comparator = self.block.comparator();
         let mut left = 0;
         let mut right = self.block.offsets.len();
 
         while left < right {
             let mid = (right - left) / 2 + left;
             let offset = self.block.offsets[mid] as usize;
-            let mut buf = &self.block.data[offset..];
-            let klen = buf.get_u16() as usize;
-            let mid_key = &buf[..klen];
-            match mid_key.cmp(key) {
-                std::cmp::Ordering::Greater => right = mid,
-                std::cmp::Ordering::Less => left = mid + 1,
-                std::cmp::Ordering::Equal => return self.seek_to(mid),
+            let mid_key = self.block.entry_at(offset).unshared_key;
+            match cmp_with_prefix(comparator, &self.block.prefix, mid_key, key) {
+                Ordering::Greater => right = mid,
+                Ordering::Less => left = mid + 1,
+                Ordering::Equal => return self.seek_to_restart(mid),
             }
         }
 
-        self.seek_to(left)
+        self.seek_to_restart(left.saturating_sub(1));
+        while self.is_valid() && self.block.comparator().compare(&self.key, key).is_lt() {
+            self.decode_next();
+        }
+    }
+}
+
+/// Compare `prefix + suffix` with `key`, keys only have a prefix in bytewise order.
+fn cmp_with_prefix(
+    comparator: &dyn Comparator,
+    prefix: &[u8],
+    suffix: &[u8],
+    key: &[u8],
+) -> Ordering {
+    if prefix.is_empty() {
+        return comparator.compare(suffix, key);
     }
+    prefix.iter().chain(suffix).cmp(key.iter())
 }
@@ -7,15 +7,26 @@ use super::*;
 #[test]
 fn test_block_build_single_key() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(b"233", b"233333"));
+    assert!(builder.add(b"233", b"233333").unwrap());
     builder.build();
 }
 
 #[test]
 fn test_block_build_full() {
     let mut builder = BlockBuilder::new(16);
-    assert!(builder.add(b"11", b"11"));
-    assert!(!builder.add(b"22", b"22"));
+    assert!(builder.add(b"11", b"11").unwrap());
+    assert!(!builder.add(b"22", b"22").unwrap());
+    builder.build();
+}
+
+#[test]
+fn test_block_build_empty_key() {
+    let mut builder = BlockBuilder::new(16);
+    let err = builder.add(b"", b"11").unwrap_err();
+    assert_eq!(err.to_string(), "key must not be empty");
+    assert!(builder.add_tombstone(b"").is_err());
+    assert!(builder.is_empty());
+    assert!(builder.add(b"11", b"11").unwrap());
     builder.build();
 }
 
@@ -36,7 +47,7 @@ fn generate_block() -> Block {
     for idx in 0..num_of_keys() {
         let key = key_of(idx);
         let value = value_of(idx);
-        assert!(builder.add(&key[..], &value[..]));
+        assert!(builder.add(&key[..], &value[..]).unwrap());
     }
     builder.build()
 }
@@ -49,13 +60,17 @@ fn test_block_build_all() {
 #[test]
 fn test_block_encode() {
     let block = generate_block();
-    block.encode(CompressOptions::Uncompress).unwrap();
+    block
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
 }
 
 #[test]
 fn test_block_decode() {
     let block = generate_block();
-    let encoded = block.encode(CompressOptions::Uncompress).unwrap();
+    let encoded = block
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
     let decoded_block = Block::decode(&encoded).unwrap();
     assert_eq!(block.offsets, decoded_block.offsets);
     assert_eq!(block.data, decoded_block.data);
@@ -121,3 +136,389 @@ fn test_block_seek_key() {
         iter.seek_to_key(b"k");
     }
 }
+
+#[test]
+fn test_block_iterator_rev() {
+    let block = Arc::new(generate_block());
+    let mut iter = BlockIterator::create_and_seek_to_last(block);
+    for _ in 0..5 {
+        for i in (0..num_of_keys()).rev() {
+            let key = iter.key();
+            let value = iter.value();
+            assert_eq!(
+                key,
+                key_of(i),
+                "expected key: {:?}, actual key: {:?}",
+                as_bytes(&key_of(i)),
+                as_bytes(key)
+            );
+            assert_eq!(
+                value,
+                value_of(i),
+                "expected value: {:?}, actual value: {:?}",
+                as_bytes(&value_of(i)),
+                as_bytes(value)
+            );
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+    }
+}
+
+#[test]
+fn test_block_seek_key_rev() {
+    let block = Arc::new(generate_block());
+    let mut iter = BlockIterator::create_and_seek_to_key_rev(block, &key_of(num_of_keys() - 1));
+    for offset in 1..=5 {
+        for i in (0..num_of_keys()).rev() {
+            let key = iter.key();
+            let value = iter.value();
+            assert_eq!(
+                key,
+                key_of(i),
+                "expected key: {:?}, actual key: {:?}",
+                as_bytes(&key_of(i)),
+                as_bytes(key)
+            );
+            assert_eq!(
+                value,
+                value_of(i),
+                "expected value: {:?}, actual value: {:?}",
+                as_bytes(&value_of(i)),
+                as_bytes(value)
+            );
+            if i > 0 {
+                iter.seek_to_key_rev(&format!("key_{:03}", i * 5 - offset).into_bytes());
+            }
+        }
+        iter.seek_to_key_rev(b"z");
+    }
+    iter.seek_to_key_rev(b"k");
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_decode_and_iter() {
+    let block = generate_block();
+    let encoded = block
+        .encode(CompressOptions::Snappy, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    for i in 0..num_of_keys() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i));
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    let mut iter = Arc::new(generate_block()).iter();
+    assert_eq!(iter.key(), key_of(0));
+    iter.seek_to_last();
+    assert_eq!(iter.key(), key_of(num_of_keys() - 1));
+}
+
+#[test]
+fn test_block_tombstone() {
+    let mut builder = BlockBuilder::new(10000);
+    assert!(builder.add(b"a", b"").unwrap());
+    assert!(builder.add_tombstone(b"b").unwrap());
+    assert!(builder.add(b"c", b"1").unwrap());
+    let encoded = builder
+        .build()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    for (key, value, tombstone) in [
+        (b"a", &b""[..], false),
+        (b"b", b"", true),
+        (b"c", b"1", false),
+    ] {
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.value(), value);
+        assert_eq!(iter.is_tombstone(), tombstone);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_expiry() {
+    let mut builder = BlockBuilder::new(10000);
+    assert!(builder.add_with_expiry(b"key_a", b"1", 0).unwrap());
+    assert!(builder.add(b"key_b", b"2").unwrap());
+    assert!(builder.add_with_expiry(b"key_c", b"3", u64::MAX).unwrap());
+    let encoded = builder
+        .build_strip_prefix(4)
+        .unwrap()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let block = Block::decode(&encoded)
+        .unwrap()
+        .with_prefix(Bytes::from("key_"));
+    let mut iter = Arc::new(block).iter();
+    // an expired value is a tombstone
+    for (key, value, expire_at, tombstone) in [
+        (b"key_a", b"1", Some(0), true),
+        (b"key_b", b"2", None, false),
+        (b"key_c", b"3", Some(u64::MAX), false),
+    ] {
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.value(), value);
+        assert_eq!(iter.expire_at(), expire_at);
+        assert_eq!(iter.is_tombstone(), tombstone);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_decode_untagged() {
+    // |key_len|key|value_len|value| written before entries are tagged
+    let mut data = BytesMut::new();
+    let mut offsets = vec![];
+    for (key, value) in [(&b"a"[..], &b"1"[..]), (b"b", b"")] {
+        offsets.push(data.len() as u16);
+        data.put_u16(key.len() as u16);
+        data.put(key);
+        data.put_u16(value.len() as u16);
+        data.put(value);
+    }
+    let block = Block {
+        data: data.freeze(),
+        offsets,
+        prefix: Bytes::new(),
+        format: Format::Untagged,
+        comparator: None,
+    };
+    let encoded = block
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    assert_eq!(iter.key(), b"a");
+    assert_eq!(iter.value(), b"1");
+    assert!(!iter.is_tombstone());
+    iter.next();
+    // an empty value was a tombstone
+    assert_eq!(iter.key(), b"b");
+    assert!(iter.is_tombstone());
+}
+
+#[test]
+fn test_block_decode_prefix_compressed_u16() {
+    // |shared_len|unshared_len|unshared key|value_type|value_len|value| with u16 lengths
+    let mut data = BytesMut::new();
+    for (shared, unshared, value) in [(0, &b"ab"[..], &b"1"[..]), (1, b"c", b"22")] {
+        data.put_u16(shared);
+        data.put_u16(unshared.len() as u16);
+        data.put(unshared);
+        data.put_u8(ValueType::Put as u8);
+        data.put_u16(value.len() as u16);
+        data.put(value);
+    }
+    let block = Block {
+        data: data.freeze(),
+        offsets: vec![0],
+        prefix: Bytes::new(),
+        format: Format::PrefixCompressed,
+        comparator: None,
+    };
+    let encoded = block
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::XxHash3)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    assert_eq!(iter.key(), b"ab");
+    assert_eq!(iter.value(), b"1");
+    iter.next();
+    assert_eq!(iter.key(), b"ac");
+    assert_eq!(iter.value(), b"22");
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_large_value() {
+    let value = vec![b'v'; 100 * 1024];
+    let mut builder = BlockBuilder::new(4096);
+    assert!(builder.add(b"a", &value).unwrap());
+    // the block is full after the value
+    assert!(!builder.add(b"b", b"1").unwrap());
+    let encoded = builder
+        .build()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    assert_eq!(iter.key(), b"a");
+    assert_eq!(iter.value(), value);
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_block_large_values() {
+    let value_of = |idx: usize| vec![b'a' + idx as u8; 40 * 1024];
+    let mut builder = BlockBuilder::with_restart_interval(1 << 20, 4);
+    for idx in 0..4 {
+        assert!(builder.add(&key_of(idx), &value_of(idx)).unwrap());
+    }
+    // the next restart point is past what a u16 offset holds
+    assert!(!builder.add(&key_of(4), &value_of(4)).unwrap());
+    let encoded = builder
+        .build()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    for idx in 0..4 {
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    iter.seek_to_last();
+    for idx in (0..4).rev() {
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.prev();
+    }
+    assert!(!iter.is_valid());
+    iter.seek_to_key(&key_of(2));
+    assert_eq!(iter.value(), value_of(2));
+}
+
+#[test]
+fn test_block_prefix_compression() {
+    let block = generate_block();
+    // every entry takes a full key and an offset without prefix compression
+    let full_size = SIZEOF_U16
+        + (0..num_of_keys())
+            .map(|idx| Entry::new(&key_of(idx), &value_of(idx)).encode_len() + SIZEOF_U16)
+            .sum::<usize>();
+    assert!(block.uncompress_size() < full_size);
+
+    let mut builder = BlockBuilder::new(10000);
+    for idx in 0..num_of_keys() {
+        assert!(builder.add(&key_of(idx), &value_of(idx)).unwrap());
+    }
+    assert_eq!(builder.common_prefix(), b"key_");
+    let encoded = builder
+        .build_strip_prefix(4)
+        .unwrap()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let block = Arc::new(
+        Block::decode(&encoded)
+            .unwrap()
+            .with_prefix(Bytes::from("key_")),
+    );
+    for idx in 0..num_of_keys() {
+        let iter = BlockIterator::create_and_seek_to_key(block.clone(), &key_of(idx));
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        // between two keys
+        let mut key = key_of(idx);
+        key.push(0);
+        let mut iter = BlockIterator::create_and_seek_to_key_rev(block.clone(), &key);
+        assert_eq!(iter.key(), key_of(idx));
+        iter.prev();
+        assert_eq!(iter.is_valid(), idx > 0);
+        if idx > 0 {
+            assert_eq!(iter.key(), key_of(idx - 1));
+        }
+    }
+}
+
+#[test]
+fn test_block_checksum_type() {
+    let block = generate_block();
+    for checksum_type in [ChecksumType::Crc32, ChecksumType::XxHash3] {
+        let encoded = block
+            .encode(CompressOptions::Uncompress, 0, checksum_type)
+            .unwrap();
+        let decoded = Block::decode(&encoded).unwrap();
+        assert_eq!(block.offsets, decoded.offsets);
+        assert_eq!(block.data, decoded.data);
+
+        // decoding with the other checksum type fails
+        let mut encoded = encoded.to_vec();
+        encoded[0] ^= (XXHASH3 >> 8) as u8;
+        assert!(Block::decode(&encoded).is_err());
+    }
+
+    // only prefix compressed blocks may use xxh3
+    let legacy = Block {
+        format: Format::Tagged,
+        ..generate_block()
+    };
+    assert!(legacy
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::XxHash3)
+        .is_err());
+}
+
+#[test]
+fn test_block_restart_interval() {
+    let mut sizes = vec![];
+    for interval in [1, 4, 16, 1000] {
+        let mut builder = BlockBuilder::with_restart_interval(10000, interval);
+        for idx in 0..num_of_keys() {
+            assert!(builder.add(&key_of(idx), &value_of(idx)).unwrap());
+        }
+        let block = builder.build();
+        assert_eq!(block.offsets.len(), num_of_keys().div_ceil(interval));
+        let encoded = block
+            .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+            .unwrap();
+        sizes.push(encoded.len());
+
+        let block = Arc::new(Block::decode(&encoded).unwrap());
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for idx in 0..num_of_keys() {
+            assert_eq!(iter.key(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        for idx in (0..num_of_keys()).rev() {
+            assert_eq!(iter.key(), key_of(idx));
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+
+        for idx in 0..num_of_keys() {
+            iter.seek_to_key(&key_of(idx));
+            assert_eq!(iter.key(), key_of(idx));
+            assert_eq!(iter.value(), value_of(idx));
+            // between two keys
+            let mut key = key_of(idx);
+            key.push(0);
+            iter.seek_to_key(&key);
+            assert_eq!(iter.is_valid(), idx + 1 < num_of_keys());
+            iter.seek_to_key_rev(&key);
+            assert_eq!(iter.key(), key_of(idx));
+        }
+    }
+    // keys between restart points share their prefixes with the previous keys
+    assert!(sizes.windows(2).all(|w| w[0] > w[1]), "{sizes:?}");
+}
+
+#[test]
+fn test_block_max_restarts() {
+    // a restart point per entry, more entries than the number of restart points can count
+    let mut builder = BlockBuilder::with_restart_interval(60000, 1);
+    let mut num = 0;
+    while builder.add(&(num as u16).to_be_bytes(), b"").unwrap() {
+        num += 1;
+    }
+    assert_eq!(num, VARINT as usize - 1);
+    let encoded = builder
+        .build()
+        .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+        .unwrap();
+    let mut iter = Block::decode_and_iter(&encoded).unwrap();
+    for idx in 0..num {
+        assert_eq!(iter.key(), (idx as u16).to_be_bytes());
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
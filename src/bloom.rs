@@ -47,6 +47,10 @@ impl Bloom {
 
     pub fn from_keys(keys: &[u64], fpp: f64) -> Self {
         assert!((0.0..1.0).contains(&fpp));
+        // k = 0 means the filter contains nothing
+        if keys.is_empty() {
+            return Self { filter: vec![0] };
+        }
         let n = keys.len() as f64;
         let m = -(n * fpp.ln()) / std::f64::consts::LN_2.powi(2);
 
@@ -71,7 +75,10 @@ impl Bloom {
 
     pub fn may_contain(&self, mut h: u64) -> bool {
         let delta = Self::delta(h);
-        let k = *self.filter.last().unwrap();
+        let k = match self.filter.last() {
+            Some(&k) if k > 0 && self.filter.len() > 1 => k,
+            _ => return false,
+        };
         let limit = (self.filter.len() - 1) * 8;
         for _ in 0..k {
             let bit_pos = (h as usize) % limit;
@@ -141,6 +148,26 @@ mod tests {
         assert!(!bloom.may_contain(check_hash[3]));
     }
 
+    #[test]
+    fn test_empty_bloom_filter() {
+        let bloom = Bloom::from_keys(&[], 0.01);
+        assert!(!bloom.may_contain(xxh3::xxh3_64(b"key1")));
+        assert!(!bloom.may_contain(0));
+        let bloom = Bloom::decode(&bloom.encode());
+        assert!(!bloom.may_contain(xxh3::xxh3_64(b"key1")));
+        assert!(!Bloom::decode(&[]).may_contain(0));
+    }
+
+    #[test]
+    fn test_one_key_bloom_filter() {
+        let hash = xxh3::xxh3_64(b"key1");
+        let bloom = Bloom::from_keys(&[hash], 0.01);
+        assert!(bloom.may_contain(hash));
+        assert!(!bloom.may_contain(xxh3::xxh3_64(b"key2")));
+        let bloom = Bloom::decode(&bloom.encode());
+        assert!(bloom.may_contain(hash));
+    }
+
     #[test]
     fn test_fpp_bloom_filter() {
         let hash: Vec<_> = (0..1000)
@@ -1,16 +1,93 @@
 use anyhow::{Ok, Result};
 use crc32fast::Hasher;
+use xxhash_rust::xxh3::Xxh3;
 
 pub const CHECKSUM_SIZE: usize = 4;
 
+/// The algorithm of checksums, which is stored with the checksum.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ChecksumType {
+    #[default]
+    Crc32 = 0,
+    /// The lower 32 bits of xxh3.
+    XxHash3 = 1,
+}
+
+impl TryFrom<u8> for ChecksumType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(ChecksumType::Crc32),
+            1 => Ok(ChecksumType::XxHash3),
+            _ => Err(anyhow::anyhow!("unknown checksum type {value}")),
+        }
+    }
+}
+
+impl ChecksumType {
+    pub fn calculate(self, data: &[u8]) -> u32 {
+        match self {
+            ChecksumType::Crc32 => {
+                let mut hasher = Hasher::new();
+                hasher.update(data);
+                hasher.finalize()
+            }
+            ChecksumType::XxHash3 => xxhash_rust::xxh3::xxh3_64(data) as u32,
+        }
+    }
+
+    pub fn verify(self, data: &[u8], expected: u32) -> Result<()> {
+        compare_checksum(self.calculate(data), expected)
+    }
+
+    /// Create a hasher to calculate the checksum of data fed in chunks.
+    pub fn hasher(self) -> ChecksumHasher {
+        match self {
+            ChecksumType::Crc32 => ChecksumHasher::Crc32(Hasher::new()),
+            ChecksumType::XxHash3 => ChecksumHasher::XxHash3(Box::new(Xxh3::new())),
+        }
+    }
+}
+
+/// Calculates the checksum of data fed in chunks.
+pub enum ChecksumHasher {
+    Crc32(Hasher),
+    XxHash3(Box<Xxh3>),
+}
+
+impl ChecksumHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ChecksumHasher::Crc32(hasher) => hasher.update(data),
+            ChecksumHasher::XxHash3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> u32 {
+        match self {
+            ChecksumHasher::Crc32(hasher) => hasher.finalize(),
+            ChecksumHasher::XxHash3(hasher) => hasher.digest() as u32,
+        }
+    }
+}
+
 pub fn calculate_checksum(data: &[u8]) -> u32 {
-    let mut hasher = Hasher::new();
-    hasher.update(data);
-    hasher.finalize()
+    ChecksumType::Crc32.calculate(data)
 }
 
 pub fn verify_checksum(data: &[u8], expected: u32) -> Result<()> {
-    let actual = calculate_checksum(data);
+    ChecksumType::Crc32.verify(data, expected)
+}
+
+/// Compare a checksum calculated elsewhere, e.g. by a `Hasher` fed in chunks.
+pub fn compare_checksum(actual: u32, expected: u32) -> Result<()> {
     if expected == actual {
         return Ok(());
     }
@@ -22,7 +99,7 @@ pub fn verify_checksum(data: &[u8], expected: u32) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-    use super::{calculate_checksum, verify_checksum};
+    use super::{calculate_checksum, verify_checksum, ChecksumType};
 
     #[test]
     fn simple_test() {
@@ -31,4 +108,25 @@ mod test {
         verify_checksum(data, checksum).unwrap();
         assert!(verify_checksum(data, 123).is_err())
     }
+
+    #[test]
+    fn checksum_type_test() {
+        let data = &b"12312nskjdhsdi9823r1y3r9"[..];
+        for (ty, other) in [
+            (ChecksumType::Crc32, ChecksumType::XxHash3),
+            (ChecksumType::XxHash3, ChecksumType::Crc32),
+        ] {
+            let checksum = ty.calculate(data);
+            ty.verify(data, checksum).unwrap();
+            assert!(other.verify(data, checksum).is_err());
+            assert_eq!(ChecksumType::try_from(ty as u8).unwrap(), ty);
+
+            let mut hasher = ty.hasher();
+            for chunk in data.chunks(5) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), checksum);
+        }
+        assert!(ChecksumType::try_from(2).is_err());
+    }
 }
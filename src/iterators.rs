@@ -1,6 +1,10 @@
+pub mod loser_tree_iterator;
 pub mod merge_iterator;
+pub mod shadowed_iterator;
 pub mod two_merge_iterator;
 
+use crate::opt::{BytewiseComparator, Comparator};
+
 pub trait StorageIterator {
     /// Get the current value.
     fn value(&self) -> &[u8];
@@ -8,11 +12,45 @@ pub trait StorageIterator {
     /// Get the current key.
     fn key(&self) -> &[u8];
 
+    /// Check if the current entry is a tombstone of a deleted key. Iterators returned to users
+    /// skip tombstones.
+    fn is_tombstone(&self) -> bool {
+        false
+    }
+
+    /// Check if the current value is a list of merge operands, which are combined with older
+    /// values of the key, see [`crate::merge`].
+    fn is_merge(&self) -> bool {
+        false
+    }
+
+    /// Get the expiry time of the current value in milliseconds since the UNIX epoch, None if it
+    /// never expires. An expired value is a tombstone.
+    fn expire_at(&self) -> Option<u64> {
+        None
+    }
+
+    /// Get the order of the keys. Iterators over other iterators take it from them, so merging
+    /// iterators compare keys by the comparator of the tables.
+    fn comparator(&self) -> &dyn Comparator {
+        &BytewiseComparator
+    }
+
     /// Check if the current iterator is valid.
     fn is_valid(&self) -> bool;
 
     /// Move to the next position.
     fn next(&mut self) -> anyhow::Result<()>;
+
+    /// Move to the previous position.
+    fn prev(&mut self) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("prev is unsupported"))
+    }
+
+    /// Move to the first key which >= `key`.
+    fn seek(&mut self, _key: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("seek is unsupported"))
+    }
 }
 
 #[cfg(test)]
@@ -5,9 +5,12 @@ use std::collections::BinaryHeap;
 use anyhow::Result;
 
 use super::StorageIterator;
+use crate::opt::{BytewiseComparator, Comparator};
 
 // note: use '>' to compare priority, not fields
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+// The third field tells the direction: the smallest key has the highest priority when moving
+// forward, and the biggest one when moving backward.
+struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>, pub bool);
 
 #[cfg(not(tarpaulin_include))]
 impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
@@ -20,12 +23,12 @@ impl<I: StorageIterator> Eq for HeapWrapper<I> {}
 
 impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        match self.1.key().cmp(other.1.key()) {
-            cmp::Ordering::Greater => Some(cmp::Ordering::Greater),
-            cmp::Ordering::Less => Some(cmp::Ordering::Less),
-            cmp::Ordering::Equal => self.0.partial_cmp(&other.0),
-        }
-        .map(|x| x.reverse())
+        let comparator = self.1.comparator();
+        let key_order = match self.2 {
+            true => comparator.compare(self.1.key(), other.1.key()),
+            false => comparator.compare(other.1.key(), self.1.key()),
+        };
+        Some(key_order.then_with(|| other.0.cmp(&self.0)))
     }
 }
 
@@ -37,61 +40,89 @@ impl<I: StorageIterator> Ord for HeapWrapper<I> {
 }
 
 /// Merge multiple iterators of the same type. If the same key occurs multiple times in some
-/// iterators, perfer the one with smaller index.
+/// iterators, prefer the one with smaller index.
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// Invalid iterators, kept to be reused by `seek`.
+    exhausted: Vec<HeapWrapper<I>>,
+    rev: bool,
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
     pub fn create(iters: Vec<Box<I>>) -> Self {
-        let mut iters = iters
+        Self::create_inner(iters, false)
+    }
+
+    /// Create a merge iterator moving backward by `prev`, all `iters` should be positioned at
+    /// their last entries.
+    pub fn create_rev(iters: Vec<Box<I>>) -> Self {
+        Self::create_inner(iters, true)
+    }
+
+    fn create_inner(iters: Vec<Box<I>>, rev: bool) -> Self {
+        let (iters, exhausted): (Vec<_>, Vec<_>) = iters
             .into_iter()
-            .filter(|x| x.is_valid())
             .enumerate()
-            .map(|(id, b)| HeapWrapper(id, b))
-            .collect::<BinaryHeap<_>>();
-        // assert!(!iters.is_empty(), "iters is invalid");
+            .map(|(id, b)| HeapWrapper(id, b, rev))
+            .partition(|x| x.1.is_valid());
+        let mut iters = BinaryHeap::from(iters);
+        // invalid if all `iters` are invalid
         let current = iters.pop();
-        Self { iters, current }
-    }
-}
-
-impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
-    fn key(&self) -> &[u8] {
-        self.current.as_ref().unwrap().1.key()
+        Self {
+            iters,
+            current,
+            exhausted,
+            rev,
+        }
     }
 
-    fn value(&self) -> &[u8] {
-        self.current.as_ref().unwrap().1.value()
+    fn step(iter: &mut I, rev: bool) -> Result<()> {
+        match rev {
+            true => iter.prev(),
+            false => iter.next(),
+        }
     }
 
-    fn is_valid(&self) -> bool {
-        self.current.is_some()
+    /// Get the iterator of the current entry. Entries of an invalid merge iterator must not be
+    /// accessed, check `is_valid` first.
+    fn current(&self) -> &I {
+        &self
+            .current
+            .as_ref()
+            .expect("access to an invalid merge iterator")
+            .1
     }
 
-    fn next(&mut self) -> Result<()> {
-        let key = self.key().to_vec();
+    fn advance(&mut self) -> Result<()> {
+        if !self.is_valid() {
+            return Ok(());
+        }
+        let rev = self.rev;
+        // the current iterator doesn't move until the duplicates are skipped, so its key is
+        // borrowed instead of copied
+        let key = self.current.as_ref().unwrap().1.key();
 
         while let Some(mut inner) = self.iters.peek_mut() {
             if key != inner.1.key() {
                 break;
             }
-            if let e @ Err(_) = inner.1.next() {
-                PeekMut::pop(inner);
+            if let e @ Err(_) = Self::step(&mut inner.1, rev) {
+                self.exhausted.push(PeekMut::pop(inner));
                 return e;
             }
 
             if !inner.1.is_valid() {
-                PeekMut::pop(inner);
+                self.exhausted.push(PeekMut::pop(inner));
             }
         }
 
         let current = self.current.as_mut().unwrap();
-        current.1.next()?;
+        Self::step(&mut current.1, rev)?;
 
         if !current.1.is_valid() {
-            self.current = self.iters.pop();
+            let current = std::mem::replace(&mut self.current, self.iters.pop());
+            self.exhausted.extend(current);
             return Ok(());
         }
 
@@ -104,3 +135,74 @@ impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
         Ok(())
     }
 }
+
+impl<I: StorageIterator> StorageIterator for MergeIterator<I> {
+    fn key(&self) -> &[u8] {
+        self.current().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current().value()
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.current().is_tombstone()
+    }
+
+    fn is_merge(&self) -> bool {
+        self.current().is_merge()
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        self.current().expire_at()
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        let iter = self.current.iter().chain(self.iters.peek());
+        match iter.chain(self.exhausted.first()).next() {
+            Some(iter) => iter.1.comparator(),
+            None => &BytewiseComparator,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "next is unsupported by a reverse merge iterator"
+            ));
+        }
+        self.advance()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.rev {
+            return Err(anyhow::anyhow!(
+                "prev is unsupported by a forward merge iterator"
+            ));
+        }
+        self.advance()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "seek is unsupported by a reverse merge iterator"
+            ));
+        }
+        let mut iters = std::mem::take(&mut self.exhausted);
+        iters.extend(self.current.take());
+        iters.extend(std::mem::take(&mut self.iters));
+        for iter in iters.iter_mut() {
+            iter.1.seek(key)?;
+        }
+        let (iters, exhausted): (Vec<_>, Vec<_>) = iters.into_iter().partition(|x| x.1.is_valid());
+        self.iters = BinaryHeap::from(iters);
+        self.current = self.iters.pop();
+        self.exhausted = exhausted;
+        Ok(())
+    }
+}
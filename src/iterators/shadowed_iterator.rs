@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use super::StorageIterator;
+use crate::opt::Comparator;
+use crate::range_tombstone::RangeTombstones;
+
+/// Skips the keys of an iterator which are deleted by range tombstones of newer tables.
+pub struct ShadowedIterator<I: StorageIterator> {
+    iter: I,
+    shadow: Arc<RangeTombstones>,
+    rev: bool,
+}
+
+impl<I: StorageIterator> ShadowedIterator<I> {
+    pub fn create(iter: I, shadow: Arc<RangeTombstones>) -> Result<Self> {
+        Self::create_inner(iter, shadow, false)
+    }
+
+    /// Create an iterator moving backward by `prev`.
+    pub fn create_rev(iter: I, shadow: Arc<RangeTombstones>) -> Result<Self> {
+        Self::create_inner(iter, shadow, true)
+    }
+
+    fn create_inner(iter: I, shadow: Arc<RangeTombstones>, rev: bool) -> Result<Self> {
+        let mut iter = Self { iter, shadow, rev };
+        iter.skip_shadowed()?;
+        Ok(iter)
+    }
+
+    fn skip_shadowed(&mut self) -> Result<()> {
+        while self.iter.is_valid() {
+            let end = match self.shadow.covering(self.iter.key()) {
+                Some(range) => &range.end,
+                None => break,
+            };
+            if self.rev {
+                self.iter.prev()?;
+            } else {
+                // the smallest key > end
+                let mut key = end.to_vec();
+                key.push(0);
+                self.iter.seek(&key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for ShadowedIterator<I> {
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.iter.is_tombstone()
+    }
+
+    fn is_merge(&self) -> bool {
+        self.iter.is_merge()
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        self.iter.expire_at()
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.iter.comparator()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_shadowed()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.iter.prev()?;
+        self.skip_shadowed()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek(key)?;
+        self.skip_shadowed()
+    }
+}
@@ -3,6 +3,7 @@ use bytes::Bytes;
 
 use super::StorageIterator;
 
+pub mod loser_tree_iterator_test;
 pub mod merge_iterator_test;
 pub mod two_merge_iterator_test;
 
@@ -16,6 +17,12 @@ impl MockIterator {
     pub fn new(data: Vec<(Bytes, Bytes)>) -> Self {
         Self { data, index: 0 }
     }
+
+    /// Create an iterator positioned at the last entry.
+    pub fn new_rev(data: Vec<(Bytes, Bytes)>) -> Self {
+        let index = data.len().saturating_sub(1);
+        Self { data, index }
+    }
 }
 
 impl StorageIterator for MockIterator {
@@ -26,6 +33,15 @@ impl StorageIterator for MockIterator {
         Ok(())
     }
 
+    fn prev(&mut self) -> Result<()> {
+        if self.index == 0 {
+            self.index = self.data.len();
+        } else if self.index < self.data.len() {
+            self.index -= 1;
+        }
+        Ok(())
+    }
+
     fn key(&self) -> &[u8] {
         self.data[self.index].0.as_ref()
     }
@@ -0,0 +1,99 @@
+use super::*;
+use crate::iterators::loser_tree_iterator::LoserTreeIterator;
+use crate::iterators::merge_iterator::MergeIterator;
+
+fn key_of(idx: usize) -> Bytes {
+    Bytes::from(format!("key_{:05}", idx * 5))
+}
+
+/// `num` iterators overlapping each other, the values tell which iterator an entry comes from.
+fn generate_iters(num: usize) -> Vec<MockIterator> {
+    (0..num)
+        .map(|id| {
+            let data = (0..1000)
+                .filter(|idx| (idx * 7 + id) % (id + 2) == 0)
+                .map(|idx| (key_of(idx), Bytes::from(format!("value_{idx}_{id}"))))
+                .collect();
+            MockIterator::new(data)
+        })
+        .collect()
+}
+
+fn collect(mut iter: impl StorageIterator) -> Vec<(Bytes, Bytes)> {
+    let mut entries = vec![];
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    entries
+}
+
+#[allow(clippy::vec_box)]
+fn boxed(iters: &[MockIterator]) -> Vec<Box<MockIterator>> {
+    iters.iter().cloned().map(Box::new).collect()
+}
+
+#[test]
+fn test_loser_tree_prefer_smaller_index() {
+    let i1 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.1")),
+        (Bytes::from("c"), Bytes::from("3.1")),
+    ]);
+    let i2 = MockIterator::new(vec![
+        (Bytes::from("a"), Bytes::from("1.2")),
+        (Bytes::from("b"), Bytes::from("2.2")),
+        (Bytes::from("c"), Bytes::from("3.2")),
+    ]);
+    let i3 = MockIterator::new(vec![
+        (Bytes::from("b"), Bytes::from("2.3")),
+        (Bytes::from("d"), Bytes::from("4.3")),
+    ]);
+
+    let iter = LoserTreeIterator::create(boxed(&[i3.clone(), i1.clone(), i2.clone()]));
+    assert_eq!(
+        collect(iter),
+        vec![
+            (Bytes::from("a"), Bytes::from("1.1")),
+            (Bytes::from("b"), Bytes::from("2.3")),
+            (Bytes::from("c"), Bytes::from("3.1")),
+            (Bytes::from("d"), Bytes::from("4.3")),
+        ]
+    );
+
+    let iter = LoserTreeIterator::create(boxed(&[i2, i3, i1]));
+    assert_eq!(
+        collect(iter),
+        vec![
+            (Bytes::from("a"), Bytes::from("1.2")),
+            (Bytes::from("b"), Bytes::from("2.2")),
+            (Bytes::from("c"), Bytes::from("3.2")),
+            (Bytes::from("d"), Bytes::from("4.3")),
+        ]
+    );
+}
+
+#[test]
+fn test_loser_tree_empty() {
+    let iter = LoserTreeIterator::<MockIterator>::create(vec![]);
+    assert!(!iter.is_valid());
+
+    let iter = LoserTreeIterator::create(boxed(&vec![MockIterator::new(vec![]); 3]));
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_loser_tree_same_as_merge_iterator() {
+    for num in 1..=9 {
+        let iters = generate_iters(num);
+        let expected = collect(MergeIterator::create(boxed(&iters)));
+        assert!(!expected.is_empty());
+        assert_eq!(
+            collect(LoserTreeIterator::create(boxed(&iters))),
+            expected,
+            "{num} iterators"
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 
 use super::StorageIterator;
+use crate::opt::Comparator;
 
 /// Merges two iterators of different types into one. If the two iterators have the same key, only
 /// produce the key once and prefer the entry from A.
@@ -8,20 +9,28 @@ pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
     choose_a: bool,
+    rev: bool,
 }
 
 impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
     pub fn create(a: A, b: B) -> Result<Self> {
+        Self::create_inner(a, b, false)
+    }
+
+    /// Create a merge iterator moving backward by `prev`, both `a` and `b` should be positioned
+    /// at their last entries.
+    pub fn create_rev(a: A, b: B) -> Result<Self> {
+        Self::create_inner(a, b, true)
+    }
+
+    fn create_inner(a: A, b: B, rev: bool) -> Result<Self> {
         let mut iter = Self {
             a,
             b,
             choose_a: false,
+            rev,
         };
-        if iter.a.is_valid() {
-            while iter.b.is_valid() && iter.b.key() == iter.a.key() {
-                iter.b.next()?;
-            }
-        }
+        iter.skip_b()?;
         iter.choose_a = iter.choose_a();
         Ok(iter)
     }
@@ -31,7 +40,27 @@ impl<A: StorageIterator, B: StorageIterator> TwoMergeIterator<A, B> {
             return true;
         }
 
-        self.a.is_valid() && self.a.key() <= self.b.key()
+        if !self.a.is_valid() {
+            return false;
+        }
+        let order = self.a.comparator().compare(self.a.key(), self.b.key());
+        match self.rev {
+            true => order.is_ge(),
+            false => order.is_le(),
+        }
+    }
+
+    /// Skip the entries of B which are shadowed by A.
+    fn skip_b(&mut self) -> Result<()> {
+        if self.a.is_valid() {
+            while self.b.is_valid() && self.b.key() == self.a.key() {
+                match self.rev {
+                    true => self.b.prev()?,
+                    false => self.b.next()?,
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -50,22 +79,81 @@ impl<A: StorageIterator, B: StorageIterator> StorageIterator for TwoMergeIterato
         self.b.value()
     }
 
+    fn is_tombstone(&self) -> bool {
+        if self.choose_a {
+            return self.a.is_tombstone();
+        }
+        self.b.is_tombstone()
+    }
+
+    fn is_merge(&self) -> bool {
+        if self.choose_a {
+            return self.a.is_merge();
+        }
+        self.b.is_merge()
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        if self.choose_a {
+            return self.a.expire_at();
+        }
+        self.b.expire_at()
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        if self.choose_a {
+            return self.a.comparator();
+        }
+        self.b.comparator()
+    }
+
     fn is_valid(&self) -> bool {
         self.a.is_valid() || self.b.is_valid()
     }
 
     fn next(&mut self) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "next is unsupported by a reverse merge iterator"
+            ));
+        }
         if self.choose_a {
             self.a.next()?;
         } else {
             self.b.next()?;
         }
 
-        if self.a.is_valid() {
-            while self.b.is_valid() && self.b.key() == self.a.key() {
-                self.b.next()?;
-            }
+        self.skip_b()?;
+        self.choose_a = self.choose_a();
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.rev {
+            return Err(anyhow::anyhow!(
+                "prev is unsupported by a forward merge iterator"
+            ));
+        }
+        if self.choose_a {
+            self.a.prev()?;
+        } else {
+            self.b.prev()?;
+        }
+
+        self.skip_b()?;
+        self.choose_a = self.choose_a();
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "seek is unsupported by a reverse merge iterator"
+            ));
         }
+        self.a.seek(key)?;
+        self.b.seek(key)?;
+        self.skip_b()?;
         self.choose_a = self.choose_a();
         Ok(())
     }
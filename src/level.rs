@@ -1,52 +1,204 @@
 mod range;
 mod task;
 use std::{
-    collections::HashSet,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    fs,
     ops::Bound,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Ok, Result};
 use bytes::Bytes;
 use crossbeam_channel::{select, tick, unbounded, Receiver};
 use log::{error, info};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 use yatp::task::callback::Handle;
 
 use crate::{
-    block::Block,
-    iterators::{merge_iterator::MergeIterator, StorageIterator},
+    block::{Block, BlockIterator},
+    iterators::{
+        loser_tree_iterator::LoserTreeIterator, merge_iterator::MergeIterator,
+        shadowed_iterator::ShadowedIterator, StorageIterator,
+    },
     level::{
         range::RwsSlice,
         task::{Task, TaskPriority},
     },
     lsm_storage::ThreadPool,
-    manifest::{Change, ManifestChangeSet, ManifestFile},
-    opt::LsmOptions,
+    manifest::{Change, ManifestChangeSet, ManifestFile, DEFAULT_CF},
+    merge::{MergeChain, Record},
+    opt::{FilterDecision, LsmOptions, PathLayout},
+    range_tombstone::{shadows, RangeTombstone, RangeTombstones},
+    rate_limiter::RateLimiter,
     table::{FileObject, SsTable, SsTableBuilder, SsTableIterator},
-    util::sstable_file_path,
+    util::{tmp_file_path, TMP_FILE_EXT},
 };
 
-const MAX_LEVEL: usize = 6;
-pub type BlockCache = moka::sync::Cache<(u64, usize), Arc<Block>>;
+/// Cache of blocks keyed by table id and block index, which counts hits and misses.
+#[derive(Debug)]
+pub struct BlockCache {
+    cache: moka::sync::Cache<(u64, usize), Arc<Block>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` bytes of uncompressed blocks.
+    pub fn new(capacity: u64) -> Self {
+        Self::with_weigher(capacity, |block| {
+            block.uncompress_size().try_into().unwrap_or(u32::MAX)
+        })
+    }
+
+    /// Create a cache whose total weight of blocks is at most `capacity`, `weigher` gets the
+    /// weight of a block.
+    pub fn with_weigher(
+        capacity: u64,
+        weigher: impl Fn(&Block) -> u32 + Send + Sync + 'static,
+    ) -> Self {
+        let cache = moka::sync::Cache::builder()
+            .max_capacity(capacity)
+            .weigher(move |_, block: &Arc<Block>| weigher(block))
+            .build();
+        Self {
+            cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the block of `key`, or load and insert it if it isn't cached.
+    pub fn try_get_with(
+        &self,
+        key: (u64, usize),
+        load: impl FnOnce() -> Result<Arc<Block>>,
+    ) -> Result<Arc<Block>> {
+        let mut missed = false;
+        let block = self
+            .cache
+            .try_get_with(key, || {
+                missed = true;
+                load()
+            })
+            .map_err(|e| anyhow::anyhow!(e))?;
+        match missed {
+            true => self.misses.fetch_add(1, Ordering::Relaxed),
+            false => self.hits.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(block)
+    }
+
+    /// Get number of reads served by the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Get number of reads which load the block.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Get the approximate number of cached blocks.
+    pub fn entry_count(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    /// Get the approximate total weight of cached blocks, which is bytes by default.
+    pub fn weighted_size(&self) -> u64 {
+        self.cache.weighted_size()
+    }
+}
+
+/// Statistics of a level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// Number of the tables which are not being compacted.
+    pub num_tables: usize,
+    /// Total size of the tables which are not being compacted.
+    pub size: usize,
+    /// The score multiplied by 1000, see [`LevelStats::score`].
+    pub score_milli: u64,
+}
+
+impl LevelStats {
+    /// The level needs compaction if it's at least 1.0, by its size or number of tables.
+    pub fn score(&self) -> f64 {
+        self.score_milli as f64 / 1000.0
+    }
+}
+
+/// What a compaction merged and produced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionResult {
+    /// Number of entries of the input tables, including tombstones.
+    pub input_entries: u64,
+    /// Number of entries of the output tables, including tombstones.
+    pub output_entries: u64,
+    /// Total size of the input tables.
+    pub input_bytes: u64,
+    /// Total size of the output tables.
+    pub output_bytes: u64,
+    /// Number of tombstones dropped in the last level, where nothing older is left for them to
+    /// hide. Snapshots keep reading the input tables, so they still see the tombstones.
+    pub dropped_tombstones: u64,
+    /// Number of expired entries dropped in the last level.
+    pub dropped_expired: u64,
+}
+
+impl CompactionResult {
+    fn new<'a>(
+        inputs: impl IntoIterator<Item = &'a Arc<SsTable>>,
+        outputs: impl IntoIterator<Item = &'a Arc<SsTable>>,
+        dropped_tombstones: u64,
+        dropped_expired: u64,
+    ) -> Self {
+        let mut result = Self {
+            dropped_tombstones,
+            dropped_expired,
+            ..Default::default()
+        };
+        for table in inputs {
+            result.input_entries += table.num_entries().unwrap_or_default();
+            result.input_bytes += table.size as u64;
+        }
+        for table in outputs {
+            result.output_entries += table.num_entries().unwrap_or_default();
+            result.output_bytes += table.size as u64;
+        }
+        result
+    }
+}
 
 struct LevelsControllerInner {
-    next_sst_id: AtomicU64,
+    // shared by column families, so ids of their tables are unique
+    next_sst_id: Arc<AtomicU64>,
     levels: Vec<RwLock<Vec<Arc<SsTable>>>>,
     compact_job: Arc<Vec<Mutex<HashSet<u64>>>>,
     manifest: Arc<ManifestFile>,
     opts: Arc<LsmOptions>,
+    // column family of the tables
+    cf: u32,
+    // shared by compactor threads of all column families
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // the first error of background flushes and compactions
+    bg_error: Mutex<Option<String>>,
+    // signalled when a compactor finishes a compaction
+    compacted: Condvar,
+    compacted_lock: Mutex<()>,
 }
 
 impl LevelsControllerInner {
     fn max_level_byte(&self, level: usize) -> usize {
         let mut base_byte = self.opts.max_bytes_for_level_base;
         for _ in 1..=level {
-            base_byte *= self.opts.max_bytes_for_level_multiplier;
+            base_byte = base_byte.saturating_mul(self.opts.max_bytes_for_level_multiplier);
         }
         base_byte
     }
@@ -55,23 +207,101 @@ impl LevelsControllerInner {
         let file_size_base = self.opts.max_bytes_for_level_base / self.opts.target_file_size_base;
         let mut num = file_size_base;
         for _ in 1..=level {
-            num *= file_size_base;
+            num = num.saturating_mul(file_size_base);
         }
         num
     }
 
     fn new(opts: Arc<LsmOptions>, block_cache: Arc<BlockCache>) -> Result<Self> {
-        let path = &opts.dir;
-        let (manifest, l0_ids) = ManifestFile::open(path)?;
+        let layout = &opts.path_layout;
+        let manifest_path = layout.manifest_path(&opts.dir);
+        let sst_dir = sstable_dir(&opts, &opts.dir);
+        if !opts.read_only {
+            for dir in manifest_path
+                .parent()
+                .into_iter()
+                .chain([sst_dir.as_path()])
+            {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let (manifest, l0_ids) = ManifestFile::open_file(&manifest_path, opts.read_only)?;
+        // keys are stored in the order of the comparator, so it must never change
+        let comparator = opts.comparator.name();
+        match manifest.comparator() {
+            Some(name) if name != comparator => {
+                return Err(anyhow::anyhow!(
+                    "storage is ordered by comparator {name:?}, but opened with {comparator:?}"
+                ))
+            }
+            Some(_) => {}
+            None if opts.read_only => {}
+            None => manifest.set_comparator(comparator)?,
+        }
         let id_level = manifest.get_id_level();
-        let next_sst_id = AtomicU64::new(id_level.keys().copied().max().unwrap_or(0) + 1);
+        if let Some((id, level)) = id_level
+            .iter()
+            .find(|(_, level)| **level >= opts.num_levels)
+        {
+            return Err(anyhow::anyhow!(
+                "table {id} is at level {level}, but num_levels is {}",
+                opts.num_levels
+            ));
+        }
+        // tables of all column families are in the directory
+        let max_disk_id = match opts.read_only {
+            true => 0,
+            false => remove_orphan_files(layout.as_ref(), &sst_dir, &id_level)?,
+        };
+        let max_id = id_level.keys().copied().max().unwrap_or(0).max(max_disk_id);
+        let next_sst_id = Arc::new(AtomicU64::new(max_id + 1));
+        let rate_limiter = opts
+            .compaction_bytes_per_sec
+            .map(|bytes_per_sec| Arc::new(RateLimiter::new(bytes_per_sec)));
+        Self::load(
+            opts,
+            Arc::new(manifest),
+            DEFAULT_CF,
+            next_sst_id,
+            rate_limiter,
+            &l0_ids,
+            block_cache,
+        )
+    }
+
+    /// Open the tables of column family `cf` in the manifest, `l0_ids` are ids of level 0 in the
+    /// order of creation.
+    fn load(
+        opts: Arc<LsmOptions>,
+        manifest: Arc<ManifestFile>,
+        cf: u32,
+        next_sst_id: Arc<AtomicU64>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+        l0_ids: &[u64],
+        block_cache: Arc<BlockCache>,
+    ) -> Result<Self> {
+        let id_level = manifest.get_cf_id_level(cf);
         let mut levels = vec![vec![]; opts.num_levels];
 
-        for id in l0_ids {
-            if id_level.contains_key(&id) {
-                let file = FileObject::open(&sstable_file_path(path, id), opts.o_direct)?;
-                let table = Arc::new(SsTable::open(id, Some(block_cache.clone()), file)?);
-                levels[0].push(table);
+        for &id in l0_ids {
+            if id_level.get(&id) == Some(&0) {
+                let file = FileObject::open_with_retries(
+                    opts.sstable_path(id),
+                    opts.o_direct,
+                    opts.use_mmap,
+                    opts.open_retries,
+                    opts.open_timeout,
+                )?;
+                if opts.read_only {
+                    file.save();
+                }
+                let table = SsTable::open_with_comparator(
+                    id,
+                    Some(block_cache.clone()),
+                    file,
+                    opts.comparator.clone(),
+                )?;
+                levels[0].push(Arc::new(table));
             }
         }
 
@@ -79,13 +309,31 @@ impl LevelsControllerInner {
             if level == 0 {
                 continue;
             }
-            let file = FileObject::open(&sstable_file_path(path, id), opts.o_direct)?;
-            let table = Arc::new(SsTable::open(id, Some(block_cache.clone()), file)?);
-            levels[level].push(table);
+            let file = FileObject::open_with_retries(
+                opts.sstable_path(id),
+                opts.o_direct,
+                opts.use_mmap,
+                opts.open_retries,
+                opts.open_timeout,
+            )?;
+            if opts.read_only {
+                file.save();
+            }
+            let table = SsTable::open_with_comparator(
+                id,
+                Some(block_cache.clone()),
+                file,
+                opts.comparator.clone(),
+            )?;
+            levels[level].push(Arc::new(table));
+        }
+        // the manifest doesn't keep the order of tables
+        for level in levels.iter_mut().skip(1) {
+            sort_tables(level);
         }
         let levels = levels.into_iter().map(RwLock::new).collect();
-        let mut compact_job = Vec::with_capacity(MAX_LEVEL);
-        for _ in 0..MAX_LEVEL {
+        let mut compact_job = Vec::with_capacity(opts.num_levels);
+        for _ in 0..opts.num_levels {
             compact_job.push(Mutex::new(HashSet::new()))
         }
         let compact_job = Arc::new(compact_job);
@@ -94,45 +342,105 @@ impl LevelsControllerInner {
             next_sst_id,
             levels,
             compact_job,
-            manifest: Arc::new(manifest),
+            manifest,
+            cf,
+            rate_limiter,
+            bg_error: Mutex::new(None),
+            compacted: Condvar::new(),
+            compacted_lock: Mutex::new(()),
         })
     }
 
     fn level_size(&self, level: usize) -> usize {
-        let tables = self.levels[level].read().clone();
+        self.idle_tables(level).iter().map(|table| table.size).sum()
+    }
+
+    /// Get the tables of `level` which are not being compacted.
+    fn idle_tables(&self, level: usize) -> Vec<Arc<SsTable>> {
+        let mut tables = self.levels[level].read().clone();
         let job = self.compact_job[level].lock();
-        let mut size = 0;
-        for table in tables {
-            if !job.contains(&table.id) {
-                size += table.size;
+        tables.retain(|table| !job.contains(&table.id));
+        tables
+    }
+
+    /// Estimate the size of next level overlapping with `level`, divided by the size of `level`.
+    fn overlap_ratio(&self, level: usize) -> f64 {
+        if level == 0 || level + 1 >= self.levels.len() {
+            return 0.0;
+        }
+        let tables = self.levels[level].read().clone();
+        let next_tables = self.levels[level + 1].read().clone();
+        let size = tables.iter().map(|table| table.size).sum::<usize>();
+        if size == 0 {
+            return 0.0;
+        }
+        let mut overlap = 0;
+        for table in &tables {
+            for next_table in &next_tables {
+                if next_table.overlaps(&table.smallest_key, &table.biggest_key) {
+                    overlap += next_table.overlap_size(&table.smallest_key, &table.biggest_key);
+                }
             }
         }
-        size
+        overlap as f64 / size as f64
+    }
+
+    /// Get the fraction of entries of `level` which are tombstones, tables without properties
+    /// are skipped.
+    fn tombstone_density(&self, level: usize) -> f64 {
+        let tables = self.levels[level].read().clone();
+        let (entries, tombstones) = tables.iter().filter_map(|table| table.properties()).fold(
+            (0, 0),
+            |(entries, tombstones), properties| {
+                (
+                    entries + properties.num_entries,
+                    tombstones + properties.num_tombstones,
+                )
+            },
+        );
+        match entries {
+            0 => 0.0,
+            _ => tombstones as f64 / entries as f64,
+        }
+    }
+
+    fn level_score(&self, level: usize) -> f64 {
+        let size_score = self.level_size(level) as f64 / self.max_level_byte(level) as f64;
+        let num_score = self.levels[level].read().len() as f64 / self.max_level_file(level) as f64;
+        size_score.max(num_score)
     }
 
     fn pick_compact_levels(&self) -> Vec<TaskPriority> {
         let mut prios = vec![];
 
         for i in 0..self.levels.len() {
-            let size = self.level_size(i);
-            let size_score = size as f64 / self.max_level_byte(i) as f64;
-            let num_score = self.levels[i].read().len() as f64 / self.max_level_file(i) as f64;
-            let pri = TaskPriority::new(i, size_score.max(num_score));
-            prios.push(pri);
+            let score = self.level_score(i);
+            // a level needs compaction only when score >= 1, the overlap and tombstones only
+            // affect the order
+            if score >= 1.0 {
+                let boost = 1.0 + self.opts.tombstone_compaction_weight * self.tombstone_density(i);
+                let pri = TaskPriority::new(i, score * (1.0 + self.overlap_ratio(i)) * boost);
+                prios.push(pri);
+            }
         }
 
-        assert_eq!(prios.len(), self.levels.len());
-
         // Remove last level.
-        prios.pop();
-        let mut x: Vec<TaskPriority> = prios.into_iter().filter(|x| x.score > 1.0).collect();
-        x.sort_by(|x, y| y.score.partial_cmp(&x.score).unwrap());
-        x
+        prios.retain(|x| x.level + 1 < self.levels.len());
+        prios.sort_by(|x, y| y.score.partial_cmp(&x.score).unwrap());
+        prios
+    }
+
+    /// Record the first error of background flushes and compactions.
+    fn set_bg_error(&self, err: &anyhow::Error) {
+        let mut bg_error = self.bg_error.lock();
+        if bg_error.is_none() {
+            *bg_error = Some(format!("{err:#}"));
+        }
     }
 
-    fn do_compact(self: &Arc<Self>, idx: usize, pri: TaskPriority) -> Result<()> {
+    fn do_compact(self: &Arc<Self>, idx: usize, pri: TaskPriority) -> Result<CompactionResult> {
         let level = pri.level;
-        assert!(level + 1 < MAX_LEVEL);
+        assert!(level + 1 < self.opts.num_levels);
         // TODO: 如果是level 判断是否要走l0的tired compaction
 
         let task = self.create_task(pri.level);
@@ -140,17 +448,77 @@ impl LevelsControllerInner {
         info!("compactor {idx} creates task {}", task.is_some());
 
         if task.is_none() {
-            return Ok(());
+            return Ok(CompactionResult::default());
         }
 
         let task = Arc::new(task.unwrap());
+        let new_tables = self.run_task(&task);
+        self.notify_compacted();
+        let new_tables = new_tables?;
+        let result = CompactionResult::new(
+            task.this_tables.iter().chain(&task.next_tables),
+            &new_tables,
+            task.dropped_tombstones.load(Ordering::Relaxed),
+            task.dropped_expired.load(Ordering::Relaxed),
+        );
+
+        info!(
+            "compactor #{} on level {} success: {:?}",
+            idx, task.this_level_id, result
+        );
+
+        Ok(result)
+    }
+
+    /// Wake up threads waiting for compactions to be idle.
+    fn notify_compacted(&self) {
+        let _guard = self.compacted_lock.lock();
+        self.compacted.notify_all();
+    }
 
-        let rws = RwsSlice::create(&task);
-        // TODO: 得到sub_compact线程数
-        let num_sub_compact = 4;
-        let mean = rws.total_size / num_sub_compact;
+    /// Check that no table is being compacted and no level needs compaction.
+    fn is_compaction_idle(&self) -> bool {
+        self.compact_job.iter().all(|job| job.lock().is_empty())
+            && self.pick_compact_levels().is_empty()
+    }
+
+    /// Merge the tables of `task` into the next level, return the new tables.
+    fn run_task(self: &Arc<Self>, task: &Arc<Task>) -> Result<Vec<Arc<SsTable>>> {
+        let rws = RwsSlice::create(task, self.opts.comparator.as_ref());
+        let num_sub_compact = self.opts.subcompactor_num.max(1);
+        // overlap size may be 0 when tables are small
+        let mean = (rws.total_size / num_sub_compact).max(1);
         let ranges = rws.split(mean);
 
+        let mut new_tables = if self.opts.deterministic_compaction {
+            self.sub_compact_deterministic(task, &ranges)?
+        } else {
+            self.sub_compact_parallel(task, &ranges)?
+        };
+        sort_tables(&mut new_tables);
+
+        let change_set = build_change_set(self.cf, task, &new_tables);
+        self.manifest.apply_change_set(&change_set)?;
+        self.update_with_tables(task, &new_tables)?;
+        // the inputs are removed from the levels
+        for (level, tables) in [
+            (task.this_level_id, &task.this_tables),
+            (task.next_level_id, &task.next_tables),
+        ] {
+            let mut job = self.compact_job[level].lock();
+            for table in tables {
+                job.remove(&table.id);
+            }
+        }
+        Ok(new_tables)
+    }
+
+    /// Run sub-compactions in threads, each writes its tables as soon as they are full.
+    fn sub_compact_parallel(
+        self: &Arc<Self>,
+        task: &Arc<Task>,
+        ranges: &[(Bound<Bytes>, Bound<Bytes>)],
+    ) -> Result<Vec<Arc<SsTable>>> {
         let (tx, rx) = unbounded();
         for (lower, upper) in ranges.iter() {
             let this = self.clone();
@@ -158,23 +526,52 @@ impl LevelsControllerInner {
             let tx = tx.clone();
             let lower = lower.clone();
             let upper = upper.clone();
-            std::thread::spawn(move || tx.send(this.sub_compact(&task, lower, upper)));
+            std::thread::spawn(move || {
+                let ret = this.sub_compact(&task, lower, upper);
+                // release the tables before the compaction is seen as done
+                drop((this, task));
+                tx.send(ret)
+            });
         }
+        drop(tx);
+        // keys of a failed range would be lost, so the whole task fails, and the written tables
+        // are removed when they are dropped
         let mut new_tables = vec![];
-        for mut table in rx.iter().take(ranges.len()).flatten() {
-            new_tables.append(&mut table);
+        let mut done = 0;
+        for tables in rx.iter() {
+            new_tables.append(&mut tables?);
+            done += 1;
         }
-        new_tables.sort_by(|a, b| a.smallest_key.partial_cmp(&b.smallest_key).unwrap());
-
-        let change_set = build_change_set(&task, &new_tables);
-        self.manifest.apply_change_set(&change_set)?;
-        self.update_with_tables(&task, &new_tables)?;
+        if done < ranges.len() {
+            return Err(anyhow::anyhow!("sub compaction exited unexpectedly"));
+        }
+        Ok(new_tables)
+    }
 
-        info!("compactor #{} on level {} success", idx, task.this_level_id);
+    /// Run sub-compactions one after another in key order, so the same inputs always produce the
+    /// same tables and ids.
+    fn sub_compact_deterministic(
+        self: &Arc<Self>,
+        task: &Arc<Task>,
+        ranges: &[(Bound<Bytes>, Bound<Bytes>)],
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let mut new_tables = vec![];
+        for (lower, upper) in ranges {
+            new_tables.append(&mut self.sub_compact(task, lower.clone(), upper.clone())?);
+        }
+        Ok(new_tables)
+    }
 
-        Ok(())
+    /// Write a table of the compaction output with a new id.
+    fn build_table(&self, mut builder: SsTableBuilder) -> Result<Arc<SsTable>> {
+        let id = self.next_sst_id.fetch_add(1, Ordering::Relaxed);
+        builder.set_rate_limiter(self.rate_limiter.clone());
+        let table = builder.build(id, None, self.opts.sstable_path(id))?;
+        Ok(Arc::new(table))
     }
 
+    /// Merge the keys of `task` in the range into tables of the next level. The tables are removed
+    /// when they are dropped, so nothing is left behind if the compaction fails.
     fn sub_compact(
         self: &Arc<Self>,
         task: &Task,
@@ -185,45 +582,159 @@ impl LevelsControllerInner {
         tables.extend_from_slice(&task.this_tables);
         tables.extend_from_slice(&task.next_tables);
 
+        // keys of a table are deleted by range tombstones of the tables before it
+        let shadows = shadows(tables.iter().map(|table| table.range_tombstones()));
         let mut iters = Vec::with_capacity(tables.len());
-        for table in tables {
+        for (table, shadow) in tables.iter().zip(shadows) {
             let iter = match lower {
-                Bound::Included(ref key) => SsTableIterator::create_and_seek_to_key(table, key)?,
+                Bound::Included(ref key) => {
+                    SsTableIterator::create_and_seek_to_key(table.clone(), key)?
+                }
                 _ => panic!("invalid lower"),
             };
-            iters.push(Box::new(iter));
+            iters.push(Box::new(ShadowedIterator::create(iter, shadow)?));
         }
 
-        let mut iter = MergeIterator::create(iters);
+        // nothing is shadowed by tombstones when no deeper level has data
+        let bottom = self.levels[task.next_level_id + 1..]
+            .iter()
+            .all(|level| level.read().is_empty());
+        let tombstones = match bottom {
+            true => vec![],
+            false => output_tombstones(&tables, &lower, &upper)?,
+        };
+        match self.opts.loser_tree_compaction {
+            true => {
+                let iter = LoserTreeIterator::create(iters);
+                self.compact_entries(task, &tables, iter, &upper, bottom, &tombstones)
+            }
+            false => {
+                let iter = MergeIterator::create(iters);
+                self.compact_entries(task, &tables, iter, &upper, bottom, &tombstones)
+            }
+        }
+    }
+
+    /// Write the merged entries of a sub compaction into tables, a table is written once it
+    /// reaches the capacity. `tables` are the input tables, newer first.
+    ///
+    /// A table takes `tombstones` after the last key of the previous table until its last key,
+    /// and the last table takes the rest, so tables don't overlap.
+    fn compact_entries(
+        &self,
+        task: &Task,
+        tables: &[Arc<SsTable>],
+        mut iter: impl StorageIterator,
+        upper: &Bound<Bytes>,
+        bottom: bool,
+        tombstones: &[RangeTombstone],
+    ) -> Result<Vec<Arc<SsTable>>> {
         let mut new_tables = vec![];
-        fn key_vaild(iter: &MergeIterator<SsTableIterator>, upper: &Bound<Bytes>) -> bool {
+        let mut start = None;
+        let mut rest_taken = false;
+        let filter = self.opts.compaction_filter.as_ref();
+        fn key_vaild(iter: &impl StorageIterator, upper: &Bound<Bytes>) -> bool {
             match upper {
                 Bound::Unbounded => panic!("invalid upper"),
-                Bound::Included(key) => iter.key() <= key,
-                Bound::Excluded(key) => iter.key() < key,
+                Bound::Included(key) => iter.comparator().compare(iter.key(), key).is_le(),
+                Bound::Excluded(key) => iter.comparator().compare(iter.key(), key).is_lt(),
             }
         }
-        while iter.is_valid() && key_vaild(&iter, &upper) {
-            let mut build = SsTableBuilder::new(self.opts.clone());
-
-            while iter.is_valid() && !build.reach_capacity() && key_vaild(&iter, &upper) {
-                build.add(iter.key(), iter.value())?;
+        fn add_tombstones(
+            build: &mut SsTableBuilder,
+            tombstones: &[RangeTombstone],
+            start: Option<&[u8]>,
+            end: Option<&[u8]>,
+        ) {
+            for tombstone in tombstones {
+                if let Some(tombstone) = tombstone.clip(start, end) {
+                    build.add_range_tombstone(tombstone);
+                }
+            }
+        }
+        while iter.is_valid() && key_vaild(&iter, upper) {
+            let mut build = SsTableBuilder::new_for_level(self.opts.clone(), task.next_level_id);
+            let mut last_key = vec![];
+
+            while iter.is_valid() && !build.reach_capacity() && key_vaild(&iter, upper) {
+                let (key, value, expire_at) = (iter.key(), iter.value(), iter.expire_at());
+                let expired = expire_at.is_some() && iter.is_tombstone();
+                match filter {
+                    _ if iter.is_merge() => self.compact_merge(&mut build, tables, key, bottom)?,
+                    // an expired value keeps hiding older values until it reaches the last level
+                    _ if expired && bottom => {
+                        task.dropped_expired.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ if expired => add_value(&mut build, key, value, expire_at)?,
+                    // tombstones are not filtered, and hide older values until the last level
+                    _ if iter.is_tombstone() && bottom => {
+                        task.dropped_tombstones.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ if iter.is_tombstone() => build.add_tombstone(key)?,
+                    Some(filter) => match (filter.0)(key, value) {
+                        FilterDecision::Keep => add_value(&mut build, key, value, expire_at)?,
+                        FilterDecision::Remove if bottom => {}
+                        FilterDecision::Remove => build.add_tombstone(key)?,
+                        FilterDecision::Change(value) => {
+                            add_value(&mut build, key, &value, expire_at)?
+                        }
+                    },
+                    None => add_value(&mut build, key, value, expire_at)?,
+                }
+                last_key.clear();
+                last_key.extend_from_slice(key);
                 iter.next()?;
             }
 
-            let id = self.next_sst_id.fetch_add(1, Ordering::Relaxed);
-            new_tables.push(Arc::new(build.build(
-                id,
-                None,
-                sstable_file_path(&self.opts.dir, id),
-            )?));
+            if build.is_empty() {
+                continue;
+            }
+            let more = iter.is_valid() && key_vaild(&iter, upper);
+            let end = more.then_some(&last_key[..]);
+            add_tombstones(&mut build, tombstones, start.as_deref(), end);
+            start = end.map(successor);
+            rest_taken = !more;
+            new_tables.push(self.build_table(build)?);
+        }
+
+        // no table takes the rest when the entries after the last table are all dropped
+        if !rest_taken {
+            let mut build = SsTableBuilder::new_for_level(self.opts.clone(), task.next_level_id);
+            add_tombstones(&mut build, tombstones, start.as_deref(), None);
+            if !build.is_empty() {
+                new_tables.push(self.build_table(build)?);
+            }
         }
         Ok(new_tables)
     }
 
+    /// Add the merge record of `key` combined with the older records of the key in `tables`. The
+    /// operands are merged into a value once the chain is complete or nothing older is left below
+    /// the bottom level, otherwise they are kept as one merge record.
+    fn compact_merge(
+        &self,
+        build: &mut SsTableBuilder,
+        tables: &[Arc<SsTable>],
+        key: &[u8],
+        bottom: bool,
+    ) -> Result<()> {
+        let mut chain = MergeChain::default();
+        if !get_in_tables(tables, key, &mut chain)? && !bottom {
+            return build.add_merge(key, &chain.operands());
+        }
+        match chain.resolve(key, self.opts.merge_operator.as_ref())? {
+            Some(value) => build.add(key, &value),
+            None if bottom => Ok(()),
+            None => build.add_tombstone(key),
+        }
+    }
+
     fn fill_table_l0(&self) -> Option<Task> {
-        let this_tables = self.levels[0].read().clone();
-        let next_tables = self.levels[1].read().clone();
+        // the levels are locked until the jobs are updated, see `LevelController::add_ingested`
+        let this_guard = self.levels[0].read();
+        let next_guard = self.levels[1].read();
+        let this_tables = this_guard.clone();
+        let next_tables = next_guard.clone();
         let mut task = Task {
             this_level_id: 0,
             next_level_id: 1,
@@ -234,15 +745,13 @@ impl LevelsControllerInner {
         let mut next_compact_job = self.compact_job[1].lock();
         let mut job = HashSet::new();
         // l0 tables all must be not in compact job
-        for table in this_tables.iter().rev() {
+        for table in l0_newest_first(&this_tables) {
             if this_compact_job.contains(&table.id) {
                 return None;
             }
             let mut family = vec![];
             for next_table in &next_tables {
-                if next_table.smallest_key > table.biggest_key
-                    || next_table.biggest_key < table.smallest_key
-                {
+                if !next_table.overlaps(&table.smallest_key, &table.biggest_key) {
                     continue;
                 }
                 if next_compact_job.contains(&next_table.id) {
@@ -285,9 +794,11 @@ impl LevelsControllerInner {
     fn fill_table(&self, level: usize) -> Option<Task> {
         assert_ne!(level, 0);
 
-        let mut this_tables = self.levels[level].read().clone();
+        let this_guard = self.levels[level].read();
+        let next_guard = self.levels[level + 1].read();
+        let mut this_tables = this_guard.clone();
         this_tables.sort_by(|a, b| b.size.partial_cmp(&a.size).unwrap());
-        let next_tables = self.levels[level + 1].read().clone();
+        let next_tables = next_guard.clone();
 
         let mut task = Task {
             this_level_id: level,
@@ -306,9 +817,7 @@ impl LevelsControllerInner {
             let mut choose = true;
             let mut family = vec![];
             for next_table in &next_tables {
-                if next_table.smallest_key > table.biggest_key
-                    || next_table.biggest_key < table.smallest_key
-                {
+                if !next_table.overlaps(&table.smallest_key, &table.biggest_key) {
                     continue;
                 }
                 if next_compact_job.contains(&next_table.id) {
@@ -343,6 +852,65 @@ impl LevelsControllerInner {
         Some(task)
     }
 
+    /// Create a task compacting the tables of `level` in `ids` into the next level. All l0 tables
+    /// are compacted if any of them is in `ids`, since they overlap with each other.
+    ///
+    /// Return None if any table of the task is being compacted.
+    fn fill_table_range(&self, level: usize, ids: &HashSet<u64>) -> Option<Task> {
+        let this_guard = self.levels[level].read();
+        let next_guard = self.levels[level + 1].read();
+        let this_tables = this_guard.clone();
+        let next_tables = next_guard.clone();
+        let mut task = Task {
+            this_level_id: level,
+            next_level_id: level + 1,
+            ..Default::default()
+        };
+
+        let mut this_compact_job = self.compact_job[level].lock();
+        let mut next_compact_job = self.compact_job[level + 1].lock();
+        if level == 0 {
+            if this_tables.iter().any(|table| ids.contains(&table.id)) {
+                task.this_tables = l0_newest_first(&this_tables).into_iter().cloned().collect();
+            }
+        } else {
+            task.this_tables = this_tables
+                .into_iter()
+                .filter(|table| ids.contains(&table.id))
+                .collect();
+        }
+        if task
+            .this_tables
+            .iter()
+            .any(|table| this_compact_job.contains(&table.id))
+        {
+            return None;
+        }
+
+        for next_table in next_tables {
+            let overlaps = task
+                .this_tables
+                .iter()
+                .any(|table| next_table.overlaps(&table.smallest_key, &table.biggest_key));
+            if !overlaps {
+                continue;
+            }
+            if next_compact_job.contains(&next_table.id) {
+                return None;
+            }
+            task.next_tables.push(next_table);
+        }
+
+        for table in &task.this_tables {
+            this_compact_job.insert(table.id);
+        }
+        for table in &task.next_tables {
+            next_compact_job.insert(table.id);
+        }
+
+        Some(task)
+    }
+
     fn update_with_tables(&self, task: &Task, new_tables: &[Arc<SsTable>]) -> Result<()> {
         // l0 tired compaction
         if task.this_level_id == task.next_level_id {
@@ -371,7 +939,7 @@ impl LevelsControllerInner {
                 .cloned()
                 .collect::<Vec<_>>();
             new_level.extend_from_slice(new_tables);
-            new_level.sort_by(|a, b| a.smallest_key.partial_cmp(&b.smallest_key).unwrap());
+            sort_tables(&mut new_level);
             *level = new_level;
         }
         {
@@ -388,11 +956,119 @@ impl LevelsControllerInner {
     }
 }
 
-fn build_change_set(task: &Task, new_tables: &[Arc<SsTable>]) -> ManifestChangeSet {
+/// Get the smallest key bigger than `key`.
+fn successor(key: &[u8]) -> Vec<u8> {
+    let mut key = key.to_vec();
+    key.push(0);
+    key
+}
+
+/// Get the biggest key smaller than `key` in the tables.
+fn last_key_before(tables: &[Arc<SsTable>], key: &[u8]) -> Result<Option<Bytes>> {
+    let mut last: Option<Bytes> = None;
+    for table in tables {
+        let mut iter = SsTableIterator::create_and_seek_to_key_rev(table.clone(), key)?;
+        if iter.is_valid() && iter.key() == key {
+            iter.prev()?;
+        }
+        if iter.is_valid() && last.as_ref().is_none_or(|last| iter.key() > &last[..]) {
+            last = Some(Bytes::copy_from_slice(iter.key()));
+        }
+    }
+    Ok(last)
+}
+
+/// Get the range tombstones of `tables` which are output by the sub compaction of [`lower`,
+/// `upper`].
+///
+/// Sub compactions split range tombstones at the last keys before their bounds, so that every
+/// key not in the tables is covered by the same sub compaction as before.
+fn output_tombstones(
+    tables: &[Arc<SsTable>],
+    lower: &Bound<Bytes>,
+    upper: &Bound<Bytes>,
+) -> Result<Vec<RangeTombstone>> {
+    let mut union = RangeTombstones::new();
+    for table in tables {
+        union.extend(table.range_tombstones());
+    }
+    if union.is_empty() {
+        return Ok(vec![]);
+    }
+    let start = match lower {
+        Bound::Included(key) => last_key_before(tables, key)?.map(|key| successor(&key)),
+        _ => None,
+    };
+    // the last sub compaction has an included upper bound
+    let end = match upper {
+        Bound::Excluded(key) => match last_key_before(tables, key)? {
+            Some(key) => Some(key),
+            None => return Ok(vec![]),
+        },
+        _ => None,
+    };
+    Ok(union
+        .iter()
+        .filter_map(|tombstone| tombstone.clip(start.as_deref(), end.as_deref()))
+        .collect())
+}
+
+/// Hard link `src` as `dest`, or copy it if it can't be linked.
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_ok() {
+        return Ok(());
+    }
+    let tmp = tmp_file_path(dest);
+    fs::copy(src, &tmp)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, dest)?;
+    Ok(())
+}
+
+/// Get the directory of sstables of a storage in `dir`.
+fn sstable_dir(opts: &LsmOptions, dir: &Path) -> PathBuf {
+    let path = opts.path_layout.sstable_path(dir, 0);
+    path.parent().unwrap_or(dir).to_path_buf()
+}
+
+/// Remove sstables that are not in the manifest and temporary files, which may be left by a crash.
+///
+/// Return the max sstable id on the disk.
+fn remove_orphan_files(
+    layout: &dyn PathLayout,
+    dir: &Path,
+    id_level: &HashMap<u64, usize>,
+) -> Result<u64> {
+    let mut max_id = 0;
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let file_name = file.file_name();
+        let file_name = file_name.to_string_lossy();
+        let tmp = file_name.ends_with(TMP_FILE_EXT);
+        let id =
+            layout.parse_sstable_id(file_name.strip_suffix(TMP_FILE_EXT).unwrap_or(&file_name));
+        if let Some(id) = id {
+            max_id = max_id.max(id);
+        }
+
+        let orphan = match id {
+            _ if tmp => true,
+            Some(id) => !id_level.contains_key(&id),
+            None => false,
+        };
+        if orphan {
+            info!("remove orphan file {file_name}");
+            fs::remove_file(file.path())?;
+        }
+    }
+    Ok(max_id)
+}
+
+fn build_change_set(cf: u32, task: &Task, new_tables: &[Arc<SsTable>]) -> ManifestChangeSet {
     let mut changes = vec![];
 
     for table in new_tables {
-        changes.push(Change::create(table.id, task.next_level_id));
+        changes.push(Change::create_in(cf, table.id, task.next_level_id));
     }
     for table in &task.this_tables {
         changes.push(Change::delete(table.id));
@@ -412,6 +1088,7 @@ pub struct LevelController {
 
 impl LevelController {
     pub fn open(opts: Arc<LsmOptions>) -> Result<Self> {
+        opts.validate()?;
         let block_cache = Arc::new(BlockCache::new(opts.block_cache_size));
         let inner = Arc::new(LevelsControllerInner::new(
             opts.clone(),
@@ -424,44 +1101,103 @@ impl LevelController {
         })
     }
 
+    /// Open the tables of column family `cf`, which share the manifest, the block cache and the
+    /// allocation of table ids with this controller.
+    pub fn open_cf(&self, cf: u32) -> Result<Self> {
+        let inner = Arc::new(LevelsControllerInner::load(
+            self.opts.clone(),
+            self.inner.manifest.clone(),
+            cf,
+            self.inner.next_sst_id.clone(),
+            self.inner.rate_limiter.clone(),
+            &self.inner.manifest.l0_ids(),
+            self.block_cache.clone(),
+        )?);
+        Ok(Self {
+            inner,
+            block_cache: self.block_cache.clone(),
+            opts: self.opts.clone(),
+        })
+    }
+
+    /// Create a column family named `name` in the manifest, return its id.
+    pub fn create_cf(&self, name: &str) -> Result<u32> {
+        self.inner.manifest.create_cf(name)
+    }
+
+    /// Get the ids of column families by their names, including the default one.
+    pub fn column_families(&self) -> HashMap<String, u32> {
+        self.inner.manifest.column_families()
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        let tables = self.inner.levels[0].read().clone();
-        if !tables.is_empty() {
-            for table in tables.iter().rev() {
-                if !table.may_contain(key) {
-                    continue;
-                }
-                let iter = SsTableIterator::create_and_seek_to_key(table.clone(), key)?;
-                if iter.is_valid() && iter.key() == key {
-                    if iter.value().is_empty() {
-                        return Ok(None);
-                    }
-                    return Ok(Some(Bytes::copy_from_slice(iter.value())));
-                }
-            }
-        }
+        let mut chain = MergeChain::default();
+        self.get_chain(key, &mut chain)?;
+        chain.resolve(key, self.opts.merge_operator.as_ref())
+    }
 
-        for i in 1..self.opts.num_levels {
+    /// Push the records of `key` in levels to `chain`, which has the records of newer tables,
+    /// until it's complete.
+    pub(crate) fn get_chain(&self, key: &[u8], chain: &mut MergeChain) -> Result<()> {
+        for i in 0..self.opts.num_levels {
             let tables = self.inner.levels[i].read().clone();
-            if tables.is_empty() {
-                continue;
-            }
-            let idx = tables
-                .partition_point(|table| table.smallest_key <= key)
-                .saturating_sub(1);
-            let table = tables[idx].clone();
-            if !table.may_contain(key) {
-                continue;
+            if get_in_level(i, &tables, key, chain, |table| get_in_table(table, key))? {
+                break;
             }
-            let iter = SsTableIterator::create_and_seek_to_key(table, key)?;
-            if iter.is_valid() && iter.key() == key {
-                if iter.value().is_empty() {
-                    return Ok(None);
+        }
+        Ok(())
+    }
+
+    /// Get statistics of each level.
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        (0..self.inner.levels.len())
+            .map(|level| {
+                let tables = self.inner.idle_tables(level);
+                LevelStats {
+                    num_tables: tables.len(),
+                    size: tables.iter().map(|table| table.size).sum(),
+                    score_milli: (self.inner.level_score(level) * 1000.0) as u64,
                 }
-                return Ok(Some(Bytes::copy_from_slice(iter.value())));
+            })
+            .collect()
+    }
+
+    /// Render the tables of each level with their ids, key ranges and sizes, for debugging:
+    ///
+    /// ```text
+    /// level 0:
+    ///   12: b"a"..=b"k" 4096B
+    /// level 1:
+    /// ```
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        for (level, tables) in self.snapshot_levels().iter().enumerate() {
+            writeln!(out, "level {level}:").unwrap();
+            for table in tables {
+                writeln!(
+                    out,
+                    "  {}: {:?}..={:?} {}B",
+                    table.id, table.smallest_key, table.biggest_key, table.size
+                )
+                .unwrap();
             }
         }
-        Ok(None)
+        out
+    }
+
+    pub fn block_cache(&self) -> &Arc<BlockCache> {
+        &self.block_cache
+    }
+
+    /// Get tables of all levels at the same time.
+    pub fn snapshot_levels(&self) -> Vec<Vec<Arc<SsTable>>> {
+        let guards = self
+            .inner
+            .levels
+            .iter()
+            .map(|level| level.read())
+            .collect::<Vec<_>>();
+        guards.iter().map(|guard| guard.to_vec()).collect()
     }
 
     pub fn start_compact(&self, pool: Arc<ThreadPool>, closer: Arc<Receiver<()>>) {
@@ -495,8 +1231,8 @@ impl LevelController {
                     }
 
                     if let Err(err) = inner.do_compact(idx, p) {
-                        error!("compactor {idx} error: {err}")
-                        // TODO: Handle error.
+                        error!("compactor {idx} error: {err}");
+                        inner.set_bg_error(&err);
                     }
                 }
             };
@@ -514,18 +1250,201 @@ impl LevelController {
         });
     }
 
+    /// Block until no table is being compacted and no level needs compaction, or fail after
+    /// `timeout`. It fails with the background error if a compaction fails.
+    pub fn wait_for_idle(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut guard = self.inner.compacted_lock.lock();
+        loop {
+            self.check_bg_error()?;
+            if self.inner.is_compaction_idle() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(anyhow::anyhow!("compaction is not idle after {timeout:?}"));
+            }
+            // tables reserved by others are released without a signal
+            let wait = (deadline - now).min(Duration::from_millis(50));
+            self.inner.compacted.wait_for(&mut guard, wait);
+        }
+    }
+
+    /// Record an error of a background flush or compaction, writes fail with it from now on.
+    pub fn set_bg_error(&self, err: &anyhow::Error) {
+        self.inner.set_bg_error(err);
+    }
+
+    /// Return the error of background flushes and compactions, if any.
+    pub fn check_bg_error(&self) -> Result<()> {
+        match &*self.inner.bg_error.lock() {
+            Some(err) => Err(anyhow::anyhow!("background error: {err}")),
+            None => Ok(()),
+        }
+    }
+
     pub fn l0_push_sstable(&self, builder: SsTableBuilder) -> Result<()> {
-        let id = self.inner.next_sst_id.fetch_add(1, Ordering::Relaxed);
-        let table = Arc::new(builder.build(
+        self.do_l0_push_sstable(builder, self.next_sst_id())
+    }
+
+    /// Push a sstable with a given id to l0, ids allocated later are bigger than it.
+    #[cfg(test)]
+    pub(crate) fn l0_push_sstable_with_id(&self, builder: SsTableBuilder, id: u64) -> Result<()> {
+        self.inner.next_sst_id.fetch_max(id + 1, Ordering::Relaxed);
+        self.do_l0_push_sstable(builder, id)
+    }
+
+    fn do_l0_push_sstable(&self, builder: SsTableBuilder, id: u64) -> Result<()> {
+        let table = self.build_l0_sstable(builder, id)?;
+        self.l0_push_sstables(vec![table])
+    }
+
+    /// Allocate the id of a new sstable.
+    pub fn next_sst_id(&self) -> u64 {
+        self.inner.next_sst_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Build a sstable of l0 with an id from [`LevelController::next_sst_id`], it isn't live
+    /// until it's pushed by [`LevelController::l0_push_sstables`], and its file is removed if
+    /// it's dropped before that.
+    pub fn build_l0_sstable(&self, builder: SsTableBuilder, id: u64) -> Result<Arc<SsTable>> {
+        Ok(Arc::new(builder.build(
             id,
             Some(self.block_cache.clone()),
-            sstable_file_path(&self.opts.dir, id),
-        )?);
-        self.inner.manifest.apply_change(&Change::create(id, 0))?;
-        self.inner.levels[0].write().push(table);
+            self.opts.sstable_path(id),
+        )?))
+    }
+
+    /// Push built sstables to l0 in one manifest change, older tables go first.
+    pub fn l0_push_sstables(&self, tables: Vec<Arc<SsTable>>) -> Result<()> {
+        let changes = tables
+            .iter()
+            .map(|table| Change::create_in(self.inner.cf, table.id, 0))
+            .collect();
+        self.inner
+            .manifest
+            .apply_change_set(&ManifestChangeSet { changes })?;
+        self.inner.levels[0].write().extend(tables);
         Ok(())
     }
 
+    /// Open an external sstable in place to validate it, the file is still owned by the caller.
+    pub fn open_external(&self, path: &Path) -> Result<SsTable> {
+        let file = FileObject::open(path, self.opts.o_direct, self.opts.use_mmap)?;
+        file.save();
+        SsTable::open_with_comparator(0, None, file, self.opts.comparator.clone())
+    }
+
+    /// Link or copy an external sstable into the directory with a new id, after validating it.
+    ///
+    /// The table isn't live until it's added by [`LevelController::add_ingested`], its file is
+    /// removed if it's dropped before that.
+    pub fn link_external(&self, path: &Path) -> Result<Arc<SsTable>> {
+        self.open_external(path)?;
+
+        let id = self.inner.next_sst_id.fetch_add(1, Ordering::Relaxed);
+        link_or_copy(path, &self.opts.sstable_path(id))?;
+        fs::File::open(sstable_dir(&self.opts, &self.opts.dir))?.sync_all()?;
+
+        let file = FileObject::open(
+            self.opts.sstable_path(id),
+            self.opts.o_direct,
+            self.opts.use_mmap,
+        )?;
+        Ok(Arc::new(SsTable::open_with_comparator(
+            id,
+            Some(self.block_cache.clone()),
+            file,
+            self.opts.comparator.clone(),
+        )?))
+    }
+
+    /// Make a table returned by [`LevelController::link_external`] live, return its level.
+    ///
+    /// It's placed at the deepest level whose tables and the tables above don't overlap with it,
+    /// and no compaction into which may write tables over its key range, or pushed to l0 as the
+    /// newest table. Keys in memtables are not checked.
+    pub fn add_ingested(&self, table: Arc<SsTable>) -> Result<usize> {
+        let mut guards = self
+            .inner
+            .levels
+            .iter()
+            .map(|level| level.write())
+            .collect::<Vec<_>>();
+        let jobs = self
+            .inner
+            .compact_job
+            .iter()
+            .map(|job| job.lock())
+            .collect::<Vec<_>>();
+        let (lower, upper) = table.key_range();
+        let mut level = 0;
+        for (i, guard) in guards.iter().enumerate() {
+            if guard.iter().any(|t| t.overlaps(lower, upper)) {
+                break;
+            }
+            // the inputs of a compaction into the level are the tables being compacted in the
+            // level and the level above
+            if i > 0 {
+                let inputs = guards[i - 1]
+                    .iter()
+                    .filter(|t| jobs[i - 1].contains(&t.id))
+                    .chain(guard.iter().filter(|t| jobs[i].contains(&t.id)));
+                if span_overlaps(inputs, &table, lower, upper) {
+                    break;
+                }
+            }
+            level = i;
+        }
+        drop(jobs);
+
+        self.inner
+            .manifest
+            .apply_change(&Change::create_in(self.inner.cf, table.id, level))?;
+        let guard = &mut guards[level];
+        guard.push(table);
+        if level > 0 {
+            sort_tables(guard);
+        }
+        Ok(level)
+    }
+
+    /// Link (or copy) the files of current tables into `dest` with a manifest of them, which can
+    /// be opened as another storage.
+    pub fn checkpoint(&self, dest: &Path) -> Result<()> {
+        if self.column_families().len() > 1 {
+            return Err(anyhow::anyhow!(
+                "checkpoint of column families is unsupported"
+            ));
+        }
+        // files are not removed while the tables are referenced
+        let levels = self.snapshot_levels();
+        let layout = &self.opts.path_layout;
+        let manifest_path = layout.manifest_path(dest);
+        let sst_dir = sstable_dir(&self.opts, dest);
+        for dir in manifest_path
+            .parent()
+            .into_iter()
+            .chain([sst_dir.as_path()])
+        {
+            fs::create_dir_all(dir)?;
+        }
+        if manifest_path.exists() {
+            return Err(anyhow::anyhow!("{:?} already has a manifest", dest));
+        }
+        for table in levels.iter().flatten() {
+            let dest = layout.sstable_path(dest, table.id);
+            link_or_copy(&self.opts.sstable_path(table.id), &dest)?;
+        }
+        fs::File::open(&sst_dir)?.sync_all()?;
+        let ids = levels
+            .iter()
+            .map(|tables| tables.iter().map(|table| table.id).collect())
+            .collect::<Vec<_>>();
+        // also syncs the directory of the manifest
+        ManifestFile::write_new(&manifest_path, self.opts.comparator.name(), &ids)
+    }
+
     pub fn mark_save(&self) {
         for level in &self.inner.levels {
             let mut guard = level.write();
@@ -535,48 +1454,369 @@ impl LevelController {
         }
     }
 
+    /// Reserve the tables entirely within [`lower`, `upper`] which are not being compacted.
+    ///
+    /// Compactors skip reserved tables until they are deleted by [`LevelController::delete_tables`]
+    /// or released by [`LevelController::release_tables`].
+    pub fn reserve_tables_within(&self, lower: &[u8], upper: Bound<&[u8]>) -> Vec<Arc<SsTable>> {
+        let mut reserved = vec![];
+        for (level, tables) in self.inner.levels.iter().enumerate() {
+            let tables = tables.read();
+            let mut job = self.inner.compact_job[level].lock();
+            for table in tables.iter() {
+                let within_upper = match upper {
+                    Bound::Included(key) => table.compare(&table.biggest_key, key).is_le(),
+                    Bound::Excluded(key) => table.compare(&table.biggest_key, key).is_lt(),
+                    Bound::Unbounded => true,
+                };
+                let within_lower = table.compare(&table.smallest_key, lower).is_ge();
+                if within_lower && within_upper && !job.contains(&table.id) {
+                    job.insert(table.id);
+                    reserved.push(table.clone());
+                }
+            }
+        }
+        reserved
+    }
+
+    /// Release tables reserved by [`LevelController::reserve_tables_within`].
+    pub fn release_tables(&self, tables: &[Arc<SsTable>]) {
+        for job in self.inner.compact_job.iter() {
+            let mut job = job.lock();
+            for table in tables {
+                job.remove(&table.id);
+            }
+        }
+    }
+
+    /// Delete reserved tables, return the total size of them.
+    pub fn delete_tables(&self, tables: &[Arc<SsTable>]) -> Result<u64> {
+        if tables.is_empty() {
+            return Ok(0);
+        }
+        let changes = tables
+            .iter()
+            .map(|table| Change::delete(table.id))
+            .collect();
+        self.inner
+            .manifest
+            .apply_change_set(&ManifestChangeSet { changes })?;
+
+        let ids = tables.iter().map(|table| table.id).collect::<HashSet<_>>();
+        for level in self.inner.levels.iter() {
+            level.write().retain(|table| !ids.contains(&table.id));
+        }
+        self.release_tables(tables);
+        Ok(tables.iter().map(|table| table.size as u64).sum())
+    }
+
+    /// Compact the tables overlapping with the range level by level, until they reach the last
+    /// level. Tables being compacted in the background are waited for.
+    ///
+    /// Returns the result from the tables compacted to the tables left in the last level, tables
+    /// in between are not counted.
+    pub fn compact_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<CompactionResult> {
+        let mut inputs = vec![];
+        let mut outputs = HashMap::new();
+        let mut dropped_tombstones = 0;
+        let mut dropped_expired = 0;
+        for level in 0..self.opts.num_levels - 1 {
+            let task = loop {
+                let ids = self
+                    .level_tables_sorted(lower, upper)
+                    .iter()
+                    .map(|table| table.id)
+                    .collect::<HashSet<_>>();
+                if let Some(task) = self.inner.fill_table_range(level, &ids) {
+                    break task;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            };
+            if task.this_tables.is_empty() {
+                continue;
+            }
+            let task = Arc::new(task);
+            let new_tables = self.inner.run_task(&task)?;
+            // outputs of a level are compacted again into the next one
+            for table in task.this_tables.iter().chain(&task.next_tables) {
+                if outputs.remove(&table.id).is_none() {
+                    inputs.push(table.clone());
+                }
+            }
+            outputs.extend(new_tables.into_iter().map(|table| (table.id, table)));
+            dropped_tombstones += task.dropped_tombstones.load(Ordering::Relaxed);
+            dropped_expired += task.dropped_expired.load(Ordering::Relaxed);
+        }
+        Ok(CompactionResult::new(
+            &inputs,
+            outputs.values(),
+            dropped_tombstones,
+            dropped_expired,
+        ))
+    }
+
     pub fn level_tables_sorted(
         &self,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Vec<Arc<SsTable>> {
-        let smallest_key = match lower {
-            Bound::Included(key) => Some(key),
-            Bound::Excluded(key) => Some(key),
-            Bound::Unbounded => None,
-        };
-        let biggest_key = match upper {
-            Bound::Included(key) => Some(key),
-            Bound::Excluded(key) => Some(key),
-            Bound::Unbounded => None,
-        };
-        let mut res = vec![];
-        let mut filter_push = |tables: Vec<Arc<SsTable>>| {
-            for table in tables {
-                if let Some(key) = smallest_key {
-                    if table.biggest_key < key {
-                        continue;
-                    }
-                }
-                if let Some(key) = biggest_key {
-                    if table.smallest_key > key {
-                        continue;
-                    }
+        tables_sorted(&self.snapshot_levels(), lower, upper)
+    }
+}
+
+/// Push the records of `key` in the tables of `level` to `chain`, return true if it's complete.
+/// A table is looked up by `get`.
+fn get_in_level(
+    level: usize,
+    tables: &[Arc<SsTable>],
+    key: &[u8],
+    chain: &mut MergeChain,
+    mut get: impl FnMut(&Arc<SsTable>) -> Result<Option<Record>>,
+) -> Result<bool> {
+    if tables.is_empty() {
+        return Ok(false);
+    }
+    if level == 0 {
+        for table in l0_newest_first(tables) {
+            if let Some(record) = get(table)? {
+                if chain.push(record) {
+                    return Ok(true);
                 }
-                res.push(table);
+            }
+        }
+        return Ok(false);
+    }
+
+    let Some(table) = find_table_in_level(tables, key) else {
+        return Ok(false);
+    };
+    Ok(match get(table)? {
+        Some(record) => chain.push(record),
+        None => false,
+    })
+}
+
+/// Find the table whose range contains `key` in the sorted tables of a level, None if the key is
+/// below the first table, above the last one, or in a gap between two tables.
+fn find_table_in_level<'a>(tables: &'a [Arc<SsTable>], key: &[u8]) -> Option<&'a Arc<SsTable>> {
+    let idx = tables.partition_point(|table| table.compare(&table.smallest_key, key).is_le());
+    let table = &tables[idx.checked_sub(1)?];
+    table.overlaps(key, key).then_some(table)
+}
+
+/// Sort the tables of a level by their smallest keys.
+fn sort_tables(tables: &mut [Arc<SsTable>]) {
+    tables.sort_by(|a, b| a.compare(&a.smallest_key, &b.smallest_key));
+}
+
+/// Check if the key range spanned by `tables` overlaps with [`lower`, `upper`], comparing keys
+/// by the comparator of `table`.
+fn span_overlaps<'a>(
+    tables: impl Iterator<Item = &'a Arc<SsTable>> + Clone,
+    table: &SsTable,
+    lower: &[u8],
+    upper: &[u8],
+) -> bool {
+    let smallest = tables
+        .clone()
+        .map(|t| &t.smallest_key)
+        .min_by(|a, b| table.compare(a, b));
+    let biggest = tables
+        .map(|t| &t.biggest_key)
+        .max_by(|a, b| table.compare(a, b));
+    match (smallest, biggest) {
+        (Some(smallest), Some(biggest)) => {
+            table.compare(smallest, upper).is_le() && table.compare(biggest, lower).is_ge()
+        }
+        _ => false,
+    }
+}
+
+/// Order the tables of level 0 by their ids, the newer first. Ids are allocated in the order of
+/// creation, so the newer table wins on equal keys however the tables are placed in the level.
+fn l0_newest_first(tables: &[Arc<SsTable>]) -> Vec<&Arc<SsTable>> {
+    let mut tables: Vec<_> = tables.iter().collect();
+    tables.sort_by_key(|table| Reverse(table.id));
+    tables
+}
+
+/// Add a value to `builder`, keeping its expiry time.
+fn add_value(
+    builder: &mut SsTableBuilder,
+    key: &[u8],
+    value: &[u8],
+    expire_at: Option<u64>,
+) -> Result<()> {
+    match expire_at {
+        Some(expire_at) => builder.add_with_expiry(key, value, expire_at),
+        None => builder.add(key, value),
+    }
+}
+
+/// Push the records of `key` in `tables` (newer first) to `chain`, return true if it's complete.
+pub(crate) fn get_in_tables(
+    tables: &[Arc<SsTable>],
+    key: &[u8],
+    chain: &mut MergeChain,
+) -> Result<bool> {
+    for table in tables {
+        if let Some(record) = get_in_table(table, key)? {
+            if chain.push(record) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Get the record of `key` in `table`.
+fn get_in_table(table: &Arc<SsTable>, key: &[u8]) -> Result<Option<Record>> {
+    if table.may_contain(key) {
+        let iter = SsTableIterator::create_and_seek_to_key(table.clone(), key)?;
+        if iter.is_valid() && iter.key() == key {
+            return Ok(Some(iter_record(&iter)));
+        }
+    }
+    // keys of the table are newer than its range tombstones
+    if table.range_deleted(key) {
+        return Ok(Some(Record::Deleted));
+    }
+    Ok(None)
+}
+
+/// Get the record of the current entry of `iter`.
+fn iter_record(iter: &impl StorageIterator) -> Record {
+    let value = Bytes::copy_from_slice(iter.value());
+    match iter.is_merge() {
+        _ if iter.is_tombstone() => Record::Deleted,
+        true => Record::Merge(value),
+        false => Record::Value(value),
+    }
+}
+
+/// Point lookups in the tables of levels, which reuses the block read last time for each table.
+///
+/// Keys are expected to be looked up in order, so that near keys share blocks.
+pub(crate) struct LevelsGetter<'a> {
+    levels: &'a [Vec<Arc<SsTable>>],
+    blocks: HashMap<u64, (usize, Arc<Block>)>,
+}
+
+impl<'a> LevelsGetter<'a> {
+    pub(crate) fn new(levels: &'a [Vec<Arc<SsTable>>]) -> Self {
+        Self {
+            levels,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Push the records of `key` in the tables of levels to `chain`, which has the records of
+    /// newer tables, until it's complete.
+    pub(crate) fn get(&mut self, key: &[u8], chain: &mut MergeChain) -> Result<()> {
+        let hash = xxhash_rust::xxh3::xxh3_64(key);
+        get_in_levels_by(self.levels, key, chain, |table| {
+            self.get_in_table(table, key, hash)
+        })
+    }
+
+    fn get_in_table(&mut self, table: &SsTable, key: &[u8], hash: u64) -> Result<Option<Record>> {
+        if !table.overlaps(key, key) {
+            return Ok(None);
+        }
+        if !table.may_contain_hash(hash) {
+            if table.range_deleted(key) {
+                return Ok(Some(Record::Deleted));
+            }
+            return Ok(None);
+        }
+        let block_idx = table.find_block_idx(key);
+        let block = match self.blocks.get(&table.id) {
+            Some((idx, block)) if *idx == block_idx => block.clone(),
+            _ => {
+                let block = table.read_block_cached(block_idx)?;
+                self.blocks.insert(table.id, (block_idx, block.clone()));
+                block
             }
         };
+        let iter = BlockIterator::create_and_seek_to_key(block, key);
+        if iter.is_valid() && iter.key() == key {
+            let value = Bytes::copy_from_slice(iter.value());
+            return Ok(Some(match iter.is_merge() {
+                _ if iter.is_tombstone() => Record::Deleted,
+                true => Record::Merge(value),
+                false => Record::Value(value),
+            }));
+        }
+        if table.range_deleted(key) {
+            return Ok(Some(Record::Deleted));
+        }
+        Ok(None)
+    }
+}
+
+/// Push the records of `key` in the tables of levels to `chain`, which has the records of newer
+/// tables, until it's complete.
+pub(crate) fn get_in_levels(
+    levels: &[Vec<Arc<SsTable>>],
+    key: &[u8],
+    chain: &mut MergeChain,
+) -> Result<()> {
+    get_in_levels_by(levels, key, chain, |table| get_in_table(table, key))
+}
 
-        let mut l0_tables = self.inner.levels[0].read().clone();
-        l0_tables.reverse();
-        filter_push(l0_tables);
+/// Like `get_in_levels`, but a table is looked up by `get`.
+fn get_in_levels_by(
+    levels: &[Vec<Arc<SsTable>>],
+    key: &[u8],
+    chain: &mut MergeChain,
+    mut get: impl FnMut(&Arc<SsTable>) -> Result<Option<Record>>,
+) -> Result<()> {
+    for (i, tables) in levels.iter().enumerate() {
+        if get_in_level(i, tables, key, chain, &mut get)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Returns tables that overlap with the range, the newer table is in front.
+pub(crate) fn tables_sorted(
+    levels: &[Vec<Arc<SsTable>>],
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Vec<Arc<SsTable>> {
+    let smallest_key = match lower {
+        Bound::Included(key) => Some(key),
+        Bound::Excluded(key) => Some(key),
+        Bound::Unbounded => None,
+    };
+    let biggest_key = match upper {
+        Bound::Included(key) => Some(key),
+        Bound::Excluded(key) => Some(key),
+        Bound::Unbounded => None,
+    };
+    let mut res = vec![];
+    let mut filter_push = |tables: &mut dyn Iterator<Item = &Arc<SsTable>>| {
+        for table in tables {
+            let lower = smallest_key.unwrap_or(&table.smallest_key);
+            let upper = biggest_key.unwrap_or(&table.biggest_key);
+            if table.overlaps(lower, upper) {
+                res.push(table.clone());
+            }
+        }
+    };
 
-        for lock in &self.inner.levels[1..] {
-            let tables = lock.read().clone();
-            filter_push(tables);
+    if let Some((l0_tables, tables)) = levels.split_first() {
+        filter_push(&mut l0_newest_first(l0_tables).into_iter());
+        for level in tables {
+            filter_push(&mut level.iter());
         }
-        res
     }
+    res
 }
 
 #[cfg(test)]
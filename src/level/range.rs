@@ -1,8 +1,9 @@
-use std::{collections::BTreeSet, ops::Bound};
+use std::ops::Bound;
 
 use bytes::Bytes;
 
 use super::task::Task;
+use crate::opt::Comparator;
 
 #[derive(Debug)]
 pub struct RwsSlice {
@@ -12,26 +13,26 @@ pub struct RwsSlice {
 
 impl RwsSlice {
     pub fn split(&self, mean: usize) -> Vec<(Bound<Bytes>, Bound<Bytes>)> {
-        if self.total_size == 0 {
+        if self.ranges.is_empty() {
             return vec![];
         }
         let mut res = vec![];
         let mut acc_size = 0;
-        let mut first_key = Bytes::new();
+        let mut first_key = None;
         for rws in &self.ranges {
-            if acc_size == 0 {
-                first_key = rws.smallest_key.clone();
+            if first_key.is_none() {
+                first_key = Some(rws.smallest_key.clone());
             }
             acc_size += rws.size;
             if acc_size >= mean {
                 res.push((
-                    Bound::Included(first_key.clone()),
+                    Bound::Included(first_key.take().unwrap()),
                     Bound::Excluded(rws.biggest_key.clone()),
                 ));
                 acc_size = 0;
             }
         }
-        if acc_size != 0 {
+        if let Some(first_key) = first_key {
             res.push((
                 Bound::Included(first_key),
                 Bound::Included(self.ranges.last().unwrap().biggest_key.clone()),
@@ -42,40 +43,51 @@ impl RwsSlice {
         res
     }
 
-    pub fn create(task: &Task) -> RwsSlice {
-        let mut set = BTreeSet::new();
+    pub fn create(task: &Task, comparator: &dyn Comparator) -> RwsSlice {
+        let mut keys = vec![];
         if task.this_level_id == 0 {
             for table in &task.this_tables {
-                set.insert(table.smallest_key.to_vec());
-                set.insert(table.biggest_key.to_vec());
+                keys.push(table.smallest_key.clone());
+                keys.push(table.biggest_key.clone());
             }
         } else {
             for table in &task.this_tables {
-                set.insert(table.smallest_key.to_vec());
+                keys.push(table.smallest_key.clone());
+            }
+            // this_tables are not sorted by key
+            if let Some(key) = task
+                .this_tables
+                .iter()
+                .map(|x| &x.biggest_key)
+                .max_by(|a, b| comparator.compare(a, b))
+            {
+                keys.push(key.clone());
             }
         }
         for table in &task.next_tables {
-            set.insert(table.smallest_key.to_vec());
+            keys.push(table.smallest_key.clone());
         }
         if !task.next_tables.is_empty() {
-            set.insert(task.next_tables.last().unwrap().biggest_key.to_vec());
+            keys.push(task.next_tables.last().unwrap().biggest_key.clone());
         }
+        // the boundaries of the ranges, in the order of the keys
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        keys.dedup();
 
-        let mut ranges = Vec::with_capacity(set.len());
+        let mut ranges = Vec::with_capacity(keys.len());
         let mut total_size = 0;
-        let mut iter = set.iter();
+        let mut iter = keys.iter();
         iter.next();
-        for (lower, upper) in set.iter().zip(iter) {
-            let lower = Bytes::copy_from_slice(lower);
-            let upper = Bytes::copy_from_slice(upper);
+        for (lower, upper) in keys.iter().zip(iter) {
+            let (lower, upper) = (lower.clone(), upper.clone());
             let mut size = 0;
             for table in &task.this_tables {
-                if table.smallest_key <= upper || table.biggest_key >= lower {
+                if table.overlaps(&lower, &upper) {
                     size += table.overlap_size(&lower, &upper);
                 }
             }
             for table in &task.next_tables {
-                if table.smallest_key <= upper || table.biggest_key >= lower {
+                if table.overlaps(&lower, &upper) {
                     size += table.overlap_size(&lower, &upper);
                 }
             }
@@ -86,6 +98,16 @@ impl RwsSlice {
                 size,
             });
         }
+        // all tables only contain the same key
+        if ranges.is_empty() {
+            if let Some(key) = keys.into_iter().next() {
+                ranges.push(RangeWithSize {
+                    smallest_key: key.clone(),
+                    biggest_key: key,
+                    size: 0,
+                });
+            }
+        }
         RwsSlice { ranges, total_size }
     }
 }
@@ -1,3 +1,4 @@
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
 use crate::table::SsTable;
@@ -20,4 +21,7 @@ pub struct Task {
     pub next_level_id: usize,
     pub this_tables: Vec<Arc<SsTable>>,
     pub next_tables: Vec<Arc<SsTable>>,
+    // counted by sub compactions
+    pub dropped_tombstones: AtomicU64,
+    pub dropped_expired: AtomicU64,
 }
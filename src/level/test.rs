@@ -1,18 +1,29 @@
-use std::{collections::BTreeMap, ops::Bound, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
 
 use bytes::Bytes;
 use tempfile::TempDir;
 
 use crate::{
-    opt::LsmOptions,
+    block::CompressOptions,
+    iterators::StorageIterator,
+    lsm_storage::scan_tables,
+    merge::MergeChain,
+    opt::{BytewiseComparator, FilterDecision, LsmOptions},
     table::{SsTable, SsTableBuilder},
-    util::sstable_file_path,
+    util::{sstable_file_path, sstable_tmp_file_path},
 };
 
 use super::{
+    get_in_levels,
     range::{RangeWithSize, RwsSlice},
+    tables_sorted,
     task::{Task, TaskPriority},
-    LevelController,
+    LevelController, LevelsGetter,
 };
 
 fn key_of(idx: usize) -> Vec<u8> {
@@ -57,7 +68,7 @@ fn create_ranges() {
         let table = generate_sst(j * 50, (j + 1) * 50, i as u64, path, "l1");
         task.next_tables.push(Arc::new(table));
     }
-    let rws = RwsSlice::create(&task);
+    let rws = RwsSlice::create(&task, &BytewiseComparator);
     let mut key = rws.ranges[0].smallest_key.clone();
     for item in &rws.ranges {
         assert_eq!(item.smallest_key, key);
@@ -65,6 +76,52 @@ fn create_ranges() {
     }
 }
 
+#[test]
+fn create_ranges_disjoint() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path();
+    let opts = Arc::new(LsmOptions::default().block_size(64));
+    let build = |lower: usize, upper: usize, id: u64| {
+        let mut builder = SsTableBuilder::new(opts.clone());
+        for idx in lower..upper {
+            builder.add(&key_of(idx), &value_of(idx, "")).unwrap();
+        }
+        Arc::new(
+            builder
+                .build(id, None, sstable_file_path(path, id))
+                .unwrap(),
+        )
+    };
+    let task = Task {
+        this_level_id: 0,
+        next_level_id: 1,
+        this_tables: vec![build(0, 100, 1), build(200, 300, 2)],
+        next_tables: vec![build(400, 500, 3)],
+        ..Default::default()
+    };
+    let tables = [
+        &task.this_tables[0],
+        &task.this_tables[1],
+        &task.next_tables[0],
+    ];
+    let rws = RwsSlice::create(&task, &BytewiseComparator);
+    // a range per table, and the gaps between them
+    assert_eq!(rws.ranges.len(), 5);
+    let mut total_size = 0;
+    for (idx, item) in rws.ranges.iter().enumerate() {
+        if idx % 2 == 0 {
+            let table = tables[idx / 2];
+            let expected = table.overlap_size(&item.smallest_key, &item.biggest_key);
+            assert!(expected > 0);
+            assert_eq!(item.size, expected, "range {idx}");
+        } else {
+            assert_eq!(item.size, 0, "range {idx}");
+        }
+        total_size += item.size;
+    }
+    assert_eq!(rws.total_size, total_size);
+}
+
 #[test]
 fn ranges_split() {
     let ranges = vec![
@@ -110,6 +167,96 @@ fn ranges_split() {
     assert_eq!(exp, bounds)
 }
 
+#[test]
+fn ranges_split_tiny_mean() {
+    let ranges = (0..5)
+        .map(|i| RangeWithSize {
+            smallest_key: Bytes::from(format!("00{i}")),
+            biggest_key: Bytes::from(format!("00{}", i + 1)),
+            size: 1,
+        })
+        .collect::<Vec<_>>();
+    let rws = RwsSlice {
+        ranges,
+        total_size: 5,
+    };
+    // every range is split out, the bounds are contiguous and the last one is inclusive
+    let bounds = rws.split(1);
+    assert_eq!(bounds.len(), 5);
+    assert_eq!(bounds[0].0, Bound::Included(Bytes::from("000")));
+    for i in 1..5 {
+        let Bound::Excluded(key) = &bounds[i - 1].1 else {
+            panic!("invalid upper {:?}", bounds[i - 1].1);
+        };
+        assert_eq!(bounds[i].0, Bound::Included(key.clone()));
+    }
+    assert_eq!(bounds[4].1, Bound::Included(Bytes::from("005")));
+}
+
+#[test]
+fn ranges_split_zero_size() {
+    let ranges = vec![
+        RangeWithSize {
+            smallest_key: Bytes::from(&b"001"[..]),
+            biggest_key: Bytes::from(&b"002"[..]),
+            size: 0,
+        },
+        RangeWithSize {
+            smallest_key: Bytes::from(&b"002"[..]),
+            biggest_key: Bytes::from(&b"003"[..]),
+            size: 0,
+        },
+    ];
+    let rws = RwsSlice {
+        ranges,
+        total_size: 0,
+    };
+    let exp = vec![(
+        Bound::Included(Bytes::from(&b"001"[..])),
+        Bound::Included(Bytes::from(&b"003"[..])),
+    )];
+    assert_eq!(exp, rws.split(1))
+}
+
+#[test]
+fn create_ranges_same_key() {
+    let dir = TempDir::new().unwrap();
+    let mut task = Task {
+        this_level_id: 0,
+        next_level_id: 1,
+        ..Default::default()
+    };
+    for i in 0..2 {
+        let table = generate_sst(10, 11, i, dir.path(), &i.to_string());
+        task.this_tables.push(Arc::new(table));
+    }
+    let rws = RwsSlice::create(&task, &BytewiseComparator);
+    let key = Bytes::from(key_of(10));
+    let exp = vec![(Bound::Included(key.clone()), Bound::Included(key))];
+    assert_eq!(exp, rws.split(1));
+}
+
+#[test]
+fn create_ranges_cover_this_tables() {
+    let dir = TempDir::new().unwrap();
+    let mut task = Task {
+        this_level_id: 1,
+        next_level_id: 2,
+        ..Default::default()
+    };
+    for (i, (lower, upper)) in [(0, 50), (60, 100)].into_iter().enumerate() {
+        let table = generate_sst(lower, upper, i as u64, dir.path(), "l1");
+        task.this_tables.push(Arc::new(table));
+    }
+    let rws = RwsSlice::create(&task, &BytewiseComparator);
+    let ranges = rws.split(1);
+    assert_eq!(ranges[0].0, Bound::Included(Bytes::from(key_of(0))));
+    assert_eq!(
+        ranges.last().unwrap().1,
+        Bound::Included(Bytes::from(key_of(99)))
+    );
+}
+
 fn lvctl_new(dir: &TempDir) -> LevelController {
     LevelController::open(Arc::new(LsmOptions::default().path(dir.path()))).unwrap()
 }
@@ -141,6 +288,58 @@ fn get_simple_not_exist() {
     assert_eq!(None, lvctl.get(b"aaaaa").unwrap());
 }
 
+#[test]
+fn get_not_exist_bloom() {
+    let dir = TempDir::new().unwrap();
+    let lvctl = lvctl_new(&dir);
+    let mut opts = LsmOptions::default().block_size(64);
+    opts.false_positive_rate = 0.01;
+    let mut builder = SsTableBuilder::new(opts.into());
+    for i in 0..100 {
+        builder.add(&key_of(i * 2), &value_of(i * 2, "")).unwrap();
+    }
+    lvctl.l0_push_sstable(builder).unwrap();
+
+    let table = lvctl.inner.levels[0].read()[0].clone();
+    let block_reads = table.block_reads();
+    assert_eq!(None, lvctl.get(&key_of(51)).unwrap());
+    assert_eq!(block_reads, table.block_reads());
+    assert!(lvctl.get(&key_of(50)).unwrap().is_some());
+    assert!(block_reads < table.block_reads());
+}
+
+#[test]
+fn get_in_gapped_level() {
+    let dir = TempDir::new().unwrap();
+    let lvctl = lvctl_new(&dir);
+    let tables = [(100, 200, 100), (300, 400, 101)]
+        .map(|(lower, upper, id)| Arc::new(generate_sst(lower, upper, id, dir.path(), "")));
+    lvctl.inner.levels[1].write().extend(tables.iter().cloned());
+    let block_reads = || {
+        tables
+            .iter()
+            .map(|table| table.block_reads())
+            .sum::<usize>()
+    };
+
+    let reads = block_reads();
+    // below the first table, in the gap, and above the last table
+    for idx in (0..100).chain(200..300).chain(400..500) {
+        assert_eq!(lvctl.get(&key_of(idx)).unwrap(), None);
+        let levels = lvctl.snapshot_levels();
+        let mut chain = MergeChain::default();
+        LevelsGetter::new(&levels)
+            .get(&key_of(idx), &mut chain)
+            .unwrap();
+        assert!(!chain.is_complete());
+    }
+    assert_eq!(block_reads(), reads);
+
+    for idx in (100..200).chain(300..400) {
+        assert_eq!(lvctl.get(&key_of(idx)).unwrap().unwrap(), value_of(idx, ""));
+    }
+}
+
 #[test]
 fn get_key_new_old() {
     let dir = TempDir::new().unwrap();
@@ -163,6 +362,45 @@ fn get_key_new_old() {
     }
 }
 
+#[test]
+fn l0_newer_id_wins() {
+    let dir = TempDir::new().unwrap();
+    let old = Arc::new(generate_sst(0, 10, 1, dir.path(), "old"));
+    let new = Arc::new(generate_sst(0, 10, 2, dir.path(), "new"));
+    for l0 in [
+        vec![old.clone(), new.clone()],
+        vec![new.clone(), old.clone()],
+    ] {
+        let levels = vec![l0, vec![]];
+        let mut getter = LevelsGetter::new(&levels);
+        for i in 0..10 {
+            let mut chain = MergeChain::default();
+            get_in_levels(&levels, &key_of(i), &mut chain).unwrap();
+            assert_eq!(
+                chain.resolve(&key_of(i), None).unwrap().unwrap(),
+                value_of(i, "new")
+            );
+            let mut chain = MergeChain::default();
+            getter.get(&key_of(i), &mut chain).unwrap();
+            assert_eq!(
+                chain.resolve(&key_of(i), None).unwrap().unwrap(),
+                value_of(i, "new")
+            );
+        }
+
+        let ssts = tables_sorted(&levels, Bound::Unbounded, Bound::Unbounded);
+        let mut iter =
+            scan_tables(None, &[], &ssts, Bound::Unbounded, Bound::Unbounded, None).unwrap();
+        for i in 0..10 {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), key_of(i));
+            assert_eq!(iter.value(), value_of(i, "new"));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
 #[test]
 fn get_key_delete() {
     let dir = TempDir::new().unwrap();
@@ -174,7 +412,7 @@ fn get_key_delete() {
     lvctl.l0_push_sstable(builder).unwrap();
     let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
     for i in 0..10 {
-        builder.add(&key_of(i), b"").unwrap();
+        builder.add_tombstone(&key_of(i)).unwrap();
     }
     lvctl.l0_push_sstable(builder).unwrap();
     for i in 0..10 {
@@ -199,19 +437,61 @@ fn get_key_drop() {
     }
 }
 
-fn generate_lvctl(path: impl AsRef<Path>) -> (LevelController, BTreeMap<Bytes, Bytes>) {
-    let lvctl = LevelController::open(LsmOptions::default().path(path).into()).unwrap();
-    let mut map = BTreeMap::new();
+#[test]
+fn open_remove_orphan_files() {
+    let dir = TempDir::new().unwrap();
+    let lvctl = lvctl_new(&dir);
+    let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
     for i in 0..10 {
-        let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
+        builder.add(&key_of(i), &value_of(i, "")).unwrap();
+    }
+    lvctl.l0_push_sstable(builder).unwrap();
+    lvctl.mark_save();
+    drop(lvctl);
+
+    // compaction outputs which are not recorded in the manifest
+    let orphan = generate_sst(0, 20, 100, dir.path(), "orphan");
+    orphan.mark_save();
+    drop(orphan);
+    let tmp_path = sstable_tmp_file_path(dir.path(), 101);
+    std::fs::write(&tmp_path, b"partial").unwrap();
+
+    let lvctl = lvctl_new(&dir);
+    assert!(!sstable_file_path(dir.path(), 100).exists());
+    assert!(!tmp_path.exists());
+    assert!(lvctl.inner.next_sst_id.load(Ordering::Relaxed) > 101);
+    for i in 0..10 {
+        assert_eq!(value_of(i, ""), lvctl.get(&key_of(i)).unwrap().unwrap());
+    }
+    assert_eq!(None, lvctl.get(&key_of(15)).unwrap());
+}
+
+/// Push `n` tables to level 0, table `i` has keys in `i * 50..i * 50 + 70` with values of info
+/// `i`, so it overlaps with the tables before and after it.
+fn fill_l0(lvctl: &LevelController, n: usize) {
+    for i in 0..n {
+        let mut builder = SsTableBuilder::new_for_level(lvctl.opts.clone(), 0);
         for j in i * 50..i * 50 + 70 {
-            let key = key_of(j);
-            let val = value_of(j, &i.to_string());
-            builder.add(&key, &val).unwrap();
-            map.insert(Bytes::copy_from_slice(&key), Bytes::copy_from_slice(&val));
+            builder
+                .add(&key_of(j), &value_of(j, &i.to_string()))
+                .unwrap();
         }
         lvctl.l0_push_sstable(builder).unwrap();
     }
+}
+
+fn generate_lvctl(path: impl AsRef<Path>) -> (LevelController, BTreeMap<Bytes, Bytes>) {
+    let opts = LsmOptions::default().path(path).block_size(64);
+    let lvctl = LevelController::open(opts.into()).unwrap();
+    fill_l0(&lvctl, 10);
+    // the newest table of a key wins
+    let map = (0..10 * 50 + 20)
+        .map(|j| {
+            let i = (j / 50).min(9);
+            let (key, val) = (key_of(j), value_of(j, &i.to_string()));
+            (Bytes::from(key), Bytes::from(val))
+        })
+        .collect();
     (lvctl, map)
 }
 
@@ -248,3 +528,550 @@ fn simple_compact() {
         assert_eq!(lvctl.get(key).unwrap().unwrap(), val);
     }
 }
+
+#[test]
+fn compact_compress_per_level() {
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path()).block_size(64);
+    opts.compress_option = CompressOptions::Snappy;
+    opts.compress_per_level = vec![CompressOptions::Uncompress, CompressOptions::Lz4];
+    let lvctl = LevelController::open(opts.into()).unwrap();
+    fill_l0(&lvctl, 4);
+
+    let check_level = |level: usize, expected: CompressOptions| {
+        let tables = lvctl.inner.levels[level].read().clone();
+        assert!(!tables.is_empty());
+        for table in tables {
+            for idx in 0..table.num_of_blocks() {
+                assert_eq!(table.block_compress_option(idx).unwrap(), expected);
+            }
+        }
+    };
+    check_level(0, CompressOptions::Uncompress);
+    lvctl
+        .inner
+        .do_compact(0, TaskPriority::new(0, 1.0))
+        .unwrap();
+    check_level(1, CompressOptions::Lz4);
+    lvctl
+        .inner
+        .do_compact(0, TaskPriority::new(1, 1.0))
+        .unwrap();
+    // falls back to compress_option
+    check_level(2, CompressOptions::Snappy);
+    for i in 0..220 {
+        assert!(lvctl.get(&key_of(i)).unwrap().is_some(), "{i}");
+    }
+}
+
+#[test]
+fn deterministic_compact() {
+    let compact = || {
+        let dir = TempDir::new().unwrap();
+        let mut opts = LsmOptions::default().path(dir.path()).block_size(64);
+        opts.deterministic_compaction = true;
+        let lvctl = LevelController::open(opts.into()).unwrap();
+        fill_l0(&lvctl, 10);
+        lvctl
+            .inner
+            .do_compact(0, TaskPriority::new(0, 1.0))
+            .unwrap();
+        let tables = lvctl.inner.levels[1].read().clone();
+        tables
+            .iter()
+            .map(|table| {
+                let content = std::fs::read(sstable_file_path(dir.path(), table.id)).unwrap();
+                (table.id, content)
+            })
+            .collect::<Vec<_>>()
+    };
+    let outputs = compact();
+    assert!(outputs.len() > 1);
+    // ids are assigned in key order
+    assert!(outputs.windows(2).all(|x| x[0].0 < x[1].0));
+    assert_eq!(outputs, compact());
+}
+
+#[test]
+fn pick_compact_levels_overlap() {
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path());
+    opts.max_bytes_for_level_base = 1024;
+    opts.max_bytes_for_level_multiplier = 1;
+    opts.target_file_size_base = 1024;
+    let lvctl = LevelController::open(opts.into()).unwrap();
+    let path = dir.path();
+    // level 1 and 2 have the same size, only level 2 overlaps with its next level
+    let tables = [
+        (1, generate_sst(0, 1000, 100, path, "1")),
+        (2, generate_sst(2000, 3000, 101, path, "2")),
+        (3, generate_sst(2000, 3000, 102, path, "3")),
+    ];
+    for (level, table) in tables {
+        lvctl.inner.levels[level].write().push(Arc::new(table));
+    }
+    let prios = lvctl.inner.pick_compact_levels();
+    let levels = prios.iter().map(|x| x.level).collect::<Vec<_>>();
+    assert_eq!(levels[0], 2);
+    let pos = |level| levels.iter().position(|&x| x == level).unwrap();
+    assert!(pos(2) < pos(1));
+}
+
+#[test]
+fn pick_compact_levels_tombstones() {
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path());
+    opts.max_bytes_for_level_base = 1024;
+    opts.max_bytes_for_level_multiplier = 1;
+    opts.target_file_size_base = 1024;
+    let opts = Arc::new(opts);
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    // level 1 has live data, and level 2 has no more data of mostly tombstones
+    let live = generate_sst(0, 1000, 100, dir.path(), "live");
+    let mut builder = SsTableBuilder::new(opts.clone());
+    for idx in 2000..5000 {
+        match idx % 10 {
+            0 => builder.add(&key_of(idx), &value_of(idx, "live")).unwrap(),
+            _ => builder.add_tombstone(&key_of(idx)).unwrap(),
+        }
+    }
+    let deletes = builder
+        .build(101, None, sstable_file_path(dir.path(), 101))
+        .unwrap();
+    assert!(deletes.size <= live.size);
+    lvctl.inner.levels[1].write().push(Arc::new(live));
+    lvctl.inner.levels[2].write().push(Arc::new(deletes));
+    let prios = lvctl.inner.pick_compact_levels();
+    assert_eq!(prios.len(), 2);
+    assert_eq!(prios[0].level, 2);
+    assert!(prios[0].score > prios[1].score);
+}
+
+#[test]
+fn l0_push_sstable_with_id() {
+    let dir = TempDir::new().unwrap();
+    let lvctl = lvctl_new(&dir);
+    for (i, id) in [30, 10, 20].into_iter().enumerate() {
+        let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
+        for j in i * 10..i * 10 + 10 {
+            builder.add(&key_of(j), &value_of(j, "")).unwrap();
+        }
+        lvctl.l0_push_sstable_with_id(builder, id).unwrap();
+    }
+    let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
+    builder.add(&key_of(100), &value_of(100, "")).unwrap();
+    lvctl.l0_push_sstable(builder).unwrap();
+
+    let expected = [(30, 0), (10, 0), (20, 0), (31, 0)];
+    assert_eq!(
+        lvctl.inner.manifest.get_id_level(),
+        expected.into_iter().collect::<HashMap<_, _>>()
+    );
+    lvctl.mark_save();
+    drop(lvctl);
+
+    let lvctl = lvctl_new(&dir);
+    let ids = lvctl.inner.levels[0]
+        .read()
+        .iter()
+        .map(|table| table.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![30, 10, 20, 31]);
+    assert_eq!(lvctl.inner.next_sst_id.load(Ordering::Relaxed), 32);
+    for i in 0..30 {
+        assert_eq!(value_of(i, ""), lvctl.get(&key_of(i)).unwrap().unwrap());
+    }
+}
+
+#[test]
+fn levels_getter_reuse_block() {
+    let dir = TempDir::new().unwrap();
+    let table = Arc::new(generate_sst(0, 1000, 1, dir.path(), ""));
+    let levels = vec![vec![], vec![table.clone()]];
+    let block_reads = table.block_reads();
+    let mut getter = LevelsGetter::new(&levels);
+    let mut get = |key: &[u8]| {
+        let mut chain = MergeChain::default();
+        getter.get(key, &mut chain).unwrap();
+        chain.resolve(key, None).unwrap()
+    };
+    for i in 0..1000 {
+        assert_eq!(get(&key_of(i)).unwrap(), value_of(i, ""));
+    }
+    assert_eq!(get(b"key_a"), None);
+    assert_eq!(table.block_reads() - block_reads, table.num_of_blocks());
+}
+
+#[test]
+fn compact_with_filter() {
+    let dir = TempDir::new().unwrap();
+    let opts = LsmOptions::default()
+        .path(dir.path())
+        .compaction_filter(|key, value| {
+            if key.starts_with(b"key_00") {
+                FilterDecision::Remove
+            } else if key.starts_with(b"key_01") {
+                FilterDecision::Change(Bytes::copy_from_slice(&value[..10]))
+            } else {
+                FilterDecision::Keep
+            }
+        });
+    let lvctl = LevelController::open(opts.into()).unwrap();
+    fill_l0(&lvctl, 4);
+    lvctl
+        .inner
+        .do_compact(0, TaskPriority::new(0, 1.0))
+        .unwrap();
+
+    // compacted into the bottom level, so removed keys are not written at all
+    let tables = lvctl.inner.levels[1].read().clone();
+    assert!(tables
+        .iter()
+        .all(|table| !table.smallest_key.starts_with(b"key_00")));
+    for i in 0..220 {
+        let value = lvctl.get(&key_of(i)).unwrap();
+        match i {
+            0..=99 => assert!(value.is_none(), "{i}"),
+            100..=199 => assert_eq!(value.unwrap(), value_of(i, "")[..10]),
+            _ => assert!(value.unwrap().starts_with(&value_of(i, "")), "{i}"),
+        }
+    }
+}
+
+#[test]
+fn compact_subcompactor_num() {
+    for (num, loser_tree) in [0, 1, 8]
+        .into_iter()
+        .flat_map(|num| [(num, false), (num, true)])
+    {
+        let dir = TempDir::new().unwrap();
+        let mut opts = LsmOptions::default().path(dir.path()).block_size(64);
+        opts.subcompactor_num = num;
+        opts.loser_tree_compaction = loser_tree;
+        let lvctl = LevelController::open(opts.into()).unwrap();
+        fill_l0(&lvctl, 4);
+        lvctl
+            .inner
+            .do_compact(0, TaskPriority::new(0, 1.0))
+            .unwrap();
+
+        assert!(lvctl.inner.levels[0].read().is_empty());
+        let tables = lvctl.inner.levels[1].read().clone();
+        for pair in tables.windows(2) {
+            assert!(pair[0].biggest_key < pair[1].smallest_key);
+        }
+        for j in 0..220 {
+            // newer tables win where they overlap
+            let i = (j / 50).min(3);
+            assert_eq!(
+                lvctl.get(&key_of(j)).unwrap().unwrap(),
+                value_of(j, &i.to_string()),
+                "{num} {loser_tree} {j}"
+            );
+        }
+    }
+}
+
+#[test]
+fn compact_rate_limited() {
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path());
+    opts.compaction_bytes_per_sec = Some(64 * 1024);
+    opts.compress_option = CompressOptions::Uncompress;
+    let opts = Arc::new(opts);
+    // the bucket starts empty when the controller is opened
+    let start = std::time::Instant::now();
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    for i in 0..4 {
+        let mut builder = SsTableBuilder::new_for_level(opts.clone(), 0);
+        for j in i * 1000..i * 1000 + 1000 {
+            builder
+                .add(&key_of(j), &value_of(j, &i.to_string()))
+                .unwrap();
+        }
+        lvctl.l0_push_sstable(builder).unwrap();
+    }
+    lvctl
+        .inner
+        .do_compact(0, TaskPriority::new(0, 1.0))
+        .unwrap();
+    let elapsed = start.elapsed();
+
+    let written = lvctl.inner.levels[1]
+        .read()
+        .iter()
+        .map(|table| table.size)
+        .sum::<usize>();
+    assert!(written > 64 * 1024, "{written}");
+    let min = std::time::Duration::from_secs_f64(written as f64 / (64 * 1024) as f64);
+    assert!(elapsed >= min, "{elapsed:?} < {min:?}");
+}
+
+#[test]
+fn compact_range() {
+    let dir = TempDir::new().unwrap();
+    let opts = Arc::new(LsmOptions::default().path(dir.path()).block_size(64));
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    fill_l0(&lvctl, 4);
+
+    // only the last l0 table overlaps, but all of them are compacted
+    lvctl
+        .compact_range(Bound::Included(&key_of(210)), Bound::Unbounded)
+        .unwrap();
+    assert!(lvctl.inner.levels[0].read().is_empty());
+    // tables overlapping the range are moved to the last level
+    for level in 1..opts.num_levels - 1 {
+        for table in lvctl.inner.levels[level].read().iter() {
+            assert!(table.biggest_key < key_of(210)[..], "{level}");
+        }
+    }
+    assert!(!lvctl.inner.levels[opts.num_levels - 1].read().is_empty());
+    for j in 0..220 {
+        let i = (j / 50).min(3);
+        assert_eq!(
+            lvctl.get(&key_of(j)).unwrap().unwrap(),
+            value_of(j, &i.to_string()),
+            "{j}"
+        );
+    }
+
+    // tables being compacted are waited for
+    let mut builder = SsTableBuilder::new_for_level(opts.clone(), 0);
+    builder.add(&key_of(0), &value_of(0, "new")).unwrap();
+    lvctl.l0_push_sstable(builder).unwrap();
+    let id = lvctl.inner.levels[0].read()[0].id;
+    lvctl.inner.compact_job[0].lock().insert(id);
+    let inner = lvctl.inner.clone();
+    let handle = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        inner.compact_job[0].lock().remove(&id);
+    });
+    lvctl
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    handle.join().unwrap();
+    assert!(lvctl.inner.levels[0].read().is_empty());
+    assert_eq!(lvctl.get(&key_of(0)).unwrap().unwrap(), value_of(0, "new"));
+}
+
+#[test]
+fn level_stats_skip_compacting_tables() {
+    let dir = TempDir::new().unwrap();
+    let (lvctl, _) = generate_lvctl(dir.path());
+    let stats = lvctl.level_stats()[0];
+    assert_eq!(stats.num_tables, 10);
+
+    let table = lvctl.inner.levels[0].read()[0].clone();
+    lvctl.inner.compact_job[0].lock().insert(table.id);
+    let compacting = lvctl.level_stats()[0];
+    assert_eq!(compacting.num_tables, 9);
+    assert_eq!(compacting.size, stats.size - table.size);
+}
+
+#[test]
+fn ingest_during_compaction() {
+    let dir = TempDir::new().unwrap();
+    let external = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path()).block_size(64);
+    opts.num_levels = 3;
+    let opts = Arc::new(opts);
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    let ingest = |name: &str, info: &str| {
+        let path = external.path().join(name);
+        let mut builder = SsTableBuilder::new(opts.clone());
+        for j in 100..150 {
+            builder.add(&key_of(j), &value_of(j, info)).unwrap();
+        }
+        builder.build(0, None, &path).unwrap().mark_save();
+        let table = lvctl.link_external(&path).unwrap();
+        lvctl.add_ingested(table).unwrap()
+    };
+    assert_eq!(ingest("1.sst", "old"), 2);
+
+    // l0 tables with a gap between their keys are being compacted into l1
+    for keys in [0..50, 200..250] {
+        let mut builder = SsTableBuilder::new_for_level(opts.clone(), 0);
+        for j in keys {
+            builder.add(&key_of(j), &value_of(j, "l0")).unwrap();
+        }
+        lvctl.l0_push_sstable(builder).unwrap();
+    }
+    let task = Arc::new(lvctl.inner.create_task(0).unwrap());
+    // the compaction may write a table over the gap in l1
+    assert_eq!(ingest("2.sst", "new"), 0);
+    lvctl.inner.run_task(&task).unwrap();
+
+    let levels = lvctl.snapshot_levels();
+    assert_eq!(levels[0].len(), 1);
+    for tables in &levels[1..] {
+        for pair in tables.windows(2) {
+            assert!(pair[0].biggest_key < pair[1].smallest_key);
+        }
+    }
+    for j in 100..150 {
+        assert_eq!(lvctl.get(&key_of(j)).unwrap().unwrap(), value_of(j, "new"));
+    }
+    for j in (0..50).chain(200..250) {
+        assert_eq!(lvctl.get(&key_of(j)).unwrap().unwrap(), value_of(j, "l0"));
+    }
+}
+
+#[test]
+fn compact_to_target_file_size() {
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path()).block_size(256);
+    opts.compress_option = CompressOptions::Uncompress;
+    opts.target_file_size_base = 4096;
+    opts.max_bytes_for_level_multiplier = 4;
+    opts.num_levels = 3;
+    opts.subcompactor_num = 1;
+    let opts = Arc::new(opts);
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    for i in 0..4 {
+        let mut builder = SsTableBuilder::new_for_level(opts.clone(), 0);
+        for j in i * 1000..i * 1000 + 1000 {
+            builder.add(&key_of(j), &value_of(j, "")).unwrap();
+        }
+        lvctl.l0_push_sstable(builder).unwrap();
+    }
+
+    lvctl
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert!(lvctl.inner.levels[0].read().is_empty());
+    assert!(lvctl.inner.levels[1].read().is_empty());
+    let target = opts.target_file_size_of_level(2);
+    assert_eq!(target, 4 * 4096);
+    // deeper levels have larger tables by default
+    let default_opts = LsmOptions::default();
+    assert_eq!(
+        default_opts.target_file_size_of_level(1),
+        default_opts.target_file_size_base
+    );
+    assert!(default_opts.target_file_size_of_level(2) > default_opts.target_file_size_of_level(1));
+    let mut opts = (*opts).clone();
+    opts.target_file_size_multiplier = Some(2);
+    assert_eq!(opts.target_file_size_of_level(3), 4 * 4096);
+    let tables = lvctl.inner.levels[2].read().clone();
+    assert!(tables.len() > 2, "{}", tables.len());
+    // tables are full once they reach the target size, except the last one
+    for table in &tables[..tables.len() - 1] {
+        assert!(table.size >= target, "{}", table.size);
+        assert!(table.size < target + target / 4, "{}", table.size);
+    }
+    assert!(tables.last().unwrap().size < target + target / 4);
+    for j in (0..4000).step_by(7) {
+        assert_eq!(lvctl.get(&key_of(j)).unwrap().unwrap(), value_of(j, ""));
+    }
+}
+
+#[test]
+fn failed_compact_removes_outputs() {
+    use crate::util::parse_sstable_id;
+    let dir = TempDir::new().unwrap();
+    let mut opts = LsmOptions::default().path(dir.path()).block_size(256);
+    opts.target_file_size_base = 4096;
+    opts.subcompactor_num = 1;
+    let opts = Arc::new(opts);
+    let lvctl = LevelController::open(opts.clone()).unwrap();
+    let mut builder = SsTableBuilder::new_for_level(opts, 0);
+    for j in 0..1000 {
+        builder.add(&key_of(j), &value_of(j, "")).unwrap();
+    }
+    // no merge operator is set, so the compaction fails at the last key after writing tables
+    builder.add_merge(&key_of(1000), b"operand").unwrap();
+    lvctl.l0_push_sstable(builder).unwrap();
+    let id = lvctl.inner.levels[0].read()[0].id;
+
+    assert!(lvctl
+        .inner
+        .do_compact(0, TaskPriority::new(0, 1.0))
+        .is_err());
+    assert!(lvctl.inner.next_sst_id.load(Ordering::Relaxed) > id + 2);
+    let disk_ids = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter_map(|entry| parse_sstable_id(entry.unwrap().file_name().to_str()?))
+        .collect::<Vec<_>>();
+    assert_eq!(disk_ids, vec![id]);
+    assert!(lvctl.inner.levels[1].read().is_empty());
+}
+
+#[test]
+fn block_cache_bounded_by_bytes() {
+    use crate::block::BlockBuilder;
+
+    let cache = super::BlockCache::new(64 * 1024);
+    let mut builder = BlockBuilder::new(4096);
+    let mut idx = 0;
+    while builder.add(&key_of(idx), &value_of(idx, "")).unwrap() {
+        idx += 1;
+    }
+    let block = Arc::new(builder.build());
+    let size = block.uncompress_size() as u64;
+    assert!(size > 2048, "{size}");
+
+    for id in 0..10000 {
+        let block = cache.try_get_with((id, 0), || Ok(block.clone())).unwrap();
+        assert_eq!(block.uncompress_size() as u64, size);
+    }
+    assert_eq!(cache.misses(), 10000);
+    // blocks are evicted once their total size exceeds the capacity
+    assert!(
+        cache.entry_count() <= 64 * 1024 / size + 16,
+        "{}",
+        cache.entry_count()
+    );
+    assert!(cache.weighted_size() <= 64 * 1024 + 16 * size);
+
+    // a custom weigher counts blocks instead of bytes
+    let cache = super::BlockCache::with_weigher(100, |_| 1);
+    for id in 0..1000 {
+        cache.try_get_with((id, 0), || Ok(block.clone())).unwrap();
+    }
+    assert!(cache.entry_count() <= 100 + 16, "{}", cache.entry_count());
+}
+
+#[test]
+fn reopen_allocates_new_ids() {
+    use crate::util::parse_sstable_id;
+    use std::collections::HashSet;
+    let disk_ids = |dir: &TempDir| {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|entry| parse_sstable_id(entry.unwrap().file_name().to_str()?))
+            .collect::<HashSet<_>>()
+    };
+    let dir = TempDir::new().unwrap();
+    let (lvctl, map) = generate_lvctl(dir.path());
+    lvctl
+        .inner
+        .do_compact(
+            0,
+            TaskPriority {
+                level: 0,
+                score: 1.0,
+            },
+        )
+        .unwrap();
+    lvctl.mark_save();
+    drop(lvctl);
+
+    let old_ids = disk_ids(&dir);
+    let max_id = *old_ids.iter().max().unwrap();
+    let lvctl = lvctl_new(&dir);
+    let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(64).into());
+    builder.add(&key_of(1000), &value_of(1000, "")).unwrap();
+    lvctl.l0_push_sstable(builder).unwrap();
+    let new_ids = disk_ids(&dir);
+    assert_eq!(
+        new_ids.difference(&old_ids).collect::<Vec<_>>(),
+        vec![&(max_id + 1)]
+    );
+    for (key, val) in map.iter() {
+        assert_eq!(lvctl.get(key).unwrap().unwrap(), val);
+    }
+    assert_eq!(
+        lvctl.get(&key_of(1000)).unwrap().unwrap(),
+        value_of(1000, "")
+    );
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "tokio")]
+pub mod async_storage;
 pub mod block;
 pub mod bloom;
 pub mod checksum;
@@ -7,7 +9,11 @@ pub mod lsm_iterator;
 pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
+pub mod merge;
 pub mod opt;
+pub mod range_tombstone;
+pub mod rate_limiter;
+pub mod snapshot;
 pub mod table;
 pub mod util;
 pub mod wal;
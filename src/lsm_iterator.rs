@@ -1,35 +1,331 @@
 use std::ops::Bound;
+use std::sync::Arc;
 
 use anyhow::{Ok, Result};
 use bytes::Bytes;
 
 use crate::{
     iterators::{
-        merge_iterator::MergeIterator, two_merge_iterator::TwoMergeIterator, StorageIterator,
+        merge_iterator::MergeIterator, shadowed_iterator::ShadowedIterator,
+        two_merge_iterator::TwoMergeIterator, StorageIterator,
     },
-    mem_table::MemTableIterator,
-    table::SsTableIterator,
+    level::get_in_tables,
+    mem_table::{get_in_memtables, MemTable, MemTableIterator},
+    merge::MergeChain,
+    opt::{Comparator, MergeOperator},
+    range_tombstone::RangeTombstones,
+    table::{SsTable, SsTableIterator},
 };
-type LsmIteratorInner =
-    TwoMergeIterator<MergeIterator<MemTableIterator>, MergeIterator<SsTableIterator>>;
+pub type MemTableShadowedIterator = ShadowedIterator<MemTableIterator>;
+pub type SsTableShadowedIterator = ShadowedIterator<SsTableIterator>;
+type MergedIterator =
+    TwoMergeIterator<MergeIterator<MemTableShadowedIterator>, MergeIterator<SsTableConcatIterator>>;
+
+pub enum LsmIteratorInner {
+    Merged(MergedIterator),
+    /// The range is only served by a single sorted run of sstables
+    Table(SsTableConcatIterator),
+}
+
+impl StorageIterator for LsmIteratorInner {
+    fn is_valid(&self) -> bool {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.is_valid(),
+            LsmIteratorInner::Table(iter) => iter.is_valid(),
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.key(),
+            LsmIteratorInner::Table(iter) => iter.key(),
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.value(),
+            LsmIteratorInner::Table(iter) => iter.value(),
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.is_tombstone(),
+            LsmIteratorInner::Table(iter) => iter.is_tombstone(),
+        }
+    }
+
+    fn is_merge(&self) -> bool {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.is_merge(),
+            LsmIteratorInner::Table(iter) => iter.is_merge(),
+        }
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.comparator(),
+            LsmIteratorInner::Table(iter) => iter.comparator(),
+        }
+    }
+
+    fn next(&mut self) -> Result<()> {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.next(),
+            LsmIteratorInner::Table(iter) => iter.next(),
+        }
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.prev(),
+            LsmIteratorInner::Table(iter) => iter.prev(),
+        }
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        match self {
+            LsmIteratorInner::Merged(iter) => iter.seek(key),
+            LsmIteratorInner::Table(iter) => iter.seek(key),
+        }
+    }
+}
+
+/// Concatenate sstables whose key ranges are sorted and don't overlap, like the tables of a level.
+/// A table is only seeked when the iterator reaches it, so a scan stopping early doesn't read the
+/// tables after it.
+pub struct SsTableConcatIterator {
+    /// Tables in the order of iteration, descending for a reverse iterator, with the range
+    /// tombstones shadowing them.
+    tables: Vec<(Arc<SsTable>, Arc<RangeTombstones>)>,
+    idx: usize,
+    current: SsTableShadowedIterator,
+    rev: bool,
+}
+
+impl SsTableConcatIterator {
+    /// Create an iterator of `tables` in ascending order, `first` is the iterator of the first
+    /// table positioned at the start of the scan.
+    pub fn create(
+        first: SsTableShadowedIterator,
+        tables: Vec<(Arc<SsTable>, Arc<RangeTombstones>)>,
+    ) -> Result<Self> {
+        Self::create_inner(first, tables, false)
+    }
+
+    /// Create an iterator of `tables` in descending order moving backward by `prev`, `first` is
+    /// the iterator of the first table positioned at the end of the scan.
+    pub fn create_rev(
+        first: SsTableShadowedIterator,
+        tables: Vec<(Arc<SsTable>, Arc<RangeTombstones>)>,
+    ) -> Result<Self> {
+        Self::create_inner(first, tables, true)
+    }
+
+    fn create_inner(
+        first: SsTableShadowedIterator,
+        tables: Vec<(Arc<SsTable>, Arc<RangeTombstones>)>,
+        rev: bool,
+    ) -> Result<Self> {
+        assert!(!tables.is_empty(), "no tables to concatenate");
+        let mut iter = Self {
+            tables,
+            idx: 0,
+            current: first,
+            rev,
+        };
+        iter.skip_exhausted()?;
+        Ok(iter)
+    }
+
+    /// Move to the following tables until one has an entry left.
+    fn skip_exhausted(&mut self) -> Result<()> {
+        while !self.current.is_valid() && self.idx + 1 < self.tables.len() {
+            self.idx += 1;
+            let (table, shadow) = self.tables[self.idx].clone();
+            self.current = match self.rev {
+                true => ShadowedIterator::create_rev(
+                    SsTableIterator::create_and_seek_to_last(table)?,
+                    shadow,
+                )?,
+                false => ShadowedIterator::create(
+                    SsTableIterator::create_and_seek_to_first(table)?,
+                    shadow,
+                )?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl StorageIterator for SsTableConcatIterator {
+    fn is_valid(&self) -> bool {
+        self.current.is_valid()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.value()
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.current.is_tombstone()
+    }
+
+    fn is_merge(&self) -> bool {
+        self.current.is_merge()
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        self.current.expire_at()
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.tables[0].0.comparator()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "next is unsupported by a reverse concat iterator"
+            ));
+        }
+        self.current.next()?;
+        self.skip_exhausted()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.rev {
+            return Err(anyhow::anyhow!(
+                "prev is unsupported by a forward concat iterator"
+            ));
+        }
+        self.current.prev()?;
+        self.skip_exhausted()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!(
+                "seek is unsupported by a reverse concat iterator"
+            ));
+        }
+        // the first table which may have a key >= `key`
+        self.idx = self
+            .tables
+            .partition_point(|(table, _)| table.compare(&table.biggest_key, key).is_lt())
+            .min(self.tables.len() - 1);
+        let (table, shadow) = self.tables[self.idx].clone();
+        self.current =
+            ShadowedIterator::create(SsTableIterator::create_and_seek_to_key(table, key)?, shadow)?;
+        self.skip_exhausted()
+    }
+}
+
+/// Resolves the merge records a scan reaches, by looking up their keys in the tables of the scan.
+pub struct MergeResolver {
+    /// older memtable is in front
+    memtables: Vec<Arc<MemTable>>,
+    /// newer table is in front
+    ssts: Vec<Arc<SsTable>>,
+    operator: Option<MergeOperator>,
+}
+
+impl MergeResolver {
+    pub fn new(
+        memtables: Vec<Arc<MemTable>>,
+        ssts: Vec<Arc<SsTable>>,
+        operator: Option<MergeOperator>,
+    ) -> Self {
+        Self {
+            memtables,
+            ssts,
+            operator,
+        }
+    }
+
+    /// Get the value of `key` with its merge records combined, None if it's deleted.
+    fn resolve(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let mut chain = MergeChain::default();
+        if !get_in_memtables(&self.memtables, key, &mut chain) {
+            get_in_tables(&self.ssts, key, &mut chain)?;
+        }
+        chain.resolve(key, self.operator.as_ref())
+    }
+}
+
 pub struct LsmIterator {
     inner: LsmIteratorInner,
+    /// The lower bound, `seek` never moves before it.
+    start: Bound<Bytes>,
+    /// The upper bound, or the lower bound if `rev` is set.
     end: Bound<Bytes>,
+    rev: bool,
     is_valid: bool,
+    resolver: MergeResolver,
+    /// The value of the current entry if it's a merge record.
+    merged: Option<Bytes>,
 }
 
 impl LsmIterator {
-    pub fn new(inner: LsmIteratorInner, end: Bound<Bytes>) -> Result<Self> {
+    pub fn new(
+        inner: LsmIteratorInner,
+        start: Bound<Bytes>,
+        end: Bound<Bytes>,
+        resolver: MergeResolver,
+    ) -> Result<Self> {
+        Self::new_inner(inner, start, end, false, resolver)
+    }
+
+    /// Create an iterator moving backward by `prev` until the lower bound `end`.
+    pub fn new_rev(
+        inner: LsmIteratorInner,
+        end: Bound<Bytes>,
+        resolver: MergeResolver,
+    ) -> Result<Self> {
+        Self::new_inner(inner, Bound::Unbounded, end, true, resolver)
+    }
+
+    fn new_inner(
+        inner: LsmIteratorInner,
+        start: Bound<Bytes>,
+        end: Bound<Bytes>,
+        rev: bool,
+        resolver: MergeResolver,
+    ) -> Result<Self> {
         let mut iter = Self {
             is_valid: inner.is_valid(),
             inner,
+            start,
             end,
+            rev,
+            resolver,
+            merged: None,
         };
+        iter.check_end();
+        iter.skip_deleted()?;
+        Ok(iter)
+    }
 
-        while iter.is_valid && iter.value().is_empty() {
-            iter.next_inner()?;
+    /// Move past tombstones and merge records resolved to deletions from the current entry.
+    fn skip_deleted(&mut self) -> Result<()> {
+        self.merged = None;
+        while self.is_valid {
+            if self.inner.is_merge() {
+                self.merged = self.resolver.resolve(self.inner.key())?;
+                if self.merged.is_some() {
+                    break;
+                }
+            } else if !self.inner.is_tombstone() {
+                break;
+            }
+            self.next_inner()?;
         }
-        Ok(iter)
+        Ok(())
     }
 
     fn next_inner(&mut self) -> Result<()> {
@@ -37,18 +333,63 @@ impl LsmIterator {
             return Ok(());
         }
 
-        self.inner.next()?;
+        match self.rev {
+            true => self.inner.prev()?,
+            false => self.inner.next()?,
+        }
         if !self.inner.is_valid() {
             self.is_valid = false;
             return Ok(());
         }
 
-        match &self.end {
-            Bound::Included(key) if self.inner.key() > key => self.is_valid = false,
-            Bound::Excluded(key) if self.inner.key() >= key => self.is_valid = false,
+        self.check_end();
+        Ok(())
+    }
+
+    fn check_end(&mut self) {
+        if !self.is_valid {
+            return;
+        }
+        let order = match &self.end {
+            Bound::Included(end) | Bound::Excluded(end) => {
+                self.inner.comparator().compare(self.inner.key(), end)
+            }
+            Bound::Unbounded => return,
+        };
+        match (&self.end, self.rev) {
+            (Bound::Included(_), false) if order.is_gt() => self.is_valid = false,
+            (Bound::Excluded(_), false) if order.is_ge() => self.is_valid = false,
+            (Bound::Included(_), true) if order.is_lt() => self.is_valid = false,
+            (Bound::Excluded(_), true) if order.is_le() => self.is_valid = false,
             _ => {}
         }
-        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        self.next_inner()?;
+        self.skip_deleted()
+    }
+
+    fn seek_inner(&mut self, key: &[u8]) -> Result<()> {
+        let comparator = self.inner.comparator();
+        let key = match &self.start {
+            Bound::Included(start) | Bound::Excluded(start)
+                if comparator.compare(key, start).is_le() =>
+            {
+                start.clone()
+            }
+            _ => Bytes::copy_from_slice(key),
+        };
+        self.inner.seek(&key)?;
+        self.is_valid = self.inner.is_valid();
+        if matches!(&self.start, Bound::Excluded(start) if *start == key)
+            && self.is_valid
+            && self.inner.key() == key
+        {
+            self.next_inner()?;
+        }
+        self.check_end();
+        self.skip_deleted()
     }
 }
 
@@ -62,15 +403,32 @@ impl StorageIterator for LsmIterator {
     }
 
     fn value(&self) -> &[u8] {
-        self.inner.value()
+        match &self.merged {
+            Some(value) => value,
+            None => self.inner.value(),
+        }
     }
 
     fn next(&mut self) -> Result<()> {
-        self.next_inner()?;
-        while self.is_valid && self.value().is_empty() {
-            self.next_inner()?;
+        if self.rev {
+            return Err(anyhow::anyhow!("next is unsupported by a reverse scan"));
         }
-        Ok(())
+        self.advance()
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.rev {
+            return Err(anyhow::anyhow!("prev is unsupported by a forward scan"));
+        }
+        self.advance()
+    }
+
+    /// Move to the first key which >= `key` within the range of the scan.
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if self.rev {
+            return Err(anyhow::anyhow!("seek is unsupported by a reverse scan"));
+        }
+        self.seek_inner(key)
     }
 }
 
@@ -84,6 +442,62 @@ impl<I: StorageIterator> FusedIterator<I> {
     pub fn new(iter: I) -> Self {
         Self { iter }
     }
+
+    /// Unwrap the inner iterator.
+    pub fn into_inner(self) -> I {
+        self.iter
+    }
+
+    /// Convert into an iterator of owned key-value pairs.
+    pub fn into_items(self) -> Items<I> {
+        Items {
+            iter: self.iter,
+            started: false,
+            failed: false,
+        }
+    }
+}
+
+impl<I: StorageIterator> IntoIterator for FusedIterator<I> {
+    type Item = Result<(Bytes, Bytes)>;
+    type IntoIter = Items<I>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_items()
+    }
+}
+
+/// An `Iterator` of the key-value pairs of a storage iterator copied into `Bytes`, it ends after
+/// yielding an error.
+pub struct Items<I: StorageIterator> {
+    iter: I,
+    // the storage iterator is moved to the next entry lazily, after the current one is copied
+    started: bool,
+    failed: bool,
+}
+
+impl<I: StorageIterator> Iterator for Items<I> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.started {
+            if let Err(e) = self.iter.next() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        self.started = true;
+        if !self.iter.is_valid() {
+            return None;
+        }
+        Some(Ok((
+            Bytes::copy_from_slice(self.iter.key()),
+            Bytes::copy_from_slice(self.iter.value()),
+        )))
+    }
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
@@ -105,4 +519,114 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         }
         self.iter.next()
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.is_valid() {
+            return Ok(());
+        }
+        self.iter.prev()
+    }
+
+    /// Unlike `next`, `seek` can reposition an invalid iterator.
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek(key)
+    }
+}
+
+/// A wrapper around existing iterator, will return an error when the total size of keys and values
+/// read exceeds `limit` bytes.
+pub struct ByteLimited<I: StorageIterator> {
+    iter: I,
+    limit: usize,
+    read_bytes: usize,
+}
+
+impl<I: StorageIterator> ByteLimited<I> {
+    pub fn new(iter: I, limit: usize) -> Result<Self> {
+        let mut iter = Self {
+            iter,
+            limit,
+            read_bytes: 0,
+        };
+        iter.account()?;
+        Ok(iter)
+    }
+
+    /// Get the total size of keys and values read.
+    pub fn read_bytes(&self) -> usize {
+        self.read_bytes
+    }
+
+    fn account(&mut self) -> Result<()> {
+        if !self.iter.is_valid() {
+            return Ok(());
+        }
+        self.read_bytes += self.iter.key().len() + self.iter.value().len();
+        if self.read_bytes > self.limit {
+            return Err(anyhow::anyhow!(
+                "scan exceeds the byte limit {}: {} bytes read",
+                self.limit,
+                self.read_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for ByteLimited<I> {
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid() && self.read_bytes <= self.limit
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.account()
+    }
+}
+
+/// A wrapper around existing iterator, which becomes invalid after `limit` entries. The inner
+/// iterator doesn't move past the last entry, so it reads nothing after it.
+pub struct CountLimited<I: StorageIterator> {
+    iter: I,
+    // entries left, including the current one
+    remaining: usize,
+}
+
+impl<I: StorageIterator> CountLimited<I> {
+    pub fn new(iter: I, limit: usize) -> Self {
+        Self {
+            iter,
+            remaining: limit,
+        }
+    }
+}
+
+impl<I: StorageIterator> StorageIterator for CountLimited<I> {
+    fn is_valid(&self) -> bool {
+        self.remaining > 0 && self.iter.is_valid()
+    }
+
+    fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining == 0 {
+            return Ok(());
+        }
+        self.iter.next()
+    }
 }
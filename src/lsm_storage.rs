@@ -1,35 +1,75 @@
-use std::collections::BTreeMap;
-use std::ops::Bound;
+use std::collections::HashMap;
+use std::ops::{Bound, Range};
+use std::path::Path;
 
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Ok, Result};
 use bytes::Bytes;
 
 use crossbeam_channel::{select, tick, Receiver, Sender};
 use log::{debug, error, info};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 use yatp::task::callback::{Handle, TaskCell};
 
 use crate::iterators::merge_iterator::MergeIterator;
+use crate::iterators::shadowed_iterator::ShadowedIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::iterators::StorageIterator;
-use crate::level::LevelController;
-use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::mem_table::MemTables;
-use crate::opt::LsmOptions;
-use crate::table::{SsTableBuilder, SsTableIterator};
+use crate::level::{CompactionResult, LevelController, LevelStats, LevelsGetter};
+use crate::lsm_iterator::{
+    ByteLimited, CountLimited, FusedIterator, LsmIterator, LsmIteratorInner, MergeResolver,
+    SsTableConcatIterator,
+};
+use crate::manifest::{DEFAULT_CF, DEFAULT_CF_NAME};
+use crate::mem_table::{get_in_memtables, MemTable, MemTables};
+use crate::merge::MergeChain;
+use crate::opt::{LsmOptions, MergeOperator};
+use crate::range_tombstone::{shadows, RangeTombstone, RangeTombstones};
+use crate::snapshot::Snapshot;
+use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::util::{cf_dir, now_millis, prefix_upper_bound};
 
 pub struct LsmStorageInner {
     /// Memory table
     memtables: RwLock<MemTables>,
     lvctl: LevelController,
     opts: Arc<LsmOptions>,
+    /// Number of requests sent to the write core.
+    enqueued_requests: AtomicU64,
+    /// Number of requests applied by the write core.
+    applied_requests: AtomicU64,
+    /// Held to wait for requests applied by the write core.
+    apply_lock: Mutex<()>,
+    /// Notified when the write core applies requests or exits.
+    applied: Condvar,
+    /// Set when the write core exits, queued requests are no longer applied.
+    write_core_stopped: AtomicBool,
+    /// Held when immutable memtables are flushed to l0.
+    flush_lock: Mutex<()>,
+    /// Writers wait on it while there are too many immutable memtables.
+    stall_lock: Mutex<()>,
+    /// Notified when immutable memtables are flushed.
+    flushed: Condvar,
+    /// Set when background flushing stops, writers no longer wait for it.
+    stopped: AtomicBool,
+}
+
+/// Marks the write core stopped when it's dropped.
+struct WriteCoreStopped<'a>(&'a LsmStorageInner);
+
+impl Drop for WriteCoreStopped<'_> {
+    fn drop(&mut self) {
+        self.0.write_core_stopped.store(true, Ordering::Release);
+        self.0.notify_applied();
+    }
 }
 
 pub struct Request {
-    entries: Vec<(Bytes, Bytes)>,
+    /// A None value is a tombstone.
+    entries: Vec<(Bytes, Option<Bytes>)>,
     sender: Option<Sender<Result<(), String>>>,
 }
 
@@ -39,9 +79,342 @@ impl LsmStorageInner {
             memtables: RwLock::new(MemTables::new(opts.clone())?),
             lvctl: LevelController::open(opts.clone())?,
             opts,
+            enqueued_requests: AtomicU64::new(0),
+            applied_requests: AtomicU64::new(0),
+            apply_lock: Mutex::new(()),
+            applied: Condvar::new(),
+            write_core_stopped: AtomicBool::new(false),
+            flush_lock: Mutex::new(()),
+            stall_lock: Mutex::new(()),
+            flushed: Condvar::new(),
+            stopped: AtomicBool::new(false),
         })
     }
 
+    /// Open column family `cf`, whose WALs are in a sub directory.
+    fn open_cf(&self, cf: u32) -> Result<Self> {
+        let wal_dir = cf_dir(&self.opts.dir, cf);
+        Ok(Self {
+            memtables: RwLock::new(MemTables::open_in(wal_dir, self.opts.clone())?),
+            lvctl: self.lvctl.open_cf(cf)?,
+            opts: self.opts.clone(),
+            enqueued_requests: AtomicU64::new(0),
+            applied_requests: AtomicU64::new(0),
+            apply_lock: Mutex::new(()),
+            applied: Condvar::new(),
+            write_core_stopped: AtomicBool::new(false),
+            flush_lock: Mutex::new(()),
+            stall_lock: Mutex::new(()),
+            flushed: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Start flushing memtables and compaction in the background.
+    fn start_background(self: &Arc<Self>, pool: &Arc<ThreadPool>, closer: &Arc<Receiver<()>>) {
+        self.lvctl.start_compact(pool.clone(), closer.clone());
+        self.clone().start_flush(pool.clone(), closer.clone());
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let view = self.memtables.read().view();
+
+        let mut chain = MergeChain::default();
+        if !get_in_memtables(&view, key, &mut chain) {
+            self.lvctl.get_chain(key, &mut chain)?;
+        }
+        chain.resolve(key, self.opts.merge_operator.as_ref())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.opts.read_only {
+            return Err(anyhow::anyhow!("storage is opened read-only"));
+        }
+        // the storage is wedged once flushing or compaction fails
+        self.lvctl.check_bg_error()
+    }
+
+    /// Check that keys are in bytewise order, which range tombstones and prefixes depend on.
+    fn check_bytewise(&self, op: &str) -> Result<()> {
+        match self.opts.comparator.is_bytewise() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(
+                "{op} is unsupported by comparator {:?}",
+                self.opts.comparator
+            )),
+        }
+    }
+
+    fn check_key_size(&self, key: &[u8]) -> Result<()> {
+        match key.len() > self.opts.max_key_size {
+            true => Err(anyhow::anyhow!(
+                "key size {} exceeds max_key_size {}",
+                key.len(),
+                self.opts.max_key_size
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn check_value_size(&self, value: &[u8]) -> Result<()> {
+        // values are stored with u32 lengths
+        let max_size = self.opts.max_value_size.unwrap_or(u32::MAX as usize);
+        match value.len() > max_size {
+            true => Err(anyhow::anyhow!(
+                "value size {} exceeds max_value_size {}",
+                value.len(),
+                max_size
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Put `value` of `key`, a None value is a tombstone.
+    fn do_put(&self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        self.check_key_size(key)?;
+        self.check_writable()?;
+        let size = {
+            let guard = self.memtables.read();
+            match value {
+                Some(value) => guard.put(key, value)?,
+                None => guard.delete(key)?,
+            }
+            guard.memtable.size()
+        };
+        self.may_use_new_table(size)
+    }
+
+    /// Merge `operand` into the value of `key` by the merge operator.
+    fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.check_key_size(key)?;
+        self.check_value_size(operand)?;
+        self.check_writable()?;
+        let size = {
+            let guard = self.memtables.read();
+            guard.merge(key, operand)?;
+            guard.memtable.size()
+        };
+        self.may_use_new_table(size)
+    }
+
+    /// Freeze the mutable memtable if it's larger than `memtable_size`.
+    ///
+    /// A write is done under the read lock of memtables, and freezing needs the write lock, so a
+    /// write is always in the memtable before it's frozen. A frozen memtable stays readable until
+    /// it's flushed, so no write is lost even if another thread freezes the memtable first.
+    fn may_use_new_table(&self, size: usize) -> Result<()> {
+        if size > self.opts.memtable_size {
+            self.wait_for_flush()?;
+            let mut guard = self.memtables.write();
+            // secondary check
+            if guard.memtable.size() > self.opts.memtable_size {
+                guard.use_new_table()?;
+                debug!("use new memtable");
+            }
+        }
+
+        if let Some(budget) = self.opts.db_write_buffer_size {
+            while self.memtables.read().total_size() > budget {
+                if !self.flush_oldest_imm()? {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Block while there are `max_memtable_num` immutable memtables, until the flush thread
+    /// drains them, so memory doesn't grow without limit when flushing can't keep up.
+    fn wait_for_flush(&self) -> Result<()> {
+        // the flush thread only flushes min_memtable_to_merge memtables at once
+        let max = self
+            .opts
+            .max_memtable_num
+            .max(self.opts.min_memtable_to_merge);
+        let mut guard = self.stall_lock.lock();
+        while self.memtables.read().imm_memtables.len() >= max
+            && !self.stopped.load(Ordering::Acquire)
+        {
+            self.lvctl.check_bg_error()?;
+            debug!("stall writes for flushing");
+            self.flushed.wait(&mut guard);
+        }
+        Ok(())
+    }
+
+    /// Wait until the write core has applied `target` requests, fail if it exits before that.
+    fn wait_for_applied(&self, target: u64) -> Result<()> {
+        let mut guard = self.apply_lock.lock();
+        while self.applied_requests.load(Ordering::Acquire) < target {
+            if self.write_core_stopped.load(Ordering::Acquire) {
+                return Err(anyhow::anyhow!(
+                    "write core stopped before applying queued requests"
+                ));
+            }
+            self.applied.wait(&mut guard);
+        }
+        Ok(())
+    }
+
+    /// Wake up callers waiting for the write core.
+    fn notify_applied(&self) {
+        let _guard = self.apply_lock.lock();
+        self.applied.notify_all();
+    }
+
+    /// Wake up writers waiting for flushing, the memtables lock must not be held.
+    fn notify_flushed(&self) {
+        let _guard = self.stall_lock.lock();
+        self.flushed.notify_all();
+    }
+
+    /// Stop waiting for flushing, which is not running anymore.
+    fn stop_stalling(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.notify_flushed();
+    }
+
+    /// Flush the oldest immutable memtable to l0. If there is no immutable memtable, the mutable
+    /// memtable will be flushed.
+    ///
+    /// Return false if all memtables are empty.
+    fn flush_oldest_imm(&self) -> Result<bool> {
+        let _lock = self.flush_lock.lock();
+
+        let memtable = {
+            let mut guard = self.memtables.write();
+            if guard.imm_memtables.is_empty() {
+                if guard.memtable.size() == 0 {
+                    return Ok(false);
+                }
+                guard.use_new_table()?;
+            }
+            guard.imm_memtables.front().unwrap().clone()
+        };
+
+        if memtable.size() > 0 {
+            let mut builder = SsTableBuilder::new_for_level(self.opts.clone(), 0);
+            memtable.flush(&mut builder)?;
+            self.lvctl.l0_push_sstable(builder)?;
+        }
+        self.memtables.write().imm_memtables.pop_front();
+        self.notify_flushed();
+        debug!("flush oldest memtable");
+
+        Ok(true)
+    }
+
+    /// Build immutable memtables, older first, into l0 tables which are returned older first.
+    /// Memtables are split into at most `flush_num` groups, which are built concurrently in
+    /// `pool`, empty groups are skipped. It blocks until the groups are built, so `pool` must have
+    /// `flush_num` threads besides the ones blocked, see `LsmStorage::open`.
+    fn build_l0_tables(
+        self: &Arc<Self>,
+        pool: &ThreadPool,
+        memtables: &[Arc<MemTable>],
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let groups = flush_groups(
+            memtables.len(),
+            self.opts.min_memtable_to_merge,
+            self.opts.flush_num,
+        )
+        .into_iter()
+        .map(|range| memtables[range].to_vec())
+        .filter(|group| group.iter().any(|x| x.size() > 0))
+        // ids of older tables are smaller
+        .map(|group| (group, self.lvctl.next_sst_id()))
+        .collect::<Vec<_>>();
+
+        let build = |inner: &Self, group: &[Arc<MemTable>], id| -> Result<Arc<SsTable>> {
+            let mut builder = SsTableBuilder::new_for_level(inner.opts.clone(), 0);
+            build_memtables(group, &mut builder)?;
+            inner.lvctl.build_l0_sstable(builder, id)
+        };
+        if groups.len() <= 1 {
+            return groups
+                .iter()
+                .map(|(group, id)| build(self, group, *id))
+                .collect();
+        }
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let num = groups.len();
+        for (idx, (group, id)) in groups.into_iter().enumerate() {
+            let inner = self.clone();
+            let tx = tx.clone();
+            pool.spawn(move |_: &mut Handle| {
+                // built tables are removed if the flush fails
+                let _ = tx.send((idx, build(&inner, &group, id)));
+            });
+        }
+        drop(tx);
+
+        let mut tables = (0..num).map(|_| None).collect::<Vec<_>>();
+        for (idx, ret) in rx.iter() {
+            tables[idx] = Some(ret?);
+        }
+        tables
+            .into_iter()
+            .map(|table| table.ok_or_else(|| anyhow::anyhow!("flush task exited unexpectedly")))
+            .collect()
+    }
+
+    /// Flush all memtables to l0, the flush lock must be held.
+    fn flush_memtables(&self) -> Result<()> {
+        let mut guard = self.memtables.write();
+        guard.use_new_table()?;
+
+        let len = guard.imm_memtables.len();
+        let memtables = guard.imm_memtables.iter().cloned().collect::<Vec<_>>();
+
+        let mut builder = SsTableBuilder::new_for_level(self.opts.clone(), 0);
+        build_memtables(&memtables, &mut builder)?;
+
+        if builder.is_empty() {
+            return Ok(());
+        }
+
+        self.lvctl.l0_push_sstable(builder)?;
+
+        for _ in 0..len {
+            guard.imm_memtables.pop_front().unwrap();
+        }
+        drop(guard);
+        self.notify_flushed();
+
+        Ok(())
+    }
+
+    /// Flush all memtables to l0.
+    fn sync(&self) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.flush_lock.lock();
+        self.flush_memtables()
+    }
+
+    /// Freeze the mutable memtable unless it's empty, and leave it to the flush thread.
+    fn flush(&self) -> Result<()> {
+        self.check_writable()?;
+        self.wait_for_flush()?;
+        let mut guard = self.memtables.write();
+        if guard.memtable.size() > 0 {
+            guard.use_new_table()?;
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        pool: &ThreadPool,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let memtables = self.memtables.read().view();
+        let ssts = self.lvctl.level_tables_sorted(lower, upper);
+        let operator = self.opts.merge_operator.as_ref();
+        scan_tables(Some(pool), &memtables, &ssts, lower, upper, operator)
+    }
+
     fn start_write(
         self: Arc<Self>,
         pool: Arc<ThreadPool>,
@@ -50,24 +423,32 @@ impl LsmStorageInner {
     ) {
         pool.spawn(move |_: &mut Handle| {
             use std::result::Result::Ok;
+            // waiters must not wait for the write core once it exits, even by panicking
+            let _stopped = WriteCoreStopped(&self);
             let mut buf = Vec::new();
             let mut senders = Vec::new();
             loop {
                 while let Ok(mut request) = receiver.try_recv() {
                     buf.append(&mut request.entries);
+                    senders.push(request.sender);
                     if buf.len() > self.opts.wait_entry_num {
                         break;
                     }
-                    senders.push(request.sender);
                 }
                 let mut ret = Ok(());
-                if let Err(e) = self.memtables.read().put_entries(&buf) {
-                    error!("put_entries error: {e}");
-                    ret = Err(format!("{}", e));
+                if !buf.is_empty() {
+                    if let Err(e) = self.memtables.read().put_entries(&buf) {
+                        error!("put_entries error: {e}");
+                        ret = Err(format!("{}", e));
+                    }
                 }
+                self.applied_requests
+                    .fetch_add(senders.len() as u64, Ordering::Release);
+                self.notify_applied();
 
                 for sender in senders.iter().flatten() {
-                    sender.send(ret.clone()).unwrap();
+                    // the caller may not wait for the result
+                    let _ = sender.send(ret.clone());
                 }
                 buf.clear();
                 senders.clear();
@@ -82,40 +463,34 @@ impl LsmStorageInner {
     //TODO: channel send task
     fn start_flush(self: Arc<Self>, pool: Arc<ThreadPool>, closer: Arc<Receiver<()>>) {
         let inner = self.clone();
+        let flush_pool = pool.clone();
         pool.spawn(move |_: &mut Handle| {
             let run_once = || -> Result<()> {
-                let mut imm_memtable = inner.memtables.read().imm_memtables.clone();
-                if imm_memtable.len() < inner.opts.min_memtable_to_merge {
+                let _lock = inner.flush_lock.lock();
+                let memtables = inner
+                    .memtables
+                    .read()
+                    .imm_memtables
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if memtables.len() < inner.opts.min_memtable_to_merge {
                     return Ok(());
                 }
-                let mut memtables = Vec::with_capacity(inner.opts.min_memtable_to_merge);
-                while let Some(memtable) = imm_memtable.pop_front() {
-                    memtables.push(memtable);
-                }
-
-                let mut iter = MergeIterator::create(
-                    memtables
-                        .iter()
-                        .map(|x| Box::new(x.scan(Bound::Unbounded, Bound::Unbounded)))
-                        .collect(),
-                );
-
-                let mut builder = SsTableBuilder::new(self.opts.clone());
 
-                while iter.is_valid() {
-                    builder.add(iter.key(), iter.value())?;
-                    iter.next()?;
-                }
-
-                inner.lvctl.l0_push_sstable(builder)?;
+                let tables = inner.build_l0_tables(&flush_pool, &memtables)?;
+                let num = tables.len();
+                // memtables are removed together, after all of their tables are in l0
+                inner.lvctl.l0_push_sstables(tables)?;
                 {
                     let mut guard = inner.memtables.write();
                     for _ in 0..memtables.len() {
                         guard.imm_memtables.pop_front();
                     }
                 }
+                inner.notify_flushed();
 
-                info!("push l0 sstable");
+                info!("push {num} l0 sstables");
                 Ok(())
             };
 
@@ -135,8 +510,10 @@ impl LsmStorageInner {
                     recv(ticker_check) -> _ => full_run(),
                     recv(closer) -> _ => break,
                 } {
-                    // TODO: err handling
-                    error!("error {}", e)
+                    error!("flush error: {e}");
+                    self.lvctl.set_bg_error(&e);
+                    // writers waiting for flushing fail with the error
+                    self.notify_flushed();
                 }
             }
         });
@@ -145,21 +522,128 @@ impl LsmStorageInner {
 
 pub type ThreadPool = yatp::ThreadPool<TaskCell>;
 
+/// Statistics of the storage, see [`LsmStorage::stats`].
+#[derive(Clone, Debug, Default)]
+pub struct StorageStats {
+    pub levels: Vec<LevelStats>,
+    pub num_imm_memtables: usize,
+    /// Size of the mutable memtable.
+    pub memtable_size: usize,
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+}
+
+/// Puts and deletes which are written to the storage atomically.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    /// A None value is a tombstone.
+    entries: Vec<(Bytes, Option<Bytes>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(mut self, key: &[u8], value: &[u8]) -> Self {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries.push((
+            Bytes::copy_from_slice(key),
+            Some(Bytes::copy_from_slice(value)),
+        ));
+        self
+    }
+
+    pub fn delete(mut self, key: &[u8]) -> Self {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.entries.push((Bytes::copy_from_slice(key), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 /// The storage interface of the LSM tree.
 pub struct LsmStorage {
     inner: Arc<LsmStorageInner>,
+    /// Column families except the default one.
+    cfs: RwLock<HashMap<String, Arc<LsmStorageInner>>>,
     opts: Arc<LsmOptions>,
     closer: Option<Sender<()>>,
+    close_receiver: Arc<Receiver<()>>,
     write_sender: Option<Sender<Request>>,
     pool: Arc<ThreadPool>,
-    flush_lock: Mutex<()>,
+    closed: bool,
+}
+
+/// A handle of a column family, which is a keyspace separate from the others with its own
+/// memtables and levels. Column families share the thread pool, the block cache and the manifest.
+#[derive(Clone)]
+pub struct ColumnFamily {
+    name: String,
+    inner: Arc<LsmStorageInner>,
+    pool: Arc<ThreadPool>,
+}
+
+impl ColumnFamily {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get a key from the column family.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.get(key)
+    }
+
+    /// Put a key-value pair into the column family.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.check_value_size(value)?;
+        self.inner.do_put(key, Some(value))
+    }
+
+    /// Remove a key from the column family by writing a tombstone.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.do_put(key, None)
+    }
+
+    /// Create an iterator over a range of keys of the column family.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan(&self.pool, lower, upper)
+    }
+
+    /// Persist data of the column family to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    /// Freeze the mutable memtable of the column family without waiting for it to be flushed,
+    /// see `LsmStorage::flush`.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl LsmStorage {
     pub fn open(opts: LsmOptions) -> Result<Self> {
+        opts.validate()?;
+        // the flush loop waits for its tables built in the pool, so there are `flush_num` threads
+        // besides the ones of the background loops
         let pool = yatp::Builder::new("topazdb")
-            .max_thread_count(opts.compactor_num * 6 + 2)
-            .min_thread_count(opts.compactor_num * 4 + 2)
+            .max_thread_count(opts.compactor_num * 6 + opts.flush_num + 2)
+            .min_thread_count(opts.compactor_num * 4 + opts.flush_num + 2)
             .build_callback_pool();
 
         let opts = Arc::new(opts);
@@ -171,68 +655,234 @@ impl LsmStorage {
         let (sender, receiver) = crossbeam_channel::unbounded();
 
         let closer = Arc::new(receiver);
-        inner.lvctl.start_compact(pool.clone(), closer.clone());
+        let mut write_sender = None;
+        if !opts.read_only {
+            inner.start_background(&pool, &closer);
 
-        let flush_core = inner.clone();
-        flush_core.start_flush(pool.clone(), closer.clone());
+            if opts.wait_entry_num > 0 {
+                let write_core = inner.clone();
+                let (sender, recevier) = crossbeam_channel::unbounded();
+                write_core.start_write(pool.clone(), recevier, closer.clone());
+                write_sender = Some(sender);
+            }
+        }
 
-        let mut write_sender = None;
-        if opts.wait_entry_num > 0 {
-            let write_core = inner.clone();
-            let (sender, recevier) = crossbeam_channel::unbounded();
-            write_core.start_write(pool.clone(), recevier, closer);
-            write_sender = Some(sender);
+        let mut cfs = HashMap::new();
+        for (name, cf) in inner.lvctl.column_families() {
+            if cf == DEFAULT_CF {
+                continue;
+            }
+            let cf_inner = Arc::new(inner.open_cf(cf)?);
+            if !opts.read_only {
+                cf_inner.start_background(&pool, &closer);
+            }
+            cfs.insert(name, cf_inner);
         }
 
-        Ok(Self {
+        let storage = Self {
             inner,
-            flush_lock: Mutex::new(()),
+            cfs: RwLock::new(cfs),
             closer: Some(sender),
+            close_receiver: closer,
             write_sender,
             pool,
             opts,
+            closed: false,
+        };
+        if storage.opts.paranoid_checks {
+            storage.verify_checksums()?;
+        }
+        // WAL may grow beyond memtable_size before crash
+        if !storage.opts.read_only {
+            for inner in storage.all_cfs() {
+                if inner.memtables.read().imm_oversized() {
+                    inner.sync()?;
+                }
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Create a column family named `name`.
+    pub fn create_cf(&self, name: &str) -> Result<ColumnFamily> {
+        self.inner.check_writable()?;
+        let mut cfs = self.cfs.write();
+        if cfs.contains_key(name) {
+            return Err(anyhow::anyhow!("column family {name:?} already exists"));
+        }
+        let cf = self.inner.lvctl.create_cf(name)?;
+        let inner = Arc::new(self.inner.open_cf(cf)?);
+        inner.start_background(&self.pool, &self.close_receiver);
+        cfs.insert(name.to_string(), inner.clone());
+        Ok(ColumnFamily {
+            name: name.to_string(),
+            inner,
+            pool: self.pool.clone(),
         })
     }
 
+    /// Get the handle of column family `name`, None if it doesn't exist.
+    pub fn cf(&self, name: &str) -> Option<ColumnFamily> {
+        let inner = match name {
+            DEFAULT_CF_NAME => self.inner.clone(),
+            _ => self.cfs.read().get(name)?.clone(),
+        };
+        Some(ColumnFamily {
+            name: name.to_string(),
+            inner,
+            pool: self.pool.clone(),
+        })
+    }
+
+    /// Get the default column family and the others.
+    fn all_cfs(&self) -> Vec<Arc<LsmStorageInner>> {
+        let mut cfs = vec![self.inner.clone()];
+        cfs.extend(self.cfs.read().values().cloned());
+        cfs
+    }
+
+    /// Open an existing storage for reads only.
+    ///
+    /// Nothing in the directory is created, removed or modified: memtables are replayed from
+    /// their WALs, no background task is started, and writes return an error.
+    pub fn open_read_only(mut opts: LsmOptions) -> Result<Self> {
+        opts.read_only = true;
+        Self::open(opts)
+    }
+
     /// Get a key from the storage.
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
         assert!(!key.is_empty(), "key cannot be empty");
 
-        let view = self.inner.memtables.read().view();
+        self.inner.get(key)
+    }
 
-        for memtable in view.iter().rev() {
-            if let Some(value) = memtable.get(key) {
-                if value.is_empty() {
-                    return Ok(None);
-                }
-                return Ok(Some(value));
-            }
+    /// Get keys from the storage, results are in the same order as `keys`.
+    ///
+    /// Memtables and sstables are captured once, and keys are looked up in order so that keys in
+    /// the same block share the block read. If `multi_get_batch_size` > 0 and there are more keys
+    /// than it, sorted keys are split into batches which are looked up in the thread pool.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Bytes>>> {
+        assert!(
+            keys.iter().all(|key| !key.is_empty()),
+            "key cannot be empty"
+        );
+
+        let memtables = self.inner.memtables.read().view();
+        let levels = self.inner.lvctl.snapshot_levels();
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| self.opts.comparator.compare(keys[a], keys[b]));
+
+        let batch_size = self.opts.multi_get_batch_size;
+        let values = if batch_size == 0 || keys.len() <= batch_size {
+            let sorted_keys = order.iter().map(|&idx| keys[idx]).collect::<Vec<_>>();
+            multi_get_in(
+                &memtables,
+                &levels,
+                &sorted_keys,
+                self.opts.merge_operator.as_ref(),
+            )?
+        } else {
+            self.multi_get_parallel(memtables, levels, keys, &order, batch_size)?
+        };
+
+        let mut results = vec![None; keys.len()];
+        for (idx, value) in order.into_iter().zip(values) {
+            results[idx] = value;
+        }
+        Ok(results)
+    }
+
+    /// Look up keys in `order` by batches in the thread pool, returns values in `order`.
+    fn multi_get_parallel(
+        &self,
+        memtables: Vec<Arc<MemTable>>,
+        levels: Vec<Vec<Arc<SsTable>>>,
+        keys: &[&[u8]],
+        order: &[usize],
+        batch_size: usize,
+    ) -> Result<Vec<Option<Bytes>>> {
+        let memtables = Arc::new(memtables);
+        let levels = Arc::new(levels);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut batch_num = 0;
+        for (idx, batch) in order.chunks(batch_size).enumerate() {
+            let batch = batch
+                .iter()
+                .map(|&idx| Bytes::copy_from_slice(keys[idx]))
+                .collect::<Vec<_>>();
+            let memtables = memtables.clone();
+            let levels = levels.clone();
+            let tx = tx.clone();
+            let operator = self.opts.merge_operator.clone();
+            self.pool.spawn(move |_: &mut Handle| {
+                let batch = batch.iter().map(|key| &key[..]).collect::<Vec<_>>();
+                let ret = multi_get_in(&memtables, &levels, &batch, operator.as_ref());
+                // multi_get may have returned with an error
+                let _ = tx.send((idx, ret));
+            });
+            batch_num += 1;
         }
+        drop(tx);
 
-        self.inner.lvctl.get(key)
+        let mut results = vec![vec![]; batch_num];
+        for (idx, ret) in rx.iter() {
+            results[idx] = ret?;
+        }
+        let results = results.into_iter().flatten().collect::<Vec<_>>();
+        if results.len() != keys.len() {
+            return Err(anyhow::anyhow!("multi_get task exited unexpectedly"));
+        }
+        Ok(results)
     }
 
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        assert!(!value.is_empty(), "value cannot be empty");
         assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.check_value_size(value)?;
 
-        self.do_put(key, value)
+        self.inner.do_put(key, Some(value))
     }
 
-    /// Remove a key from the storage by writing an empty value.
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
+    /// Put a key-value pair which expires after `ttl`. An expired key is absent from reads, and
+    /// compaction drops it.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
         assert!(!key.is_empty(), "key cannot be empty");
-        self.do_put(key, b"")
-    }
+        self.inner.check_key_size(key)?;
+        self.inner.check_value_size(value)?;
+        self.inner.check_writable()?;
 
-    fn do_put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
         let size = {
             let guard = self.inner.memtables.read();
-            guard.put(key, value)?;
+            guard.put_with_expiry(key, value, expire_at)?;
             guard.memtable.size()
         };
-        self.may_use_new_table(size)
+        self.inner.may_use_new_table(size)
+    }
+
+    fn check_entries(&self, entries: &[(Bytes, Option<Bytes>)]) -> Result<()> {
+        for (key, value) in entries {
+            self.inner.check_key_size(key)?;
+            if let Some(value) = value {
+                self.inner.check_value_size(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a key from the storage by writing a tombstone.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.do_put(key, None)
+    }
+
+    /// Merge `operand` into the value of `key` without reading it first. The operand is combined
+    /// with the value by `merge_operator` when the key is read or compacted, it fails if the
+    /// operator is not set.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        assert!(!key.is_empty(), "key cannot be empty");
+        self.inner.merge(key, operand)
     }
 
     // 1. channel send entry to write core
@@ -245,140 +895,794 @@ impl LsmStorage {
         &self,
         entries: Vec<(Bytes, Bytes)>,
     ) -> Result<crossbeam_channel::Receiver<Result<(), String>>> {
+        self.write_to_channel(into_puts(entries))
+    }
+
+    fn write_to_channel(
+        &self,
+        entries: Vec<(Bytes, Option<Bytes>)>,
+    ) -> Result<crossbeam_channel::Receiver<Result<(), String>>> {
+        self.inner.check_writable()?;
         if self.write_sender.is_none() {
             return Err(anyhow::anyhow!("write sender is empty"));
         }
+        self.check_entries(&entries)?;
         let (sender, receiver) = crossbeam_channel::unbounded();
         let request = Request {
             entries,
             sender: Some(sender),
         };
-        self.write_sender.as_ref().unwrap().send(request)?;
+        self.send_request(request)?;
         Ok(receiver)
     }
 
     pub fn put_to_channel_not_msg(&self, entries: Vec<(Bytes, Bytes)>) -> Result<()> {
+        self.inner.check_writable()?;
         if self.write_sender.is_none() {
             return Err(anyhow::anyhow!("write sender is empty"));
         }
+        let entries = into_puts(entries);
+        self.check_entries(&entries)?;
         let request = Request {
             entries,
             sender: None,
         };
+        self.send_request(request)
+    }
+
+    fn send_request(&self, request: Request) -> Result<()> {
+        self.inner.enqueued_requests.fetch_add(1, Ordering::AcqRel);
         self.write_sender.as_ref().unwrap().send(request)?;
         Ok(())
     }
 
-    fn may_use_new_table(&self, size: usize) -> Result<()> {
-        if size <= self.opts.memtable_size {
-            return Ok(());
+    /// Wait until the write core has applied all requests sent before, fail if it has exited.
+    fn wait_for_channel_writes(&self) -> Result<()> {
+        let target = self.inner.enqueued_requests.load(Ordering::Acquire);
+        self.inner.wait_for_applied(target)
+    }
+
+    /// Get statistics of levels, memtables and the block cache.
+    pub fn stats(&self) -> StorageStats {
+        let (num_imm_memtables, memtable_size) = {
+            let guard = self.inner.memtables.read();
+            (guard.imm_memtables.len(), guard.memtable.size())
+        };
+        let block_cache = self.inner.lvctl.block_cache();
+        StorageStats {
+            levels: self.inner.lvctl.level_stats(),
+            num_imm_memtables,
+            memtable_size,
+            block_cache_hits: block_cache.hits(),
+            block_cache_misses: block_cache.misses(),
         }
+    }
 
-        let mut guard = self.inner.memtables.write();
-        // secondary check
-        if guard.memtable.size() > self.opts.memtable_size {
-            guard.use_new_table()?;
-            debug!("use new memtable");
+    /// Render the tables of each level of the default column family, see
+    /// [`LevelController::describe`].
+    pub fn describe_levels(&self) -> String {
+        self.inner.lvctl.describe()
+    }
+
+    /// Load blocks of sstables into the block cache in the thread pool, from the last level to
+    /// level 0, stopping once a block doesn't fit in the free space of the cache. Returns the
+    /// bytes loaded.
+    pub fn warmup(&self) -> Result<u64> {
+        let block_cache = self.inner.lvctl.block_cache();
+        let capacity = self
+            .opts
+            .block_cache_size
+            .saturating_sub(block_cache.weighted_size());
+        let cf_levels: Vec<_> = self
+            .all_cfs()
+            .iter()
+            .map(|cf| cf.lvctl.snapshot_levels())
+            .collect();
+        let tables = (0..self.opts.num_levels).rev().flat_map(|level| {
+            cf_levels
+                .iter()
+                .flat_map(move |levels| levels[level].iter().cloned())
+        });
+
+        let loaded = Arc::new(AtomicU64::new(0));
+        let full = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for table in tables {
+            let (block_cache, loaded, full) = (block_cache.clone(), loaded.clone(), full.clone());
+            let tx = tx.clone();
+            self.pool.spawn(move |_: &mut Handle| {
+                let load = || {
+                    for idx in 0..table.num_of_blocks() {
+                        if full.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let block = table.read_block(idx)?;
+                        let size = block.uncompress_size() as u64;
+                        let reserved =
+                            loaded.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                                (n + size <= capacity).then_some(n + size)
+                            });
+                        if reserved.is_err() {
+                            full.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        block_cache.try_get_with((table.id, idx), || Ok(block))?;
+                    }
+                    Ok(())
+                };
+                let _ = tx.send(load());
+            });
         }
+        drop(tx);
+        for ret in rx.iter() {
+            ret?;
+        }
+        Ok(loaded.load(Ordering::Relaxed))
+    }
 
+    /// Decode every block of the live sstables, which checks their checksums. Returns the first
+    /// failure with the id of the table and the index of the block.
+    pub fn verify_checksums(&self) -> Result<()> {
+        for cf in self.all_cfs() {
+            for table in cf.lvctl.snapshot_levels().iter().flatten() {
+                for idx in 0..table.num_of_blocks() {
+                    table.read_block(idx).map_err(|e| {
+                        anyhow::anyhow!("table {} block {idx} is corrupted: {e}", table.id)
+                    })?;
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Estimate the bytes of data blocks of sstables in a range of keys without reading them,
+    /// by the blocks of each overlapping table. Memtables are not counted.
+    pub fn estimate_range_size(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> usize {
+        self.inner
+            .lvctl
+            .level_tables_sorted(lower, upper)
+            .iter()
+            .map(|table| {
+                // the range covers the last block of the table
+                let upper = match upper {
+                    Bound::Included(key) | Bound::Excluded(key)
+                        if table.compare(key, &table.biggest_key).is_lt() =>
+                    {
+                        upper
+                    }
+                    _ => Bound::Unbounded,
+                };
+                table.range_size(lower, upper)
+            })
+            .sum()
+    }
+
+    /// Get the total size of the mutable and immutable memtables.
+    pub fn memtable_usage(&self) -> usize {
+        self.inner.memtables.read().total_size()
+    }
+
+    /// Hold the flush lock, so no memtable is flushed until the guard is dropped.
+    #[cfg(test)]
+    pub(crate) fn pause_flush(&self) -> parking_lot::MutexGuard<'_, ()> {
+        self.inner.flush_lock.lock()
+    }
+
+    /// Stop the background threads, as if the storage is closing.
+    #[cfg(test)]
+    pub(crate) fn stop_background(&mut self) {
+        self.closer.take();
+    }
+
+    /// Put entries into the storage.
+    ///
+    /// If the write core is running, a batch with no more than `wait_entry_num` entries is merged
+    /// with other requests in the channel, and a larger batch is written directly after the
+    /// requests sent before it are applied.
     pub fn batch_put(&self, entries: &[(Bytes, Bytes)]) -> Result<()> {
+        self.write_entries(into_puts(entries.to_vec()))
+    }
+
+    /// Write entries in one WAL record, a None value is a tombstone.
+    fn write_entries(&self, entries: Vec<(Bytes, Option<Bytes>)>) -> Result<()> {
+        self.inner.check_writable()?;
+        self.check_entries(&entries)?;
+
+        if self.write_sender.is_some() {
+            if entries.len() <= self.opts.wait_entry_num {
+                return self
+                    .write_to_channel(entries)?
+                    .recv()?
+                    .map_err(|e| anyhow::anyhow!(e));
+            }
+            self.wait_for_channel_writes()?;
+        }
+
         let size = {
             let guard = self.inner.memtables.read();
-            guard.put_entries(entries)?;
+            guard.put_entries(&entries)?;
             guard.memtable.size()
         };
 
-        self.may_use_new_table(size)
+        self.inner.may_use_new_table(size)
     }
 
-    /// Persist data to disk.
-    pub fn sync(&self) -> Result<()> {
-        let _lock = self.flush_lock.lock();
+    /// Write a batch of puts and deletes, they are appended to the WAL in one record.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_entries(batch.entries)
+    }
 
-        let mut guard = self.inner.memtables.write();
-        guard.use_new_table()?;
+    /// Delete all keys in the range by writing a range tombstone, instead of a tombstone per key.
+    pub fn delete_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<()> {
+        self.inner.check_writable()?;
+        self.inner.check_bytewise("delete_range")?;
+        if self.write_sender.is_some() {
+            self.wait_for_channel_writes()?;
+        }
+        // later writes are not deleted, so the tombstone only needs to cover existing keys
+        let iter = self.scan(lower, upper)?;
+        if !iter.is_valid() {
+            return Ok(());
+        }
+        let start = Bytes::copy_from_slice(iter.key());
+        let iter = self.scan_rev(lower, upper)?;
+        if !iter.is_valid() {
+            return Ok(());
+        }
+        let tombstone = RangeTombstone::new(start, Bytes::copy_from_slice(iter.key()));
 
-        let len = guard.imm_memtables.len();
+        let size = {
+            let guard = self.inner.memtables.read();
+            guard.delete_range(tombstone)?;
+            guard.memtable.size()
+        };
 
-        let mut map = BTreeMap::new();
-        for i in 0..len {
-            let table = guard.imm_memtables[i].clone();
-            let mut iter = table.scan(Bound::Unbounded, Bound::Unbounded);
-            while iter.is_valid() {
-                map.insert(iter.key().to_vec(), iter.value().to_vec());
-                iter.next()?;
-            }
-        }
+        self.inner.may_use_new_table(size)
+    }
 
-        if map.is_empty() {
-            return Ok(());
+    /// Drop all keys starting with `prefix`, return the size of deleted sstables.
+    ///
+    /// SSTables entirely within the prefix are deleted without compaction, other keys are deleted
+    /// by a range tombstone.
+    pub fn drop_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        assert!(!prefix.is_empty(), "prefix cannot be empty");
+        self.inner.check_writable()?;
+        self.inner.check_bytewise("drop_prefix")?;
+        let upper = prefix_upper_bound(prefix);
+        let lower = Bound::Included(prefix);
+        let upper = match upper {
+            Some(ref key) => Bound::Excluded(&key[..]),
+            None => Bound::Unbounded,
+        };
+
+        let reserved = self.inner.lvctl.reserve_tables_within(prefix, upper);
+        let ret = self.delete_range(lower, upper);
+        if let Err(e) = ret {
+            self.inner.lvctl.release_tables(&reserved);
+            return Err(e);
         }
+        self.inner.lvctl.delete_tables(&reserved)
+    }
 
-        let mut builder = SsTableBuilder::new(self.opts.clone());
-        for (key, value) in &map {
-            builder.add(key, value).unwrap();
+    /// Ingest a sstable built outside of the storage, e.g. by [`SsTableBuilder`], return the level
+    /// it's placed at.
+    ///
+    /// The file is linked (or copied) into the storage and left in place. Its keys override the
+    /// existing ones, memtables overlapping with it are flushed first.
+    pub fn ingest_sst(&self, path: &Path) -> Result<usize> {
+        self.inner.check_writable()?;
+        let external = self.inner.lvctl.open_external(path)?;
+        let (lower, upper) = external.key_range();
+        // writes sent to the channel before are in the memtables when they are checked
+        if self.write_sender.is_some() {
+            self.wait_for_channel_writes()?;
+        }
+        let range = (Bound::Included(&lower[..]), Bound::Included(&upper[..]));
+        let overlapped = self.inner.memtables.read().view().iter().any(|memtable| {
+            memtable.scan(range.0, range.1).is_valid()
+                || memtable
+                    .range_tombstones()
+                    .iter()
+                    .any(|tombstone| tombstone.start <= upper && tombstone.end >= lower)
+        });
+        if overlapped {
+            self.sync()?;
         }
+        // linked after flushing, so its id is bigger than the flushed tables
+        let table = self.inner.lvctl.link_external(path)?;
+        self.inner.lvctl.add_ingested(table)
+    }
+
+    /// Persist data to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
 
-        self.inner.lvctl.l0_push_sstable(builder)?;
+    /// Freeze the mutable memtable and return without waiting for it to be flushed, unlike
+    /// `sync`. The flush thread writes immutable memtables to level 0 once there are
+    /// `min_memtable_to_merge` of them.
+    ///
+    /// It still blocks like writes while there are `max_memtable_num` immutable memtables, until
+    /// the flush thread drains them, and returns the error of a failed background flush.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
 
-        for _ in 0..len {
-            guard.imm_memtables.pop_front().unwrap();
+    /// Block until background compactions of all column families are idle, so no level needs
+    /// compaction, or fail after `timeout`.
+    pub fn wait_for_compaction(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        for cf in self.all_cfs() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            cf.lvctl.wait_for_idle(timeout)?;
         }
-
         Ok(())
     }
 
+    /// Create a checkpoint of the storage in `dest`, which can be opened as another storage.
+    ///
+    /// Memtables are flushed, then the files of all sstables are hard linked (or copied) with a
+    /// manifest of them. Writes after flushing are not in the checkpoint.
+    pub fn checkpoint(&self, dest: &Path) -> Result<()> {
+        self.inner.check_writable()?;
+        if self.write_sender.is_some() {
+            self.wait_for_channel_writes()?;
+        }
+        let _lock = self.inner.flush_lock.lock();
+        self.inner.flush_memtables()?;
+        self.inner.lvctl.checkpoint(dest)
+    }
+
     /// Create an iterator over a range of keys.
     pub fn scan(
         &self,
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan(&self.pool, lower, upper)
+    }
+
+    /// Create an iterator over keys starting with `prefix`.
+    ///
+    /// Tables are skipped by their prefix bloom filters if `prefix_bloom_len` is set.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.check_bytewise("scan_prefix")?;
+        let upper = prefix_upper_bound(prefix);
+        let lower = Bound::Included(prefix);
+        let upper = match upper {
+            Some(ref key) => Bound::Excluded(&key[..]),
+            None => Bound::Unbounded,
+        };
         let memtables = self.inner.memtables.read().view();
-        let mem_iters = memtables
-            .iter()
-            .rev()
-            .map(|table| Box::new(table.scan(lower, upper)))
-            .collect::<Vec<_>>();
-        let mem_iter = MergeIterator::create(mem_iters);
+        let mut ssts = self.inner.lvctl.level_tables_sorted(lower, upper);
+        ssts.retain(|table| table.may_contain_prefix(prefix));
+        let operator = self.opts.merge_operator.as_ref();
+        scan_tables(Some(&self.pool), &memtables, &ssts, lower, upper, operator)
+    }
 
+    /// Create an iterator over a range of keys in descending order, which starts from the last
+    /// key and moves backward by `prev`.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let memtables = self.inner.memtables.read().view();
         let ssts = self.inner.lvctl.level_tables_sorted(lower, upper);
-        let mut sst_iters = Vec::with_capacity(ssts.len());
-        for table in ssts.iter() {
-            let iter = match lower {
-                Bound::Included(key) => {
-                    SsTableIterator::create_and_seek_to_key(table.clone(), key)?
-                }
-                Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table.clone())?,
-                Bound::Excluded(key) => {
-                    let mut iter = SsTableIterator::create_and_seek_to_key(table.clone(), key)?;
-                    if iter.is_valid() && iter.key() == key {
-                        iter.next()?;
-                    }
-                    iter
+        let operator = self.opts.merge_operator.as_ref();
+        scan_tables_rev(Some(&self.pool), &memtables, &ssts, lower, upper, operator)
+    }
+
+    /// Create an iterator over a range of keys, which returns an error once the total size of
+    /// keys and values read exceeds `limit` bytes.
+    pub fn scan_with_byte_limit(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<FusedIterator<ByteLimited<LsmIterator>>> {
+        let iter = self.scan(lower, upper)?;
+        Ok(FusedIterator::new(ByteLimited::new(
+            iter.into_inner(),
+            limit,
+        )?))
+    }
+
+    /// Create an iterator over the first `limit` keys of a range. Tables of a level after the
+    /// last key returned are not read.
+    pub fn scan_limit(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        limit: usize,
+    ) -> Result<FusedIterator<CountLimited<LsmIterator>>> {
+        let iter = self.scan(lower, upper)?;
+        Ok(FusedIterator::new(CountLimited::new(
+            iter.into_inner(),
+            limit,
+        )))
+    }
+
+    /// Compact sstables overlapping with the range down to the last level, memtables are not
+    /// flushed. Returns what the compactions of all levels merged and produced.
+    pub fn compact_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<CompactionResult> {
+        self.inner.check_writable()?;
+        self.inner.lvctl.compact_range(lower, upper)
+    }
+
+    /// Create a snapshot whose reads don't observe later writes and compactions.
+    ///
+    /// The snapshot reads the mutable memtable at the version of its last write, neither copying
+    /// nor freezing it. Values replaced later are kept for the snapshot until the memtable is
+    /// dropped.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // flushed memtables are not removed before the levels are taken
+        let guard = self.inner.memtables.read();
+        let memtables = guard.snapshot()?;
+        let levels = self.inner.lvctl.snapshot_levels();
+        drop(guard);
+        Ok(Snapshot::new(
+            memtables,
+            levels,
+            self.opts.merge_operator.clone(),
+        ))
+    }
+
+    /// Create a snapshot, see [`LsmStorage::snapshot`].
+    #[deprecated(note = "use `snapshot` instead")]
+    pub fn snapshot_handle(&self) -> Result<Snapshot> {
+        self.snapshot()
+    }
+
+    /// Flush all memtables and stop background tasks, returning the error of flushing.
+    ///
+    /// Dropping the storage does the same, but only logs the error.
+    pub fn close(mut self) -> Result<()> {
+        self.close_inner()
+    }
+
+    fn close_inner(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        // 1. stop accepting writes, and wait for the write core to apply queued requests
+        self.write_sender.take();
+        let mut ret = self.wait_for_channel_writes();
+        // 2. flush all memtables, or keep their WALs to replay them at the next open
+        let cfs = self.all_cfs();
+        if !self.opts.read_only {
+            // every column family is flushed, the first error is returned
+            for inner in &cfs {
+                ret = ret.and(inner.sync());
+            }
+        }
+        if ret.is_err() {
+            for inner in &cfs {
+                for table in inner.memtables.read().view() {
+                    table.save_wal();
                 }
-            };
-            sst_iters.push(Box::new(iter));
+            }
         }
-        let sst_iter = MergeIterator::create(sst_iters);
-        let iter = TwoMergeIterator::create(mem_iter, sst_iter)?;
-        let end = match upper {
-            Bound::Included(key) => Bound::Included(Bytes::copy_from_slice(key)),
-            Bound::Unbounded => Bound::Unbounded,
-            Bound::Excluded(key) => Bound::Excluded(Bytes::copy_from_slice(key)),
+        // 3. stop background tasks, shutdown waits for running flushes and compactions
+        self.closer.take();
+        self.pool.shutdown();
+        // 4. keep files of current sstables, even if flushing failed
+        for inner in &cfs {
+            inner.stop_stalling();
+            inner.lvctl.mark_save();
+        }
+        ret
+    }
+}
+
+/// Add the entries and range tombstones of `memtables` (older first) to `builder`, newer memtables
+/// have higher priority.
+fn build_memtables(memtables: &[Arc<MemTable>], builder: &mut SsTableBuilder) -> Result<()> {
+    let shadows = shadows(memtables.iter().rev().map(|table| table.range_tombstones()));
+    let mut iters = Vec::with_capacity(memtables.len());
+    for (table, shadow) in memtables.iter().rev().zip(shadows) {
+        let iter = table.scan(Bound::Unbounded, Bound::Unbounded);
+        iters.push(Box::new(ShadowedIterator::create(iter, shadow)?));
+    }
+    let mut iter = MergeIterator::create(iters);
+    while iter.is_valid() {
+        if iter.is_merge() {
+            // combine it with the records of older memtables
+            let mut chain = MergeChain::default();
+            match get_in_memtables(memtables, iter.key(), &mut chain) {
+                true => match chain.resolve(iter.key(), builder.opts.merge_operator.as_ref())? {
+                    Some(value) => builder.add(iter.key(), &value)?,
+                    None => builder.add_tombstone(iter.key())?,
+                },
+                false => builder.add_merge(iter.key(), &chain.operands())?,
+            }
+            iter.next()?;
+            continue;
+        }
+        // expired values are kept, compaction drops them in the last level
+        match (iter.expire_at(), iter.is_tombstone()) {
+            (Some(expire_at), _) => builder.add_with_expiry(iter.key(), iter.value(), expire_at)?,
+            (None, true) => builder.add_tombstone(iter.key())?,
+            (None, false) => builder.add(iter.key(), iter.value())?,
+        }
+        iter.next()?;
+    }
+    for table in memtables {
+        for tombstone in table.range_tombstones() {
+            builder.add_range_tombstone(tombstone);
+        }
+    }
+    Ok(())
+}
+
+/// Get sorted `keys` from memtables (older first) and levels.
+fn multi_get_in(
+    memtables: &[Arc<MemTable>],
+    levels: &[Vec<Arc<SsTable>>],
+    keys: &[&[u8]],
+    operator: Option<&MergeOperator>,
+) -> Result<Vec<Option<Bytes>>> {
+    let mut getter = LevelsGetter::new(levels);
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        let mut chain = MergeChain::default();
+        if !get_in_memtables(memtables, key, &mut chain) {
+            getter.get(key, &mut chain)?;
+        }
+        values.push(chain.resolve(key, operator)?);
+    }
+    Ok(values)
+}
+
+/// Tables are seeked in the thread pool if a scan reads at least this many tables.
+const PARALLEL_SEEK_TABLES: usize = 4;
+
+/// Create an iterator of `table` at the first key in `lower`.
+fn seek_table(table: Arc<SsTable>, lower: Bound<&[u8]>) -> Result<SsTableIterator> {
+    Ok(match lower {
+        Bound::Included(key) => SsTableIterator::create_and_seek_to_key(table, key)?,
+        Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
+        Bound::Excluded(key) => {
+            let mut iter = SsTableIterator::create_and_seek_to_key(table, key)?;
+            if iter.is_valid() && iter.key() == key {
+                iter.next()?;
+            }
+            iter
+        }
+    })
+}
+
+/// Create an iterator of `table` at the last key in `upper`.
+fn seek_table_rev(table: Arc<SsTable>, upper: Bound<&[u8]>) -> Result<SsTableIterator> {
+    Ok(match upper {
+        Bound::Included(key) => SsTableIterator::create_and_seek_to_key_rev(table, key)?,
+        Bound::Unbounded => SsTableIterator::create_and_seek_to_last(table)?,
+        Bound::Excluded(key) => {
+            let mut iter = SsTableIterator::create_and_seek_to_key_rev(table, key)?;
+            if iter.is_valid() && iter.key() == key {
+                iter.prev()?;
+            }
+            iter
+        }
+    })
+}
+
+/// Split `ssts` into runs of consecutive tables in ascending key order which don't overlap, like
+/// the tables of a level. A run is concatenated instead of merged. Its tables are consecutive in
+/// the priority order of `ssts`, and don't share keys, so the merge result is the same.
+fn sorted_runs(ssts: &[Arc<SsTable>]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = vec![];
+    for (idx, table) in ssts.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run)
+                if table
+                    .compare(&ssts[run.end - 1].biggest_key, &table.smallest_key)
+                    .is_lt() =>
+            {
+                run.end = idx + 1
+            }
+            _ => runs.push(idx..idx + 1),
+        }
+    }
+    runs
+}
+
+/// Split `len` memtables into at most `max_groups` groups of at least `min_size` memtables
+/// unless there are fewer of them, return the ranges of the groups in order.
+fn flush_groups(len: usize, min_size: usize, max_groups: usize) -> Vec<Range<usize>> {
+    let num = (len / min_size).clamp(1, max_groups);
+    let (size, extra) = (len / num, len % num);
+    let mut start = 0;
+    (0..num)
+        .map(|idx| {
+            let end = start + size + usize::from(idx < extra);
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+/// Create iterators of `ssts` positioned by `seek` at `bound`, in the order of `ssts`. Seeks may
+/// read blocks from disk, so they run in `pool` when there are many tables.
+fn seek_tables(
+    pool: Option<&ThreadPool>,
+    ssts: &[Arc<SsTable>],
+    bound: Bound<&[u8]>,
+    seek: fn(Arc<SsTable>, Bound<&[u8]>) -> Result<SsTableIterator>,
+) -> Result<Vec<SsTableIterator>> {
+    let pool = match pool {
+        Some(pool) if ssts.len() >= PARALLEL_SEEK_TABLES => pool,
+        _ => {
+            return ssts
+                .iter()
+                .map(|table| seek(table.clone(), bound))
+                .collect()
+        }
+    };
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    for (idx, table) in ssts.iter().enumerate() {
+        let table = table.clone();
+        let bound = bound_to_bytes(bound);
+        let tx = tx.clone();
+        pool.spawn(move |_: &mut Handle| {
+            let ret = seek(table, bound.as_ref().map(|key| &key[..]));
+            // the scan may have returned with an error
+            let _ = tx.send((idx, ret));
+        });
+    }
+    drop(tx);
+
+    let mut iters = (0..ssts.len()).map(|_| None).collect::<Vec<_>>();
+    for (idx, ret) in rx.iter() {
+        iters[idx] = Some(ret?);
+    }
+    iters
+        .into_iter()
+        .map(|iter| iter.ok_or_else(|| anyhow::anyhow!("seek task exited unexpectedly")))
+        .collect()
+}
+
+/// Create an iterator over memtables (older first) and sorted sstables (newer first), sstables are
+/// seeked in `pool` if it's given.
+pub(crate) fn scan_tables(
+    pool: Option<&ThreadPool>,
+    memtables: &[Arc<MemTable>],
+    ssts: &[Arc<SsTable>],
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    operator: Option<&MergeOperator>,
+) -> Result<FusedIterator<LsmIterator>> {
+    scan_tables_inner(pool, memtables, ssts, lower, upper, operator, false)
+}
+
+/// Scan `memtables` (older first) and `ssts` (newer first) in descending order like
+/// `scan_tables`.
+pub(crate) fn scan_tables_rev(
+    pool: Option<&ThreadPool>,
+    memtables: &[Arc<MemTable>],
+    ssts: &[Arc<SsTable>],
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    operator: Option<&MergeOperator>,
+) -> Result<FusedIterator<LsmIterator>> {
+    scan_tables_inner(pool, memtables, ssts, lower, upper, operator, true)
+}
+
+fn scan_tables_inner(
+    pool: Option<&ThreadPool>,
+    memtables: &[Arc<MemTable>],
+    ssts: &[Arc<SsTable>],
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    operator: Option<&MergeOperator>,
+    rev: bool,
+) -> Result<FusedIterator<LsmIterator>> {
+    let (mem_shadows, sst_shadows) = table_shadows(memtables, ssts);
+    let mut mem_iters = Vec::with_capacity(memtables.len());
+    for (table, shadow) in memtables.iter().rev().zip(mem_shadows) {
+        let iter = match rev {
+            true => ShadowedIterator::create_rev(table.scan_rev(lower, upper), shadow)?,
+            false => ShadowedIterator::create(table.scan(lower, upper), shadow)?,
+        };
+        mem_iters.push(Box::new(iter));
+    }
+    let mem_iter = match rev {
+        true => MergeIterator::create_rev(mem_iters),
+        false => MergeIterator::create(mem_iters),
+    };
+
+    // only the first table of each run in the direction is seeked now, the others when the scan
+    // reaches them
+    let runs = sorted_runs(ssts);
+    let firsts: Vec<_> = runs
+        .iter()
+        .map(|run| ssts[if rev { run.end - 1 } else { run.start }].clone())
+        .collect();
+    let seeks = match rev {
+        true => seek_tables(pool, &firsts, upper, seek_table_rev)?,
+        false => seek_tables(pool, &firsts, lower, seek_table)?,
+    };
+    let mut sst_iters = Vec::with_capacity(runs.len());
+    for (run, first) in runs.into_iter().zip(seeks) {
+        let mut tables: Vec<_> = ssts[run.clone()]
+            .iter()
+            .cloned()
+            .zip(sst_shadows[run].iter().cloned())
+            .collect();
+        let iter = match rev {
+            true => {
+                tables.reverse();
+                let first = ShadowedIterator::create_rev(first, tables[0].1.clone())?;
+                SsTableConcatIterator::create_rev(first, tables)?
+            }
+            false => {
+                let first = ShadowedIterator::create(first, tables[0].1.clone())?;
+                SsTableConcatIterator::create(first, tables)?
+            }
         };
-        Ok(FusedIterator::new(LsmIterator::new(iter, end)?))
+        sst_iters.push(Box::new(iter));
+    }
+    let iter = if !mem_iter.is_valid() && sst_iters.len() == 1 {
+        // fast path: no merging is needed
+        LsmIteratorInner::Table(*sst_iters.pop().unwrap())
+    } else if rev {
+        let sst_iter = MergeIterator::create_rev(sst_iters);
+        LsmIteratorInner::Merged(TwoMergeIterator::create_rev(mem_iter, sst_iter)?)
+    } else {
+        let sst_iter = MergeIterator::create(sst_iters);
+        LsmIteratorInner::Merged(TwoMergeIterator::create(mem_iter, sst_iter)?)
+    };
+    let resolver = MergeResolver::new(memtables.to_vec(), ssts.to_vec(), operator.cloned());
+    let iter = match rev {
+        true => LsmIterator::new_rev(iter, bound_to_bytes(lower), resolver)?,
+        false => LsmIterator::new(iter, bound_to_bytes(lower), bound_to_bytes(upper), resolver)?,
+    };
+    Ok(FusedIterator::new(iter))
+}
+
+/// Get the range tombstones deleting keys of each memtable (newer first) and each sstable, see
+/// [`shadows`].
+fn table_shadows(
+    memtables: &[Arc<MemTable>],
+    ssts: &[Arc<SsTable>],
+) -> (Vec<Arc<RangeTombstones>>, Vec<Arc<RangeTombstones>>) {
+    let mem_tombstones = memtables.iter().rev().map(|table| table.range_tombstones());
+    let sst_tombstones = ssts.iter().map(|table| table.range_tombstones().to_vec());
+    let mut shadows = shadows(mem_tombstones.chain(sst_tombstones));
+    let sst_shadows = shadows.split_off(memtables.len());
+    (shadows, sst_shadows)
+}
+
+/// Convert key-value pairs to entries of puts.
+fn into_puts(entries: Vec<(Bytes, Bytes)>) -> Vec<(Bytes, Option<Bytes>)> {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key, Some(value)))
+        .collect()
+}
+
+pub(crate) fn bound_to_bytes(bound: Bound<&[u8]>) -> Bound<Bytes> {
+    match bound {
+        Bound::Included(key) => Bound::Included(Bytes::copy_from_slice(key)),
+        Bound::Unbounded => Bound::Unbounded,
+        Bound::Excluded(key) => Bound::Excluded(Bytes::copy_from_slice(key)),
     }
 }
 
 impl Drop for LsmStorage {
     fn drop(&mut self) {
-        self.closer.take();
-        self.pool.shutdown();
-        self.sync().unwrap();
-        self.inner.lvctl.mark_save();
+        if let Err(e) = self.close_inner() {
+            error!("failed to close the storage: {e}");
+        }
     }
 }
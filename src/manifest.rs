@@ -1,45 +1,266 @@
 use anyhow::{Ok, Result};
 use bytes::{Buf, BufMut};
+use log::{info, warn};
 use parking_lot::Mutex;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, File},
     io::{Read, Write},
 };
 
+use crate::checksum::{self, CHECKSUM_SIZE};
+use crate::util::{manifest_file_path, tmp_file_path};
+
+// |MAGIC|version(u32)|records|
+//
+// Version 2 adds records of change sets, and version 3 the record of the comparator. Files of
+// older versions and files written before the header, which are records of
+// |op(u8)|id(u64)|level(u8)| without lengths and checksums, are rewritten in the current format when
+// they are opened for writing.
+const MAGIC: &[u8; 8] = b"TOPAZMFT";
+const VERSION: u32 = 3;
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+
+/// The manifest is rewritten when it has more records than this many times the live tables.
+const REWRITE_RATIO: usize = 4;
+/// The manifest is never rewritten when it has less records than this.
+const REWRITE_MIN_RECORDS: usize = 1024;
+
+/// Id of the default column family, which always exists.
+pub const DEFAULT_CF: u32 = 0;
+pub const DEFAULT_CF_NAME: &str = "default";
+
 struct ManifestFileInner {
     fs: File,
-    map: HashMap<u64, usize>,
+    path: PathBuf,
+    // id -> (column family, level)
+    map: HashMap<u64, (u32, usize)>,
+    // ids of level 0 in the order of creation
+    l0_ids: Vec<u64>,
+    // column families except the default one
+    cfs: HashMap<String, u32>,
+    // name of the comparator of keys, None if it's not recorded yet
+    comparator: Option<String>,
+    // number of records in the file
+    records: usize,
 }
 
 impl ManifestFileInner {
-    fn delete(&mut self, id: u64) -> Result<()> {
-        if !self.map.contains_key(&id) {
-            return Err(anyhow::anyhow!("non-existent id"));
+    /// Write `changes` in one record, so a torn write loses either all or none of them.
+    fn apply(&mut self, changes: &[Change]) -> Result<()> {
+        self.check(changes)?;
+        let mut buf = vec![];
+        match changes {
+            [] => return Ok(()),
+            [change] => Self::encode_change(&mut buf, change),
+            _ => Self::encode_change_set(&mut buf, changes),
         }
-        let mut buf = Vec::with_capacity(9);
-        buf.put_u8(1);
-        buf.put_u64(id);
         self.fs.write_all(&buf)?;
-        self.map.remove(&id);
+        self.records += changes.len();
+        for change in changes {
+            replay(&mut self.map, &mut self.l0_ids, change);
+        }
+        Ok(())
+    }
+
+    /// Check that `changes` can be applied in order, before any of them is written.
+    fn check(&self, changes: &[Change]) -> Result<()> {
+        let mut added = HashSet::new();
+        let mut deleted = HashSet::new();
+        for change in changes {
+            let id = change.table_id;
+            let exists =
+                added.contains(&id) || self.map.contains_key(&id) && !deleted.contains(&id);
+            match change.op {
+                Operation::Create if exists => return Err(anyhow::anyhow!("repeated id")),
+                Operation::Create => {
+                    let cf = change.cf;
+                    if cf != DEFAULT_CF && !self.cfs.values().any(|x| *x == cf) {
+                        return Err(anyhow::anyhow!("non-existent column family {cf}"));
+                    }
+                    added.insert(id);
+                }
+                Operation::Delete if !exists => return Err(anyhow::anyhow!("non-existent id")),
+                Operation::Delete => {
+                    added.remove(&id);
+                    deleted.insert(id);
+                }
+            }
+        }
         Ok(())
     }
 
-    fn add(&mut self, id: u64, level: usize) -> Result<()> {
-        if self.map.contains_key(&id) {
-            return Err(anyhow::anyhow!("repeated id"));
+    fn create_cf(&mut self, name: &str) -> Result<u32> {
+        if name == DEFAULT_CF_NAME || self.cfs.contains_key(name) {
+            return Err(anyhow::anyhow!("column family {name:?} already exists"));
         }
-        let mut buf = Vec::with_capacity(10);
-        buf.put_u8(0);
-        buf.put_u64(id);
-        buf.put_u8(level as u8);
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("column family name is empty"));
+        }
+        let cf = self.cfs.values().copied().max().unwrap_or(DEFAULT_CF) + 1;
+        let mut buf = Vec::with_capacity(name.len() + 13);
+        Self::encode_create_cf(&mut buf, cf, name);
+        self.fs.write_all(&buf)?;
+        self.fs.sync_all()?;
+        self.records += 1;
+        self.cfs.insert(name.to_string(), cf);
+        Ok(cf)
+    }
+
+    fn set_comparator(&mut self, name: &str) -> Result<()> {
+        let mut buf = Vec::with_capacity(name.len() + 9);
+        Self::encode_comparator(&mut buf, name);
         self.fs.write_all(&buf)?;
-        self.map.insert(id, level);
+        self.fs.sync_all()?;
+        self.records += 1;
+        self.comparator = Some(name.to_string());
         Ok(())
     }
+
+    fn encode_header(buf: &mut Vec<u8>) {
+        buf.put_slice(MAGIC);
+        buf.put_u32(VERSION);
+    }
+
+    // |len(u32)|op(u8)|id(u64)|level(u8)|cf(u32)|checksum(u32)|, cf is omitted for the default
+    // column family
+    fn encode_create(buf: &mut Vec<u8>, id: u64, level: usize, cf: u32) {
+        let mut record = Vec::with_capacity(14);
+        record.put_u8(Operation::Create as u8);
+        record.put_u64(id);
+        record.put_u8(level as u8);
+        if cf != DEFAULT_CF {
+            record.put_u32(cf);
+        }
+        Self::encode_record(buf, &record);
+    }
+
+    // |len(u32)|op(u8)|cf(u32)|name|checksum(u32)|
+    fn encode_create_cf(buf: &mut Vec<u8>, cf: u32, name: &str) {
+        let mut record = Vec::with_capacity(name.len() + 5);
+        record.put_u8(OP_CREATE_CF);
+        record.put_u32(cf);
+        record.put_slice(name.as_bytes());
+        Self::encode_record(buf, &record);
+    }
+
+    // |len(u32)|op(u8)|name|checksum(u32)|
+    fn encode_comparator(buf: &mut Vec<u8>, name: &str) {
+        let mut record = Vec::with_capacity(name.len() + 1);
+        record.put_u8(OP_COMPARATOR);
+        record.put_slice(name.as_bytes());
+        Self::encode_record(buf, &record);
+    }
+
+    // |len(u32)|op(u8)|id(u64)|checksum(u32)|
+    fn encode_delete(buf: &mut Vec<u8>, id: u64) {
+        let mut record = Vec::with_capacity(9);
+        record.put_u8(Operation::Delete as u8);
+        record.put_u64(id);
+        Self::encode_record(buf, &record);
+    }
+
+    fn encode_change(buf: &mut Vec<u8>, change: &Change) {
+        match change.op {
+            Operation::Create => Self::encode_create(buf, change.table_id, change.level, change.cf),
+            Operation::Delete => Self::encode_delete(buf, change.table_id),
+        }
+    }
+
+    // |len(u32)|op(u8)|changes|checksum(u32)|, a change is |op(u8)|id(u64)|level(u8)|cf(u32)|
+    fn encode_change_set(buf: &mut Vec<u8>, changes: &[Change]) {
+        let mut record = Vec::with_capacity(1 + changes.len() * CHANGE_SIZE);
+        record.put_u8(OP_CHANGE_SET);
+        for change in changes {
+            record.put_u8(change.op as u8);
+            record.put_u64(change.table_id);
+            record.put_u8(change.level as u8);
+            record.put_u32(change.cf);
+        }
+        Self::encode_record(buf, &record);
+    }
+
+    fn encode_record(buf: &mut Vec<u8>, record: &[u8]) {
+        buf.put_u32(record.len() as u32);
+        buf.put_slice(record);
+        buf.put_u32(checksum::calculate_checksum(record));
+    }
+
+    /// Write the live tables to a new file, and replace the old one with it.
+    fn rewrite(&mut self) -> Result<()> {
+        let mut others = self
+            .map
+            .iter()
+            .filter(|(_, (_, level))| *level != 0)
+            .map(|(id, (cf, level))| (*id, *cf, *level))
+            .collect::<Vec<_>>();
+        others.sort_unstable();
+        let mut cfs = self.cfs.iter().collect::<Vec<_>>();
+        cfs.sort_unstable_by_key(|(_, cf)| **cf);
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.map.len() * 22);
+        Self::encode_header(&mut buf);
+        if let Some(name) = &self.comparator {
+            Self::encode_comparator(&mut buf, name);
+        }
+        // column families are created before their tables
+        for (name, cf) in cfs {
+            Self::encode_create_cf(&mut buf, *cf, name);
+        }
+        // keep the order of level 0
+        for id in &self.l0_ids {
+            Self::encode_create(&mut buf, *id, 0, self.map[id].0);
+        }
+        for (id, cf, level) in others {
+            Self::encode_create(&mut buf, id, level, cf);
+        }
+
+        // the new file is opened before it replaces the old one, so a failure leaves both the file
+        // and `self.fs` as they were
+        self.fs = write_atomically(&self.path, &buf)?;
+        self.records = self.live_records();
+        Ok(())
+    }
+
+    /// Sync the records written, then rewrite the file if it has too many stale records.
+    ///
+    /// The records are durable once synced, so a failed rewrite is only logged. It's tried again
+    /// on the next change.
+    fn sync(&mut self) -> Result<()> {
+        self.fs.sync_all()?;
+        if self.need_rewrite() {
+            if let Err(e) = self.rewrite() {
+                warn!("failed to rewrite manifest {:?}: {e}", self.path);
+            }
+        }
+        Ok(())
+    }
+
+    fn need_rewrite(&self) -> bool {
+        self.records >= REWRITE_MIN_RECORDS && self.records > self.live_records() * REWRITE_RATIO
+    }
+
+    /// Get the number of records a rewritten file has.
+    fn live_records(&self) -> usize {
+        usize::from(self.comparator.is_some()) + self.cfs.len() + self.map.len()
+    }
+}
+
+/// Write `buf` to a temporary file, and replace `path` with it. Returns the new file, later writes
+/// to it are appended after `buf`.
+fn write_atomically(path: &Path, buf: &[u8]) -> Result<File> {
+    let tmp_path = tmp_file_path(path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(buf)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(tmp)
 }
 
 pub struct ManifestFile {
@@ -47,41 +268,172 @@ pub struct ManifestFile {
 }
 
 impl ManifestFile {
+    /// Get the level of each table of all column families.
     pub fn get_id_level(&self) -> HashMap<u64, usize> {
-        self.inner.lock().map.clone()
+        let inner = self.inner.lock();
+        inner
+            .map
+            .iter()
+            .map(|(id, (_, level))| (*id, *level))
+            .collect()
+    }
+
+    /// Get the level of each table of column family `cf`.
+    pub fn get_cf_id_level(&self, cf: u32) -> HashMap<u64, usize> {
+        let inner = self.inner.lock();
+        inner
+            .map
+            .iter()
+            .filter(|(_, (x, _))| *x == cf)
+            .map(|(id, (_, level))| (*id, *level))
+            .collect()
+    }
+
+    /// Get ids of level 0 of all column families in the order of creation.
+    pub fn l0_ids(&self) -> Vec<u64> {
+        self.inner.lock().l0_ids.clone()
+    }
+
+    /// Get the ids of column families by their names, including the default one.
+    pub fn column_families(&self) -> HashMap<String, u32> {
+        let mut cfs = self.inner.lock().cfs.clone();
+        cfs.insert(DEFAULT_CF_NAME.to_string(), DEFAULT_CF);
+        cfs
+    }
+
+    /// Create a column family named `name`, return its id.
+    pub fn create_cf(&self, name: &str) -> Result<u32> {
+        self.inner.lock().create_cf(name)
+    }
+
+    /// Get the name of the comparator of keys, None if it's not recorded, e.g. the manifest is new
+    /// or written before the comparator was recorded.
+    pub fn comparator(&self) -> Option<String> {
+        self.inner.lock().comparator.clone()
+    }
+
+    /// Record the name of the comparator of keys, it replaces the recorded one.
+    pub fn set_comparator(&self, name: &str) -> Result<()> {
+        self.inner.lock().set_comparator(name)
     }
 
-    /// return Self and level0 ordered and unfiltered id
+    /// return Self and ids of level 0 in the order of creation
+    ///
+    /// Records after the first one failing its checksum are a torn write, they are dropped.
     pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<u64>)> {
-        let manifest_path = path.as_ref().join("MANIFEST");
+        Self::open_file(manifest_file_path(path.as_ref()), false)
+    }
+
+    /// Same as `open`, but the manifest is never created or truncated. Changes can't be applied.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<(Self, Vec<u64>)> {
+        Self::open_file(manifest_file_path(path.as_ref()), true)
+    }
+
+    /// Same as `open` or `open_read_only`, but `path` is the manifest file instead of its
+    /// directory.
+    pub fn open_file(path: impl AsRef<Path>, read_only: bool) -> Result<(Self, Vec<u64>)> {
+        let manifest_path = path.as_ref().to_path_buf();
+        if read_only && !manifest_path.exists() {
+            return Err(anyhow::anyhow!(
+                "manifest {:?} doesn't exist",
+                manifest_path
+            ));
+        }
         if !manifest_path.exists() {
-            fs::File::create(&manifest_path)?;
+            let mut buf = Vec::with_capacity(HEADER_SIZE);
+            ManifestFileInner::encode_header(&mut buf);
+            write_atomically(&manifest_path, &buf)?;
         }
 
         let mut reader = BufReader::new(File::open(&manifest_path)?);
-        let mut buf = String::new();
-        reader.read_to_string(&mut buf)?;
-        let mut buf = buf.as_bytes();
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
         let mut map = HashMap::new();
+        let mut cfs = HashMap::new();
+        let mut comparator = None;
         let mut ids = vec![];
-        while !buf.is_empty() {
-            let op = buf.get_u8();
-            let id = buf.get_u64();
-            match Operation::from(op) {
-                Operation::Create => {
-                    let level = buf.get_u8();
-                    map.insert(id, level as usize);
-                    if level == 0 {
-                        ids.push(id);
+        let mut records = 0;
+        let version = match data.get(..MAGIC.len()) {
+            Some(magic) if magic == MAGIC && data.len() >= HEADER_SIZE => {
+                (&data[MAGIC.len()..]).get_u32()
+            }
+            _ => 0,
+        };
+        let mut buf = match version {
+            0 => {
+                let changes = decode_legacy(&data)
+                    .ok_or_else(|| anyhow::anyhow!("bad magic of manifest {:?}", manifest_path))?;
+                for change in &changes {
+                    replay(&mut map, &mut ids, change);
+                }
+                records = changes.len();
+                &data[data.len()..]
+            }
+            1 | 2 | VERSION => &data[HEADER_SIZE..],
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "unsupported manifest version {version}, expected {VERSION}"
+                ))
+            }
+        };
+
+        while let Some((record, rest)) = decode_record(buf) {
+            buf = rest;
+            match record {
+                Record::Changes(changes) => {
+                    records += changes.len();
+                    for change in &changes {
+                        replay(&mut map, &mut ids, change);
                     }
                 }
-                Operation::Delete => {
-                    map.remove(&id);
+                Record::CreateCf(cf, name) => {
+                    records += 1;
+                    cfs.insert(name, cf);
+                }
+                Record::Comparator(name) => {
+                    records += 1;
+                    comparator = Some(name);
                 }
             }
         }
-        let fs = fs::File::options().append(true).open(&manifest_path)?;
-        let inner = ManifestFileInner { fs, map };
+        if !buf.is_empty() && read_only {
+            warn!(
+                "manifest {:?} has a torn write of {} bytes, ignored",
+                manifest_path,
+                buf.len()
+            );
+        } else if !buf.is_empty() {
+            warn!(
+                "manifest {:?} has a torn write of {} bytes",
+                manifest_path,
+                buf.len()
+            );
+            // the following records are appended after the valid ones
+            let fs = fs::File::options().write(true).open(&manifest_path)?;
+            fs.set_len((data.len() - buf.len()) as u64)?;
+            fs.sync_all()?;
+        }
+
+        let fs = match read_only {
+            true => File::open(&manifest_path)?,
+            false => fs::File::options().append(true).open(&manifest_path)?,
+        };
+        let mut inner = ManifestFileInner {
+            fs,
+            path: manifest_path,
+            map,
+            l0_ids: ids.clone(),
+            cfs,
+            comparator,
+            records,
+        };
+        if version != VERSION && !read_only {
+            info!(
+                "rewrite manifest {:?} of version {version} in version {VERSION}",
+                inner.path
+            );
+            inner.rewrite()?;
+        }
         Ok((
             Self {
                 inner: Mutex::new(inner),
@@ -90,31 +442,134 @@ impl ManifestFile {
         ))
     }
 
+    /// Apply the changes in one record, so they are all or none replayed after a crash.
     pub fn apply_change_set(&self, change_set: &ManifestChangeSet) -> Result<()> {
         let mut w = self.inner.lock();
-        for change in &change_set.changes {
-            let id = change.table_id;
-            let level = change.level;
-            match change.op {
-                Operation::Create => w.add(id, level)?,
-                Operation::Delete => w.delete(id)?,
-            }
-        }
-        w.fs.sync_all()?;
-        Ok(())
+        w.apply(&change_set.changes)?;
+        w.sync()
     }
 
     pub fn apply_change(&self, change: &Change) -> Result<()> {
         let mut w = self.inner.lock();
-        let id = change.table_id;
-        let level = change.level;
-        match change.op {
-            Operation::Create => w.add(id, level)?,
-            Operation::Delete => w.delete(id)?,
+        w.apply(std::slice::from_ref(change))?;
+        w.sync()
+    }
+
+    /// Write a new manifest file at `path` which only has the comparator named `comparator` and
+    /// the tables of `levels`, tables of level 0 are in the order of creation.
+    pub fn write_new(path: impl AsRef<Path>, comparator: &str, levels: &[Vec<u64>]) -> Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        ManifestFileInner::encode_header(&mut buf);
+        ManifestFileInner::encode_comparator(&mut buf, comparator);
+        for (level, ids) in levels.iter().enumerate() {
+            for id in ids {
+                ManifestFileInner::encode_create(&mut buf, *id, level, DEFAULT_CF);
+            }
         }
-        w.fs.sync_all()?;
+        write_atomically(path.as_ref(), &buf)?;
         Ok(())
     }
+
+    /// Rewrite the manifest with only the live tables, so it doesn't grow without bound.
+    ///
+    /// It's done automatically when the manifest has too many stale records.
+    pub fn rewrite(&self) -> Result<()> {
+        self.inner.lock().rewrite()
+    }
+}
+
+enum Record {
+    Changes(Vec<Change>),
+    CreateCf(u32, String),
+    Comparator(String),
+}
+
+/// Decode a record from `buf`, return None if it's incomplete or corrupted.
+fn decode_record(mut buf: &[u8]) -> Option<(Record, &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    if buf.len() < len + CHECKSUM_SIZE {
+        return None;
+    }
+    let (mut record, mut rest) = buf.split_at(len);
+    let checksum = rest.get_u32();
+    checksum::verify_checksum(record, checksum).ok()?;
+
+    let decoded = match (record.first()?, len) {
+        (0, 10) => {
+            record.advance(1);
+            let change = Change::create(record.get_u64(), record.get_u8() as usize);
+            Record::Changes(vec![change])
+        }
+        (0, 14) => {
+            record.advance(1);
+            let (id, level) = (record.get_u64(), record.get_u8() as usize);
+            Record::Changes(vec![Change::create_in(record.get_u32(), id, level)])
+        }
+        (1, 9) => {
+            record.advance(1);
+            Record::Changes(vec![Change::delete(record.get_u64())])
+        }
+        (&OP_CREATE_CF, 5..) => {
+            record.advance(1);
+            let cf = record.get_u32();
+            Record::CreateCf(cf, String::from_utf8(record.to_vec()).ok()?)
+        }
+        (&OP_COMPARATOR, _) => {
+            record.advance(1);
+            Record::Comparator(String::from_utf8(record.to_vec()).ok()?)
+        }
+        (&OP_CHANGE_SET, _) if (len - 1).is_multiple_of(CHANGE_SIZE) => {
+            record.advance(1);
+            let mut changes = Vec::with_capacity(len / CHANGE_SIZE);
+            while record.has_remaining() {
+                let (op, id, level) = (record.get_u8(), record.get_u64(), record.get_u8());
+                let cf = record.get_u32();
+                changes.push(match op {
+                    0 => Change::create_in(cf, id, level as usize),
+                    1 => Change::delete(id),
+                    _ => return None,
+                });
+            }
+            Record::Changes(changes)
+        }
+        _ => return None,
+    };
+    Some((decoded, rest))
+}
+
+/// Decode a manifest written before the header, return None if it's not one.
+fn decode_legacy(mut buf: &[u8]) -> Option<Vec<Change>> {
+    let mut changes = vec![];
+    while buf.has_remaining() {
+        let change = match buf.get_u8() {
+            0 if buf.len() >= 9 => Change::create(buf.get_u64(), buf.get_u8() as usize),
+            1 if buf.len() >= 8 => Change::delete(buf.get_u64()),
+            _ => return None,
+        };
+        changes.push(change);
+    }
+    Some(changes)
+}
+
+/// Apply `change` to the level of each table and the ids of level 0.
+fn replay(map: &mut HashMap<u64, (u32, usize)>, l0_ids: &mut Vec<u64>, change: &Change) {
+    let id = change.table_id;
+    match change.op {
+        Operation::Create => {
+            map.insert(id, (change.cf, change.level));
+            if change.level == 0 {
+                l0_ids.push(id);
+            }
+        }
+        Operation::Delete => {
+            if let Some((_, 0)) = map.remove(&id) {
+                l0_ids.retain(|x| *x != id);
+            }
+        }
+    }
 }
 
 pub struct ManifestChangeSet {
@@ -122,33 +577,41 @@ pub struct ManifestChangeSet {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 enum Operation {
     Create = 0,
     Delete = 1,
 }
 
-impl From<u8> for Operation {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => Self::Create,
-            1 => Self::Delete,
-            _ => panic!("invalid value"),
-        }
-    }
-}
+/// Operation of a record which creates a column family.
+const OP_CREATE_CF: u8 = 2;
+/// Operation of a record which has the changes of a `ManifestChangeSet`.
+const OP_CHANGE_SET: u8 = 3;
+/// Operation of a record which has the name of the comparator of keys.
+const OP_COMPARATOR: u8 = 4;
+/// Size of a change in a record of `OP_CHANGE_SET`.
+const CHANGE_SIZE: usize = 14;
 
 pub struct Change {
     op: Operation,
     table_id: u64,
     level: usize,
+    cf: u32,
 }
 
 impl Change {
+    /// Create a table in the default column family.
     pub fn create(table_id: u64, level: usize) -> Self {
+        Self::create_in(DEFAULT_CF, table_id, level)
+    }
+
+    /// Create a table in column family `cf`.
+    pub fn create_in(cf: u32, table_id: u64, level: usize) -> Self {
         Self {
             op: Operation::Create,
             table_id,
             level,
+            cf,
         }
     }
 
@@ -157,6 +620,7 @@ impl Change {
             op: Operation::Delete,
             table_id,
             level: 0,
+            cf: DEFAULT_CF,
         }
     }
 }
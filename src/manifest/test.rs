@@ -4,7 +4,7 @@ use tempfile::TempDir;
 
 use crate::manifest::ManifestChangeSet;
 
-use super::{Change, ManifestFile};
+use super::{Change, ManifestFile, DEFAULT_CF, DEFAULT_CF_NAME, HEADER_SIZE};
 
 #[test]
 fn create() {
@@ -72,3 +72,253 @@ fn replay() {
     assert_eq!(exp, v);
     assert_eq!(l0_ids, vec![0, 10]);
 }
+
+#[test]
+fn rewrite() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    for i in 0..3000 {
+        manifest
+            .apply_change(&Change::create(i, (i % 3) as usize))
+            .unwrap();
+        if i % 100 != 0 {
+            manifest.apply_change(&Change::delete(i)).unwrap();
+        }
+    }
+    // stale records are removed automatically
+    let size = std::fs::metadata(&manifest_path).unwrap().len();
+    assert!(size < 3000 * 18, "{size}");
+
+    manifest.rewrite().unwrap();
+    let size = std::fs::metadata(&manifest_path).unwrap().len();
+    assert_eq!(size as usize, HEADER_SIZE + 30 * 18);
+    let exp = manifest.get_id_level();
+    assert_eq!(exp.len(), 30);
+    drop(manifest);
+
+    let (manifest, l0_ids) = ManifestFile::open(dir.path()).unwrap();
+    assert_eq!(manifest.get_id_level(), exp);
+    assert_eq!(
+        l0_ids,
+        vec![0, 300, 600, 900, 1200, 1500, 1800, 2100, 2400, 2700]
+    );
+    assert!(!dir.path().join("MANIFEST.tmp").exists());
+}
+
+#[test]
+fn rewrite_failure() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    let tmp_path = dir.path().join("MANIFEST.tmp");
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    // the temporary file can't be created
+    std::fs::create_dir(&tmp_path).unwrap();
+    for i in 0..3000 {
+        manifest.apply_change(&Change::create(i, 1)).unwrap();
+        manifest.apply_change(&Change::delete(i)).unwrap();
+    }
+    assert!(manifest.rewrite().is_err());
+    let size = std::fs::metadata(&manifest_path).unwrap().len();
+    assert!(size > 3000 * 18, "{size}");
+
+    // the failed rewrite is tried again on the next change
+    std::fs::remove_dir(&tmp_path).unwrap();
+    manifest.apply_change(&Change::create(3000, 1)).unwrap();
+    let size = std::fs::metadata(&manifest_path).unwrap().len();
+    assert_eq!(size as usize, HEADER_SIZE + 18);
+    // changes are appended to the new file
+    manifest.apply_change(&Change::create(3001, 1)).unwrap();
+    drop(manifest);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(3000, 1), (3001, 1)]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+}
+
+#[test]
+fn column_families() {
+    let dir = TempDir::new().unwrap();
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let cf = manifest.create_cf("users").unwrap();
+    assert!(manifest.create_cf("users").is_err());
+    assert!(manifest.create_cf("").is_err());
+    assert!(manifest
+        .apply_change(&Change::create_in(cf + 1, 1, 1))
+        .is_err());
+    manifest.apply_change(&Change::create(1, 1)).unwrap();
+    manifest.apply_change(&Change::create_in(cf, 2, 0)).unwrap();
+    manifest.apply_change(&Change::create_in(cf, 3, 2)).unwrap();
+    manifest.apply_change(&Change::delete(3)).unwrap();
+    drop(manifest);
+
+    let (manifest, l0_ids) = ManifestFile::open(dir.path()).unwrap();
+    assert_eq!(l0_ids, vec![2]);
+    let exp = vec![(1, 1)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_cf_id_level(DEFAULT_CF), exp);
+    let exp = vec![(2, 0)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_cf_id_level(cf), exp);
+    assert_eq!(manifest.get_id_level().len(), 2);
+
+    manifest.rewrite().unwrap();
+    drop(manifest);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    assert_eq!(manifest.get_cf_id_level(cf), exp);
+    let cfs = manifest.column_families();
+    assert_eq!(cfs.len(), 2);
+    assert_eq!(cfs["users"], cf);
+    assert_eq!(cfs[DEFAULT_CF_NAME], DEFAULT_CF);
+}
+
+#[test]
+fn torn_record() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    for i in 0..3 {
+        manifest.apply_change(&Change::create(i, 1)).unwrap();
+    }
+    drop(manifest);
+    let len = std::fs::metadata(&manifest_path).unwrap().len();
+
+    // a half written record
+    let file = std::fs::File::options()
+        .write(true)
+        .open(&manifest_path)
+        .unwrap();
+    file.set_len(len - 5).unwrap();
+    drop(file);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(0, 1), (1, 1)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+    // appended after the valid records
+    manifest.apply_change(&Change::create(3, 1)).unwrap();
+    drop(manifest);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(0, 1), (1, 1), (3, 1)]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+    drop(manifest);
+
+    // a record failing its checksum
+    let mut data = std::fs::read(&manifest_path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    std::fs::write(&manifest_path, &data).unwrap();
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(0, 1), (1, 1)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+}
+
+#[test]
+fn bad_magic() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    manifest.apply_change(&Change::create(1, 1)).unwrap();
+    drop(manifest);
+
+    let mut data = std::fs::read(&manifest_path).unwrap();
+    data[0] ^= 0xff;
+    std::fs::write(&manifest_path, &data).unwrap();
+    assert!(ManifestFile::open(dir.path()).is_err());
+
+    // an empty file written before the header
+    std::fs::write(&manifest_path, b"").unwrap();
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    assert!(manifest.get_id_level().is_empty());
+}
+
+#[test]
+fn torn_change_set() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    manifest.apply_change(&Change::create(1, 1)).unwrap();
+    let changes = vec![
+        Change::create(2, 1),
+        Change::create(3, 1),
+        Change::delete(1),
+    ];
+    manifest
+        .apply_change_set(&ManifestChangeSet { changes })
+        .unwrap();
+    drop(manifest);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(2, 1), (3, 1)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+    drop(manifest);
+
+    // none of the changes in a half written change set are replayed
+    let len = std::fs::metadata(&manifest_path).unwrap().len();
+    let file = std::fs::File::options()
+        .write(true)
+        .open(&manifest_path)
+        .unwrap();
+    file.set_len(len - 5).unwrap();
+    drop(file);
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(1, 1)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+}
+
+#[test]
+fn legacy_format() {
+    let dir = TempDir::new().unwrap();
+    let manifest_path = dir.path().join("MANIFEST");
+    // |op(u8)|id(u64)|level(u8)| and |op(u8)|id(u64)| without a header
+    let mut data = vec![];
+    for (id, level) in [(1u64, 0u8), (2, 1), (3, 0)] {
+        data.push(0);
+        data.extend_from_slice(&id.to_be_bytes());
+        data.push(level);
+    }
+    data.push(1);
+    data.extend_from_slice(&1u64.to_be_bytes());
+    std::fs::write(&manifest_path, &data).unwrap();
+
+    let (manifest, l0_ids) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(2, 1), (3, 0)].into_iter().collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+    assert_eq!(l0_ids, vec![3]);
+    manifest.apply_change(&Change::create(4, 2)).unwrap();
+    drop(manifest);
+
+    // rewritten in the current format
+    let data = std::fs::read(&manifest_path).unwrap();
+    assert_eq!(&data[..8], b"TOPAZMFT");
+    let (manifest, l0_ids) = ManifestFile::open(dir.path()).unwrap();
+    let exp = vec![(2, 1), (3, 0), (4, 2)]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    assert_eq!(manifest.get_id_level(), exp);
+    assert_eq!(l0_ids, vec![3]);
+}
+
+#[test]
+fn comparator() {
+    let dir = TempDir::new().unwrap();
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    assert_eq!(manifest.comparator(), None);
+    manifest.set_comparator("reverse").unwrap();
+    manifest.apply_change(&Change::create(1, 1)).unwrap();
+    drop(manifest);
+
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    assert_eq!(manifest.comparator().as_deref(), Some("reverse"));
+    // kept by a rewrite
+    manifest.rewrite().unwrap();
+    drop(manifest);
+    let (manifest, _) = ManifestFile::open_read_only(dir.path()).unwrap();
+    assert_eq!(manifest.comparator().as_deref(), Some("reverse"));
+    assert_eq!(manifest.get_id_level().len(), 1);
+
+    let new_dir = TempDir::new().unwrap();
+    let path = new_dir.path().join("MANIFEST");
+    ManifestFile::write_new(&path, "bytewise", &[vec![2], vec![3]]).unwrap();
+    let (manifest, l0_ids) = ManifestFile::open(new_dir.path()).unwrap();
+    assert_eq!(manifest.comparator().as_deref(), Some("bytewise"));
+    assert_eq!(l0_ids, vec![2]);
+}
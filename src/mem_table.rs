@@ -1,53 +1,91 @@
+use std::cmp::Ordering as CmpOrdering;
 use std::collections::VecDeque;
 use std::fs;
 use std::ops::Bound;
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use anyhow::{Ok, Result};
 use bytes::Bytes;
 use crossbeam::skiplist as crossbeam_skiplist;
-use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
+use parking_lot::RwLock;
 
 use crate::iterators::StorageIterator;
-use crate::opt::LsmOptions;
+use crate::merge::{append_operands, encode_operand, full_merge, MergeChain, Record};
+use crate::opt::{BytewiseComparator, Comparator, LsmOptions, MergeOperator, WalSync};
+use crate::range_tombstone::RangeTombstone;
 use crate::table::SsTableBuilder;
-use crate::util::{memtable_file_path, MEMTABLE_FILE_EXT};
+use crate::util::is_expired;
 use crate::wal::Wal;
 
 pub struct MemTables {
     pub memtable: Arc<MemTable>,
     pub imm_memtables: VecDeque<Arc<MemTable>>,
     pub next_mem_id: usize,
+    // directory of the WALs
+    dir: PathBuf,
     opt: Arc<LsmOptions>,
 }
 
 impl MemTables {
     pub fn new(opt: Arc<LsmOptions>) -> Result<Self> {
-        let (imm_memtables, next_mem_id) = Self::open_mem_tables(&opt)?;
+        Self::open_in(opt.dir.clone(), opt)
+    }
+
+    /// Open memtables whose WALs are in `dir` instead of the directory of the storage.
+    pub fn open_in(dir: PathBuf, opt: Arc<LsmOptions>) -> Result<Self> {
+        let layout = opt.path_layout.as_ref();
+        let wal_dir = layout.memtable_path(&dir, 0);
+        let wal_dir = wal_dir.parent().unwrap_or(&dir);
+        if !opt.read_only {
+            fs::create_dir_all(wal_dir)?;
+        }
+        let (imm_memtables, next_mem_id) = Self::open_mem_tables(&dir, wal_dir, &opt)?;
+        if opt.read_only {
+            // keep the WALs, and there is no WAL for the mutable memtable
+            for memtable in &imm_memtables {
+                memtable.save_wal();
+            }
+            return Ok(MemTables {
+                memtable: Arc::new(MemTable::create_read_only()),
+                imm_memtables,
+                next_mem_id,
+                dir,
+                opt,
+            });
+        }
 
         Ok(MemTables {
-            memtable: Arc::new(MemTable::create(&opt.dir, next_mem_id)?),
+            memtable: Arc::new(MemTable::create(
+                layout.memtable_path(&dir, next_mem_id),
+                opt.wal_sync,
+                Self::new_index(&opt)?,
+            )?),
             imm_memtables,
             next_mem_id: next_mem_id + 1,
+            dir,
             opt,
         })
     }
 
-    fn open_mem_tables(opts: &LsmOptions) -> Result<(VecDeque<Arc<MemTable>>, usize)> {
+    /// Replay the WALs of `dir` found in `wal_dir`.
+    fn open_mem_tables(
+        dir: &Path,
+        wal_dir: &Path,
+        opt: &LsmOptions,
+    ) -> Result<(VecDeque<Arc<MemTable>>, usize)> {
+        let layout = opt.path_layout.as_ref();
         let mut fids = vec![];
-        let mut mts = VecDeque::new();
+        let mut mts: VecDeque<Arc<MemTable>> = VecDeque::new();
 
-        for file in fs::read_dir(&opts.dir)? {
+        for file in fs::read_dir(wal_dir)? {
             let file = file?;
             let filename_ = file.file_name();
             let filename = filename_.to_string_lossy();
-            if filename.ends_with(MEMTABLE_FILE_EXT) {
-                let end = filename.len() - MEMTABLE_FILE_EXT.len();
-                let fid: usize = filename[end - 5..end].parse().unwrap();
+            if let Some(fid) = layout.parse_memtable_id(&filename) {
                 fids.push(fid);
             }
         }
@@ -55,7 +93,15 @@ impl MemTables {
         fids.sort_unstable();
 
         for fid in &fids {
-            let memtable = MemTable::open(&opts.dir, *fid)?;
+            let path = layout.memtable_path(dir, *fid);
+            let operator = opt.merge_operator.as_ref();
+            let index = Self::new_index(opt)?;
+            let memtable = MemTable::open(path, operator, index).inspect_err(|_| {
+                // keep the WALs replayed so far
+                for memtable in &mts {
+                    memtable.save_wal();
+                }
+            })?;
             mts.push_back(Arc::new(memtable));
         }
 
@@ -70,6 +116,22 @@ impl MemTables {
         Ok((mts, next_mem_fid))
     }
 
+    /// Check if a recovered immutable memtable is bigger than `memtable_size`.
+    pub fn imm_oversized(&self) -> bool {
+        self.imm_memtables
+            .iter()
+            .any(|table| table.size() > self.opt.memtable_size)
+    }
+
+    /// Get the total size of the mutable and immutable memtables.
+    pub fn total_size(&self) -> usize {
+        self.imm_memtables
+            .iter()
+            .map(|table| table.size())
+            .sum::<usize>()
+            + self.memtable.size()
+    }
+
     /// Get current sorted MemTables
     pub fn view(&self) -> Vec<Arc<MemTable>> {
         let mut view = Vec::with_capacity(self.imm_memtables.len() + 1);
@@ -80,9 +142,39 @@ impl MemTables {
         view
     }
 
+    /// Create an empty index for a memtable, check that it's ordered by the comparator.
+    fn new_index(opt: &LsmOptions) -> Result<Arc<dyn MemTableIndex>> {
+        let index = opt.new_memtable_index();
+        let (name, expected) = (index.comparator().name(), opt.comparator.name());
+        if name != expected {
+            return Err(anyhow::anyhow!(
+                "memtable index is ordered by comparator {name:?}, but the comparator is {expected:?}"
+            ));
+        }
+        Ok(index)
+    }
+
+    /// Get the memtables like `view`, but the mutable memtable is a snapshot of it, so later
+    /// writes are not seen.
+    pub fn snapshot(&self) -> Result<Vec<Arc<MemTable>>> {
+        let mut memtables = self.imm_memtables.iter().cloned().collect::<Vec<_>>();
+        if self.memtable.size() > 0 {
+            memtables.push(Arc::new(self.memtable.snapshot()?));
+        }
+        Ok(memtables)
+    }
+
     /// Push old mutable memtable to immutable mmtables, and create a mutable memtable
     pub fn use_new_table(&mut self) -> Result<()> {
-        let table = Arc::new(MemTable::create(&self.opt.dir, self.next_mem_id)?);
+        if self.opt.read_only {
+            return Err(anyhow::anyhow!("memtables are read-only"));
+        }
+        let path = self
+            .opt
+            .path_layout
+            .memtable_path(&self.dir, self.next_mem_id);
+        let index = Self::new_index(&self.opt)?;
+        let table = Arc::new(MemTable::create(path, self.opt.wal_sync, index)?);
         self.next_mem_id += 1;
         let memtable = std::mem::replace(&mut self.memtable, table);
         self.imm_memtables.push_back(memtable);
@@ -94,109 +186,487 @@ impl MemTables {
         self.memtable.put(key, value)
     }
 
-    pub fn put_entries(&self, entries: &[(Bytes, Bytes)]) -> Result<()> {
+    /// Put a key-value pair which expires at `expire_at` into the mutable mem-table.
+    pub fn put_with_expiry(&self, key: &[u8], value: &[u8], expire_at: u64) -> Result<()> {
+        self.memtable.put_with_expiry(key, value, expire_at)
+    }
+
+    /// Put a tombstone of `key` into the mutable mem-table.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.memtable.delete(key)
+    }
+
+    /// Put entries into the mutable mem-table, a None value is a tombstone.
+    pub fn put_entries(&self, entries: &[(Bytes, Option<Bytes>)]) -> Result<()> {
         self.memtable.put_entries(entries)
     }
+
+    /// Delete keys in the range from the mutable mem-table and older tables.
+    pub fn delete_range(&self, tombstone: RangeTombstone) -> Result<()> {
+        self.memtable.delete_range(tombstone)
+    }
+
+    /// Merge `operand` into the value of `key` in the mutable mem-table.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let operator = self
+            .opt
+            .merge_operator
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("merge operator is not set"))?;
+        self.memtable.merge(key, operand, operator)
+    }
+}
+
+/// Push the records of `key` in `memtables` (older first) to `chain` from the newest memtable,
+/// return true if it's complete.
+pub(crate) fn get_in_memtables(
+    memtables: &[Arc<MemTable>],
+    key: &[u8],
+    chain: &mut MergeChain,
+) -> bool {
+    memtables
+        .iter()
+        .rev()
+        .filter_map(|table| table.get(key))
+        .any(|record| chain.push(record))
+}
+
+/// An ordered map from keys to their newest records, which indexes the entries of a mem-table.
+/// `SkipMap` is the default, see `LsmOptions::memtable_index`.
+///
+/// It's read and written concurrently, and inserted into while a range of it is iterated.
+pub trait MemTableIndex: Send + Sync {
+    /// Insert `value` of `key` unless the index has a newer version of the key, see
+    /// [`Value::version`]. A value of the same version is replaced, so the last write wins.
+    /// Return false if it's not inserted.
+    fn insert(&self, key: Bytes, value: Value) -> bool;
+
+    fn get(&self, key: &[u8]) -> Option<Value>;
+
+    /// Iterate over the records of a range of keys in order. It may not observe inserts after it's
+    /// created.
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_>;
+
+    /// Get the order of the keys, which must be `LsmOptions::comparator`.
+    fn comparator(&self) -> &dyn Comparator;
+}
+
+pub type MemTableRange<'a> = Box<dyn DoubleEndedIterator<Item = (Bytes, Value)> + Send + 'a>;
+
+impl MemTableIndex for SkipMap<Bytes, Value> {
+    fn insert(&self, key: Bytes, value: Value) -> bool {
+        let version = value.version;
+        let entry = self.compare_insert(key, value, |x| x.version <= version);
+        entry.value().version == version
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        SkipMap::get(self, key).map(|entry| entry.value().clone())
+    }
+
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_> {
+        let range = SkipMap::range(self, (lower, upper));
+        Box::new(range.map(|entry| (entry.key().clone(), entry.value().clone())))
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        &BytewiseComparator
+    }
+}
+
+/// A key ordered by a comparator.
+#[derive(Clone, Debug)]
+struct OrderedKey {
+    key: Bytes,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
 }
 
-/// A basic mem-table based on crossbeam-skiplist
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
+
+/// A `SkipMap` ordered by a comparator other than the bytewise one.
+pub struct ComparatorIndex {
+    map: SkipMap<OrderedKey, Value>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl ComparatorIndex {
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self {
+            map: SkipMap::new(),
+            comparator,
+        }
+    }
+
+    fn ordered(&self, key: Bytes) -> OrderedKey {
+        OrderedKey {
+            key,
+            comparator: self.comparator.clone(),
+        }
+    }
+}
+
+impl MemTableIndex for ComparatorIndex {
+    fn insert(&self, key: Bytes, value: Value) -> bool {
+        let version = value.version;
+        let entry = self
+            .map
+            .compare_insert(self.ordered(key), value, |x| x.version <= version);
+        entry.value().version == version
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        let key = self.ordered(Bytes::copy_from_slice(key));
+        self.map.get(&key).map(|entry| entry.value().clone())
+    }
+
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_> {
+        let range = (
+            lower.map(|key| self.ordered(key)),
+            upper.map(|key| self.ordered(key)),
+        );
+        let range = self.map.range(range);
+        Box::new(range.map(|entry| (entry.key().key.clone(), entry.value().clone())))
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.comparator.as_ref()
+    }
+}
+
+/// A basic mem-table based on crossbeam-skiplist, or another `MemTableIndex`.
 pub struct MemTable {
-    map: Arc<SkipMap<Bytes, Value>>,
+    map: Arc<dyn MemTableIndex>,
     size: AtomicUsize,
-    wal: Wal,
+    // None if the mem-table is read-only
+    wal: Option<Wal>,
+    /// Range tombstones with their versions, keys of older versions are deleted.
+    range_tombstones: RwLock<Vec<(RangeTombstone, u64)>>,
+    /// Held for reading by writes and for writing by merges, so nothing is written between a
+    /// merge reading the value of its key and replacing it.
+    write_lock: RwLock<()>,
+    /// Values replaced while snapshots of the mem-table are alive.
+    history: Arc<History>,
 }
 
 impl MemTable {
-    /// Create a new mem-table.
-    pub fn create(path: impl AsRef<Path>, id: usize) -> Result<Self> {
+    /// Create a new mem-table indexed by `index`, with a WAL at `path` which is synced according
+    /// to `sync`.
+    pub fn create(
+        path: impl AsRef<Path>,
+        sync: WalSync,
+        index: Arc<dyn MemTableIndex>,
+    ) -> Result<Self> {
         Ok(Self {
-            map: Arc::new(SkipMap::new()),
-            wal: Wal::create(memtable_file_path(path, id))?,
+            map: index,
+            wal: Some(Wal::create(path, sync)?),
             size: AtomicUsize::new(0),
+            range_tombstones: RwLock::new(vec![]),
+            write_lock: RwLock::new(()),
+            history: Arc::default(),
         })
     }
 
-    pub fn open(path: impl AsRef<Path>, id: usize) -> Result<Self> {
-        let wal = Wal::open(memtable_file_path(path, id))?;
+    /// Create an empty mem-table without a WAL, writes to it return an error.
+    pub fn create_read_only() -> Self {
+        Self {
+            map: Arc::new(SkipMap::new()),
+            wal: None,
+            size: AtomicUsize::new(0),
+            range_tombstones: RwLock::new(vec![]),
+            write_lock: RwLock::new(()),
+            history: Arc::default(),
+        }
+    }
+
+    /// Open a mem-table indexed by `index` by replaying its WAL at `path`, merges are replayed by
+    /// `operator`.
+    pub fn open(
+        path: impl AsRef<Path>,
+        operator: Option<&MergeOperator>,
+        index: Arc<dyn MemTableIndex>,
+    ) -> Result<Self> {
+        let wal = Wal::open(path)?;
         let mut iter = wal.iter()?;
-        let map = SkipMap::new();
-        let mut size = 0;
+        let table = Self {
+            map: index,
+            wal: Some(wal),
+            size: AtomicUsize::new(0),
+            range_tombstones: RwLock::new(vec![]),
+            write_lock: RwLock::new(()),
+            history: Arc::default(),
+        };
 
         while iter.is_valid() {
-            let key = Bytes::copy_from_slice(iter.key());
-            let value = Bytes::copy_from_slice(iter.value());
-            size += key.len() + value.len();
-            map.insert(
-                key,
-                Value {
-                    val: value,
-                    version: 0,
-                },
-            );
+            match iter.range_tombstone() {
+                Some(tombstone) => table.do_delete_range(tombstone.clone(), iter.version()),
+                None if iter.is_tombstone() => {
+                    table.do_mem_put(iter.key(), None, None, iter.version())
+                }
+                None if iter.is_merge() => {
+                    let key = iter.key();
+                    if let Err(e) = table.do_mem_merge(key, iter.value(), iter.version(), operator)
+                    {
+                        // keep the WAL to replay it again
+                        table.save_wal();
+                        return Err(e);
+                    }
+                }
+                None => table.do_mem_put(
+                    iter.key(),
+                    Some(iter.value()),
+                    iter.expire_at(),
+                    iter.version(),
+                ),
+            }
             iter.next();
         }
-        Ok(Self {
-            map: map.into(),
-            wal,
-            size: AtomicUsize::new(size),
-        })
+        Ok(table)
     }
 
     pub fn size(&self) -> usize {
         self.size.load(Ordering::Relaxed)
     }
 
-    /// Get a value by key.
-    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.map.get(key).map(|entry| entry.value().val.clone())
+    /// Get the record of a key. `Record::Deleted` is returned if the key is deleted, including by
+    /// a range tombstone or by expiry, so that older tables are not searched.
+    pub fn get(&self, key: &[u8]) -> Option<Record> {
+        match self.map.get(key) {
+            Some(value) => Some(value.record()),
+            None if self.range_deleted(key) => Some(Record::Deleted),
+            None => None,
+        }
+    }
+
+    /// Check if `key` is deleted by a range tombstone of the mem-table.
+    pub fn range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones
+            .read()
+            .iter()
+            .any(|(tombstone, _)| tombstone.covers(key))
+    }
+
+    /// Get the range tombstones of the mem-table.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones
+            .read()
+            .iter()
+            .map(|(tombstone, _)| tombstone.clone())
+            .collect()
+    }
+
+    /// Create a read-only view of the mem-table at the version of its last write, which doesn't
+    /// see later writes. Nothing is copied, values replaced while the view is alive are kept in
+    /// the history of the mem-table, until the mem-table is dropped.
+    pub fn snapshot(&self) -> Result<Self> {
+        // a batch of entries is either seen as a whole or not at all, and every version up to
+        // the last one is in the index
+        let _guard = self.write_lock.write();
+        let version = self.wal()?.last_version()?;
+        self.history.version.fetch_max(version, Ordering::SeqCst);
+        self.history.snapshots.fetch_add(1, Ordering::SeqCst);
+        let index = SnapshotIndex {
+            index: self.map.clone(),
+            history: self.history.clone(),
+            version,
+        };
+        Ok(Self {
+            map: Arc::new(index),
+            wal: None,
+            size: AtomicUsize::new(self.size()),
+            range_tombstones: RwLock::new(self.range_tombstones.read().clone()),
+            write_lock: RwLock::new(()),
+            history: Arc::default(),
+        })
+    }
+
+    /// Keep the WAL file after the mem-table is dropped, so it can be replayed.
+    pub fn save_wal(&self) {
+        if let Some(wal) = &self.wal {
+            wal.save_file();
+        }
+    }
+
+    fn wal(&self) -> Result<&Wal> {
+        self.wal
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("mem-table is read-only"))
     }
 
     /// Put a key-value pair into the mem-table.
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        let version = self.wal.add(key, value)?;
-        self.do_mem_put(key, value, version);
+        let _guard = self.write_lock.read();
+        let version = self.wal()?.add(key, value)?;
+        self.do_mem_put(key, Some(value), None, version);
+        Ok(())
+    }
+
+    /// Put a key-value pair which expires at `expire_at` into the mem-table.
+    fn put_with_expiry(&self, key: &[u8], value: &[u8], expire_at: u64) -> Result<()> {
+        let _guard = self.write_lock.read();
+        let version = self.wal()?.add_with_expiry(key, value, expire_at)?;
+        self.do_mem_put(key, Some(value), Some(expire_at), version);
+        Ok(())
+    }
+
+    /// Put a tombstone of `key` into the mem-table.
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let _guard = self.write_lock.read();
+        let version = self.wal()?.delete(key)?;
+        self.do_mem_put(key, None, None, version);
         Ok(())
     }
 
-    fn put_entries(&self, entries: &[(Bytes, Bytes)]) -> Result<()> {
-        let version = self.wal.add_entries(entries)?;
+    fn put_entries(&self, entries: &[(Bytes, Option<Bytes>)]) -> Result<()> {
+        let _guard = self.write_lock.read();
+        let version = self.wal()?.add_entries(entries)?;
         for (key, value) in entries {
-            self.do_mem_put(key, value, version);
+            self.do_mem_put(key, value.as_deref(), None, version);
         }
         Ok(())
     }
 
-    fn do_mem_put(&self, key: &[u8], value: &[u8], version: u64) {
-        let old_size = self
-            .map
-            .get(key)
-            .map(|entry| entry.key().len() + entry.value().val.len())
-            .unwrap_or(0);
+    /// Delete keys in the range, from this mem-table and older tables.
+    fn delete_range(&self, tombstone: RangeTombstone) -> Result<()> {
+        let _guard = self.write_lock.read();
+        let version = self.wal()?.add_range_tombstone(&tombstone)?;
+        self.do_delete_range(tombstone, version);
+        Ok(())
+    }
 
-        let val = Bytes::copy_from_slice(value);
-        let insert_version = self
-            .map
-            .compare_insert(Bytes::copy_from_slice(key), Value { val, version }, |x| {
-                x.version < version
-            })
-            .value()
-            .version;
+    /// Merge `operand` into the value of `key`, see [`MemTable::do_mem_merge`].
+    fn merge(&self, key: &[u8], operand: &[u8], operator: &MergeOperator) -> Result<()> {
+        let _guard = self.write_lock.write();
+        let operands = encode_operand(operand);
+        let version = self.wal()?.merge(key, &operands)?;
+        self.do_mem_merge(key, &operands, version, Some(operator))
+    }
+
+    /// Merge encoded `operands` into the record of `key`. They are combined with a value or a
+    /// tombstone of the key in the mem-table right away, otherwise they are appended to the merge
+    /// record of the key, which is combined with older tables when it's read or compacted.
+    fn do_mem_merge(
+        &self,
+        key: &[u8],
+        operands: &[u8],
+        version: u64,
+        operator: Option<&MergeOperator>,
+    ) -> Result<()> {
+        let base = match self.get(key) {
+            Some(Record::Value(value)) => Some(value),
+            Some(Record::Deleted) => None,
+            Some(Record::Merge(older)) => {
+                let operands = append_operands(&older, operands);
+                self.do_mem_put_inner(key, Value::merge(operands, version));
+                return Ok(());
+            }
+            None => {
+                let operands = Bytes::copy_from_slice(operands);
+                self.do_mem_put_inner(key, Value::merge(operands, version));
+                return Ok(());
+            }
+        };
+        let value = full_merge(operator, key, base.as_deref(), operands)?;
+        self.do_mem_put_inner(
+            key,
+            Value {
+                val: value,
+                expire_at: None,
+                merge: false,
+                version,
+            },
+        );
+        Ok(())
+    }
 
-        if version != insert_version {
+    fn do_delete_range(&self, tombstone: RangeTombstone, version: u64) {
+        // puts of older versions which are not inserted yet wait for the lock
+        let mut guard = self.range_tombstones.write();
+        let range = (
+            Bound::Included(tombstone.start.clone()),
+            Bound::Included(tombstone.end.clone()),
+        );
+        for (key, _) in self.map.range(range.0, range.1) {
+            self.do_mem_put_inner(&key, Value::tombstone(version));
+        }
+        self.size.fetch_add(
+            tombstone.start.len() + tombstone.end.len(),
+            Ordering::Relaxed,
+        );
+        guard.push((tombstone, version));
+    }
+
+    /// Put `value` of `key`, a None value is a tombstone.
+    fn do_mem_put(&self, key: &[u8], value: Option<&[u8]>, expire_at: Option<u64>, version: u64) {
+        let guard = self.range_tombstones.read();
+        let deleted = guard
+            .iter()
+            .any(|(tombstone, v)| *v > version && tombstone.covers(key));
+        let value = match deleted {
+            true => Value::tombstone(version),
+            false => Value {
+                val: value.map(Bytes::copy_from_slice),
+                expire_at,
+                merge: false,
+                version,
+            },
+        };
+        self.do_mem_put_inner(key, value);
+    }
+
+    fn do_mem_put_inner(&self, key: &[u8], value: Value) {
+        let old = self.map.get(key);
+        let old_size = old
+            .as_ref()
+            .map(|value| key.len() + value.len())
+            .unwrap_or(0);
+        let key = Bytes::copy_from_slice(key);
+        // kept before it's replaced, so a snapshot never misses it
+        if let Some(old) = old {
+            self.history.keep(&key, old);
+        }
+
+        let new_size = key.len() + value.len();
+        if !self.map.insert(key, value) {
             return;
         }
 
-        if key.len() + value.len() >= old_size {
-            let add = key.len() + value.len() - old_size;
-            self.size.fetch_add(add, Ordering::Relaxed);
+        if new_size >= old_size {
+            self.size.fetch_add(new_size - old_size, Ordering::Relaxed);
         } else {
-            let sub = old_size - key.len() + value.len();
-            self.size.fetch_sub(sub, Ordering::Relaxed);
+            self.size.fetch_sub(old_size - new_size, Ordering::Relaxed);
         }
     }
 
     /// Get an iterator over a range of keys.
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
+        self.scan_inner(lower, upper, false)
+    }
+
+    /// Get an iterator over a range of keys, which starts from the last key and moves backward
+    /// by `prev`.
+    pub fn scan_rev(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
+        self.scan_inner(lower, upper, true)
+    }
+
+    fn scan_inner(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, rev: bool) -> MemTableIterator {
         fn bound_u8_to_bytes(bound: Bound<&[u8]>) -> Bound<Bytes> {
             match bound {
                 Bound::Excluded(data) => Bound::Excluded(Bytes::copy_from_slice(data)),
@@ -206,68 +676,261 @@ impl MemTable {
         }
 
         let (lower, upper) = (bound_u8_to_bytes(lower), bound_u8_to_bytes(upper));
-
-        let mut iter = MemTableIteratorBuilder {
-            map: self.map.clone(),
-            item: (Bytes::new(), Bytes::new()),
-            iter_builder: |map| map.range((lower, upper)),
-        }
-        .build();
-
-        iter.with_mut(|x| *x.item = entry_to_item(x.iter.next()));
-        iter
+        MemTableIterator::create(self.map.clone(), lower, upper, rev)
     }
 
     /// Flush the mem-table to SSTable.
     pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
-        for entry in self.map.iter() {
-            builder.add(entry.key(), &entry.value().val)?;
+        for (key, value) in self.map.range(Bound::Unbounded, Bound::Unbounded) {
+            match (&value.val, value.expire_at) {
+                (Some(operands), _) if value.merge => builder.add_merge(&key, operands)?,
+                (Some(val), Some(expire_at)) => builder.add_with_expiry(&key, val, expire_at)?,
+                (Some(val), None) => builder.add(&key, val)?,
+                (None, _) => builder.add_tombstone(&key)?,
+            }
+        }
+        for tombstone in self.range_tombstones() {
+            builder.add_range_tombstone(tombstone);
         }
         Ok(())
     }
 }
 
-struct Value {
-    val: Bytes,
+/// Values of a mem-table replaced while snapshots of it are alive, see [`MemTable::snapshot`].
+#[derive(Default)]
+struct History {
+    /// The newest version seen by a snapshot.
+    version: AtomicU64,
+    /// Number of snapshots alive.
+    snapshots: AtomicUsize,
+    /// (key, version) -> the value replaced.
+    values: SkipMap<(Bytes, u64), Value>,
+}
+
+impl History {
+    /// Keep `old`, which is about to be replaced, if a snapshot may see it.
+    fn keep(&self, key: &Bytes, old: Value) {
+        if self.snapshots.load(Ordering::SeqCst) > 0
+            && old.version <= self.version.load(Ordering::SeqCst)
+        {
+            self.values.insert((key.clone(), old.version), old);
+        }
+    }
+
+    /// Get the newest value of `key` kept at or before `version`.
+    fn get(&self, key: &[u8], version: u64) -> Option<Value> {
+        let key = Bytes::copy_from_slice(key);
+        self.values
+            .range((key.clone(), 0)..=(key, version))
+            .next_back()
+            .map(|entry| entry.value().clone())
+    }
+}
+
+/// The index of a mem-table seen by a snapshot, values of newer versions are replaced by the ones
+/// kept in the history, or hidden if the key is written after the snapshot.
+struct SnapshotIndex {
+    index: Arc<dyn MemTableIndex>,
+    history: Arc<History>,
+    version: u64,
+}
+
+impl SnapshotIndex {
+    fn visible(&self, key: &[u8], value: Value) -> Option<Value> {
+        match value.version <= self.version {
+            true => Some(value),
+            false => self.history.get(key, self.version),
+        }
+    }
+}
+
+impl MemTableIndex for SnapshotIndex {
+    fn insert(&self, _key: Bytes, _value: Value) -> bool {
+        false
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        self.visible(key, self.index.get(key)?)
+    }
+
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_> {
+        let range = self.index.range(lower, upper);
+        Box::new(range.filter_map(|(key, value)| {
+            let value = self.visible(&key, value)?;
+            Some((key, value))
+        }))
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.index.comparator()
+    }
+}
+
+impl Drop for SnapshotIndex {
+    fn drop(&mut self) {
+        self.history.snapshots.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A record of a key in a mem-table.
+#[derive(Clone)]
+pub struct Value {
+    /// None is a tombstone.
+    val: Option<Bytes>,
+    /// Expiry time in milliseconds since the UNIX epoch, None if it never expires.
+    expire_at: Option<u64>,
+    /// Set if `val` is a list of merge operands.
+    merge: bool,
     version: u64,
 }
 
-type SkipMapRangeIter<'a> =
-    crossbeam_skiplist::map::Range<'a, Bytes, (Bound<Bytes>, Bound<Bytes>), Bytes, Value>;
+impl Value {
+    fn tombstone(version: u64) -> Self {
+        Value {
+            val: None,
+            expire_at: None,
+            merge: false,
+            version,
+        }
+    }
+
+    fn merge(operands: Bytes, version: u64) -> Self {
+        Value {
+            val: Some(operands),
+            expire_at: None,
+            merge: true,
+            version,
+        }
+    }
+
+    fn record(&self) -> Record {
+        match &self.val {
+            _ if self.is_expired() => Record::Deleted,
+            None => Record::Deleted,
+            Some(operands) if self.merge => Record::Merge(operands.clone()),
+            Some(value) => Record::Value(value.clone()),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(is_expired)
+    }
+
+    /// Get the version of the record, records of bigger versions are newer.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn len(&self) -> usize {
+        self.val.as_ref().map_or(0, |val| val.len())
+    }
+}
 
-/// An iterator over a range of `SkipMap`.
+/// An iterator over a range of a `MemTableIndex`.
 #[self_referencing]
 pub struct MemTableIterator {
-    map: Arc<SkipMap<Bytes, Value>>,
+    map: Arc<dyn MemTableIndex>,
     #[borrows(map)]
     #[not_covariant]
-    iter: SkipMapRangeIter<'this>,
-    item: (Bytes, Bytes),
+    iter: MemTableRange<'this>,
+    // key, value, expiry time and whether the value is a list of merge operands
+    item: (Bytes, Option<Bytes>, Option<u64>, bool),
+    upper: Bound<Bytes>,
+    /// Whether the iterator is created by `scan_rev`.
+    rev: bool,
 }
 
-fn entry_to_item(entry: Option<Entry<Bytes, Value>>) -> (Bytes, Bytes) {
+impl MemTableIterator {
+    fn create(
+        map: Arc<dyn MemTableIndex>,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        rev: bool,
+    ) -> Self {
+        let mut iter = MemTableIteratorBuilder {
+            map,
+            item: (Bytes::new(), None, None, false),
+            upper: upper.clone(),
+            rev,
+            iter_builder: |map| map.range(lower, upper),
+        }
+        .build();
+
+        iter.with_mut(|x| match rev {
+            true => *x.item = entry_to_item(x.iter.next_back()),
+            false => *x.item = entry_to_item(x.iter.next()),
+        });
+        iter
+    }
+}
+
+fn entry_to_item(entry: Option<(Bytes, Value)>) -> (Bytes, Option<Bytes>, Option<u64>, bool) {
     entry
-        .map(|x| (x.key().clone(), x.value().val.clone()))
-        .unwrap_or((Bytes::new(), Bytes::new()))
+        .map(|(key, value)| (key, value.val, value.expire_at, value.merge))
+        .unwrap_or((Bytes::new(), None, None, false))
 }
 
 impl StorageIterator for MemTableIterator {
     fn value(&self) -> &[u8] {
-        &self.borrow_item().1
+        self.borrow_item().1.as_deref().unwrap_or_default()
+    }
+
+    fn is_tombstone(&self) -> bool {
+        let (_, value, expire_at, _) = self.borrow_item();
+        value.is_none() || expire_at.is_some_and(is_expired)
+    }
+
+    fn is_merge(&self) -> bool {
+        self.borrow_item().3
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        self.borrow_item().2
     }
 
     fn key(&self) -> &[u8] {
         &self.borrow_item().0
     }
 
+    fn comparator(&self) -> &dyn Comparator {
+        self.borrow_map().comparator()
+    }
+
     fn is_valid(&self) -> bool {
         !self.borrow_item().0.is_empty()
     }
 
     fn next(&mut self) -> Result<()> {
+        if *self.borrow_rev() {
+            return Err(anyhow::anyhow!(
+                "next is unsupported by a reverse memtable iterator"
+            ));
+        }
         self.with_mut(|x| *x.item = entry_to_item(x.iter.next()));
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if !*self.borrow_rev() {
+            return Err(anyhow::anyhow!(
+                "prev is unsupported by a forward memtable iterator"
+            ));
+        }
+        self.with_mut(|x| *x.item = entry_to_item(x.iter.next_back()));
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if *self.borrow_rev() {
+            return Err(anyhow::anyhow!(
+                "seek is unsupported by a reverse memtable iterator"
+            ));
+        }
+        let lower = Bound::Included(Bytes::copy_from_slice(key));
+        let upper = self.borrow_upper().clone();
+        *self = Self::create(self.borrow_map().clone(), lower, upper, false);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
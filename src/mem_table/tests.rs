@@ -1,60 +1,180 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
 use bytes::Bytes;
+use crossbeam::skiplist::SkipMap;
+use parking_lot::RwLock;
 use tempfile::{tempdir, TempDir};
 
-use super::MemTable;
+use super::{ComparatorIndex, MemTable, MemTableIndex, MemTableRange, MemTables, Value};
 
 use crate::iterators::StorageIterator;
-use crate::opt::LsmOptions;
+use crate::merge::{append_operands, encode_operand, Record};
+use crate::opt::{BytewiseComparator, Comparator, LsmOptions, MergeOperator, WalSync};
+use crate::range_tombstone::RangeTombstone;
 use crate::table::{SsTableBuilder, SsTableIterator};
+use crate::util::memtable_file_path;
+
+/// Creates the index of the mem-tables of a test.
+type NewIndex = fn() -> Arc<dyn MemTableIndex>;
+
+/// A `BTreeMap` behind a lock, whose ranges are collected before they're iterated.
+#[derive(Default)]
+struct BTreeIndex(RwLock<BTreeMap<Bytes, Value>>);
+
+impl MemTableIndex for BTreeIndex {
+    fn insert(&self, key: Bytes, value: Value) -> bool {
+        let mut map = self.0.write();
+        if map
+            .get(&key)
+            .is_some_and(|old| old.version() > value.version())
+        {
+            return false;
+        }
+        map.insert(key, value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        self.0.read().get(key).cloned()
+    }
+
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_> {
+        let map = self.0.read();
+        let entries: Vec<_> = map
+            .range((lower, upper))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        &BytewiseComparator
+    }
+}
 
-fn create_for_test() -> (TempDir, MemTable) {
+/// Run each test against the default skiplist, `BTreeIndex` and `ComparatorIndex`.
+macro_rules! index_tests {
+    ($($test:ident),* $(,)?) => {
+        mod skiplist {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(|| std::sync::Arc::new(super::SkipMap::new()));
+                }
+            )*
+        }
+
+        mod btree {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(|| std::sync::Arc::new(super::BTreeIndex::default()));
+                }
+            )*
+        }
+
+        mod comparator {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(|| {
+                        let comparator = std::sync::Arc::new(super::BytewiseComparator);
+                        std::sync::Arc::new(super::ComparatorIndex::new(comparator))
+                    });
+                }
+            )*
+        }
+    };
+}
+
+index_tests!(
+    test_memtable_get,
+    test_memtable_get2,
+    test_memtable_overwrite,
+    test_memtable_overwrite_shorter_size,
+    test_memtable_flush,
+    test_memtable_iter,
+    test_memtable_iter_rev,
+    test_memtable_replay,
+    test_memtable_merge_replay,
+    test_memtable_replay_torn_write,
+    test_memtable_replay_latest_wins,
+    test_memtables_open_oversized,
+    test_memtable_index_last_write_wins,
+    test_memtable_snapshot,
+);
+
+fn create_for_test(new_index: NewIndex) -> (TempDir, MemTable) {
     let dir = TempDir::new().unwrap();
-    let path = dir.path().to_path_buf();
-    (dir, MemTable::create(path, 0).unwrap())
+    let path = memtable_file_path(dir.path(), 0);
+    (
+        dir,
+        MemTable::create(path, WalSync::Always, new_index()).unwrap(),
+    )
 }
 
-#[test]
-fn test_memtable_get() {
-    let (_dir, memtable) = create_for_test();
+fn test_memtable_get(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     memtable.put(b"key1", b"value1").unwrap();
     memtable.put(b"key2", b"value2").unwrap();
     memtable.put(b"key3", b"value3").unwrap();
-    assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value1");
-    assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value2");
-    assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value3");
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value1".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("value2".into())));
+    assert_eq!(memtable.get(b"key3"), Some(Record::Value("value3".into())));
 }
 
-#[test]
-fn test_memtable_get2() {
-    let (_dir, memtable) = create_for_test();
+fn test_memtable_get2(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     let input = vec![
-        (Bytes::from_static(b"key1"), Bytes::from_static(b"value1")),
-        (Bytes::from_static(b"key2"), Bytes::from_static(b"value2")),
-        (Bytes::from_static(b"key3"), Bytes::from_static(b"value3")),
+        (
+            Bytes::from_static(b"key1"),
+            Some(Bytes::from_static(b"value1")),
+        ),
+        (
+            Bytes::from_static(b"key2"),
+            Some(Bytes::from_static(b"value2")),
+        ),
+        (
+            Bytes::from_static(b"key3"),
+            Some(Bytes::from_static(b"value3")),
+        ),
     ];
     memtable.put_entries(&input).unwrap();
-    assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value1");
-    assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value2");
-    assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value3");
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value1".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("value2".into())));
+    assert_eq!(memtable.get(b"key3"), Some(Record::Value("value3".into())));
 }
 
-#[test]
-fn test_memtable_overwrite() {
-    let (_dir, memtable) = create_for_test();
+fn test_memtable_overwrite(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     memtable.put(b"key1", b"value1").unwrap();
     memtable.put(b"key2", b"value2").unwrap();
     memtable.put(b"key3", b"value3").unwrap();
     memtable.put(b"key1", b"value11").unwrap();
     memtable.put(b"key2", b"value22").unwrap();
     memtable.put(b"key3", b"value33").unwrap();
-    assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value11");
-    assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value22");
-    assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value33");
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value11".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("value22".into())));
+    assert_eq!(memtable.get(b"key3"), Some(Record::Value("value33".into())));
+}
+
+fn test_memtable_overwrite_shorter_size(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
+    memtable.put(b"other", b"value").unwrap();
+    for len in (0..=10).rev() {
+        let value = vec![b'v'; len];
+        memtable.put(b"key1", &value).unwrap();
+        assert_eq!(
+            memtable.size(),
+            b"other".len() + b"value".len() + b"key1".len() + len
+        );
+    }
 }
 
-#[test]
-fn test_memtable_flush() {
-    let (_dir, memtable) = create_for_test();
+fn test_memtable_flush(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     memtable.put(b"key1", b"value1").unwrap();
     memtable.put(b"key2", b"value2").unwrap();
     memtable.put(b"key3", b"value3").unwrap();
@@ -75,10 +195,8 @@ fn test_memtable_flush() {
     assert!(!iter.is_valid());
 }
 
-#[test]
-fn test_memtable_iter() {
-    use std::ops::Bound;
-    let (_dir, memtable) = create_for_test();
+fn test_memtable_iter(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     memtable.put(b"key1", b"value1").unwrap();
     memtable.put(b"key2", b"value2").unwrap();
     memtable.put(b"key3", b"value3").unwrap();
@@ -117,17 +235,264 @@ fn test_memtable_iter() {
     }
 }
 
-#[test]
-fn test_memtable_replay() {
+fn test_memtable_iter_rev(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
+
+    {
+        let mut iter = memtable.scan_rev(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(iter.key(), b"key3");
+        assert_eq!(iter.value(), b"value3");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"key1");
+        assert_eq!(iter.value(), b"value1");
+        iter.prev().unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    {
+        let mut iter = memtable.scan_rev(Bound::Included(b"key1"), Bound::Included(b"key2"));
+        assert_eq!(iter.key(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), b"key1");
+        assert_eq!(iter.value(), b"value1");
+        iter.prev().unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    {
+        let mut iter = memtable.scan_rev(Bound::Excluded(b"key1"), Bound::Excluded(b"key3"));
+        assert_eq!(iter.key(), b"key2");
+        assert_eq!(iter.value(), b"value2");
+        iter.prev().unwrap();
+        assert!(!iter.is_valid());
+    }
+}
+
+fn test_memtable_replay(new_index: NewIndex) {
+    let dir = tempdir().unwrap();
+    let memtable = MemTable::create(
+        memtable_file_path(dir.path(), 1),
+        WalSync::Always,
+        new_index(),
+    )
+    .unwrap();
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
+    memtable.save_wal();
+    drop(memtable);
+    let memtable = MemTable::open(memtable_file_path(dir.path(), 1), None, new_index()).unwrap();
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value1".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("value2".into())));
+    assert_eq!(memtable.get(b"key3"), Some(Record::Value("value3".into())));
+}
+
+fn test_memtable_merge_replay(new_index: NewIndex) {
+    // concatenates the operands to the value
+    let operator = MergeOperator(Arc::new(|_, existing, operands| {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        Some(Bytes::from(value))
+    }));
+    let dir = tempdir().unwrap();
+    let memtable = MemTable::create(
+        memtable_file_path(dir.path(), 1),
+        WalSync::Always,
+        new_index(),
+    )
+    .unwrap();
+    memtable.merge(b"key1", b"a", &operator).unwrap();
+    memtable.merge(b"key1", b"b", &operator).unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.merge(b"key2", b"c", &operator).unwrap();
+    memtable.delete(b"key3").unwrap();
+    memtable.merge(b"key3", b"d", &operator).unwrap();
+    memtable.merge(b"key3", b"e", &operator).unwrap();
+
+    let expected = [
+        (
+            &b"key1"[..],
+            Record::Merge(append_operands(
+                &encode_operand(b"a"),
+                &encode_operand(b"b"),
+            )),
+        ),
+        (b"key2", Record::Value("value2c".into())),
+        (b"key3", Record::Value("de".into())),
+    ];
+    for (key, record) in &expected {
+        assert_eq!(memtable.get(key).as_ref(), Some(record));
+    }
+    let size = memtable.size();
+    memtable.save_wal();
+    drop(memtable);
+
+    assert!(MemTable::open(memtable_file_path(dir.path(), 1), None, new_index()).is_err());
+    let memtable = MemTable::open(
+        memtable_file_path(dir.path(), 1),
+        Some(&operator),
+        new_index(),
+    )
+    .unwrap();
+    for (key, record) in &expected {
+        assert_eq!(memtable.get(key).as_ref(), Some(record));
+    }
+    assert_eq!(memtable.size(), size);
+}
+
+fn test_memtable_replay_torn_write(new_index: NewIndex) {
+    use crate::util::memtable_file_path;
+    let dir = tempdir().unwrap();
+    let memtable = MemTable::create(
+        memtable_file_path(dir.path(), 1),
+        WalSync::Always,
+        new_index(),
+    )
+    .unwrap();
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key3", b"value3").unwrap();
+    memtable.save_wal();
+    drop(memtable);
+    let path = memtable_file_path(dir.path(), 1);
+    let size = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::File::options().write(true).open(&path).unwrap();
+    file.set_len(size - 3).unwrap();
+    drop(file);
+
+    let memtable = MemTable::open(memtable_file_path(dir.path(), 1), None, new_index()).unwrap();
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value1".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("value2".into())));
+    assert!(memtable.get(b"key3").is_none());
+}
+
+fn test_memtable_replay_latest_wins(new_index: NewIndex) {
     let dir = tempdir().unwrap();
-    let memtable = MemTable::create(dir.path(), 1).unwrap();
+    let memtable = MemTable::create(
+        memtable_file_path(dir.path(), 1),
+        WalSync::Always,
+        new_index(),
+    )
+    .unwrap();
+    memtable.put(b"key1", b"value1").unwrap();
+    memtable.put(b"key1", b"value11").unwrap();
+    memtable.put(b"key2", b"value2").unwrap();
+    memtable.put(b"key2", b"v").unwrap();
+    let size = memtable.size();
+    memtable.save_wal();
+    drop(memtable);
+    let memtable = MemTable::open(memtable_file_path(dir.path(), 1), None, new_index()).unwrap();
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("value11".into())));
+    assert_eq!(memtable.get(b"key2"), Some(Record::Value("v".into())));
+    assert_eq!(memtable.size(), size);
+}
+
+fn test_memtable_index_last_write_wins(new_index: NewIndex) {
+    let index = new_index();
+    let value = |val: &'static [u8], version| Value {
+        val: Some(Bytes::from_static(val)),
+        expire_at: None,
+        merge: false,
+        version,
+    };
+    let key = Bytes::from_static(b"key1");
+    assert!(index.insert(key.clone(), value(b"value1", 2)));
+    // the same version, e.g. a key written twice in a batch
+    assert!(index.insert(key.clone(), value(b"value11", 2)));
+    assert_eq!(
+        index.get(&key).unwrap().val.as_deref(),
+        Some(&b"value11"[..])
+    );
+    assert!(!index.insert(key.clone(), value(b"value0", 1)));
+    assert_eq!(
+        index.get(&key).unwrap().val.as_deref(),
+        Some(&b"value11"[..])
+    );
+}
+
+fn test_memtable_snapshot(new_index: NewIndex) {
+    let (_dir, memtable) = create_for_test(new_index);
     memtable.put(b"key1", b"value1").unwrap();
     memtable.put(b"key2", b"value2").unwrap();
     memtable.put(b"key3", b"value3").unwrap();
-    memtable.wal.save_file();
+    let snapshot = memtable.snapshot().unwrap();
+    memtable.put(b"key1", b"new").unwrap();
+    memtable.delete(b"key2").unwrap();
+    memtable.put(b"key4", b"value4").unwrap();
+    memtable
+        .delete_range(RangeTombstone::new(&b"key3"[..], &b"key3"[..]))
+        .unwrap();
+    let snapshot2 = memtable.snapshot().unwrap();
+    memtable.put(b"key1", b"newer").unwrap();
+    assert!(snapshot.put(b"key1", b"x").is_err());
+
+    // replaced values are kept, new keys are hidden
+    assert_eq!(snapshot.get(b"key1"), Some(Record::Value("value1".into())));
+    assert_eq!(snapshot.get(b"key2"), Some(Record::Value("value2".into())));
+    assert_eq!(snapshot.get(b"key3"), Some(Record::Value("value3".into())));
+    assert_eq!(snapshot.get(b"key4"), None);
+    let mut iter = snapshot.scan(Bound::Unbounded, Bound::Unbounded);
+    for (key, value) in [
+        (b"key1", b"value1"),
+        (b"key2", b"value2"),
+        (b"key3", b"value3"),
+    ] {
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.value(), value);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    drop(iter);
+
+    assert_eq!(snapshot2.get(b"key1"), Some(Record::Value("new".into())));
+    assert_eq!(snapshot2.get(b"key2"), Some(Record::Deleted));
+    assert_eq!(snapshot2.get(b"key3"), Some(Record::Deleted));
+    assert_eq!(snapshot2.get(b"key4"), Some(Record::Value("value4".into())));
+    assert_eq!(memtable.get(b"key1"), Some(Record::Value("newer".into())));
+
+    // values are not kept once no snapshot is alive
+    drop(snapshot);
+    drop(snapshot2);
+    let kept = memtable.history.values.len();
+    memtable.put(b"key4", b"newer").unwrap();
+    assert_eq!(memtable.history.values.len(), kept);
+}
+
+fn test_memtables_open_oversized(new_index: NewIndex) {
+    let dir = tempdir().unwrap();
+    let memtable = MemTable::create(
+        memtable_file_path(dir.path(), 1),
+        WalSync::Always,
+        new_index(),
+    )
+    .unwrap();
+    for i in 0..100 {
+        memtable
+            .put(format!("key{i}").as_bytes(), format!("value{i}").as_bytes())
+            .unwrap();
+    }
+    memtable.save_wal();
     drop(memtable);
-    let memtable = MemTable::open(dir.path(), 1).unwrap();
-    assert_eq!(&memtable.get(b"key1").unwrap()[..], b"value1");
-    assert_eq!(&memtable.get(b"key2").unwrap()[..], b"value2");
-    assert_eq!(&memtable.get(b"key3").unwrap()[..], b"value3");
+
+    let mut opts = LsmOptions::default()
+        .path(dir.path())
+        .memtable_index(new_index);
+    opts.memtable_size = 64;
+    let memtables = MemTables::new(opts.into()).unwrap();
+    assert_eq!(memtables.imm_memtables.len(), 1);
+    assert_eq!(memtables.memtable.size(), 0);
+    assert!(memtables.imm_oversized());
+    assert_eq!(
+        memtables.view()[0].get(b"key1"),
+        Some(Record::Value("value1".into()))
+    );
 }
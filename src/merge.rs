@@ -0,0 +1,120 @@
+//! Merge operands written by `LsmStorage::merge` are kept as merge records until they meet an
+//! older value of their key, then they are combined with it by the merge operator.
+//!
+//! The value of a merge record is a list of operands, older first:
+//! |len(u32)|operand|len(u32)|operand|...
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::opt::MergeOperator;
+
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// The newest record of a key in a memtable or a sstable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    Value(Bytes),
+    /// The key is deleted, by a tombstone, a range tombstone or expiry.
+    Deleted,
+    /// Encoded merge operands to combine with an older record of the key.
+    Merge(Bytes),
+}
+
+/// Encode a list of a single operand.
+pub fn encode_operand(operand: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(SIZEOF_U32 + operand.len());
+    buf.put_u32(operand.len() as u32);
+    buf.put(operand);
+    buf.freeze()
+}
+
+/// Concatenate encoded lists of operands, `older` goes first.
+pub fn append_operands(older: &[u8], newer: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(older.len() + newer.len());
+    buf.put(older);
+    buf.put(newer);
+    buf.freeze()
+}
+
+/// Decode a list of operands, a truncated operand at the end is ignored.
+pub fn decode_operands(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut operands = vec![];
+    while data.len() >= SIZEOF_U32 {
+        let len = data.get_u32() as usize;
+        if data.len() < len {
+            break;
+        }
+        let (operand, rest) = data.split_at(len);
+        operands.push(operand);
+        data = rest;
+    }
+    operands
+}
+
+/// Apply `operator` to the operands of `key` and its existing value.
+pub(crate) fn full_merge(
+    operator: Option<&MergeOperator>,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &[u8],
+) -> Result<Option<Bytes>> {
+    let operator = operator.ok_or_else(|| anyhow::anyhow!("merge operator is not set"))?;
+    Ok((operator.0)(key, existing, &decode_operands(operands)))
+}
+
+/// Records of a key collected from the newest table to older ones, until one which is not a
+/// merge record completes the chain.
+#[derive(Default)]
+pub(crate) struct MergeChain {
+    // encoded operands of the merge records, newer first
+    operands: Vec<Bytes>,
+    // the record completing the chain, None if the key is deleted
+    base: Option<Option<Bytes>>,
+}
+
+impl MergeChain {
+    /// Add the record of the next older table, return true if the chain is complete, so older
+    /// tables don't need to be searched.
+    pub(crate) fn push(&mut self, record: Record) -> bool {
+        match record {
+            Record::Value(value) => self.base = Some(Some(value)),
+            Record::Deleted => self.base = Some(None),
+            Record::Merge(operands) => self.operands.push(operands),
+        }
+        self.is_complete()
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// Get the value of `key`, merging the operands into the base record, or into nothing if the
+    /// chain ends without one.
+    pub(crate) fn resolve(
+        self,
+        key: &[u8],
+        operator: Option<&MergeOperator>,
+    ) -> Result<Option<Bytes>> {
+        if self.operands.is_empty() {
+            return Ok(self.base.flatten());
+        }
+        let operands = self.operands();
+        full_merge(operator, key, self.base.flatten().as_deref(), &operands)
+    }
+
+    /// Encode the operands of all merge records, older first.
+    pub(crate) fn operands(&self) -> Bytes {
+        match self.operands.as_slice() {
+            [operands] => operands.clone(),
+            operands => {
+                let len = operands.iter().map(|operands| operands.len()).sum();
+                let mut buf = BytesMut::with_capacity(len);
+                for operands in operands.iter().rev() {
+                    buf.put(&operands[..]);
+                }
+                buf.freeze()
+            }
+        }
+    }
+}
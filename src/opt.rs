@@ -1,31 +1,263 @@
 use anyhow::Result;
-use std::path::{Path, PathBuf};
+use bytes::Bytes;
+use crossbeam::skiplist::SkipMap;
+use std::{
+    cmp::Ordering,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{block::CompressOptions, lsm_storage::LsmStorage};
+use crate::{
+    block::CompressOptions,
+    checksum::ChecksumType,
+    lsm_storage::LsmStorage,
+    mem_table::{ComparatorIndex, MemTableIndex},
+    util::{
+        manifest_file_path, memtable_file_path, parse_memtable_id, parse_sstable_id,
+        sstable_file_path,
+    },
+};
 
+/// What to do with an entry during compaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Remove,
+    /// Replace the value.
+    Change(Bytes),
+}
+
+pub type CompactionFilterFn = dyn Fn(&[u8], &[u8]) -> FilterDecision + Send + Sync;
+
+/// A callback deciding whether to keep, remove or rewrite each entry during compaction.
+#[derive(Clone)]
+pub struct CompactionFilter(pub Arc<CompactionFilterFn>);
+
+impl fmt::Debug for CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+pub type MergeOperatorFn = dyn Fn(&[u8], Option<&[u8]>, &[&[u8]]) -> Option<Bytes> + Send + Sync;
+
+/// A callback combining the existing value of a key, None if it's absent or deleted, with the
+/// operands merged into it, older first. It returns the new value, or None to delete the key.
+///
+/// It's called by reads, flushes and compactions, so it must return the same result for the same
+/// inputs.
+#[derive(Clone)]
+pub struct MergeOperator(pub Arc<MergeOperatorFn>);
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+pub type MemTableIndexFn = dyn Fn() -> Arc<dyn MemTableIndex> + Send + Sync;
+
+/// Creates an empty index for each new or replayed memtable.
+#[derive(Clone)]
+pub struct MemTableIndexFactory(pub Arc<MemTableIndexFn>);
+
+impl fmt::Debug for MemTableIndexFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MemTableIndexFactory")
+    }
+}
+
+/// Where the files of a storage are placed under its directory. The default methods are the
+/// default layout, `{id}.sst`, `{id:05}.mem` and `MANIFEST` in the directory.
+///
+/// Files are found by listing the directory of `sstable_path` and `memtable_path`, so a layout
+/// changing the file names must also parse their ids.
+pub trait PathLayout: fmt::Debug + Send + Sync {
+    fn sstable_path(&self, dir: &Path, id: u64) -> PathBuf {
+        sstable_file_path(dir, id)
+    }
+
+    /// `dir` is the directory of WALs of a column family, see `util::cf_dir`.
+    fn memtable_path(&self, dir: &Path, id: usize) -> PathBuf {
+        memtable_file_path(dir, id)
+    }
+
+    fn manifest_path(&self, dir: &Path) -> PathBuf {
+        manifest_file_path(dir)
+    }
+
+    /// Parse the id of a sstable file, None if it's not one. Temporary files are the sstable
+    /// file names with a `.tmp` suffix.
+    fn parse_sstable_id(&self, file_name: &str) -> Option<u64> {
+        parse_sstable_id(file_name)
+    }
+
+    /// Parse the id of a WAL file, None if it's not one.
+    fn parse_memtable_id(&self, file_name: &str) -> Option<usize> {
+        parse_memtable_id(file_name)
+    }
+}
+
+#[derive(Debug)]
+pub struct DefaultPathLayout;
+
+impl PathLayout for DefaultPathLayout {}
+
+/// The order of keys in memtables, sstables and scans.
+///
+/// It must be a total order, and only equal bytes are equal keys, since keys are also hashed and
+/// compared for equality as bytes. Keys are stored sorted by it, so the comparator of a storage
+/// must never change, opening a storage with a comparator of another name fails.
+///
+/// Range tombstones and prefixes assume the bytewise order, so `delete_range`, `drop_prefix`,
+/// `scan_prefix`, `prefix_bloom_len` and `key_prefix_dict` are unsupported with another
+/// comparator.
+pub trait Comparator: fmt::Debug + Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// Get the name of the order, which is recorded in the manifest, so a storage can't be opened
+    /// with a comparator of another name. Change it whenever the order changes.
+    fn name(&self) -> &str;
+
+    /// Check if it's the bytewise order, so keys can be compared as bytes directly.
+    fn is_bytewise(&self) -> bool {
+        false
+    }
+}
+
+/// Compares keys as bytes, the default comparator.
+#[derive(Debug)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn name(&self) -> &str {
+        "topazdb.BytewiseComparator"
+    }
+
+    fn is_bytewise(&self) -> bool {
+        true
+    }
+}
+
+/// When the WAL is synced to the disk. Records are always written to the file before a write
+/// returns, so they survive a crash of the process, but only synced records survive a crash of
+/// the machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum WalSync {
+    /// Sync before every write returns, concurrent writes share one sync.
+    Always,
+    /// Sync once every n records.
+    EveryN(usize),
+    /// Sync on a write if the last sync is older than the interval.
+    Interval(Duration),
+}
+
+/// Options of the storage. With the `serde` feature, it can be loaded from a config file, where
+/// missing fields take their default values.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct LsmOptions {
     pub dir: PathBuf,
-    pub flush_num: usize,             //  it must be 1 now. TODO: use lock
+    pub flush_num: usize,             // default 1, concurrent flushes
     pub compactor_num: usize,         // default 4
     pub subcompactor_num: usize,      // default 4
-    pub block_cache_size: u64,        // default 2GB
+    pub block_cache_size: u64,        // bytes, default 2GB
     pub block_size: usize,            // default 32KB
     pub memtable_size: usize,         // default 256MB
     pub max_memtable_num: usize,      // default 5
     pub min_memtable_to_merge: usize, // default 2
     pub level0_file_num_compaction_trigger: usize, // default 5
     // l1 single table size
-    pub target_file_size_base: usize,
+    pub target_file_size_base: usize, // default 256MB
+    // table size of each level deeper than l1 relative to the level above
+    pub target_file_size_multiplier: Option<usize>, // default None, max_bytes_for_level_multiplier
     // l1 total size
     // advice: memtable_size*min_memtable_to_merge * level0_file_num_compaction_trigger
     pub max_bytes_for_level_base: usize, // default 256MB * 2 * 5
     pub max_bytes_for_level_multiplier: usize, // default 10
     pub num_levels: usize,               // default 6
     pub compress_option: CompressOptions,
+    // compression of each level, compress_option is used for levels not in it
+    pub compress_per_level: Vec<CompressOptions>, // default empty
+    // 0 is the codec's default, Lz4: 1..=12 high compression, < 0 fast mode, Zstd: -7..=22
+    pub compress_level: i32, // default 0
+    // strip the common key prefix of each block, sharing the prefixes in the table footer
+    pub key_prefix_dict: bool, // default false
+    // every this many keys of a block are stored in full as restart points which seeks binary
+    // search, keys between them only store the bytes not shared with the previous key
+    pub block_restart_interval: usize, // default 16
     pub o_direct: bool,
-    pub false_positive_rate: f64, // It will build a bloom filter, if 0 < value < 1
-    pub wait_entry_num: usize,    // default 10.
+    // read sstables through a memory map instead of syscalls
+    pub use_mmap: bool, // default false
+    // checksum of sstable blocks and files, it's stored with the checksum so older tables are
+    // still readable after changing it
+    pub checksum_type: ChecksumType,   // default Crc32
+    pub false_positive_rate: f64,      // It will build a bloom filter, if 0 < value < 1
+    pub wait_entry_num: usize,         // default 10.
+    pub max_value_size: Option<usize>, // default None, which is u32::MAX
+    // keys are stored with u16 lengths, so it can't exceed u16::MAX
+    pub max_key_size: usize, // default u16::MAX
+    // total size of all memtables, the oldest memtable will be flushed when it's exceeded
+    pub db_write_buffer_size: Option<usize>, // default None
+    // transient errors (Interrupted, WouldBlock) reading a sstable are retried this many times
+    pub open_retries: usize, // default 3
+    // reading a sstable to verify its checksum when it's opened fails after this long
+    pub open_timeout: Duration, // default 60s
+    // called for every entry compacted, removed entries become tombstones unless compacting into
+    // the bottom level, where they are dropped
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub compaction_filter: Option<CompactionFilter>, // default None
+    // multi_get looks up keys in the thread pool by batches of this size, if > 0
+    pub multi_get_batch_size: usize, // default 0
+    // sub compactions run one after another in key order instead of in parallel,
+    // so the same inputs produce byte-identical tables
+    pub deterministic_compaction: bool, // default false
+    pub wal_sync: WalSync,              // default Interval(1s)
+    // open an existing storage without writing to its directory, writes return an error and no
+    // background task is started, see `LsmStorage::open_read_only`
+    pub read_only: bool, // default false
+    // bytes written per second by compactions of all threads, unlimited if None
+    pub compaction_bytes_per_sec: Option<usize>, // default None
+    // merge the inputs of a compaction by a loser tree instead of a binary heap, which may take
+    // fewer comparisons when there are many inputs
+    pub loser_tree_compaction: bool, // default false
+    // the priority of compacting a level is multiplied by 1 + weight * the fraction of its
+    // entries which are tombstones, so levels with many deletes are compacted first
+    pub tombstone_compaction_weight: f64, // default 1.0
+    // build a bloom filter of the key prefixes of this length in each sstable when the bloom
+    // filter is enabled, so `scan_prefix` skips the tables without the prefix
+    pub prefix_bloom_len: Option<usize>, // default None
+    // combines the operands written by `LsmStorage::merge` with the value of their key, merges
+    // fail if it's None
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub merge_operator: Option<MergeOperator>, // default None
+    // places the sstables, WALs and the manifest under `dir`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub path_layout: Arc<dyn PathLayout>, // default DefaultPathLayout
+    // decode every block of the live sstables on open, which fails if any is corrupted, see
+    // `LsmStorage::verify_checksums`
+    pub paranoid_checks: bool, // default false
+    // creates the indexes of memtables, a `SkipMap` if None
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub memtable_index: Option<MemTableIndexFactory>, // default None
+    // orders the keys, it must not change for an existing storage, see `Comparator`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub comparator: Arc<dyn Comparator>, // default BytewiseComparator
 }
 
 impl Default for LsmOptions {
@@ -43,12 +275,38 @@ impl Default for LsmOptions {
             level0_file_num_compaction_trigger: 5,
             max_bytes_for_level_base: 256 * 1024 * 1024 * 2 * 5,
             target_file_size_base: 256 * 1024 * 1024,
+            target_file_size_multiplier: None,
             max_bytes_for_level_multiplier: 10,
             num_levels: 6,
             compress_option: CompressOptions::Snappy,
+            compress_per_level: vec![],
+            compress_level: 0,
+            key_prefix_dict: false,
+            block_restart_interval: 16,
             o_direct: false,
+            use_mmap: false,
+            checksum_type: ChecksumType::Crc32,
             false_positive_rate: 0.1,
             wait_entry_num: 10,
+            max_value_size: None,
+            max_key_size: u16::MAX as usize,
+            db_write_buffer_size: None,
+            open_retries: 3,
+            open_timeout: Duration::from_secs(60),
+            compaction_filter: None,
+            multi_get_batch_size: 0,
+            deterministic_compaction: false,
+            wal_sync: WalSync::Interval(Duration::from_secs(1)),
+            read_only: false,
+            compaction_bytes_per_sec: None,
+            loser_tree_compaction: false,
+            tombstone_compaction_weight: 1.0,
+            prefix_bloom_len: None,
+            merge_operator: None,
+            path_layout: Arc::new(DefaultPathLayout),
+            paranoid_checks: false,
+            memtable_index: None,
+            comparator: Arc::new(BytewiseComparator),
         }
     }
 }
@@ -64,7 +322,295 @@ impl LsmOptions {
         self
     }
 
+    pub fn wal_sync(mut self, wal_sync: WalSync) -> Self {
+        self.wal_sync = wal_sync;
+        self
+    }
+
+    pub fn compaction_filter(
+        mut self,
+        filter: impl Fn(&[u8], &[u8]) -> FilterDecision + Send + Sync + 'static,
+    ) -> Self {
+        self.compaction_filter = Some(CompactionFilter(Arc::new(filter)));
+        self
+    }
+
+    pub fn merge_operator(
+        mut self,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[&[u8]]) -> Option<Bytes> + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(MergeOperator(Arc::new(operator)));
+        self
+    }
+
+    pub fn path_layout(mut self, layout: impl PathLayout + 'static) -> Self {
+        self.path_layout = Arc::new(layout);
+        self
+    }
+
+    pub fn memtable_index(
+        mut self,
+        factory: impl Fn() -> Arc<dyn MemTableIndex> + Send + Sync + 'static,
+    ) -> Self {
+        self.memtable_index = Some(MemTableIndexFactory(Arc::new(factory)));
+        self
+    }
+
+    pub fn comparator(mut self, comparator: impl Comparator + 'static) -> Self {
+        self.comparator = Arc::new(comparator);
+        self
+    }
+
+    /// Create an empty index for a memtable, a custom index must order keys by `comparator`.
+    pub fn new_memtable_index(&self) -> Arc<dyn MemTableIndex> {
+        match &self.memtable_index {
+            Some(factory) => (factory.0)(),
+            None if self.comparator.is_bytewise() => Arc::new(SkipMap::new()),
+            None => Arc::new(ComparatorIndex::new(self.comparator.clone())),
+        }
+    }
+
+    /// Get the path of sstable `id` in the directory.
+    pub fn sstable_path(&self, id: u64) -> PathBuf {
+        self.path_layout.sstable_path(&self.dir, id)
+    }
+
+    /// Get the size of tables built in `level`, `target_file_size_base` in level 0 and 1, and
+    /// multiplied by `target_file_size_multiplier` in each deeper level, which is
+    /// `max_bytes_for_level_multiplier` unless it's set.
+    pub fn target_file_size_of_level(&self, level: usize) -> usize {
+        let multiplier = self
+            .target_file_size_multiplier
+            .unwrap_or(self.max_bytes_for_level_multiplier);
+        let mut size = self.target_file_size_base;
+        for _ in 1..level {
+            size = size.saturating_mul(multiplier);
+        }
+        size
+    }
+
+    /// Get the compress option of sstables in `level`.
+    pub fn compress_option_of_level(&self, level: usize) -> CompressOptions {
+        self.compress_per_level
+            .get(level)
+            .copied()
+            .unwrap_or(self.compress_option)
+    }
+
     pub fn open(self) -> Result<LsmStorage> {
         LsmStorage::open(self)
     }
+
+    /// Check that the options make sense, return an error describing the first invalid one.
+    pub fn validate(&self) -> Result<()> {
+        let check = |valid: bool, msg: String| match valid {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("invalid options: {msg}")),
+        };
+        check(
+            self.memtable_size > 0,
+            "memtable_size must be positive".to_string(),
+        )?;
+        check(
+            (1..=u16::MAX as usize).contains(&self.block_size),
+            format!(
+                "block_size must be in [1, {}], got {}",
+                u16::MAX,
+                self.block_size
+            ),
+        )?;
+        check(
+            self.block_restart_interval > 0,
+            "block_restart_interval must be positive".to_string(),
+        )?;
+        check(
+            self.compactor_num > 0,
+            "compactor_num must be positive".to_string(),
+        )?;
+        check(self.flush_num > 0, "flush_num must be positive".to_string())?;
+        check(
+            self.min_memtable_to_merge > 0,
+            "min_memtable_to_merge must be positive".to_string(),
+        )?;
+        check(
+            self.min_memtable_to_merge <= self.max_memtable_num,
+            format!(
+                "min_memtable_to_merge {} exceeds max_memtable_num {}",
+                self.min_memtable_to_merge, self.max_memtable_num
+            ),
+        )?;
+        check(
+            self.level0_file_num_compaction_trigger > 0,
+            "level0_file_num_compaction_trigger must be positive".to_string(),
+        )?;
+        check(
+            self.target_file_size_base > 0,
+            "target_file_size_base must be positive".to_string(),
+        )?;
+        check(
+            self.target_file_size_base <= self.max_bytes_for_level_base,
+            format!(
+                "target_file_size_base {} exceeds max_bytes_for_level_base {}",
+                self.target_file_size_base, self.max_bytes_for_level_base
+            ),
+        )?;
+        check(
+            self.target_file_size_multiplier != Some(0),
+            "target_file_size_multiplier must be positive".to_string(),
+        )?;
+        check(
+            self.max_bytes_for_level_multiplier > 0,
+            "max_bytes_for_level_multiplier must be positive".to_string(),
+        )?;
+        check(
+            self.num_levels >= 2,
+            format!("num_levels must be at least 2, got {}", self.num_levels),
+        )?;
+        // 0 disables the bloom filter
+        check(
+            (0.0..1.0).contains(&self.false_positive_rate),
+            format!(
+                "false_positive_rate must be in [0, 1), got {}",
+                self.false_positive_rate
+            ),
+        )?;
+        check(
+            (1..=u16::MAX as usize).contains(&self.max_key_size),
+            format!(
+                "max_key_size must be in [1, {}], got {}",
+                u16::MAX,
+                self.max_key_size
+            ),
+        )?;
+        check(
+            self.max_value_size
+                .is_none_or(|size| size <= u32::MAX as usize),
+            format!(
+                "max_value_size must not exceed {}, got {:?}",
+                u32::MAX,
+                self.max_value_size
+            ),
+        )?;
+        check(
+            self.compaction_bytes_per_sec != Some(0),
+            "compaction_bytes_per_sec must be positive".to_string(),
+        )?;
+        check(
+            self.tombstone_compaction_weight >= 0.0 && self.tombstone_compaction_weight.is_finite(),
+            format!(
+                "tombstone_compaction_weight must be a non-negative number, got {}",
+                self.tombstone_compaction_weight
+            ),
+        )?;
+        check(
+            self.prefix_bloom_len
+                .is_none_or(|len| (1..=u16::MAX as usize).contains(&len)),
+            format!(
+                "prefix_bloom_len must be in [1, {}], got {:?}",
+                u16::MAX,
+                self.prefix_bloom_len
+            ),
+        )?;
+        check(
+            self.comparator.is_bytewise() || self.prefix_bloom_len.is_none(),
+            "prefix_bloom_len requires the bytewise comparator".to_string(),
+        )?;
+        // keys between the first and last key of a block share their prefix only in bytewise order
+        check(
+            self.comparator.is_bytewise() || !self.key_prefix_dict,
+            "key_prefix_dict requires the bytewise comparator".to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LsmOptions;
+
+    #[test]
+    fn validate_test() {
+        LsmOptions::default().validate().unwrap();
+
+        type Set = fn(&mut LsmOptions);
+        let invalid: Vec<(Set, &str)> = vec![
+            (|opts| opts.memtable_size = 0, "memtable_size"),
+            (|opts| opts.block_size = 0, "block_size"),
+            (|opts| opts.block_size = 1 << 16, "block_size"),
+            (
+                |opts| opts.block_restart_interval = 0,
+                "block_restart_interval",
+            ),
+            (|opts| opts.compactor_num = 0, "compactor_num"),
+            (|opts| opts.flush_num = 0, "flush_num"),
+            (
+                |opts| opts.min_memtable_to_merge = 0,
+                "min_memtable_to_merge",
+            ),
+            (|opts| opts.max_memtable_num = 1, "max_memtable_num"),
+            (
+                |opts| opts.level0_file_num_compaction_trigger = 0,
+                "level0_file_num_compaction_trigger",
+            ),
+            (
+                |opts| opts.target_file_size_base = 0,
+                "target_file_size_base",
+            ),
+            (
+                |opts| opts.target_file_size_base = opts.max_bytes_for_level_base + 1,
+                "max_bytes_for_level_base",
+            ),
+            (
+                |opts| opts.target_file_size_multiplier = Some(0),
+                "target_file_size_multiplier",
+            ),
+            (
+                |opts| opts.max_bytes_for_level_multiplier = 0,
+                "max_bytes_for_level_multiplier",
+            ),
+            (|opts| opts.num_levels = 1, "num_levels"),
+            (|opts| opts.false_positive_rate = 1.0, "false_positive_rate"),
+            (
+                |opts| opts.false_positive_rate = -0.1,
+                "false_positive_rate",
+            ),
+            (
+                |opts| opts.false_positive_rate = f64::NAN,
+                "false_positive_rate",
+            ),
+            (
+                |opts| opts.compaction_bytes_per_sec = Some(0),
+                "compaction_bytes_per_sec",
+            ),
+            (|opts| opts.prefix_bloom_len = Some(0), "prefix_bloom_len"),
+            (
+                |opts| opts.tombstone_compaction_weight = -1.0,
+                "tombstone_compaction_weight",
+            ),
+            (
+                |opts| opts.tombstone_compaction_weight = f64::INFINITY,
+                "tombstone_compaction_weight",
+            ),
+            (|opts| opts.max_key_size = 0, "max_key_size"),
+            (|opts| opts.max_key_size = 1 << 16, "max_key_size"),
+            (
+                |opts| opts.max_value_size = Some(u32::MAX as usize + 1),
+                "max_value_size",
+            ),
+            (
+                |opts| opts.prefix_bloom_len = Some(1 << 16),
+                "prefix_bloom_len",
+            ),
+        ];
+        for (set, field) in invalid {
+            let mut opts = LsmOptions::default();
+            set(&mut opts);
+            let err = opts.validate().unwrap_err().to_string();
+            assert!(err.contains(field), "{field}: {err}");
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut opts = LsmOptions::default().path(&dir);
+        opts.memtable_size = 0;
+        assert!(opts.open().is_err());
+    }
 }
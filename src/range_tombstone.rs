@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Ok, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::block::SIZEOF_U16;
+
+/// Keys in [`start`, `end`] are deleted, both bounds are inclusive.
+///
+/// A range tombstone only deletes keys of older memtables and sstables, or older versions in the
+/// same memtable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+}
+
+impl RangeTombstone {
+    pub fn new(start: impl Into<Bytes>, end: impl Into<Bytes>) -> Self {
+        Self {
+            start: start.into(),
+            end: end.into(),
+        }
+    }
+
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.start <= key && self.end >= key
+    }
+
+    /// Get the part within [`lower`, `upper`], None means unbounded.
+    ///
+    /// Return None if they don't overlap.
+    pub fn clip(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<Self> {
+        let start = match lower {
+            Some(lower) if self.start < lower => Bytes::copy_from_slice(lower),
+            _ => self.start.clone(),
+        };
+        let end = match upper {
+            Some(upper) if self.end > upper => Bytes::copy_from_slice(upper),
+            _ => self.end.clone(),
+        };
+        if start > end {
+            return None;
+        }
+        Some(Self { start, end })
+    }
+
+    /// Encode range tombstones to a buffer.
+    pub fn encode(tombstones: &[RangeTombstone], buf: &mut Vec<u8>) {
+        // |start_len start end_len end|
+        for tombstone in tombstones {
+            buf.put_u16(tombstone.start.len() as u16);
+            buf.put(tombstone.start.clone());
+            buf.put_u16(tombstone.end.len() as u16);
+            buf.put(tombstone.end.clone());
+        }
+    }
+
+    /// Decode range tombstones from a buffer.
+    pub fn decode(mut buf: impl Buf) -> Result<Vec<RangeTombstone>> {
+        fn get_key(buf: &mut impl Buf) -> Result<Bytes> {
+            if buf.remaining() < SIZEOF_U16 {
+                return Err(anyhow!("range tombstones are truncated"));
+            }
+            let len = buf.get_u16() as usize;
+            if buf.remaining() < len {
+                return Err(anyhow!("range tombstones are truncated"));
+            }
+            Ok(buf.copy_to_bytes(len))
+        }
+
+        let mut tombstones = vec![];
+        while buf.has_remaining() {
+            let start = get_key(&mut buf)?;
+            let end = get_key(&mut buf)?;
+            if start > end {
+                return Err(anyhow!("invalid range tombstone {start:?}..={end:?}"));
+            }
+            tombstones.push(RangeTombstone { start, end });
+        }
+        Ok(tombstones)
+    }
+}
+
+/// Union of range tombstones, which are merged into sorted disjoint ranges.
+#[derive(Clone, Debug, Default)]
+pub struct RangeTombstones {
+    ranges: Vec<RangeTombstone>,
+}
+
+impl RangeTombstones {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn add(&mut self, tombstone: &RangeTombstone) {
+        // ranges[lo..hi] overlap with the tombstone
+        let lo = self.ranges.partition_point(|x| x.end < tombstone.start);
+        let hi = self.ranges.partition_point(|x| x.start <= tombstone.end);
+        let mut merged = tombstone.clone();
+        if lo < hi {
+            merged.start = merged.start.min(self.ranges[lo].start.clone());
+            merged.end = merged.end.max(self.ranges[hi - 1].end.clone());
+        }
+        self.ranges.splice(lo..hi, [merged]);
+    }
+
+    pub fn extend<'a>(&mut self, tombstones: impl IntoIterator<Item = &'a RangeTombstone>) {
+        for tombstone in tombstones {
+            self.add(tombstone);
+        }
+    }
+
+    /// Get the range covering `key`.
+    pub fn covering(&self, key: &[u8]) -> Option<&RangeTombstone> {
+        let idx = self.ranges.partition_point(|x| x.start <= key);
+        self.ranges[..idx].last().filter(|x| x.end >= key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RangeTombstone> {
+        self.ranges.iter()
+    }
+}
+
+/// Get the range tombstones deleting keys of each table, given the tombstones of tables from the
+/// newest to the oldest. Keys of a table are only deleted by tombstones of newer tables.
+pub fn shadows<T: AsRef<[RangeTombstone]>>(
+    tombstones: impl IntoIterator<Item = T>,
+) -> Vec<Arc<RangeTombstones>> {
+    let mut shadow = Arc::new(RangeTombstones::new());
+    let mut shadows = vec![];
+    for tombstones in tombstones {
+        shadows.push(shadow.clone());
+        let tombstones = tombstones.as_ref();
+        if !tombstones.is_empty() {
+            let mut next = (*shadow).clone();
+            next.extend(tombstones);
+            shadow = Arc::new(next);
+        }
+    }
+    shadows
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RangeTombstone, RangeTombstones};
+
+    #[test]
+    fn merge_test() {
+        let mut tombstones = RangeTombstones::new();
+        tombstones.add(&RangeTombstone::new(&b"c"[..], &b"e"[..]));
+        tombstones.add(&RangeTombstone::new(&b"m"[..], &b"o"[..]));
+        tombstones.add(&RangeTombstone::new(&b"a"[..], &b"b"[..]));
+        assert_eq!(tombstones.iter().count(), 3);
+        assert!(tombstones.covering(b"f").is_none());
+        assert_eq!(
+            tombstones.covering(b"d"),
+            Some(&RangeTombstone::new(&b"c"[..], &b"e"[..]))
+        );
+
+        tombstones.add(&RangeTombstone::new(&b"d"[..], &b"m"[..]));
+        let ranges = tombstones.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                RangeTombstone::new(&b"a"[..], &b"b"[..]),
+                RangeTombstone::new(&b"c"[..], &b"o"[..]),
+            ]
+        );
+        assert!(tombstones.covering(b"f").is_some());
+        assert!(tombstones.covering(b"bb").is_none());
+        assert!(tombstones.covering(b"p").is_none());
+    }
+
+    #[test]
+    fn encode_test() {
+        let tombstones = vec![
+            RangeTombstone::new(&b""[..], &b"b"[..]),
+            RangeTombstone::new(&b"key_1"[..], &b"key_2"[..]),
+        ];
+        let mut buf = vec![];
+        RangeTombstone::encode(&tombstones, &mut buf);
+        assert_eq!(RangeTombstone::decode(&buf[..]).unwrap(), tombstones);
+        assert!(RangeTombstone::decode(&buf[..buf.len() - 1]).is_err());
+
+        let tombstone = RangeTombstone::new(&b"b"[..], &b"d"[..]);
+        assert_eq!(
+            tombstone.clip(Some(b"c"), None),
+            Some(RangeTombstone::new(&b"c"[..], &b"d"[..]))
+        );
+        assert_eq!(tombstone.clip(None, Some(b"a")), None);
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// A token bucket limiting bytes written per second, shared by threads.
+///
+/// A request larger than the available tokens is granted, the requester sleeps until the bucket
+/// is refilled to pay the debt, so large writes don't starve.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    // negative when requests are in debt
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        assert!(bytes_per_sec > 0, "bytes_per_sec must be positive");
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            state: Mutex::new(State {
+                available: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` tokens, sleep until they are refilled if there are not enough.
+    pub fn request(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock();
+            let now = Instant::now();
+            let refill = (now - state.last_refill).as_secs_f64() * self.bytes_per_sec;
+            // at most one second of idle time is saved for bursts
+            state.available = (state.available + refill).min(self.bytes_per_sec);
+            state.last_refill = now;
+            state.available -= bytes as f64;
+            match state.available {
+                available if available < 0.0 => -available / self.bytes_per_sec,
+                _ => 0.0,
+            }
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::level::{get_in_levels, tables_sorted};
+use crate::lsm_iterator::{FusedIterator, LsmIterator};
+use crate::lsm_storage::scan_tables;
+use crate::mem_table::{get_in_memtables, MemTable};
+use crate::merge::MergeChain;
+use crate::opt::MergeOperator;
+use crate::table::SsTable;
+
+/// A frozen view of memtables and sstables.
+///
+/// All memtables in the snapshot are immutable, and sstables are kept alive by the snapshot even if
+/// they have been compacted.
+pub struct Snapshot {
+    /// older memtable is in front
+    memtables: Vec<Arc<MemTable>>,
+    levels: Vec<Vec<Arc<SsTable>>>,
+    merge_operator: Option<MergeOperator>,
+}
+
+/// The former name of [`Snapshot`].
+#[deprecated(note = "use `Snapshot` instead")]
+pub type SnapshotHandle = Snapshot;
+
+impl Snapshot {
+    pub(crate) fn new(
+        memtables: Vec<Arc<MemTable>>,
+        levels: Vec<Vec<Arc<SsTable>>>,
+        merge_operator: Option<MergeOperator>,
+    ) -> Self {
+        Self {
+            memtables,
+            levels,
+            merge_operator,
+        }
+    }
+
+    /// Get a key from the snapshot.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        assert!(!key.is_empty(), "key cannot be empty");
+
+        let mut chain = MergeChain::default();
+        if !get_in_memtables(&self.memtables, key, &mut chain) {
+            get_in_levels(&self.levels, key, &mut chain)?;
+        }
+        chain.resolve(key, self.merge_operator.as_ref())
+    }
+
+    /// Create an iterator over a range of keys in the snapshot.
+    pub fn scan(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let ssts = tables_sorted(&self.levels, lower, upper);
+        let operator = self.merge_operator.as_ref();
+        scan_tables(None, &self.memtables, &ssts, lower, upper, operator)
+    }
+
+    /// Get the tables of each level in the snapshot.
+    pub fn levels(&self) -> &[Vec<Arc<SsTable>>] {
+        &self.levels
+    }
+}
@@ -10,13 +10,26 @@ pub use builder::SsTableBuilder;
 use bytes::{Buf, BufMut, Bytes};
 pub use file_object::FileObject;
 pub use iterator::SsTableIterator;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use crate::block::{Block, BlockIterator, SIZEOF_U16};
 use crate::bloom::Bloom;
 use crate::level::BlockCache;
+use crate::opt::{BytewiseComparator, Comparator};
+use crate::range_tombstone::RangeTombstone;
 
 const SIZEOF_U32: usize = 4;
+const SIZEOF_U64: usize = 8;
+/// Set in the meta offset field when the table has a properties section.
+const HAS_PROPERTIES: u32 = 1 << 31;
+/// Set in the meta offset field when the table has a key prefixes section, and block metas have
+/// the index of their key prefix.
+const HAS_KEY_PREFIXES: u32 = 1 << 30;
+/// Set in the meta offset field when the table has a range tombstones section.
+const HAS_RANGE_TOMBSTONES: u32 = 1 << 29;
+const FOOTER_FLAGS: u32 = HAS_PROPERTIES | HAS_KEY_PREFIXES | HAS_RANGE_TOMBSTONES;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -24,60 +37,200 @@ pub struct BlockMeta {
     ///
     /// It is u32 in encoding
     pub offset: usize,
+    /// Index of the key prefix stripped from the keys of this data block.
+    pub prefix_idx: u16,
     /// The first key of the data block.
     pub first_key: Bytes,
 }
 
 impl BlockMeta {
-    /// Encode block meta to a buffer.
-    pub fn encode_block_meta(block_meta: &[BlockMeta], buf: &mut Vec<u8>) {
-        // |offset first_key_len first_key|
+    /// Encode block meta to a buffer, with the key prefix indexes if `has_prefix_idx`.
+    pub fn encode_block_meta(block_meta: &[BlockMeta], has_prefix_idx: bool, buf: &mut Vec<u8>) {
+        // |offset prefix_idx first_key_len first_key|, prefix_idx is optional
+        let entry_size = SIZEOF_U32 + SIZEOF_U16 * (1 + has_prefix_idx as usize);
         let size = block_meta
             .iter()
-            .map(|meta| SIZEOF_U32 + SIZEOF_U16 + meta.first_key.len())
+            .map(|meta| entry_size + meta.first_key.len())
             .sum::<usize>();
         buf.reserve(size);
 
         for meta in block_meta {
             buf.put_u32(meta.offset as u32);
+            if has_prefix_idx {
+                buf.put_u16(meta.prefix_idx);
+            }
             buf.put_u16(meta.first_key.len() as u16);
             buf.put(meta.first_key.clone());
         }
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: impl Buf) -> Vec<BlockMeta> {
+    /// Decode block meta from a buffer, with the key prefix indexes if `has_prefix_idx`.
+    pub fn decode_block_meta(mut buf: impl Buf, has_prefix_idx: bool) -> Result<Vec<BlockMeta>> {
+        let entry_size = SIZEOF_U32 + SIZEOF_U16 * (1 + has_prefix_idx as usize);
         let mut metas = vec![];
         while buf.has_remaining() {
+            if buf.remaining() < entry_size {
+                return Err(anyhow!("block meta is truncated"));
+            }
             let offset = buf.get_u32() as usize;
+            let prefix_idx = if has_prefix_idx { buf.get_u16() } else { 0 };
             let klen = buf.get_u16() as usize;
+            if buf.remaining() < klen {
+                return Err(anyhow!("block meta is truncated"));
+            }
             let first_key = buf.copy_to_bytes(klen);
             // buf.advance(klen);
-            metas.push(BlockMeta { offset, first_key });
+            metas.push(BlockMeta {
+                offset,
+                prefix_idx,
+                first_key,
+            });
         }
-        metas
+        Ok(metas)
     }
 }
 
+/// Statistics of the entries of a table, counted when the table is built.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    /// Number of entries, including tombstones.
+    pub num_entries: u64,
+    /// Number of tombstones.
+    pub num_tombstones: u64,
+    /// Total length of the keys.
+    pub key_bytes: u64,
+    /// Total length of the values.
+    pub value_bytes: u64,
+    /// The smallest and biggest key of data and range tombstones, None if it's written by an
+    /// older version.
+    pub key_range: Option<(Bytes, Bytes)>,
+}
+
+impl TableProperties {
+    // size without the key range
+    const ENCODED_SIZE: usize = SIZEOF_U64 * 4;
+
+    /// Encode table properties to a buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        // |num_entries num_tombstones key_bytes value_bytes|smallest_len smallest biggest_len biggest|
+        buf.put_u64(self.num_entries);
+        buf.put_u64(self.num_tombstones);
+        buf.put_u64(self.key_bytes);
+        buf.put_u64(self.value_bytes);
+        if let Some((smallest, biggest)) = &self.key_range {
+            buf.put_u16(smallest.len() as u16);
+            buf.put(smallest.clone());
+            buf.put_u16(biggest.len() as u16);
+            buf.put(biggest.clone());
+        }
+    }
+
+    /// Decode table properties from a buffer.
+    ///
+    /// Trailing bytes are ignored, so new properties can be appended.
+    pub fn decode(mut buf: impl Buf) -> Result<Self> {
+        fn get_key(buf: &mut impl Buf) -> Result<Bytes> {
+            if buf.remaining() < SIZEOF_U16 {
+                return Err(anyhow!("table properties are truncated"));
+            }
+            let len = buf.get_u16() as usize;
+            if buf.remaining() < len {
+                return Err(anyhow!("table properties are truncated"));
+            }
+            Ok(buf.copy_to_bytes(len))
+        }
+
+        if buf.remaining() < Self::ENCODED_SIZE {
+            return Err(anyhow!("table properties are truncated"));
+        }
+        let mut properties = Self {
+            num_entries: buf.get_u64(),
+            num_tombstones: buf.get_u64(),
+            key_bytes: buf.get_u64(),
+            value_bytes: buf.get_u64(),
+            key_range: None,
+        };
+        if buf.has_remaining() {
+            let smallest = get_key(&mut buf)?;
+            let biggest = get_key(&mut buf)?;
+            properties.key_range = Some((smallest, biggest));
+        }
+        Ok(properties)
+    }
+}
+
+/// Encode the key prefixes shared by data blocks to a buffer.
+pub fn encode_key_prefixes(prefixes: &[Bytes], buf: &mut Vec<u8>) {
+    // |prefix_len prefix|
+    for prefix in prefixes {
+        buf.put_u16(prefix.len() as u16);
+        buf.put(prefix.clone());
+    }
+}
+
+/// Decode the key prefixes shared by data blocks from a buffer.
+pub fn decode_key_prefixes(mut buf: impl Buf) -> Result<Vec<Bytes>> {
+    let mut prefixes = vec![];
+    while buf.has_remaining() {
+        if buf.remaining() < SIZEOF_U16 {
+            return Err(anyhow!("key prefixes are truncated"));
+        }
+        let len = buf.get_u16() as usize;
+        if buf.remaining() < len {
+            return Err(anyhow!("key prefixes are truncated"));
+        }
+        prefixes.push(buf.copy_to_bytes(len));
+    }
+    Ok(prefixes)
+}
+
 #[derive(Debug)]
 pub struct SsTable {
     pub id: u64,
     file: FileObject,
     block_metas: Vec<BlockMeta>,
     block_meta_offset: usize,
+    key_prefixes: Vec<Bytes>,
+    range_tombstones: Vec<RangeTombstone>,
+    // None for tables written before the properties section was added
+    properties: Option<TableProperties>,
     block_cache: Option<Arc<BlockCache>>,
+    /// The smallest key of data and range tombstones.
     pub smallest_key: Bytes,
+    /// The biggest key of data and range tombstones.
     pub biggest_key: Bytes,
     pub size: usize,
     bloom: Option<Bloom>,
+    // the length of key prefixes and their bloom filter
+    prefix_bloom: Option<(usize, Bloom)>,
+    /// Number of blocks read from disk.
+    block_reads: AtomicUsize,
+    comparator: Arc<dyn Comparator>,
+}
+
+fn read_u32(file: &FileObject, offset: usize) -> Result<usize> {
+    Ok(file.read(offset, SIZEOF_U32)?.as_slice().get_u32() as usize)
 }
 
+// |data|block metas|key prefixes|range tombstones|properties|properties offset(u32)
+// |tombstone offset(u32)|prefix offset(u32)|meta offset(u32)|bloom|bloom offset(u32)|
+//
+// The key prefixes, range tombstones and properties sections and their offsets are optional,
+// each is only present when its flag is set in the meta offset, so tables written before a
+// section was added are read without it. The properties may be followed by a prefix bloom filter:
+// |prefix len(u16)|prefix bloom|
+//
+// Tables without a bloom filter end with an empty bloom section. Tables written without one before
+// it was added end with the meta offset, they can't be opened, as it was never possible.
 fn read_bloom(file: &FileObject) -> Result<(usize, Option<Bloom>)> {
     let size = file.size();
-    let offset = file
-        .read(size - SIZEOF_U32, SIZEOF_U32)?
-        .as_slice()
-        .get_u32() as usize;
+    if size < SIZEOF_U32 * 2 {
+        return Err(anyhow!("file is too small: {size} bytes"));
+    }
+    let offset = read_u32(file, size - SIZEOF_U32)?;
+    if offset < SIZEOF_U32 || offset > size - SIZEOF_U32 {
+        return Err(anyhow!("invalid bloom offset {offset}, file size {size}"));
+    }
     if size == offset + SIZEOF_U32 {
         return Ok((offset, None));
     }
@@ -86,38 +239,238 @@ fn read_bloom(file: &FileObject) -> Result<(usize, Option<Bloom>)> {
     Ok((offset, Some(bloom)))
 }
 
+/// Decode the prefix bloom filter following the properties, None if there is none.
+fn decode_prefix_bloom(mut buf: &[u8]) -> Result<Option<(usize, Bloom)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf.len() < SIZEOF_U16 {
+        return Err(anyhow!("prefix bloom filter is truncated"));
+    }
+    let len = buf.get_u16() as usize;
+    if len == 0 {
+        return Err(anyhow!("invalid prefix length 0"));
+    }
+    Ok(Some((len, Bloom::decode(buf))))
+}
+
+/// Extend the key range of data with range tombstones.
+fn key_range(
+    mut range: Option<(Bytes, Bytes)>,
+    tombstones: &[RangeTombstone],
+) -> Option<(Bytes, Bytes)> {
+    for tombstone in tombstones {
+        range = match range {
+            Some((smallest, biggest)) => Some((
+                smallest.min(tombstone.start.clone()),
+                biggest.max(tombstone.end.clone()),
+            )),
+            None => Some((tombstone.start.clone(), tombstone.end.clone())),
+        };
+    }
+    range
+}
+
 impl SsTable {
     /// Open SSTable from a file.
     pub fn open(id: u64, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
+        Self::open_with_comparator(id, block_cache, file, Arc::new(BytewiseComparator))
+    }
+
+    /// Open SSTable from a file whose keys are sorted by `comparator`.
+    pub fn open_with_comparator(
+        id: u64,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<Self> {
+        Self::open_inner(id, block_cache, file, comparator)
+            .map_err(|e| anyhow!("open sstable {id}: {e}"))
+    }
+
+    fn open_inner(
+        id: u64,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<Self> {
         let (offset, bloom) = read_bloom(&file)?;
-        let meta_offset = file
-            .read(offset - SIZEOF_U32, SIZEOF_U32)?
-            .as_slice()
-            .get_u32() as usize;
-        let meta_buf = file.read(meta_offset, offset - SIZEOF_U32 - meta_offset)?;
+        let meta_offset = read_u32(&file, offset - SIZEOF_U32)? as u32;
+        let flags = meta_offset & FOOTER_FLAGS;
+        let meta_offset = (meta_offset & !FOOTER_FLAGS) as usize;
+        // the offsets of optional sections precede the meta offset in reverse order
+        let mut footer_offset = offset - SIZEOF_U32;
+        let mut read_offset = |flag: u32| -> Result<Option<usize>> {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+            if footer_offset < SIZEOF_U32 {
+                return Err(anyhow!("invalid bloom offset {offset}"));
+            }
+            footer_offset -= SIZEOF_U32;
+            read_u32(&file, footer_offset).map(Some)
+        };
+        let prefix_offset = read_offset(HAS_KEY_PREFIXES)?;
+        let tombstone_offset = read_offset(HAS_RANGE_TOMBSTONES)?;
+        let properties_offset = read_offset(HAS_PROPERTIES)?;
+        let offsets = [
+            Some(meta_offset),
+            prefix_offset,
+            tombstone_offset,
+            properties_offset,
+            Some(footer_offset),
+        ];
+        if offsets
+            .iter()
+            .flatten()
+            .zip(offsets.iter().flatten().skip(1))
+            .any(|(a, b)| a > b)
+        {
+            return Err(anyhow!(
+                "invalid section offsets {offsets:?}, bloom offset {offset}"
+            ));
+        }
+        // a section ends where the next present one starts
+        let section_end = |i: usize| offsets[i + 1..].iter().flatten().next().copied().unwrap();
+
+        let meta_buf = file.read(meta_offset, section_end(0) - meta_offset)?;
+        let block_metas =
+            BlockMeta::decode_block_meta(meta_buf.as_slice(), prefix_offset.is_some())?;
+        if block_metas.iter().any(|meta| meta.offset >= meta_offset) {
+            return Err(anyhow!("invalid block offset"));
+        }
+        let key_prefixes = match prefix_offset {
+            Some(prefix_offset) => {
+                let prefix_buf = file.read(prefix_offset, section_end(1) - prefix_offset)?;
+                decode_key_prefixes(prefix_buf.as_slice())?
+            }
+            None => vec![Bytes::new()],
+        };
+        let range_tombstones = match tombstone_offset {
+            Some(tombstone_offset) => {
+                let tombstone_buf =
+                    file.read(tombstone_offset, section_end(2) - tombstone_offset)?;
+                RangeTombstone::decode(tombstone_buf.as_slice())?
+            }
+            None => vec![],
+        };
+        let (properties, prefix_bloom) = match properties_offset {
+            Some(properties_offset) => {
+                let properties_buf =
+                    file.read(properties_offset, footer_offset - properties_offset)?;
+                let mut buf = properties_buf.as_slice();
+                let properties = TableProperties::decode(&mut buf)?;
+                (Some(properties), decode_prefix_bloom(buf)?)
+            }
+            None => (None, None),
+        };
+        if block_metas
+            .iter()
+            .any(|meta| meta.prefix_idx as usize >= key_prefixes.len())
+        {
+            return Err(anyhow!("invalid key prefix index"));
+        }
 
         let mut table = Self {
             id,
             size: file.size(),
             file,
-            block_metas: BlockMeta::decode_block_meta(meta_buf.as_slice()),
+            block_metas,
             block_meta_offset: meta_offset,
+            key_prefixes,
+            range_tombstones,
+            properties,
             block_cache,
             smallest_key: Bytes::new(),
             biggest_key: Bytes::new(),
             bloom,
+            prefix_bloom,
+            block_reads: AtomicUsize::new(0),
+            comparator,
         };
         table.init_samllest_biggest_key()?;
+        if table
+            .compare(&table.smallest_key, &table.biggest_key)
+            .is_gt()
+        {
+            return Err(anyhow!(
+                "invalid key range {:?}..={:?}",
+                table.smallest_key,
+                table.biggest_key
+            ));
+        }
         Ok(table)
     }
 
+    /// Get the range tombstones, which delete keys of older tables.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Get the properties of the table, None if it's written by an older version.
+    pub fn properties(&self) -> Option<&TableProperties> {
+        self.properties.as_ref()
+    }
+
+    /// Get number of entries including tombstones, None if the table has no properties.
+    pub fn num_entries(&self) -> Option<u64> {
+        self.properties
+            .as_ref()
+            .map(|properties| properties.num_entries)
+    }
+
+    /// Check if `key` is deleted by a range tombstone of the table.
+    pub fn range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key))
+    }
+
     pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.may_contain_hash(xxhash_rust::xxh3::xxh3_64(key))
+    }
+
+    /// Same as `may_contain`, `hash` is the xxh3 hash of the key.
+    pub fn may_contain_hash(&self, hash: u64) -> bool {
         if let Some(bloom) = self.bloom.as_ref() {
-            return bloom.may_contain(xxhash_rust::xxh3::xxh3_64(key));
+            return bloom.may_contain(hash);
         }
         true
     }
 
+    /// Check if the table may contain keys starting with `prefix` by the prefix bloom filter. It's
+    /// always true when the prefix is shorter than the prefixes of the filter, or the table has
+    /// range tombstones, which may delete such keys of older tables.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        match self.prefix_bloom.as_ref() {
+            Some((len, bloom)) if prefix.len() >= *len && self.range_tombstones.is_empty() => {
+                bloom.may_contain(xxhash_rust::xxh3::xxh3_64(&prefix[..*len]))
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the smallest and biggest key of the table.
+    pub fn key_range(&self) -> (&Bytes, &Bytes) {
+        (&self.smallest_key, &self.biggest_key)
+    }
+
+    /// Check if the table overlaps with [`lower`, `upper`].
+    pub fn overlaps(&self, lower: &[u8], upper: &[u8]) -> bool {
+        self.compare(&self.smallest_key, upper).is_le()
+            && self.compare(&self.biggest_key, lower).is_ge()
+    }
+
+    /// Get the order of the keys.
+    pub fn comparator(&self) -> &dyn Comparator {
+        self.comparator.as_ref()
+    }
+
+    /// Compare two keys by the comparator of the table.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        self.comparator.compare(a, b)
+    }
+
     /// Save file when it drop
     pub(crate) fn mark_save(&self) {
         self.file.save()
@@ -125,50 +478,100 @@ impl SsTable {
 
     // calculating accurate size is expensive
     pub fn overlap_size(&self, lower: &[u8], upper: &[u8]) -> usize {
-        let l = self.find_block_idx(lower);
-        let r = self.find_block_idx(upper);
-        let loffset = self
-            .block_metas
-            .get(l)
-            .map(|x| x.offset)
-            .unwrap_or(self.block_meta_offset);
-        let roffset = self
-            .block_metas
-            .get(r)
-            .map(|x| x.offset)
-            .unwrap_or(self.block_meta_offset);
-        roffset - loffset
+        self.range_size(Bound::Included(lower), Bound::Included(upper))
     }
 
+    /// Estimate the size of data blocks in a range of keys like `overlap_size`, from the block
+    /// of `lower` to the block of `upper`. An unbounded end covers the data from the start or to
+    /// the end of the table.
+    pub fn range_size(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> usize {
+        let offset = |key: Bound<&[u8]>, end| match key {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .block_metas
+                .get(self.find_block_idx(key))
+                .map(|x| x.offset)
+                .unwrap_or(self.block_meta_offset),
+            Bound::Unbounded => end,
+        };
+        offset(upper, self.block_meta_offset).saturating_sub(offset(lower, 0))
+    }
+
+    /// Set the smallest and biggest key from the properties, or read them from the data blocks
+    /// if the table is written by an older version.
     pub fn init_samllest_biggest_key(&mut self) -> Result<()> {
-        self.smallest_key = self.block_metas[0].first_key.clone();
-        let last_block = self.read_block(self.num_of_blocks() - 1)?;
-        let mut iter = BlockIterator::create_and_seek_to_first(last_block);
-        iter.seek_to_last();
-        assert!(iter.is_valid());
-        self.biggest_key = Bytes::copy_from_slice(iter.key());
+        if let Some((smallest, biggest)) = self
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.key_range.clone())
+        {
+            self.smallest_key = smallest;
+            self.biggest_key = biggest;
+            return Ok(());
+        }
+
+        let mut range = None;
+        if let Some(meta) = self.block_metas.first() {
+            let last_block = self.read_block(self.num_of_blocks() - 1)?;
+            let mut iter = BlockIterator::create_and_seek_to_first(last_block);
+            iter.seek_to_last();
+            if !iter.is_valid() {
+                return Err(anyhow!("last block is empty"));
+            }
+            range = Some((meta.first_key.clone(), Bytes::copy_from_slice(iter.key())));
+        }
+        let (smallest, biggest) =
+            key_range(range, &self.range_tombstones).ok_or_else(|| anyhow!("no data block"))?;
+        self.smallest_key = smallest;
+        self.biggest_key = biggest;
         Ok(())
     }
 
-    /// Read a block from the disk.
+    /// Read a block from the disk, a table of only range tombstones has an empty block.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_metas[block_idx].offset;
+        if self.block_metas.is_empty() {
+            return Ok(Arc::new(Block::default()));
+        }
+        let meta = &self.block_metas[block_idx];
+        let offset = meta.offset;
         let end = self
             .block_metas
             .get(block_idx + 1)
             .map(|x| x.offset)
             .unwrap_or(self.block_meta_offset);
         let buf = self.file.read(offset, end - offset)?;
-        let block = Block::decode(&buf)?;
+        self.block_reads.fetch_add(1, Ordering::Relaxed);
+        let prefix = self.key_prefixes[meta.prefix_idx as usize].clone();
+        let block = Block::decode(&buf)?
+            .with_prefix(prefix)
+            .with_comparator(self.comparator.clone());
         Ok(Arc::new(block))
     }
 
+    /// Get the compress option of a block, which is the last byte of the encoded block.
+    #[cfg(test)]
+    pub(crate) fn block_compress_option(
+        &self,
+        block_idx: usize,
+    ) -> Result<crate::block::CompressOptions> {
+        let end = self
+            .block_metas
+            .get(block_idx + 1)
+            .map(|x| x.offset)
+            .unwrap_or(self.block_meta_offset);
+        let buf = self.file.read(end - 1, 1)?;
+        Ok(buf[0].into())
+    }
+
+    /// Get the offset of a block in the file.
+    #[cfg(test)]
+    pub(crate) fn block_offset(&self, block_idx: usize) -> usize {
+        self.block_metas[block_idx].offset
+    }
+
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
         if let Some(ref block_cache) = self.block_cache {
-            block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!(e))
+            block_cache.try_get_with((self.id, block_idx), || self.read_block(block_idx))
         } else {
             self.read_block(block_idx)
         }
@@ -177,10 +580,15 @@ impl SsTable {
     /// Find the block that may contain `key`.
     pub fn find_block_idx(&self, key: &[u8]) -> usize {
         self.block_metas
-            .partition_point(|x| x.first_key <= key)
+            .partition_point(|x| self.compare(&x.first_key, key).is_le())
             .saturating_sub(1)
     }
 
+    /// Get number of blocks read from disk.
+    pub fn block_reads(&self) -> usize {
+        self.block_reads.load(Ordering::Relaxed)
+    }
+
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
         self.block_metas.len()
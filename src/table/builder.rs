@@ -1,16 +1,23 @@
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
 use anyhow::{Ok, Result};
 use bytes::{BufMut, Bytes, BytesMut};
 
-use super::{BlockMeta, FileObject, SsTable};
-use crate::block::BlockBuilder;
+use super::{
+    encode_key_prefixes, key_range, BlockMeta, FileObject, SsTable, TableProperties,
+    HAS_KEY_PREFIXES, HAS_PROPERTIES, HAS_RANGE_TOMBSTONES,
+};
+use crate::block::{BlockBuilder, CompressOptions};
 
 use crate::block::SIZEOF_U16;
 use crate::bloom::Bloom;
 use crate::level::BlockCache;
 use crate::opt::LsmOptions;
+use crate::range_tombstone::RangeTombstone;
+use crate::rate_limiter::RateLimiter;
 
 /// Builds an SSTable from key-value pairs.
 #[derive(Debug)]
@@ -20,63 +27,183 @@ pub struct SsTableBuilder {
     // current block builder
     block_builder: BlockBuilder,
     base_key: Bytes,
+    // the last added key
+    last_key: Vec<u8>,
     pub opts: Arc<LsmOptions>,
+    compress_option: CompressOptions,
     key_hashs: Option<Vec<u64>>,
+    // hashes of distinct key prefixes of `opts.prefix_bloom_len` bytes
+    prefix_hashs: Option<Vec<u64>>,
+    // key prefixes shared by data blocks, the first one is always empty
+    key_prefixes: Vec<Bytes>,
+    key_prefix_idx: HashMap<Bytes, u16>,
+    range_tombstones: Vec<RangeTombstone>,
+    properties: TableProperties,
+    // the estimated size at which the table is full
+    capacity: usize,
+    // limits the bytes written by `build`
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
-const TABLE_CAPACITY: usize = 64 * 1024 * 1024;
+fn bloom_enabled(opts: &LsmOptions) -> bool {
+    opts.false_positive_rate > 0.0 && opts.false_positive_rate < 1.0
+}
 
 impl SsTableBuilder {
-    /// Create a builder based on target block size.
+    /// Create a builder based on target block size, which is full at `target_file_size_base`.
     pub fn new(opts: Arc<LsmOptions>) -> Self {
-        let key_hashs = if opts.false_positive_rate.is_sign_positive() {
+        let compress_option = opts.compress_option;
+        let capacity = opts.target_file_size_base;
+        Self::with_options(opts, compress_option, capacity)
+    }
+
+    /// Create a builder of a table in `level`, using the compress option and the target file
+    /// size of the level.
+    pub fn new_for_level(opts: Arc<LsmOptions>, level: usize) -> Self {
+        let compress_option = opts.compress_option_of_level(level);
+        let capacity = opts.target_file_size_of_level(level);
+        Self::with_options(opts, compress_option, capacity)
+    }
+
+    fn with_options(
+        opts: Arc<LsmOptions>,
+        compress_option: CompressOptions,
+        capacity: usize,
+    ) -> Self {
+        let key_hashs = if bloom_enabled(&opts) {
             Some(Vec::new())
         } else {
             None
         };
+        let prefix_hashs = match opts.prefix_bloom_len {
+            Some(_) if bloom_enabled(&opts) => Some(Vec::new()),
+            _ => None,
+        };
 
         Self {
             meta: vec![],
             data: BytesMut::new(),
-            block_builder: BlockBuilder::new(opts.block_size),
+            block_builder: BlockBuilder::with_restart_interval(
+                opts.block_size,
+                opts.block_restart_interval,
+            ),
             base_key: Bytes::new(),
+            last_key: vec![],
             opts,
+            compress_option,
             key_hashs,
+            prefix_hashs,
+            key_prefixes: vec![Bytes::new()],
+            key_prefix_idx: HashMap::new(),
+            range_tombstones: vec![],
+            properties: TableProperties::default(),
+            capacity,
+            rate_limiter: None,
         }
     }
 
-    /// Adds a key-value pair to SSTable
+    /// Adds a key-value pair to SSTable, an empty key is an error.
     pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.add_entry(key, Some(value), None, false)
+    }
+
+    /// Adds a key-value pair which expires at `expire_at` milliseconds since the UNIX epoch.
+    pub fn add_with_expiry(&mut self, key: &[u8], value: &[u8], expire_at: u64) -> Result<()> {
+        self.add_entry(key, Some(value), Some(expire_at), false)
+    }
+
+    /// Adds a tombstone of a deleted key to SSTable, which hides the key in older tables.
+    pub fn add_tombstone(&mut self, key: &[u8]) -> Result<()> {
+        self.add_entry(key, None, None, false)
+    }
+
+    /// Adds the encoded operands merged into a key, which are combined with the value of the key
+    /// in older tables, see [`crate::merge`].
+    pub fn add_merge(&mut self, key: &[u8], operands: &[u8]) -> Result<()> {
+        self.add_entry(key, Some(operands), None, true)
+    }
+
+    fn add_entry(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expire_at: Option<u64>,
+        merge: bool,
+    ) -> Result<()> {
         if self.base_key.is_empty() {
             self.base_key = Bytes::copy_from_slice(key);
         }
 
-        if !self.block_builder.add(key, value) {
+        let added = match (value, expire_at) {
+            (Some(operands), _) if merge => self.block_builder.add_merge(key, operands),
+            (Some(value), Some(expire_at)) => {
+                self.block_builder.add_with_expiry(key, value, expire_at)
+            }
+            (Some(value), None) => self.block_builder.add(key, value),
+            (None, _) => self.block_builder.add_tombstone(key),
+        }?;
+        if !added {
             self.block_build()?;
-            return self.add(key, value);
+            return self.add_entry(key, value, expire_at, merge);
         }
 
         if let Some(hs) = self.key_hashs.as_mut() {
             hs.push(xxhash_rust::xxh3::xxh3_64(key));
         }
+        if let (Some(hs), Some(len)) = (self.prefix_hashs.as_mut(), self.opts.prefix_bloom_len) {
+            // keys are sorted, so a prefix only needs to be hashed at its first key
+            if key.len() >= len && self.last_key.get(..len) != Some(&key[..len]) {
+                hs.push(xxhash_rust::xxh3::xxh3_64(&key[..len]));
+            }
+        }
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.properties.num_entries += 1;
+        self.properties.key_bytes += key.len() as u64;
+        match value {
+            Some(value) => self.properties.value_bytes += value.len() as u64,
+            None => self.properties.num_tombstones += 1,
+        }
 
         Ok(())
     }
 
+    /// Adds a range tombstone, which deletes keys of older tables but not keys of this table.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.range_tombstones.push(tombstone);
+    }
+
     fn block_build(&mut self) -> Result<()> {
         if self.block_builder.is_empty() {
             return Ok(());
         }
 
-        let mut builder = BlockBuilder::new(self.opts.block_size);
+        let mut builder = BlockBuilder::with_restart_interval(
+            self.opts.block_size,
+            self.opts.block_restart_interval,
+        );
         std::mem::swap(&mut self.block_builder, &mut builder);
 
-        let byte = builder.build().encode(self.opts.compress_option)?;
+        let (prefix_idx, block) = if self.opts.key_prefix_dict {
+            let prefix = Bytes::copy_from_slice(builder.common_prefix());
+            let prefix_idx = self.key_prefix_idx(prefix.clone());
+            let prefix_len = self.key_prefixes[prefix_idx as usize].len();
+            (prefix_idx, builder.build_strip_prefix(prefix_len)?)
+        } else {
+            (0, builder.build())
+        };
+        let byte = block.encode(
+            self.compress_option,
+            self.opts.compress_level,
+            self.opts.checksum_type,
+        )?;
         let mut key = Bytes::new();
         std::mem::swap(&mut key, &mut self.base_key);
 
         let meta = BlockMeta {
             offset: self.data.len(),
+            prefix_idx,
             first_key: key,
         };
         self.meta.push(meta);
@@ -84,13 +211,44 @@ impl SsTableBuilder {
         Ok(())
     }
 
+    /// Get the index of `prefix` in the key prefixes, adding it if needed.
+    ///
+    /// Returns the empty prefix when there are too many prefixes.
+    fn key_prefix_idx(&mut self, prefix: Bytes) -> u16 {
+        if prefix.is_empty() {
+            return 0;
+        }
+        if let Some(&idx) = self.key_prefix_idx.get(&prefix) {
+            return idx;
+        }
+        if self.key_prefixes.len() > u16::MAX as usize {
+            return 0;
+        }
+        let idx = self.key_prefixes.len() as u16;
+        self.key_prefixes.push(prefix.clone());
+        self.key_prefix_idx.insert(prefix, idx);
+        idx
+    }
+
+    /// Limit the bytes written to the file by [`SsTableBuilder::build`], which waits for the
+    /// tokens of each chunk before writing it.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// Check if no key or range tombstone has been added.
+    pub fn is_empty(&self) -> bool {
+        self.meta.is_empty() && self.block_builder.is_empty() && self.range_tombstones.is_empty()
+    }
+
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
         self.data.len() + self.meta.len() * SIZEOF_U16
     }
 
+    /// Check if the estimated size reaches the target file size of the table.
     pub fn reach_capacity(&self) -> bool {
-        self.estimated_size() >= TABLE_CAPACITY
+        self.estimated_size() >= self.capacity
     }
 
     /// Builds the SSTable and writes it to the given path.
@@ -101,32 +259,74 @@ impl SsTableBuilder {
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
         self.block_build()?;
+        let data_range = self
+            .meta
+            .first()
+            .map(|meta| (meta.first_key.clone(), Bytes::from(self.last_key.clone())));
+        let (smallest_key, biggest_key) = key_range(data_range, &self.range_tombstones)
+            .ok_or_else(|| anyhow::anyhow!("no data block"))?;
+        self.properties.key_range = Some((smallest_key.clone(), biggest_key.clone()));
+
+        // the optional sections are only written when they aren't empty
+        let has_key_prefixes = self.key_prefixes.len() > 1;
+        let has_range_tombstones = !self.range_tombstones.is_empty();
         let offset = self.data.len();
+        let mut flags = HAS_PROPERTIES;
+        let mut footer = vec![];
         let mut buf = vec![];
-        BlockMeta::encode_block_meta(&self.meta, &mut buf);
+        BlockMeta::encode_block_meta(&self.meta, has_key_prefixes, &mut buf);
+        if has_key_prefixes {
+            flags |= HAS_KEY_PREFIXES;
+            footer.push(offset + buf.len());
+            encode_key_prefixes(&self.key_prefixes, &mut buf);
+        }
+        if has_range_tombstones {
+            flags |= HAS_RANGE_TOMBSTONES;
+            footer.push(offset + buf.len());
+            RangeTombstone::encode(&self.range_tombstones, &mut buf);
+        }
+        footer.push(offset + buf.len());
+        self.properties.encode(&mut buf);
+        let prefix_bloom = self.build_prefix_bloom(&mut buf);
         self.data.put(buf.as_slice());
-        self.data.put_u32(offset as u32);
+        for section_offset in footer.into_iter().rev() {
+            self.data.put_u32(section_offset as u32);
+        }
+        self.data.put_u32(offset as u32 | flags);
 
         let mut bloom = None;
-        if self.opts.false_positive_rate.is_sign_positive() {
+        if bloom_enabled(&self.opts) {
             bloom = Some(self.build_bloom());
+        } else {
+            // empty bloom section
+            self.data.put_u32(self.data.len() as u32);
         }
 
-        let file = FileObject::create(path.as_ref(), &self.data, self.opts.o_direct)?;
-        let mut sst = SsTable {
+        let file = FileObject::create_rate_limited(
+            path.as_ref(),
+            &self.data,
+            self.opts.o_direct,
+            self.opts.use_mmap,
+            self.opts.checksum_type,
+            self.rate_limiter.as_deref(),
+        )?;
+        Ok(SsTable {
             id,
             size: file.size(),
             file,
             block_metas: self.meta,
             block_meta_offset: offset,
+            key_prefixes: self.key_prefixes,
+            range_tombstones: self.range_tombstones,
+            properties: Some(self.properties),
             block_cache,
-            smallest_key: Bytes::new(),
-            biggest_key: Bytes::new(),
+            smallest_key,
+            biggest_key,
             bloom,
-        };
-
-        sst.init_samllest_biggest_key()?;
-        Ok(sst)
+            prefix_bloom,
+            block_reads: AtomicUsize::new(0),
+            comparator: self.opts.comparator.clone(),
+        })
     }
 
     fn build_bloom(&mut self) -> Bloom {
@@ -140,6 +340,14 @@ impl SsTableBuilder {
         bloom
     }
 
+    fn build_prefix_bloom(&mut self, buf: &mut Vec<u8>) -> Option<(usize, Bloom)> {
+        let len = self.opts.prefix_bloom_len?;
+        let bloom = Bloom::from_keys(self.prefix_hashs.as_ref()?, self.opts.false_positive_rate);
+        buf.put_u16(len as u16);
+        buf.put(bloom.encode());
+        Some((len, bloom))
+    }
+
     #[cfg(test)]
     pub(crate) fn build_for_test(self, path: impl AsRef<Path>) -> Result<SsTable> {
         self.build(0, None, path)
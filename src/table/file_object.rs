@@ -1,28 +1,63 @@
 use anyhow::Result;
 use bytes::Buf;
+use memmap2::Mmap;
 use std::{
-    fs::{remove_file, File},
-    io::{Read, Write},
-    os::unix::prelude::{FileExt, OpenOptionsExt},
+    fs::{remove_file, rename, File},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::prelude::{AsRawFd, FileExt, OpenOptionsExt},
     path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
-use crate::checksum::{self, CHECKSUM_SIZE};
+use crate::checksum::{self, ChecksumType, CHECKSUM_SIZE};
+use crate::rate_limiter::RateLimiter;
+use crate::util::tmp_file_path;
+
+/// Size of the chunks read to verify the checksum, and written when rate limited.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Offsets, lengths and buffer addresses of `O_DIRECT` reads and writes are multiples of it, which
+/// covers both 512 and 4096 bytes sectors.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+// |data|checksum(u32)|checksum type(u8)|TRAILER_MAGIC(u32)|
+//
+// Files written before the checksum type was stored end with |data|crc32(u32)|, they are told
+// apart by the magic.
+const TRAILER_MAGIC: u32 = u32::from_be_bytes(*b"TPZF");
+const TRAILER_SIZE: usize = CHECKSUM_SIZE + 1 + 4;
 
 /// A file object.
 #[derive(Debug)]
 pub struct FileObject {
     fs: File,
+    // reads are served from the memory map if it's set
+    mmap: Option<Mmap>,
     size: usize,
+    // the file is opened with `O_DIRECT`, reads go through aligned buffers
+    o_direct: bool,
     file_name: PathBuf,
     remove_file: AtomicBool,
 }
 
 impl FileObject {
     pub fn read(&self, offset: usize, len: usize) -> Result<Vec<u8>> {
+        if let Some(mmap) = &self.mmap {
+            let end = offset
+                .checked_add(len)
+                .filter(|end| *end <= self.size)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("read {len} bytes at {offset}, file size {}", self.size)
+                })?;
+            return Ok(mmap[offset..end].to_vec());
+        }
         let mut buf = vec![0; len];
-        self.fs.read_exact_at(&mut buf, offset as u64)?;
+        if self.o_direct {
+            read_exact_direct_at(&self.fs, &mut buf, offset as u64)?;
+        } else {
+            self.fs.read_exact_at(&mut buf, offset as u64)?;
+        }
         Ok(buf)
     }
 
@@ -30,7 +65,27 @@ impl FileObject {
         self.size
     }
 
-    fn create_new(path: impl AsRef<Path>, data: &[u8], o_direct: bool) -> Result<()> {
+    /// Check if reads are served from a memory map.
+    pub fn is_mmap(&self) -> bool {
+        self.mmap.is_some()
+    }
+
+    /// Write the file to a temporary file and rename it to `path` once it's synced, so a crash
+    /// never leaves a partially written file at `path`.
+    fn create_new(
+        path: impl AsRef<Path>,
+        data: &[u8],
+        o_direct: bool,
+        checksum_type: ChecksumType,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(
+                io::Error::new(ErrorKind::AlreadyExists, format!("{path:?} exists")).into(),
+            );
+        }
+        let tmp_path = tmp_file_path(path);
         let mut op = File::options();
         op.create_new(true).write(true);
 
@@ -38,23 +93,98 @@ impl FileObject {
             op.custom_flags(libc::O_DIRECT | libc::O_SYNC);
         }
 
-        let mut fs = op.open(path)?;
-        // fs::write(): data may not actually be written to disk
-        fs.write_all(data)?;
-        let checksum = checksum::calculate_checksum(data).to_be_bytes();
-        fs.write_all(&checksum)?;
-        fs.flush()?;
+        let fs = op.open(&tmp_path)?;
+        if let Err(e) = Self::write_new(fs, data, o_direct, checksum_type, rate_limiter) {
+            let _ = remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        rename(&tmp_path, path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
         Ok(())
     }
 
-    /// Create a new file object and write the file to the disk .
-    pub fn create(path: impl AsRef<Path>, data: &[u8], o_direct: bool) -> Result<Self> {
-        Self::create_new(&path, data, o_direct)?;
-        Self::open(path, o_direct)
+    /// Write `data` and its trailer to `fs`, and sync it to the disk.
+    fn write_new(
+        mut fs: File,
+        data: &[u8],
+        o_direct: bool,
+        checksum_type: ChecksumType,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> io::Result<()> {
+        let mut trailer = Vec::with_capacity(TRAILER_SIZE);
+        trailer.extend_from_slice(&checksum_type.calculate(data).to_be_bytes());
+        trailer.push(checksum_type as u8);
+        trailer.extend_from_slice(&TRAILER_MAGIC.to_be_bytes());
+        if o_direct {
+            let mut writer = DirectWriter::new(fs);
+            write_chunks(data, rate_limiter, |chunk| writer.write_all(chunk))?;
+            writer.write_all(&trailer)?;
+            return writer.finish();
+        }
+        // fs::write(): data may not actually be written to disk
+        write_chunks(data, rate_limiter, |chunk| fs.write_all(chunk))?;
+        fs.write_all(&trailer)?;
+        fs.sync_all()
+    }
+
+    /// Create a new file object and write the file to the disk with a checksum of
+    /// `checksum_type`.
+    pub fn create(
+        path: impl AsRef<Path>,
+        data: &[u8],
+        o_direct: bool,
+        use_mmap: bool,
+        checksum_type: ChecksumType,
+    ) -> Result<Self> {
+        Self::create_rate_limited(path, data, o_direct, use_mmap, checksum_type, None)
+    }
+
+    /// Create a new file object like [`FileObject::create`], each chunk of `data` takes its
+    /// size of tokens from `rate_limiter` before it's written.
+    pub fn create_rate_limited(
+        path: impl AsRef<Path>,
+        data: &[u8],
+        o_direct: bool,
+        use_mmap: bool,
+        checksum_type: ChecksumType,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<Self> {
+        Self::create_new(&path, data, o_direct, checksum_type, rate_limiter)?;
+        Self::open(path, o_direct, use_mmap)
     }
 
     /// open file
-    pub fn open(path: impl AsRef<Path>, o_direct: bool) -> Result<Self> {
+    pub fn open(path: impl AsRef<Path>, o_direct: bool, use_mmap: bool) -> Result<Self> {
+        Self::open_with_retries(path, o_direct, use_mmap, 0, Duration::MAX)
+    }
+
+    /// Open file, transient read errors are retried at most `retries` times, and reading the file
+    /// to verify its checksum fails after `timeout`.
+    ///
+    /// Reads are served from a memory map of the file if `use_mmap` is set.
+    pub fn open_with_retries(
+        path: impl AsRef<Path>,
+        o_direct: bool,
+        use_mmap: bool,
+        retries: usize,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let deadline = Instant::now().checked_add(timeout);
+        Self::open_inner(&path, o_direct, use_mmap, retries, deadline)
+            .map_err(|e| anyhow::anyhow!("failed to open {}: {}", path.as_ref().display(), e))
+    }
+
+    fn open_inner(
+        path: impl AsRef<Path>,
+        o_direct: bool,
+        use_mmap: bool,
+        retries: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Self> {
         let mut op = File::options();
         op.read(true);
 
@@ -64,14 +194,58 @@ impl FileObject {
 
         let mut fs = op.open(&path)?;
         let size = fs.metadata()?.len() as usize;
-        let mut buf = Vec::with_capacity(size);
-        fs.read_to_end(&mut buf)?;
-        let expected = (&buf[size - CHECKSUM_SIZE..]).get_u32();
-        checksum::verify_checksum(&buf[..size - CHECKSUM_SIZE], expected)?;
+        if size < CHECKSUM_SIZE {
+            return Err(anyhow::anyhow!("file is too small: {size} bytes"));
+        }
+        let tail_size = size.min(TRAILER_SIZE);
+        let mut tail = vec![0; tail_size];
+        if o_direct {
+            read_exact_direct_at(&fs, &mut tail, (size - tail_size) as u64)?;
+        } else {
+            fs.read_exact_at(&mut tail, (size - tail_size) as u64)?;
+        }
+        let (data_size, checksum_type, expected) = decode_trailer(&tail, size)?;
+        let mmap = if use_mmap {
+            // SAFETY: sstables are immutable once written, and only removed after the file
+            // object is dropped.
+            let mmap = unsafe { Mmap::map(&fs)? };
+            if mmap.len() != size {
+                return Err(anyhow::anyhow!(
+                    "mapped {} bytes, expected {} bytes",
+                    mmap.len(),
+                    size
+                ));
+            }
+            checksum_type.verify(&mmap[..data_size], expected)?;
+            Some(mmap)
+        } else if o_direct {
+            let mut reader = DirectReader { fs: &fs, pos: 0 };
+            verify_file_checksum(
+                &mut reader,
+                data_size,
+                checksum_type,
+                expected,
+                retries,
+                deadline,
+            )?;
+            None
+        } else {
+            verify_file_checksum(
+                &mut fs,
+                data_size,
+                checksum_type,
+                expected,
+                retries,
+                deadline,
+            )?;
+            None
+        };
 
         Ok(Self {
             fs,
-            size: size - CHECKSUM_SIZE,
+            mmap,
+            size: data_size,
+            o_direct,
             file_name: path.as_ref().to_path_buf(),
             remove_file: AtomicBool::new(true),
         })
@@ -82,6 +256,214 @@ impl FileObject {
     }
 }
 
+/// Allocate a zeroed buffer of `len` bytes whose start is aligned for `O_DIRECT`, returns the
+/// allocation and the offset of the aligned buffer in it.
+fn aligned_buf(len: usize) -> (Vec<u8>, usize) {
+    let buf = vec![0; len + DIRECT_IO_ALIGN];
+    let shift = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    (buf, shift)
+}
+
+/// Read at most `buf.len()` bytes at `offset` from a file opened with `O_DIRECT`, through an
+/// aligned buffer covering the sectors of the range. Returns the number of bytes read, which is
+/// less than `buf.len()` only at the end of the file.
+fn read_direct_at(fs: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let align = DIRECT_IO_ALIGN as u64;
+    let start = offset / align * align;
+    let end = (offset + buf.len() as u64).div_ceil(align) * align;
+    let len = (end - start) as usize;
+    let (mut bounce, shift) = aligned_buf(len);
+    let bounce = &mut bounce[shift..shift + len];
+    let mut read = 0;
+    while read < len {
+        match fs.read_at(&mut bounce[read..], start + read as u64) {
+            Ok(0) => break,
+            // only the end of the file isn't aligned
+            Ok(n) if n % DIRECT_IO_ALIGN != 0 => {
+                read += n;
+                break;
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let skip = (offset - start) as usize;
+    let n = read.saturating_sub(skip).min(buf.len());
+    buf[..n].copy_from_slice(&bounce[skip..skip + n]);
+    Ok(n)
+}
+
+fn read_exact_direct_at(fs: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    if read_direct_at(fs, buf, offset)? < buf.len() {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "failed to fill whole buffer",
+        ));
+    }
+    Ok(())
+}
+
+/// Read a file opened with `O_DIRECT` sequentially.
+struct DirectReader<'a> {
+    fs: &'a File,
+    pos: u64,
+}
+
+impl Read for DirectReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_direct_at(self.fs, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Write `data` in chunks of `CHUNK_SIZE`, taking tokens of `rate_limiter` for each of them.
+fn write_chunks(
+    data: &[u8],
+    rate_limiter: Option<&RateLimiter>,
+    mut write: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    for chunk in data.chunks(CHUNK_SIZE) {
+        if let Some(limiter) = rate_limiter {
+            limiter.request(chunk.len());
+        }
+        write(chunk)?;
+    }
+    Ok(())
+}
+
+/// Write a file opened with `O_DIRECT` by aligned chunks. The last bytes short of a sector are
+/// written after clearing `O_DIRECT` of the file.
+struct DirectWriter {
+    fs: File,
+    buf: Vec<u8>,
+    shift: usize,
+    len: usize,
+}
+
+impl DirectWriter {
+    fn new(fs: File) -> Self {
+        let (buf, shift) = aligned_buf(CHUNK_SIZE);
+        Self {
+            fs,
+            buf,
+            shift,
+            len: 0,
+        }
+    }
+
+    fn write_all(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let n = data.len().min(CHUNK_SIZE - self.len);
+            let start = self.shift + self.len;
+            self.buf[start..start + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == CHUNK_SIZE {
+                self.fs
+                    .write_all(&self.buf[self.shift..self.shift + CHUNK_SIZE])?;
+                self.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let aligned = self.len / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN;
+        let buf = &self.buf[self.shift..self.shift + self.len];
+        self.fs.write_all(&buf[..aligned])?;
+        if aligned < buf.len() {
+            let fd = self.fs.as_raw_fd();
+            // SAFETY: `fd` is a valid file descriptor owned by `self.fs`.
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.fs.write_all(&buf[aligned..])?;
+        }
+        self.fs.flush()
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+}
+
+/// Fill `buf`, transient errors are retried at most `retries` times in total, and it fails once
+/// `deadline` has passed. The bytes read before an error are kept in `buf`, so the retry
+/// continues from there.
+fn read_exact_with_retries(
+    reader: &mut impl Read,
+    mut buf: &mut [u8],
+    retries: &mut usize,
+    deadline: Option<Instant>,
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "read timed out"));
+        }
+        match reader.read(buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "file is truncated",
+                ))
+            }
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if is_transient(&e) && *retries > 0 => *retries -= 1,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Decode the trailer from the last bytes of a file of `size` bytes.
+///
+/// Returns the size of data, the checksum type and the checksum.
+fn decode_trailer(tail: &[u8], size: usize) -> Result<(usize, ChecksumType, u32)> {
+    if tail.len() == TRAILER_SIZE && (&tail[TRAILER_SIZE - 4..]).get_u32() == TRAILER_MAGIC {
+        let checksum_type = ChecksumType::try_from(tail[CHECKSUM_SIZE])?;
+        let checksum = (&tail[..CHECKSUM_SIZE]).get_u32();
+        return Ok((size - TRAILER_SIZE, checksum_type, checksum));
+    }
+    let checksum = (&tail[tail.len() - CHECKSUM_SIZE..]).get_u32();
+    Ok((size - CHECKSUM_SIZE, ChecksumType::Crc32, checksum))
+}
+
+/// Verify the checksum of the first `size` bytes of a file, reading them by chunks.
+fn verify_file_checksum(
+    reader: &mut impl Read,
+    size: usize,
+    checksum_type: ChecksumType,
+    expected: u32,
+    mut retries: usize,
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let mut hasher = checksum_type.hasher();
+    let mut buf = vec![0; CHUNK_SIZE.min(size)];
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(CHUNK_SIZE)];
+        read_exact_with_retries(reader, chunk, &mut retries, deadline)?;
+        hasher.update(chunk);
+        remaining -= chunk.len();
+    }
+    checksum::compare_checksum(hasher.finalize(), expected)
+}
+
+/// Check if files in `dir` can be opened with `O_DIRECT`, tmpfs may not support it.
+#[cfg(test)]
+pub(crate) fn supports_o_direct(dir: &Path) -> bool {
+    File::options()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(dir.join("o_direct"))
+        .is_ok()
+}
+
 impl Drop for FileObject {
     fn drop(&mut self) {
         if self.remove_file.load(Ordering::Relaxed) {
@@ -92,16 +474,71 @@ impl Drop for FileObject {
 
 #[cfg(test)]
 mod test {
+    use std::fs::{self, File};
+    use std::io::{self, ErrorKind, Read};
+    use std::time::{Duration, Instant};
+
     use tempfile::tempdir;
 
-    use super::FileObject;
+    use super::{supports_o_direct, verify_file_checksum, FileObject, CHUNK_SIZE};
+    use crate::checksum::{calculate_checksum, ChecksumType, CHECKSUM_SIZE};
+    use crate::rate_limiter::RateLimiter;
+    use crate::util::tmp_file_path;
+
+    /// A reader returning the injected errors before each chunk of data.
+    struct FaultyReader {
+        data: Vec<u8>,
+        pos: usize,
+        faults: Vec<ErrorKind>,
+    }
+
+    impl Read for FaultyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(kind) = self.faults.pop() {
+                return Err(io::Error::new(kind, "injected fault"));
+            }
+            // read a few bytes at a time, so faults happen in the middle of the data
+            let len = buf.len().min(self.data.len() - self.pos).min(3);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            if len > 0 {
+                self.faults.push(ErrorKind::WouldBlock);
+            }
+            Ok(len)
+        }
+    }
 
     #[test]
     fn create_test() {
         let dir = tempdir().unwrap();
         let path = dir.path().join("1.sst");
         let data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let obj = FileObject::create(path, &data, false).unwrap();
+        let obj = FileObject::create(path, &data, false, false, ChecksumType::Crc32).unwrap();
+    }
+
+    #[test]
+    fn create_atomically_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let obj = FileObject::create(&path, &data, false, false, ChecksumType::Crc32).unwrap();
+        // written to a temporary file which is renamed
+        assert!(path.exists());
+        assert!(!tmp_file_path(&path).exists());
+
+        // an existing file is not replaced
+        let err = FileObject::create(&path, &[1], false, false, ChecksumType::Crc32).unwrap_err();
+        let err = err.downcast::<io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(!tmp_file_path(&path).exists());
+        assert_eq!(obj.read(0, data.len()).unwrap(), data);
+
+        // a temporary file left by a crash fails the write without touching it
+        let path = dir.path().join("2.sst");
+        fs::write(tmp_file_path(&path), b"partial").unwrap();
+        assert!(FileObject::create(&path, &data, false, false, ChecksumType::Crc32).is_err());
+        assert!(!path.exists());
+        assert_eq!(fs::read(tmp_file_path(&path)).unwrap(), b"partial");
     }
 
     #[test]
@@ -109,11 +546,213 @@ mod test {
         let dir = tempdir().unwrap();
         let path = dir.path().join("1.sst");
         let data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let obj = FileObject::create(&path, &data, false).unwrap();
+        let obj = FileObject::create(&path, &data, false, false, ChecksumType::Crc32).unwrap();
         obj.save();
         drop(obj);
-        let obj = FileObject::open(path, false).unwrap();
+        let obj = FileObject::open(&path, false, false).unwrap();
         let data_read = obj.read(0, data.len()).unwrap();
         assert_eq!(data, data_read);
+        obj.save();
+        drop(obj);
+
+        let obj = FileObject::open(path, false, true).unwrap();
+        assert_eq!(obj.read(0, data.len()).unwrap(), data);
+        assert_eq!(obj.read(3, 4).unwrap(), data[3..7]);
+        assert!(obj.read(8, 4).is_err());
+    }
+
+    #[test]
+    fn read_retry_test() {
+        let data = (0..10).collect::<Vec<u8>>();
+        let size = data.len();
+        let checksum = calculate_checksum(&data);
+        let crc32 = ChecksumType::Crc32;
+        let mut reader = FaultyReader {
+            data: data.clone(),
+            pos: 0,
+            faults: vec![ErrorKind::Interrupted, ErrorKind::WouldBlock],
+        };
+        verify_file_checksum(&mut reader, size, crc32, checksum, 10, None).unwrap();
+
+        let mut reader = FaultyReader {
+            data: data.clone(),
+            pos: 0,
+            faults: vec![ErrorKind::WouldBlock],
+        };
+        let err = verify_file_checksum(&mut reader, size, crc32, checksum, 0, None).unwrap_err();
+        let err = err.downcast::<io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        let mut reader = FaultyReader {
+            data: data.clone(),
+            pos: 0,
+            faults: vec![ErrorKind::PermissionDenied],
+        };
+        let err = verify_file_checksum(&mut reader, size, crc32, checksum, 10, None).unwrap_err();
+        let err = err.downcast::<io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        // a stalled read gives up at the deadline however many retries are left
+        let mut reader = FaultyReader {
+            data: data.clone(),
+            pos: 0,
+            faults: vec![ErrorKind::WouldBlock],
+        };
+        let deadline = Some(Instant::now());
+        let err = verify_file_checksum(&mut reader, size, crc32, checksum, usize::MAX, deadline)
+            .unwrap_err();
+        let err = err.downcast::<io::Error>().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let mut reader = FaultyReader {
+            data,
+            pos: 0,
+            faults: vec![],
+        };
+        assert!(verify_file_checksum(&mut reader, size, crc32, checksum ^ 1, 10, None).is_err());
+    }
+
+    #[test]
+    fn checksum_type_test() {
+        let dir = tempdir().unwrap();
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        for (i, checksum_type) in [ChecksumType::Crc32, ChecksumType::XxHash3]
+            .into_iter()
+            .enumerate()
+        {
+            let path = dir.path().join(format!("{i}.sst"));
+            let obj = FileObject::create(&path, &data, false, false, checksum_type).unwrap();
+            obj.save();
+            drop(obj);
+            for use_mmap in [false, true] {
+                let obj = FileObject::open(&path, false, use_mmap).unwrap();
+                assert_eq!(obj.size(), data.len());
+                assert_eq!(obj.read(0, data.len()).unwrap(), data);
+                obj.save();
+            }
+
+            // a corrupted byte fails the checksum
+            let mut file = fs::read(&path).unwrap();
+            file[0] ^= 1;
+            fs::write(&path, &file).unwrap();
+            assert!(FileObject::open(&path, false, false).is_err());
+            assert!(FileObject::open(&path, false, true).is_err());
+            // verified with the other type
+            file[0] ^= 1;
+            file[data.len() + CHECKSUM_SIZE] ^= 1;
+            fs::write(&path, &file).unwrap();
+            assert!(FileObject::open(&path, false, false).is_err());
+        }
+
+        // files written before the checksum type was stored
+        let path = dir.path().join("legacy.sst");
+        let mut file = data.clone();
+        file.extend_from_slice(&calculate_checksum(&data).to_be_bytes());
+        fs::write(&path, &file).unwrap();
+        let obj = FileObject::open(&path, false, false).unwrap();
+        assert_eq!(obj.size(), data.len());
+        assert_eq!(obj.read(0, data.len()).unwrap(), data);
+    }
+
+    /// A reader recording the largest buffer it's asked to fill.
+    struct TrackingReader<R> {
+        inner: R,
+        max_read: usize,
+    }
+
+    impl<R: Read> Read for TrackingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.max_read = self.max_read.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn open_large_file_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let data = (0..8 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let obj = FileObject::create(&path, &data, false, false, ChecksumType::Crc32).unwrap();
+        assert_eq!(obj.size(), data.len());
+        obj.save();
+        drop(obj);
+
+        // the file is checksummed by chunks instead of read as a whole
+        let mut reader = TrackingReader {
+            inner: File::open(&path).unwrap(),
+            max_read: 0,
+        };
+        let checksum = calculate_checksum(&data);
+        verify_file_checksum(
+            &mut reader,
+            data.len(),
+            ChecksumType::Crc32,
+            checksum,
+            0,
+            None,
+        )
+        .unwrap();
+        assert!(reader.max_read <= CHUNK_SIZE, "{}", reader.max_read);
+        let obj = FileObject::open(&path, false, false).unwrap();
+        assert_eq!(
+            obj.read(data.len() - 10, 10).unwrap(),
+            data[data.len() - 10..]
+        );
+    }
+
+    #[test]
+    fn o_direct_test() {
+        let dir = tempdir().unwrap();
+        if !supports_o_direct(dir.path()) {
+            return;
+        }
+        // neither the data nor the file is a multiple of sectors
+        for size in [10, 4096, 10000, 3 * CHUNK_SIZE + 1] {
+            let path = dir.path().join(format!("{size}.sst"));
+            let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let obj = FileObject::create(&path, &data, true, false, ChecksumType::Crc32).unwrap();
+            assert_eq!(obj.read(0, size).unwrap(), data);
+            obj.save();
+            drop(obj);
+            assert_eq!(fs::read(&path).unwrap()[..size], data);
+
+            let obj = FileObject::open(&path, true, false).unwrap();
+            assert_eq!(obj.size(), size);
+            assert_eq!(obj.read(0, size).unwrap(), data);
+            assert_eq!(obj.read(3, 4).unwrap(), data[3..7]);
+            assert_eq!(obj.read(size - 7, 7).unwrap(), data[size - 7..]);
+            if size > 4096 {
+                assert_eq!(obj.read(4000, 200).unwrap(), data[4000..4200]);
+            }
+            assert!(obj.read(size, 4096).is_err());
+        }
+    }
+
+    #[test]
+    fn create_rate_limited_test() {
+        let dir = tempdir().unwrap();
+        let limiter = RateLimiter::new(8 * CHUNK_SIZE);
+        let data = vec![1; 2 * CHUNK_SIZE];
+        let start = Instant::now();
+        let obj = FileObject::create_rate_limited(
+            dir.path().join("1.sst"),
+            &data,
+            false,
+            false,
+            ChecksumType::Crc32,
+            Some(&limiter),
+        )
+        .unwrap();
+        // the bucket starts empty, each chunk waits for its tokens
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(obj.read(0, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn open_error_path_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        let err = FileObject::open_with_retries(&path, false, false, 3, Duration::MAX).unwrap_err();
+        assert!(err.to_string().contains(&path.display().to_string()));
     }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use anyhow::{Ok, Result};
 
 use super::SsTable;
-use crate::{block::BlockIterator, iterators::StorageIterator};
+use crate::{block::BlockIterator, iterators::StorageIterator, opt::Comparator};
 
 /// An iterator over the contents of an SSTable.
 #[derive(Debug)]
@@ -26,13 +26,18 @@ impl SsTableIterator {
 
     /// Seek to the first key-value pair.
     pub fn seek_to_first(&mut self) -> Result<()> {
-        // if self.idx == 0 {
-        //     self.block_iter.seek_to_first();
-        //     return Ok(());
-        // }
+        self.load_block(0)?;
+        self.block_iter.seek_to_first();
+        Ok(())
+    }
 
-        self.idx = 0;
-        self.block_iter = Self::seek_to_first_inner(self.table.clone(), 0)?;
+    /// Move to block `idx`, which is only read if it's not the current block. The block iterator
+    /// is left at an arbitrary position.
+    fn load_block(&mut self, idx: usize) -> Result<()> {
+        if idx != self.idx {
+            self.block_iter = Self::seek_to_first_inner(self.table.clone(), idx)?;
+            self.idx = idx;
+        }
         Ok(())
     }
 
@@ -63,11 +68,56 @@ impl SsTableIterator {
         Ok((idx, block_iter))
     }
 
+    /// Create a new iterator and seek to the last key-value pair.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let idx = table.num_of_blocks().saturating_sub(1);
+        let block_iter = Self::seek_to_last_inner(table.clone(), idx)?;
+        Ok(Self {
+            block_iter,
+            table,
+            idx,
+        })
+    }
+
+    /// Seek to the last key-value pair.
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        self.load_block(self.table.num_of_blocks().saturating_sub(1))?;
+        self.block_iter.seek_to_last();
+        Ok(())
+    }
+
+    fn seek_to_last_inner(table: Arc<SsTable>, idx: usize) -> Result<BlockIterator> {
+        let block = table.read_block_cached(idx)?;
+        Ok(BlockIterator::create_and_seek_to_last(block))
+    }
+
+    /// Create a new iterator and seek to the last key-value pair which <= `key`.
+    pub fn create_and_seek_to_key_rev(table: Arc<SsTable>, key: &[u8]) -> Result<Self> {
+        let mut idx = table.find_block_idx(key);
+        let block = table.read_block_cached(idx)?;
+        let mut block_iter = BlockIterator::create_and_seek_to_key_rev(block, key);
+        // only happens when `key` is smaller than the first key of the table
+        if !block_iter.is_valid() && idx > 0 {
+            idx -= 1;
+            block_iter = Self::seek_to_last_inner(table.clone(), idx)?;
+        }
+
+        Ok(Self {
+            block_iter,
+            table,
+            idx,
+        })
+    }
+
     /// Seek to the first key-value pair which >= `key`.
     pub fn seek_to_key(&mut self, key: &[u8]) -> Result<()> {
-        let (idx, block_iter) = Self::seek_to_key_inner(self.table.clone(), key)?;
-        self.idx = idx;
-        self.block_iter = block_iter;
+        let idx = self.table.find_block_idx(key);
+        self.load_block(idx)?;
+        self.block_iter.seek_to_key(key);
+        if !self.block_iter.is_valid() && idx + 1 < self.table.num_of_blocks() {
+            self.load_block(idx + 1)?;
+            self.block_iter.seek_to_first();
+        }
         Ok(())
     }
 }
@@ -81,16 +131,48 @@ impl StorageIterator for SsTableIterator {
         self.block_iter.key()
     }
 
+    fn is_tombstone(&self) -> bool {
+        self.block_iter.is_tombstone()
+    }
+
+    fn is_merge(&self) -> bool {
+        self.block_iter.is_merge()
+    }
+
+    fn expire_at(&self) -> Option<u64> {
+        self.block_iter.expire_at()
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.table.comparator()
+    }
+
     fn is_valid(&self) -> bool {
         self.block_iter.is_valid()
     }
 
     fn next(&mut self) -> Result<()> {
         self.block_iter.next();
-        if !self.block_iter.is_valid() && self.idx < self.table.num_of_blocks() - 1 {
+        if !self.block_iter.is_valid() && self.idx + 1 < self.table.num_of_blocks() {
             self.idx += 1;
             self.block_iter = Self::seek_to_first_inner(self.table.clone(), self.idx)?;
         }
         Ok(())
     }
+
+    fn prev(&mut self) -> Result<()> {
+        if !self.block_iter.is_valid() {
+            return Ok(());
+        }
+        self.block_iter.prev();
+        if !self.block_iter.is_valid() && self.idx > 0 {
+            self.idx -= 1;
+            self.block_iter = Self::seek_to_last_inner(self.table.clone(), self.idx)?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.seek_to_key(key)
+    }
 }
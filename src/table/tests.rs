@@ -4,6 +4,8 @@ use bytes::Bytes;
 use tempfile::{tempdir, TempDir};
 
 use super::*;
+use crate::block::CompressOptions;
+use crate::checksum::ChecksumType;
 use crate::iterators::StorageIterator;
 use crate::opt::LsmOptions;
 use crate::table::SsTableBuilder;
@@ -30,6 +32,25 @@ fn test_sst_build_two_blocks() {
     builder.build_for_test(dir.path().join("1.sst")).unwrap();
 }
 
+#[test]
+fn test_sst_build_empty_key() {
+    let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(16).into());
+    builder.add(b"11", b"11").unwrap();
+    // the block is full, the key is rejected before a new block is started
+    let err = builder.add(b"", b"22").unwrap_err();
+    assert_eq!(err.to_string(), "key must not be empty");
+    assert!(builder.add_tombstone(b"").is_err());
+    builder.add(b"22", b"22").unwrap();
+    let dir = tempdir().unwrap();
+    let table = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    let mut iter = SsTableIterator::create_and_seek_to_first(table).unwrap();
+    for key in [b"11", b"22"] {
+        assert_eq!(iter.key(), key);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
 fn key_of(idx: usize) -> Vec<u8> {
     format!("key_{:03}", idx * 5).into_bytes()
 }
@@ -137,6 +158,110 @@ fn test_sst_seek_key() {
     }
 }
 
+#[test]
+fn test_sst_seek_in_current_block() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    assert!(sst.num_of_blocks() > 2);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    assert_eq!(sst.block_reads(), 1);
+    // keys of the first block
+    let mut keys = vec![];
+    while sst.find_block_idx(&key_of(keys.len())) == 0 {
+        keys.push(key_of(keys.len()));
+    }
+    for _ in 0..3 {
+        for key in keys.iter().rev() {
+            iter.seek_to_key(key).unwrap();
+            assert_eq!(iter.key(), key);
+        }
+        iter.seek_to_first().unwrap();
+        assert_eq!(iter.key(), key_of(0));
+    }
+    assert_eq!(sst.block_reads(), 1);
+
+    // the block is read again after moving to another one
+    iter.seek_to_key(&key_of(num_of_keys() - 1)).unwrap();
+    assert_eq!(iter.key(), key_of(num_of_keys() - 1));
+    iter.seek_to_last().unwrap();
+    assert_eq!(iter.key(), key_of(num_of_keys() - 1));
+    assert_eq!(sst.block_reads(), 2);
+    iter.seek_to_key(&key_of(1)).unwrap();
+    for idx in 1..num_of_keys() {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert_eq!(sst.block_reads(), 2 + sst.num_of_blocks());
+}
+
+#[test]
+fn test_sst_iterator_rev() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst.clone()).unwrap();
+    for i in (0..num_of_keys()).rev() {
+        let key = iter.key();
+        let value = iter.value();
+        assert_eq!(
+            key,
+            key_of(i),
+            "expected key: {:?}, actual key: {:?}",
+            as_bytes(&key_of(i)),
+            as_bytes(key)
+        );
+        assert_eq!(
+            value,
+            value_of(i),
+            "expected value: {:?}, actual value: {:?}",
+            as_bytes(&value_of(i)),
+            as_bytes(value)
+        );
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_seek_to_last() {
+    let (_dir, sst) = generate_sst();
+    assert!(sst.num_of_blocks() > 1);
+    let sst = Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for _ in 0..2 {
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.key(), sst.biggest_key);
+        for i in (0..num_of_keys()).rev() {
+            assert_eq!(iter.key(), key_of(i));
+            assert_eq!(iter.value(), value_of(i));
+            iter.prev().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
+#[test]
+fn test_sst_seek_key_rev() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(sst);
+    for offset in 0..5 {
+        for i in 0..num_of_keys() {
+            let key = format!("key_{:03}", i * 5 + offset).into_bytes();
+            let iter = SsTableIterator::create_and_seek_to_key_rev(sst.clone(), &key).unwrap();
+            assert_eq!(
+                iter.key(),
+                key_of(i),
+                "expected key: {:?}, actual key: {:?}",
+                as_bytes(&key_of(i)),
+                as_bytes(iter.key())
+            );
+            assert_eq!(iter.value(), value_of(i));
+        }
+    }
+    let iter = SsTableIterator::create_and_seek_to_key_rev(sst, b"k").unwrap();
+    assert!(!iter.is_valid());
+}
+
 #[test]
 fn test_sst_bloom() {
     let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(16).into());
@@ -153,3 +278,300 @@ fn test_sst_bloom() {
     assert!(!sst.may_contain(b"55"));
     assert!(!sst.may_contain(b"66"));
 }
+
+#[test]
+fn test_sst_prefix_bloom() {
+    let mut opts = LsmOptions::default().block_size(16);
+    opts.prefix_bloom_len = Some(3);
+    let mut builder = SsTableBuilder::new(opts.into());
+    for key in ["aa", "ab_1", "ab_2", "ab_3", "cd_1", "ef_1"] {
+        builder.add(key.as_bytes(), b"v").unwrap();
+    }
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let check = |sst: &SsTable| {
+        assert!(sst.may_contain_prefix(b"ab_"));
+        assert!(sst.may_contain_prefix(b"cd_1"));
+        assert!(sst.may_contain_prefix(b"ef_"));
+        assert!(!sst.may_contain_prefix(b"gh_"));
+        assert!(!sst.may_contain_prefix(b"xy_1"));
+        // shorter prefixes can't be checked
+        assert!(sst.may_contain_prefix(b"x"));
+    };
+    check(&sst);
+    let new_sst = SsTable::open(0, None, sst.file).unwrap();
+    assert_eq!(new_sst.prefix_bloom, sst.prefix_bloom);
+    check(&new_sst);
+
+    // range tombstones may delete keys of any prefix in older tables
+    let opts = LsmOptions {
+        prefix_bloom_len: Some(3),
+        ..Default::default()
+    };
+    let mut builder = SsTableBuilder::new(opts.into());
+    builder.add(b"ab_1", b"v").unwrap();
+    builder.add_range_tombstone(RangeTombstone::new(&b"a"[..], &b"z"[..]));
+    let sst = builder.build_for_test(dir.path().join("2.sst")).unwrap();
+    assert!(sst.may_contain_prefix(b"gh_"));
+}
+
+#[test]
+fn test_sst_overlaps() {
+    let (_dir, sst) = generate_sst();
+    let (smallest, biggest) = sst.key_range();
+    assert_eq!(smallest, &key_of(0));
+    assert_eq!(biggest, &key_of(num_of_keys() - 1));
+
+    assert!(sst.overlaps(smallest, biggest));
+    assert!(sst.overlaps(b"a", smallest));
+    assert!(sst.overlaps(biggest, b"z"));
+    assert!(sst.overlaps(b"a", b"z"));
+    assert!(sst.overlaps(&key_of(10), &key_of(10)));
+    assert!(!sst.overlaps(b"a", b"key_"));
+    assert!(!sst.overlaps(b"key_999", b"z"));
+}
+
+#[test]
+fn test_sst_without_bloom() {
+    let mut opts = LsmOptions::default().block_size(16);
+    opts.false_positive_rate = 0.0;
+    let mut builder = SsTableBuilder::new(opts.into());
+    builder.add(b"11", b"11").unwrap();
+    builder.add(b"22", b"22").unwrap();
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    assert!(sst.bloom.is_none());
+    let new_sst = SsTable::open(0, None, sst.file).unwrap();
+    assert!(new_sst.bloom.is_none());
+    assert_eq!(new_sst.block_metas, sst.block_metas);
+}
+
+#[test]
+fn test_sst_open_corrupted_footer() {
+    let (dir, sst) = generate_sst();
+    let mut data = sst.file.read(0, sst.file.size()).unwrap();
+    let len = data.len();
+    data[len - 4..].copy_from_slice(&u32::MAX.to_be_bytes());
+    let file = FileObject::create(
+        dir.path().join("2.sst"),
+        &data,
+        false,
+        false,
+        ChecksumType::Crc32,
+    )
+    .unwrap();
+    let err = SsTable::open(2, None, file).unwrap_err();
+    assert!(err.to_string().contains("sstable 2"), "{err}");
+
+    data.truncate(3);
+    let file = FileObject::create(
+        dir.path().join("3.sst"),
+        &data,
+        false,
+        false,
+        ChecksumType::Crc32,
+    )
+    .unwrap();
+    assert!(SsTable::open(3, None, file).is_err());
+}
+
+#[test]
+fn test_sst_o_direct() {
+    let dir = tempdir().unwrap();
+    if !super::file_object::supports_o_direct(dir.path()) {
+        return;
+    }
+    let mut opts = LsmOptions::default().block_size(128);
+    opts.o_direct = true;
+    let mut builder = SsTableBuilder::new(opts.into());
+    for idx in 0..num_of_keys() {
+        builder.add(&key_of(idx), &value_of(idx)).unwrap();
+    }
+    let path = dir.path().join("1.sst");
+    let sst = builder.build(1, None, &path).unwrap();
+    sst.file.save();
+    drop(sst);
+
+    let file = FileObject::open(&path, true, false).unwrap();
+    let sst = Arc::new(SsTable::open(1, None, file).unwrap());
+    assert!(sst.num_of_blocks() > 1);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for idx in 0..num_of_keys() {
+        assert_eq!(iter.key(), key_of(idx));
+        assert_eq!(iter.value(), value_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert_eq!(sst.block_reads(), sst.num_of_blocks());
+}
+
+#[test]
+fn test_sst_key_prefix_dict() {
+    let dir = tempdir().unwrap();
+    let key_of = |idx: usize| format!("a/very/long/shared/key/prefix/{:02}/{:04}", idx / 100, idx);
+    let build = |key_prefix_dict: bool, name: &str| {
+        let mut opts = LsmOptions::default().block_size(512);
+        opts.compress_option = CompressOptions::Uncompress;
+        opts.key_prefix_dict = key_prefix_dict;
+        let mut builder = SsTableBuilder::new(opts.into());
+        for idx in 0..1000 {
+            builder.add(key_of(idx).as_bytes(), &value_of(idx)).unwrap();
+        }
+        builder.build_for_test(dir.path().join(name)).unwrap()
+    };
+    let plain = build(false, "1.sst");
+    let sst = build(true, "2.sst");
+    assert!(sst.size < plain.size, "{} >= {}", sst.size, plain.size);
+    // blocks share the prefixes
+    assert!(sst.key_prefixes.len() < sst.num_of_blocks());
+
+    let sst = Arc::new(SsTable::open(2, None, sst.file).unwrap());
+    let mut plain_iter = SsTableIterator::create_and_seek_to_first(Arc::new(plain)).unwrap();
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    while plain_iter.is_valid() {
+        assert_eq!(iter.key(), plain_iter.key());
+        assert_eq!(iter.value(), plain_iter.value());
+        plain_iter.next().unwrap();
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    for idx in (0..1000).step_by(37) {
+        let iter =
+            SsTableIterator::create_and_seek_to_key(sst.clone(), key_of(idx).as_bytes()).unwrap();
+        assert_eq!(iter.key(), key_of(idx).as_bytes());
+        assert_eq!(iter.value(), value_of(idx));
+    }
+    let iter = SsTableIterator::create_and_seek_to_key(sst.clone(), b"a").unwrap();
+    assert_eq!(iter.key(), key_of(0).as_bytes());
+    let iter = SsTableIterator::create_and_seek_to_key(sst, b"b").unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_sst_num_entries() {
+    let mut opts = LsmOptions::default().block_size(128);
+    opts.false_positive_rate = 0.0;
+    let mut builder = SsTableBuilder::new(opts.into());
+    for idx in 0..num_of_keys() {
+        if idx % 10 == 0 {
+            builder.add_tombstone(&key_of(idx)).unwrap();
+        } else {
+            builder.add(&key_of(idx), &value_of(idx)).unwrap();
+        }
+    }
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("1.sst")).unwrap();
+    let expected = TableProperties {
+        num_entries: num_of_keys() as u64,
+        num_tombstones: num_of_keys() as u64 / 10,
+        key_bytes: (0..num_of_keys()).map(|idx| key_of(idx).len() as u64).sum(),
+        value_bytes: (0..num_of_keys())
+            .filter(|idx| idx % 10 != 0)
+            .map(|idx| value_of(idx).len() as u64)
+            .sum(),
+        key_range: Some((
+            Bytes::from(key_of(0)),
+            Bytes::from(key_of(num_of_keys() - 1)),
+        )),
+    };
+    assert_eq!(sst.num_entries(), Some(num_of_keys() as u64));
+    assert_eq!(sst.properties(), Some(&expected));
+
+    let data = sst.file.read(0, sst.file.size()).unwrap();
+    let sst = SsTable::open(1, None, sst.file).unwrap();
+    assert_eq!(sst.properties(), Some(&expected));
+
+    // a table written before the optional sections existed, without the bloom filter
+    let footer = &data[data.len() - 4 * 3..data.len() - 4];
+    let properties_offset = (&footer[..4]).get_u32() as usize;
+    let meta_offset = (&footer[4..]).get_u32();
+    assert_eq!(meta_offset & !HAS_PROPERTIES, sst.block_meta_offset as u32);
+    let mut old = data[..properties_offset].to_vec();
+    old.put_u32(meta_offset & !HAS_PROPERTIES);
+    old.put_u32(old.len() as u32);
+    let file = FileObject::create(
+        dir.path().join("2.sst"),
+        &old,
+        false,
+        false,
+        ChecksumType::Crc32,
+    )
+    .unwrap();
+    let old_sst = SsTable::open(2, None, file).unwrap();
+    assert_eq!(old_sst.num_entries(), None);
+    assert_eq!(old_sst.block_metas, sst.block_metas);
+    let mut iter = SsTableIterator::create_and_seek_to_first(Arc::new(old_sst)).unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, num_of_keys());
+}
+
+#[test]
+fn test_sst_key_range_in_footer() {
+    let (_dir, sst) = generate_sst();
+    let sst = Arc::new(SsTable::open(1, None, sst.file).unwrap());
+    assert_eq!(sst.block_reads(), 0);
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    let first = Bytes::copy_from_slice(iter.key());
+    let mut last = first.clone();
+    while iter.is_valid() {
+        last = Bytes::copy_from_slice(iter.key());
+        iter.next().unwrap();
+    }
+    assert_eq!(sst.key_range(), (&first, &last));
+
+    // range tombstones extend the key range
+    let mut builder = SsTableBuilder::new(LsmOptions::default().block_size(128).into());
+    builder.add(b"b", b"1").unwrap();
+    builder.add(b"c", b"2").unwrap();
+    builder.add_range_tombstone(RangeTombstone::new(&b"a"[..], &b"bb"[..]));
+    let dir = tempdir().unwrap();
+    let sst = builder.build_for_test(dir.path().join("2.sst")).unwrap();
+    let sst = SsTable::open(2, None, sst.file).unwrap();
+    assert_eq!(sst.block_reads(), 0);
+    assert_eq!(sst.smallest_key, &b"a"[..]);
+    assert_eq!(sst.biggest_key, &b"c"[..]);
+}
+
+#[test]
+fn test_sst_mmap() {
+    let dir = tempdir().unwrap();
+    let build = |use_mmap: bool, name: &str| {
+        let mut opts = LsmOptions::default().block_size(1024);
+        opts.use_mmap = use_mmap;
+        let mut builder = SsTableBuilder::new(opts.into());
+        for idx in 0..20000 {
+            builder.add(&key_of(idx), &value_of(idx)).unwrap();
+        }
+        builder.build_for_test(dir.path().join(name)).unwrap()
+    };
+    let sst = build(false, "1.sst");
+    let mmap_sst = build(true, "2.sst");
+    assert!(!sst.file.is_mmap());
+    assert!(mmap_sst.file.is_mmap());
+    assert!(mmap_sst.num_of_blocks() > 100);
+    assert_eq!(mmap_sst.num_of_blocks(), sst.num_of_blocks());
+    for idx in 0..sst.num_of_blocks() {
+        let block = sst.read_block(idx).unwrap();
+        let mmap_block = mmap_sst.read_block(idx).unwrap();
+        assert_eq!(
+            mmap_block
+                .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+                .unwrap(),
+            block
+                .encode(CompressOptions::Uncompress, 0, ChecksumType::Crc32)
+                .unwrap()
+        );
+    }
+    let size = sst.file.size();
+    assert_eq!(
+        mmap_sst.file.read(0, size).unwrap(),
+        sst.file.read(0, size).unwrap()
+    );
+    assert!(mmap_sst.file.read(size, 5).is_err());
+}
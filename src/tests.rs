@@ -1 +1,3 @@
+#[cfg(feature = "tokio")]
+pub mod async_storage;
 pub mod storage;
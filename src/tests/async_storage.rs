@@ -0,0 +1,76 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+use tokio_stream::StreamExt;
+
+use crate::{async_storage::AsyncLsmStorage, opt::LsmOptions};
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:04}", idx).into_bytes()
+}
+
+fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{:04}", idx).into_bytes()
+}
+
+#[tokio::test]
+async fn test_async_storage_get_put() {
+    let dir = tempdir().unwrap();
+    let storage = AsyncLsmStorage::open(LsmOptions::default().path(&dir))
+        .await
+        .unwrap();
+    storage.put(b"1", b"233").await.unwrap();
+    storage.put(b"2", b"2333").await.unwrap();
+    storage.delete(b"2").await.unwrap();
+    assert_eq!(storage.get(b"1").await.unwrap(), Some(Bytes::from("233")));
+    assert_eq!(storage.get(b"2").await.unwrap(), None);
+    assert_eq!(storage.get(b"3").await.unwrap(), None);
+
+    // the handle is shared by the clone
+    let clone = storage.clone();
+    assert!(storage.close().await.is_err());
+    clone.sync().await.unwrap();
+    clone.close().await.unwrap();
+
+    let storage = AsyncLsmStorage::open(LsmOptions::default().path(&dir))
+        .await
+        .unwrap();
+    assert_eq!(storage.get(b"1").await.unwrap(), Some(Bytes::from("233")));
+    assert_eq!(storage.get(b"2").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_async_storage_scan() {
+    let dir = tempdir().unwrap();
+    let storage = AsyncLsmStorage::open(LsmOptions::default().path(&dir))
+        .await
+        .unwrap();
+    // more entries than the buffer of the stream
+    for idx in 0..500 {
+        storage.put(&key_of(idx), &value_of(idx)).await.unwrap();
+    }
+    storage.sync().await.unwrap();
+    for idx in 500..1000 {
+        storage.put(&key_of(idx), &value_of(idx)).await.unwrap();
+    }
+
+    let entries: Vec<_> = storage
+        .scan(Bound::Included(&key_of(100)), Bound::Excluded(&key_of(900)))
+        .collect::<anyhow::Result<_>>()
+        .await
+        .unwrap();
+    let expected: Vec<_> = (100..900)
+        .map(|idx| (Bytes::from(key_of(idx)), Bytes::from(value_of(idx))))
+        .collect();
+    assert_eq!(entries, expected);
+
+    // dropping a stream stops its scan
+    let mut stream = storage.scan(Bound::Unbounded, Bound::Unbounded);
+    let first = stream.next().await.unwrap().unwrap();
+    assert_eq!(first, (Bytes::from(key_of(0)), Bytes::from(value_of(0))));
+    drop(stream);
+
+    let empty = storage.scan(Bound::Included(b"z"), Bound::Unbounded);
+    assert_eq!(empty.collect::<Vec<_>>().await.len(), 0);
+}
@@ -3,7 +3,11 @@ use std::ops::Bound;
 use bytes::Bytes;
 use tempfile::tempdir;
 
-use crate::{iterators::StorageIterator, opt::LsmOptions};
+use crate::{
+    iterators::StorageIterator,
+    mem_table::{MemTableIndex, MemTableRange, Value},
+    opt::LsmOptions,
+};
 
 fn as_bytes(x: &[u8]) -> Bytes {
     Bytes::copy_from_slice(x)
@@ -40,6 +44,29 @@ fn check_iter_result(iter: impl StorageIterator, expected: Vec<(Bytes, Bytes)>)
     assert!(!iter.is_valid());
 }
 
+fn check_iter_result_rev(iter: impl StorageIterator, expected: Vec<(Bytes, Bytes)>) {
+    let mut iter = iter;
+    for (k, v) in expected.into_iter().rev() {
+        assert!(iter.is_valid());
+        assert_eq!(
+            k,
+            iter.key(),
+            "expected key: {:?}, actual key: {:?}",
+            k,
+            as_bytes(iter.key()),
+        );
+        assert_eq!(
+            v,
+            iter.value(),
+            "expected value: {:?}, actual value: {:?}",
+            v,
+            as_bytes(iter.value()),
+        );
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
 #[test]
 fn test_storage_get() {
     use crate::lsm_storage::LsmStorage;
@@ -55,6 +82,44 @@ fn test_storage_get() {
     assert!(storage.get(b"2").unwrap().is_none());
 }
 
+#[test]
+fn test_storage_empty_value() {
+    use crate::lsm_storage::{LsmStorage, WriteBatch};
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"").unwrap();
+    storage.put(b"2", b"2").unwrap();
+    storage.put(b"3", b"3").unwrap();
+    storage
+        .write(WriteBatch::new().put(b"4", b"").delete(b"3"))
+        .unwrap();
+    storage.sync().unwrap();
+    storage.delete(b"2").unwrap();
+
+    let check = |storage: &LsmStorage| {
+        assert_eq!(storage.get(b"1").unwrap(), Some(Bytes::new()));
+        assert_eq!(storage.get(b"2").unwrap(), None);
+        assert_eq!(storage.get(b"3").unwrap(), None);
+        assert_eq!(storage.get(b"4").unwrap(), Some(Bytes::new()));
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            vec![
+                (Bytes::from("1"), Bytes::new()),
+                (Bytes::from("4"), Bytes::new()),
+            ],
+        );
+    };
+    check(&storage);
+    drop(storage);
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    check(&storage);
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    check(&storage);
+}
+
 #[test]
 fn test_storage_channel_put() {
     use crate::lsm_storage::LsmStorage;
@@ -77,6 +142,22 @@ fn test_storage_channel_put() {
     }
 }
 
+#[test]
+fn test_storage_write_core_stopped() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    let kv = (as_bytes(b"1"), as_bytes(b"1"));
+    storage.put_to_channel_not_msg(vec![kv.clone()]).unwrap();
+    storage.stop_background();
+    // the write core exits after applying the requests in the channel
+    while storage.put_to_channel_not_msg(vec![kv.clone()]).is_ok() {}
+    let kvs = (0..20)
+        .map(|idx| (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, ""))))
+        .collect::<Vec<_>>();
+    assert!(storage.batch_put(&kvs).is_err());
+}
+
 #[test]
 fn test_storage_channel_put_not_msg() {
     use crate::lsm_storage::LsmStorage;
@@ -106,6 +187,239 @@ fn test_storage_batch_put() {
     }
 }
 
+#[test]
+fn test_storage_batch_put_mix_channel() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    let large = LsmOptions::default().wait_entry_num + 1;
+
+    for round in 0..5 {
+        let info = round.to_string();
+        for idx in 0..large {
+            let entries = vec![(as_bytes(&key_of(idx)), as_bytes(&value_of(idx, "")))];
+            storage.put_to_channel_not_msg(entries).unwrap();
+        }
+        // large batch is written directly, after the channel writes above
+        let kvs = (0..large)
+            .map(|idx| (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, &info))))
+            .collect::<Vec<_>>();
+        storage.batch_put(&kvs).unwrap();
+        for (key, value) in &kvs {
+            assert_eq!(&storage.get(key).unwrap().unwrap(), value);
+        }
+
+        // small batch goes through the channel
+        let kvs = vec![(as_bytes(&key_of(0)), as_bytes(&value_of(0, "small")))];
+        storage.batch_put(&kvs).unwrap();
+        assert_eq!(storage.get(&kvs[0].0).unwrap().unwrap(), kvs[0].1);
+    }
+}
+
+#[test]
+fn test_storage_max_value_size() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.max_value_size = Some(8);
+    let storage = LsmStorage::open(opts).unwrap();
+    storage.put(b"1", b"233").unwrap();
+
+    let err = storage.put(b"1", b"233333333").unwrap_err();
+    assert!(err.to_string().contains("max_value_size"));
+    let kvs = vec![
+        (Bytes::from("2"), Bytes::from("2333")),
+        (Bytes::from("1"), Bytes::from("233333333")),
+    ];
+    assert!(storage.batch_put(&kvs).is_err());
+    assert!(storage.put_to_channel_not_msg(kvs).is_err());
+
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert!(storage.get(b"2").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_size_limits() {
+    use crate::lsm_storage::{LsmStorage, WriteBatch};
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.max_key_size = 4;
+    opts.max_value_size = Some(8);
+    let storage = LsmStorage::open(opts).unwrap();
+
+    // at the limits
+    storage.put(b"1234", b"12345678").unwrap();
+    storage
+        .put_with_ttl(b"2345", b"12345678", Duration::from_secs(60))
+        .unwrap();
+    storage.delete(b"2345").unwrap();
+    let kvs = vec![(Bytes::from("3456"), Bytes::from("12345678"))];
+    storage.batch_put(&kvs).unwrap();
+    let batch = WriteBatch::new().put(b"4567", b"12345678").delete(b"3456");
+    storage.write(batch).unwrap();
+
+    // just over the limits
+    let err = storage.put(b"12345", b"1").unwrap_err();
+    assert!(err.to_string().contains("max_key_size"), "{err}");
+    let err = storage.put(b"1", b"123456789").unwrap_err();
+    assert!(err.to_string().contains("max_value_size"), "{err}");
+    assert!(storage.delete(b"12345").is_err());
+    assert!(storage
+        .put_with_ttl(b"12345", b"1", Duration::from_secs(60))
+        .is_err());
+    assert!(storage
+        .put_with_ttl(b"1", b"123456789", Duration::from_secs(60))
+        .is_err());
+    let kvs = vec![
+        (Bytes::from("5"), Bytes::from("1")),
+        (Bytes::from("12345"), Bytes::from("1")),
+    ];
+    assert!(storage.batch_put(&kvs).is_err());
+    let batch = WriteBatch::new().put(b"6", b"1").delete(b"12345");
+    assert!(storage.write(batch).is_err());
+    let batch = WriteBatch::new().put(b"7", b"123456789");
+    assert!(storage.write(batch).is_err());
+
+    // nothing of the failed writes is written
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1234"), Bytes::from("12345678")),
+            (Bytes::from("4567"), Bytes::from("12345678")),
+        ],
+    );
+}
+
+#[test]
+fn test_storage_db_write_buffer_size() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 128;
+    opts.db_write_buffer_size = Some(512);
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..500 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+        assert!(storage.memtable_usage() <= 512);
+    }
+    for idx in 0..500 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+}
+
+#[test]
+fn test_storage_concurrent_put_rotation() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 64;
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let storage = &storage;
+            s.spawn(move || {
+                for idx in (t..4000).step_by(8) {
+                    storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+                }
+            });
+        }
+    });
+    let check = |storage: &LsmStorage| {
+        for idx in 0..4000 {
+            assert_eq!(
+                storage.get(&key_of(idx)).unwrap().unwrap(),
+                value_of(idx, ""),
+                "{idx}"
+            );
+        }
+    };
+    check(&storage);
+    drop(storage);
+    let storage = LsmStorage::open(opts).unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_storage_background_error() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::util::sstable_file_path;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 128;
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    // flushed tables can't be written where directories are in the way
+    for id in 1..100 {
+        std::fs::create_dir(sstable_file_path(dir.path(), id)).unwrap();
+    }
+    let mut failed = None;
+    for idx in 0..100000 {
+        if let Err(e) = storage.put(&key_of(idx), &value_of(idx, "")) {
+            failed = Some((idx, e));
+            break;
+        }
+    }
+    let (failed_idx, err) = failed.expect("flushing should fail");
+    assert!(err.to_string().contains("background error"), "{err}");
+    assert!(storage.put(b"1", b"1").is_err());
+    assert!(storage.sync().is_err());
+    assert_eq!(storage.get(&key_of(0)).unwrap().unwrap(), value_of(0, ""));
+    drop(storage);
+
+    // memtables are kept in WALs
+    for id in 1..100 {
+        std::fs::remove_dir(sstable_file_path(dir.path(), id)).unwrap();
+    }
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..failed_idx {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+    storage.put(b"1", b"1").unwrap();
+}
+
+#[test]
+fn test_storage_stall_on_imm_memtables() {
+    use crate::lsm_storage::LsmStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 128;
+    opts.max_memtable_num = 3;
+    let storage = LsmStorage::open(opts).unwrap();
+    let written = AtomicUsize::new(0);
+    std::thread::scope(|s| {
+        let paused = storage.pause_flush();
+        let writer = s.spawn(|| {
+            for idx in 0..500 {
+                storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+                written.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        std::thread::sleep(Duration::from_millis(300));
+        // the writer is blocked instead of piling up memtables
+        assert!(written.load(Ordering::Relaxed) < 500);
+        assert_eq!(storage.stats().num_imm_memtables, 3);
+        assert!(!writer.is_finished());
+
+        drop(paused);
+        writer.join().unwrap();
+    });
+    assert!(storage.stats().num_imm_memtables <= 3);
+    for idx in 0..500 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+}
+
 #[test]
 fn test_storage_scan_memtable_1() {
     use crate::lsm_storage::LsmStorage;
@@ -182,6 +496,73 @@ fn test_storage_get_after_sync() {
     assert!(storage.get(b"2").unwrap().is_none());
 }
 
+#[test]
+fn test_storage_get_newest_across_tiers() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    // "key" + "value_imm" exceeds it, "key" + "val" does not
+    opts.memtable_size = 10;
+    let storage = LsmStorage::open(opts).unwrap();
+
+    // l0 sstable
+    storage.put(b"key", b"value_sst").unwrap();
+    storage.sync().unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value_sst");
+    // immutable memtable
+    storage.put(b"key", b"value_imm").unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"value_imm");
+    // active memtable
+    storage.put(b"key", b"val").unwrap();
+    assert_eq!(&storage.get(b"key").unwrap().unwrap()[..], b"val");
+
+    storage.delete(b"key").unwrap();
+    assert!(storage.get(b"key").unwrap().is_none());
+}
+
+#[test]
+fn test_storage_concurrent_sync_and_flush() {
+    use crate::lsm_storage::LsmStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 256;
+    let storage = LsmStorage::open(opts).unwrap();
+    let rounds = 100;
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for round in 0..rounds {
+                for idx in 0..50 {
+                    storage
+                        .put(&key_of(idx), &value_of(idx, &round.to_string()))
+                        .unwrap();
+                }
+                if round % 10 == 0 {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            }
+            done.store(true, Ordering::Release);
+        });
+        s.spawn(|| {
+            while !done.load(Ordering::Acquire) {
+                storage.sync().unwrap();
+                std::thread::sleep(Duration::from_millis(7));
+            }
+        });
+    });
+    // wait for background flush
+    std::thread::sleep(Duration::from_millis(200));
+    let last = (rounds - 1).to_string();
+    for idx in 0..50 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, &last)
+        );
+    }
+}
+
 #[test]
 fn test_storage_scan_memtable_1_after_sync() {
     use crate::lsm_storage::LsmStorage;
@@ -246,19 +627,373 @@ fn test_storage_scan_memtable_2_after_sync() {
 }
 
 #[test]
-fn test_storage_close() {
+fn test_storage_scan_rev_memtable_1() {
     use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
     storage.put(b"1", b"233").unwrap();
-    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
-    drop(storage);
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.delete(b"2").unwrap();
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Included(b"1"), Bound::Included(b"2"))
+            .unwrap(),
+        vec![(Bytes::from("1"), Bytes::from("233"))],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .unwrap(),
+        vec![],
+    );
+}
+
+#[test]
+fn test_storage_scan_rev_memtable_2() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
     let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
-    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.delete(b"1").unwrap();
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Included(b"1"), Bound::Included(b"2"))
+            .unwrap(),
+        vec![(Bytes::from("2"), Bytes::from("2333"))],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .unwrap(),
+        vec![(Bytes::from("2"), Bytes::from("2333"))],
+    );
 }
 
 #[test]
-fn test_storage_close2() {
+fn test_storage_scan_rev_memtable_1_after_sync() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.delete(b"2").unwrap();
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Included(b"1"), Bound::Included(b"2"))
+            .unwrap(),
+        vec![(Bytes::from("1"), Bytes::from("233"))],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .unwrap(),
+        vec![],
+    );
+}
+
+#[test]
+fn test_storage_scan_rev_memtable_2_after_sync() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    storage.sync().unwrap();
+    storage.delete(b"1").unwrap();
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("3"), Bytes::from("23333")),
+        ],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Included(b"1"), Bound::Included(b"2"))
+            .unwrap(),
+        vec![(Bytes::from("2"), Bytes::from("2333"))],
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Excluded(b"1"), Bound::Excluded(b"3"))
+            .unwrap(),
+        vec![(Bytes::from("2"), Bytes::from("2333"))],
+    );
+}
+
+#[test]
+fn test_storage_scan_rev_tables() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, "1")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..1000).step_by(3) {
+        storage.put(&key_of(idx), &value_of(idx, "2")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..1000).step_by(7) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+
+    let expected = (0..1000)
+        .filter(|idx| idx % 7 != 0)
+        .map(|idx| {
+            let info = if idx % 3 == 0 { "2" } else { "1" };
+            (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, info)))
+        })
+        .collect::<Vec<_>>();
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        expected.clone(),
+    );
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Included(&key_of(100)), Bound::Excluded(&key_of(500)))
+            .unwrap(),
+        expected
+            .iter()
+            .filter(|(key, _)| key >= &key_of(100)[..] && key < &key_of(500)[..])
+            .cloned()
+            .collect(),
+    );
+}
+
+#[test]
+fn test_storage_scan_seek() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in (0..1000).step_by(2) {
+        storage.put(&key_of(idx), &value_of(idx, "1")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..1000).step_by(10) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+
+    let mut iter = storage
+        .scan(Bound::Excluded(&key_of(100)), Bound::Excluded(&key_of(900)))
+        .unwrap();
+    assert_eq!(iter.key(), key_of(102));
+    // lands on the next key when the target doesn't exist
+    iter.seek(&key_of(301)).unwrap();
+    assert_eq!(iter.key(), key_of(302));
+    assert_eq!(iter.value(), value_of(302, "1"));
+    // skips deleted keys
+    iter.seek(&key_of(500)).unwrap();
+    assert_eq!(iter.key(), key_of(502));
+    iter.next().unwrap();
+    assert_eq!(iter.key(), key_of(504));
+    // never moves before the lower bound
+    iter.seek(&key_of(0)).unwrap();
+    assert_eq!(iter.key(), key_of(102));
+    // becomes invalid beyond the upper bound, but can seek again
+    iter.seek(&key_of(900)).unwrap();
+    assert!(!iter.is_valid());
+    iter.seek(&key_of(898)).unwrap();
+    assert_eq!(iter.key(), key_of(898));
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+    iter.seek(&key_of(777)).unwrap();
+    assert_eq!(iter.key(), key_of(778));
+}
+
+#[test]
+fn test_storage_close() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    drop(storage);
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_open_legacy_wal() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::util::memtable_file_path;
+    use bytes::BufMut;
+    let dir = tempdir().unwrap();
+    // a WAL of the format before frames, the empty value of key 1 is a tombstone
+    let mut data = vec![];
+    for idx in 0..10 {
+        let value = match idx {
+            1 => vec![],
+            _ => value_of(idx, ""),
+        };
+        data.put_u16(key_of(idx).len() as u16);
+        data.put_slice(&key_of(idx));
+        data.put_u16(value.len() as u16);
+        data.put_slice(&value);
+    }
+    std::fs::write(memtable_file_path(&dir, 1), data).unwrap();
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..10 {
+        let expected = (idx != 1).then(|| as_bytes(&value_of(idx, "")));
+        assert_eq!(storage.get(&key_of(idx)).unwrap(), expected);
+    }
+    storage.put(&key_of(0), b"new").unwrap();
+    storage.close().unwrap();
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(storage.get(&key_of(0)).unwrap(), Some(Bytes::from("new")));
+    assert_eq!(storage.get(&key_of(1)).unwrap(), None);
+    assert_eq!(
+        storage.get(&key_of(9)).unwrap(),
+        Some(as_bytes(&value_of(9, "")))
+    );
+}
+
+#[test]
+fn test_storage_open_oversized_wal() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::opt::WalSync;
+    use crate::util::memtable_file_path;
+    use crate::wal::Wal;
+    let dir = tempdir().unwrap();
+    let wal = Wal::create(memtable_file_path(&dir, 1), WalSync::Always).unwrap();
+    let kvs = (0..100)
+        .map(|idx| (as_bytes(&key_of(idx)), Some(as_bytes(&value_of(idx, "")))))
+        .collect::<Vec<_>>();
+    wal.add_entries(&kvs).unwrap();
+    wal.save_file();
+    drop(wal);
+
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 64;
+    let storage = LsmStorage::open(opts).unwrap();
+    // the oversized memtable has been flushed
+    assert!(!memtable_file_path(&dir, 1).exists());
+    for (key, value) in kvs {
+        assert_eq!(storage.get(&key).unwrap(), value);
+    }
+}
+
+#[test]
+fn test_storage_large_value() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::opt::WalSync;
+    use crate::util::memtable_file_path;
+    use crate::wal::Wal;
+    let dir = tempdir().unwrap();
+    let value = vec![b'v'; 100 * 1024];
+    let wal = Wal::create(memtable_file_path(&dir, 1), WalSync::Always).unwrap();
+    wal.add(b"a", &value).unwrap();
+    wal.save_file();
+    drop(wal);
+
+    // replayed from the WAL
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(as_bytes(&value)));
+    storage.put(b"b", &value[..70000]).unwrap();
+    storage.put(b"c", b"1").unwrap();
+    storage.sync().unwrap();
+    assert_eq!(storage.stats().levels[0].num_tables, 1);
+    storage.close().unwrap();
+
+    // read from the sstable
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(as_bytes(&value)));
+    assert_eq!(storage.get(b"b").unwrap(), Some(as_bytes(&value[..70000])));
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("a"), as_bytes(&value)),
+            (Bytes::from("b"), as_bytes(&value[..70000])),
+            (Bytes::from("c"), Bytes::from("1")),
+        ],
+    );
+
+    // keys are limited by their u16 lengths
+    let key = vec![b'k'; u16::MAX as usize + 1];
+    assert!(storage.put(&key, b"1").is_err());
+    assert!(storage.delete(&key).is_err());
+}
+
+#[test]
+fn test_storage_wal_sync_every_n() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::opt::WalSync;
+    use std::sync::Arc;
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default()
+        .path(&dir)
+        .wal_sync(WalSync::EveryN(16));
+    let storage = Arc::new(LsmStorage::open(opts.clone()).unwrap());
+    let handles = (0..4)
+        .map(|t| {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for idx in (t * 100)..(t + 1) * 100 {
+                    storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let kvs = (400..450)
+        .map(|idx| (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, ""))))
+        .collect::<Vec<_>>();
+    storage.batch_put(&kvs).unwrap();
+    drop(storage);
+
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..450 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+}
+
+#[test]
+fn test_storage_close2() {
     use crate::lsm_storage::LsmStorage;
     let dir = tempdir().unwrap();
     let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
@@ -268,3 +1003,1967 @@ fn test_storage_close2() {
     let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
     storage.put(b"2", b"233").unwrap();
 }
+
+#[test]
+fn test_storage_snapshot_compaction() {
+    use crate::lsm_storage::LsmStorage;
+    use std::time::{Duration, Instant};
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    // two l0 sstables trigger a compaction
+    opts.target_file_size_base = opts.max_bytes_for_level_base;
+    let storage = LsmStorage::open(opts).unwrap();
+
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.put(&key_of(100), &value_of(100, "old")).unwrap();
+    let snapshot = storage.snapshot().unwrap();
+
+    for idx in 0..101 {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    storage.sync().unwrap();
+
+    let start = Instant::now();
+    while !storage.snapshot().unwrap().levels()[0].is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "compaction timeout"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(snapshot.levels()[0].len(), 1);
+    let expected = (0..101)
+        .map(|idx| (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, "old"))))
+        .collect::<Vec<_>>();
+    for (idx, (key, value)) in expected.iter().enumerate() {
+        assert_eq!(&snapshot.get(key).unwrap().unwrap(), value);
+        assert_eq!(storage.get(key).unwrap().unwrap(), value_of(idx, "new"));
+    }
+    check_iter_result(
+        snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        expected,
+    );
+}
+
+#[test]
+fn test_storage_parallel_multi_get() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.target_file_size_base = opts.max_bytes_for_level_base;
+    opts.multi_get_batch_size = 16;
+    let storage = LsmStorage::open(opts).unwrap();
+    for round in 0..4 {
+        for idx in (round * 80..400).step_by(2) {
+            storage
+                .put(&key_of(idx), &value_of(idx, &round.to_string()))
+                .unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    for idx in (0..400).step_by(7) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+
+    let keys = (0..400).map(key_of).collect::<Vec<_>>();
+    let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+    let serial = keys
+        .iter()
+        .map(|key| storage.get(key).unwrap())
+        .collect::<Vec<_>>();
+    assert!(serial.iter().any(|value| value.is_some()));
+    assert_eq!(storage.multi_get(&keys).unwrap(), serial);
+    assert_eq!(storage.multi_get(&keys[..10]).unwrap(), serial[..10]);
+}
+
+#[test]
+fn test_storage_scan_single_table() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    storage.put(b"4", b"23333").unwrap();
+    storage.put(b"5", b"233333").unwrap();
+    storage.delete(b"4").unwrap();
+    storage.sync().unwrap();
+    check_iter_result(
+        storage
+            .scan(Bound::Excluded(b"1"), Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("5"), Bytes::from("233333")),
+        ],
+    );
+    // the table overlaps the range without containing any key of it
+    check_iter_result(
+        storage
+            .scan(Bound::Included(b"3"), Bound::Included(b"4"))
+            .unwrap(),
+        vec![],
+    );
+    storage.put(b"3", b"2").unwrap();
+    check_iter_result(
+        storage
+            .scan(Bound::Included(b"3"), Bound::Included(b"4"))
+            .unwrap(),
+        vec![(Bytes::from("3"), Bytes::from("2"))],
+    );
+}
+
+#[test]
+fn test_storage_scan_with_byte_limit() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    // 8 bytes key, 11 bytes value
+    for idx in 0..10 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+
+    let mut iter = storage
+        .scan_with_byte_limit(Bound::Unbounded, Bound::Unbounded, 50)
+        .unwrap();
+    assert_eq!(iter.key(), key_of(0));
+    iter.next().unwrap();
+    assert_eq!(iter.key(), key_of(1));
+    assert!(iter.next().is_err());
+    assert!(!iter.is_valid());
+    iter.next().unwrap();
+
+    let mut iter = storage
+        .scan_with_byte_limit(Bound::Unbounded, Bound::Unbounded, 200)
+        .unwrap();
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, 10);
+    assert!(storage
+        .scan_with_byte_limit(Bound::Unbounded, Bound::Unbounded, 18)
+        .is_err());
+}
+
+#[test]
+fn test_storage_scan_prefix() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    let keys: [&[u8]; 9] = [
+        b"a",
+        b"ab",
+        b"ab\xff",
+        b"ab\xff\x00",
+        b"ab\xff\xff",
+        b"ac",
+        b"b",
+        b"\xff",
+        b"\xff\xff",
+    ];
+    for key in keys {
+        storage.put(key, b"v").unwrap();
+    }
+    storage.sync().unwrap();
+    storage.put(b"ab\x01", b"v").unwrap();
+
+    let check = |prefix: &[u8], expected: &[&[u8]]| {
+        let expected = expected
+            .iter()
+            .map(|key| (as_bytes(key), Bytes::from("v")))
+            .collect();
+        check_iter_result(storage.scan_prefix(prefix).unwrap(), expected);
+    };
+    check(
+        b"ab",
+        &[b"ab", b"ab\x01", b"ab\xff", b"ab\xff\x00", b"ab\xff\xff"],
+    );
+    check(b"ab\xff", &[b"ab\xff", b"ab\xff\x00", b"ab\xff\xff"]);
+    check(b"ab\xff\xff", &[b"ab\xff\xff"]);
+    check(
+        b"a",
+        &[
+            b"a",
+            b"ab",
+            b"ab\x01",
+            b"ab\xff",
+            b"ab\xff\x00",
+            b"ab\xff\xff",
+            b"ac",
+        ],
+    );
+    check(b"\xff", &[b"\xff", b"\xff\xff"]);
+    check(b"\xff\xff", &[b"\xff\xff"]);
+    check(b"c", &[]);
+}
+
+#[test]
+fn test_storage_scan_many_tables() {
+    use crate::lsm_storage::LsmStorage;
+    use std::collections::BTreeMap;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    // l0 isn't compacted until it has 100 tables
+    opts.max_bytes_for_level_base = opts.target_file_size_base * 100;
+    let storage = LsmStorage::open(opts).unwrap();
+    let mut expected = BTreeMap::new();
+    // overlapping tables, newer ones override some keys of older ones
+    for round in 0..12 {
+        let info = round.to_string();
+        for idx in (round * 20..round * 20 + 100).step_by(round % 3 + 1) {
+            storage.put(&key_of(idx), &value_of(idx, &info)).unwrap();
+            expected.insert(key_of(idx), value_of(idx, &info));
+        }
+        if round % 4 == 3 {
+            storage.delete(&key_of(round * 20)).unwrap();
+            expected.remove(&key_of(round * 20));
+        }
+        storage.sync().unwrap();
+    }
+    assert!(storage.stats().levels[0].num_tables >= 12);
+
+    let snapshot = storage.snapshot().unwrap();
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(key_of(50)), Bound::Excluded(key_of(150))),
+        (Bound::Excluded(key_of(60)), Bound::Included(key_of(200))),
+        (Bound::Included(key_of(290)), Bound::Unbounded),
+    ];
+    for (lower, upper) in bounds {
+        let (lower, upper) = (
+            lower.as_ref().map(Vec::as_slice),
+            upper.as_ref().map(Vec::as_slice),
+        );
+        let expected = expected
+            .range::<[u8], _>((lower, upper))
+            .map(|(key, value)| (as_bytes(key), as_bytes(value)))
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        // the storage seeks tables in the thread pool, the snapshot seeks them one by one
+        check_iter_result(storage.scan(lower, upper).unwrap(), expected.clone());
+        check_iter_result(snapshot.scan(lower, upper).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_storage_write_batch() {
+    use crate::lsm_storage::{LsmStorage, WriteBatch};
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+    let batch = WriteBatch::new()
+        .put(b"2", b"2333")
+        .delete(b"3")
+        .put(b"4", b"233333");
+    assert_eq!(batch.len(), 3);
+    storage.write(batch).unwrap();
+    storage.write(WriteBatch::new()).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("2"), Bytes::from("2333")),
+            (Bytes::from("4"), Bytes::from("233333")),
+        ],
+    );
+    drop(storage);
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+    assert_eq!(storage.get(b"3").unwrap(), None);
+
+    // larger than wait_entry_num, it's written directly
+    let mut batch = WriteBatch::new();
+    for idx in 0..20 {
+        batch = batch.put(&key_of(idx), &value_of(idx, ""));
+    }
+    batch = batch.delete(b"1");
+    storage.write(batch).unwrap();
+    assert_eq!(storage.get(b"1").unwrap(), None);
+    for idx in 0..20 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+}
+
+#[test]
+fn test_storage_drop_prefix() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    let key = |prefix: &str, idx: usize| format!("{prefix}/{idx:04}").into_bytes();
+    // a table only contains "a/", a table only contains "b/" and a table contains both
+    for idx in 0..100 {
+        storage.put(&key("a", idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in 0..100 {
+        storage.put(&key("b", idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in 100..120 {
+        storage.put(&key("a", idx), &value_of(idx, "")).unwrap();
+        storage.put(&key("b", idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.put(&key("a", 200), b"memtable").unwrap();
+
+    let reclaimed = storage.drop_prefix(b"a/").unwrap();
+    assert!(reclaimed > 0);
+    check_iter_result(
+        storage
+            .scan(Bound::Included(b"a/"), Bound::Excluded(b"a0"))
+            .unwrap(),
+        vec![],
+    );
+    for idx in 0..120 {
+        assert_eq!(storage.get(&key("a", idx)).unwrap(), None);
+        assert_eq!(
+            storage.get(&key("b", idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+    let tables = storage.snapshot().unwrap().levels().concat();
+    assert_eq!(tables.len(), 2);
+    assert!(tables.iter().all(
+        |table| !table.smallest_key.starts_with(b"a/") || !table.biggest_key.starts_with(b"a/")
+    ));
+    drop(storage);
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(storage.get(&key("a", 0)).unwrap(), None);
+    assert_eq!(storage.get(&key("a", 200)).unwrap(), None);
+    assert_eq!(storage.get(&key("b", 0)).unwrap().unwrap(), value_of(0, ""));
+}
+
+#[test]
+fn test_storage_delete_range() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    // the oldest keys are in the last level
+    for idx in 0..300 {
+        storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    for idx in (0..300).step_by(2) {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..300).step_by(3) {
+        storage.put(&key_of(idx), &value_of(idx, "mem")).unwrap();
+    }
+
+    storage
+        .delete_range(Bound::Included(&key_of(50)), Bound::Excluded(&key_of(150)))
+        .unwrap();
+    // nothing in the range
+    storage
+        .delete_range(Bound::Included(b"a"), Bound::Included(b"b"))
+        .unwrap();
+    // keys written later are not deleted
+    storage.put(&key_of(100), &value_of(100, "later")).unwrap();
+
+    let expected = (0..300)
+        .filter(|idx| !(50..150).contains(idx) || *idx == 100)
+        .map(|idx| {
+            let info = match idx {
+                100 => "later",
+                _ if idx % 3 == 0 => "mem",
+                _ if idx % 2 == 0 => "new",
+                _ => "old",
+            };
+            (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, info)))
+        })
+        .collect::<Vec<_>>();
+    let check = |storage: &LsmStorage| {
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            expected.clone(),
+        );
+        check_iter_result_rev(
+            storage
+                .scan_rev(Bound::Unbounded, Bound::Unbounded)
+                .unwrap(),
+            expected.clone(),
+        );
+        check_iter_result(
+            storage
+                .scan(Bound::Included(&key_of(60)), Bound::Excluded(&key_of(160)))
+                .unwrap(),
+            expected
+                .iter()
+                .filter(|(key, _)| key >= &key_of(60)[..] && key < &key_of(160)[..])
+                .cloned()
+                .collect(),
+        );
+        for idx in [0, 49, 50, 51, 99, 101, 149, 150] {
+            let value = storage.get(&key_of(idx)).unwrap();
+            assert_eq!(value.is_some(), !(50..150).contains(&idx), "key {idx}");
+        }
+    };
+    check(&storage);
+    drop(storage);
+
+    // the range tombstone is replayed from the WAL
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    check(&storage);
+    // and flushed to an sstable
+    storage.sync().unwrap();
+    check(&storage);
+    drop(storage);
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    check(&storage);
+    // compactions keep the range tombstone until the last level
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    check(&storage);
+    let tables = storage.snapshot().unwrap().levels().concat();
+    assert!(tables
+        .iter()
+        .all(|table| table.range_tombstones().is_empty()));
+}
+
+#[test]
+fn test_storage_snapshot() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.put(b"2", b"2333").unwrap();
+    let paused = storage.pause_flush();
+    let snapshot = storage.snapshot().unwrap();
+    // the memtable is not frozen
+    assert_eq!(storage.stats().num_imm_memtables, 0);
+    drop(paused);
+    storage.put(b"1", b"new").unwrap();
+    storage.delete(b"2").unwrap();
+    storage.put(b"3", b"23333").unwrap();
+
+    assert_eq!(&snapshot.get(b"1").unwrap().unwrap()[..], b"233");
+    assert_eq!(&snapshot.get(b"2").unwrap().unwrap()[..], b"2333");
+    assert_eq!(snapshot.get(b"3").unwrap(), None);
+    check_iter_result(
+        snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("1"), Bytes::from("233")),
+            (Bytes::from("2"), Bytes::from("2333")),
+        ],
+    );
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"new");
+    assert_eq!(storage.get(b"2").unwrap(), None);
+}
+
+#[test]
+fn test_storage_multi_get() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in (0..300).step_by(3) {
+        storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..300).step_by(6) {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    for idx in (0..300).step_by(9) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..300).step_by(15) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    storage.put(&key_of(301), &value_of(301, "mem")).unwrap();
+
+    // unsorted, duplicated, present, deleted and absent keys
+    let keys = (0..310)
+        .rev()
+        .chain([3, 0, 1, 301])
+        .map(key_of)
+        .collect::<Vec<_>>();
+    let keys = keys.iter().map(|key| &key[..]).collect::<Vec<_>>();
+    let expected = keys
+        .iter()
+        .map(|key| storage.get(key).unwrap())
+        .collect::<Vec<_>>();
+    assert!(expected.iter().any(|value| value.is_some()));
+    assert!(expected.iter().any(|value| value.is_none()));
+    assert_eq!(storage.multi_get(&keys).unwrap(), expected);
+    assert_eq!(storage.multi_get(&[]).unwrap(), vec![]);
+}
+
+#[test]
+fn test_storage_drop_under_load() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::manifest::ManifestFile;
+    use crate::util::parse_sstable_id;
+    use std::collections::HashSet;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 1024;
+    opts.target_file_size_base = 4096;
+    opts.max_bytes_for_level_base = 4096;
+    opts.level0_file_num_compaction_trigger = 2;
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..2000 {
+        storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+    }
+    for idx in (0..2000).step_by(4) {
+        storage
+            .put_to_channel_not_msg(vec![(
+                Bytes::from(key_of(idx)),
+                Bytes::from(value_of(idx, "new")),
+            )])
+            .unwrap();
+    }
+    drop(storage);
+
+    let (manifest, _) = ManifestFile::open(dir.path()).unwrap();
+    let ids = manifest.get_id_level().into_keys().collect::<HashSet<_>>();
+    drop(manifest);
+    let mut files = HashSet::new();
+    for file in std::fs::read_dir(dir.path()).unwrap() {
+        let name = file.unwrap().file_name().to_string_lossy().to_string();
+        assert!(!name.ends_with(".tmp"), "{name}");
+        if let Some(id) = parse_sstable_id(&name) {
+            files.insert(id);
+        }
+    }
+    assert_eq!(files, ids);
+
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..2000 {
+        let info = if idx % 4 == 0 { "new" } else { "old" };
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, info)
+        );
+    }
+}
+
+#[test]
+fn test_storage_close_explicit() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.close().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+}
+
+#[test]
+fn test_storage_drop_failed_sync() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"2", b"233").unwrap();
+    // creating the new memtable fails
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(storage.close().is_err());
+
+    std::fs::create_dir(&dir).unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    storage.put(b"1", b"233").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    // only logs the error
+    drop(storage);
+}
+
+#[test]
+fn test_storage_open_read_only() {
+    use crate::lsm_storage::{LsmStorage, WriteBatch};
+    use crate::opt::WalSync;
+    use crate::util::memtable_file_path;
+    use crate::wal::Wal;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.close().unwrap();
+    // writes not flushed before a crash
+    let wal = Wal::create(memtable_file_path(&dir, 1), WalSync::Always).unwrap();
+    for idx in 100..150 {
+        wal.add(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    wal.save_file();
+    drop(wal);
+
+    let list_dir = || {
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|file| {
+                let file = file.unwrap();
+                (file.file_name(), file.metadata().unwrap().len())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let files = list_dir();
+    let storage = LsmStorage::open_read_only(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..150 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, 150);
+
+    assert!(storage.put(b"key", b"value").is_err());
+    assert!(storage.delete(&key_of(0)).is_err());
+    assert!(storage
+        .write(WriteBatch::new().put(b"key", b"value"))
+        .is_err());
+    assert!(storage
+        .delete_range(Bound::Unbounded, Bound::Unbounded)
+        .is_err());
+    assert!(storage.sync().is_err());
+    assert!(storage.get(b"key").unwrap().is_none());
+    storage.close().unwrap();
+    assert_eq!(list_dir(), files);
+
+    // a missing storage isn't created
+    let empty = tempdir().unwrap();
+    assert!(LsmStorage::open_read_only(LsmOptions::default().path(&empty)).is_err());
+    assert_eq!(std::fs::read_dir(&empty).unwrap().count(), 0);
+
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(
+        storage.get(&key_of(120)).unwrap(),
+        Some(as_bytes(&value_of(120, "")))
+    );
+}
+
+#[test]
+fn test_storage_stats() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    let stats = storage.stats();
+    assert_eq!(stats.levels.len(), 6);
+    assert!(stats.levels.iter().all(|level| level.num_tables == 0));
+    assert_eq!(stats.memtable_size, 0);
+
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    let stats = storage.stats();
+    assert!(stats.memtable_size > 0);
+    assert_eq!(stats.num_imm_memtables, 0);
+
+    storage.sync().unwrap();
+    let stats = storage.stats();
+    assert_eq!(stats.memtable_size, 0);
+    assert_eq!(stats.levels[0].num_tables, 1);
+    assert!(stats.levels[0].size > 0);
+    assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (0, 0));
+
+    storage.get(&key_of(1)).unwrap();
+    let stats = storage.stats();
+    assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (0, 1));
+    storage.get(&key_of(1)).unwrap();
+    let stats = storage.stats();
+    assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (1, 1));
+}
+
+#[test]
+fn test_storage_num_levels() {
+    use crate::lsm_storage::LsmStorage;
+    use std::ops::Bound;
+    for num_levels in [3, 8] {
+        let dir = tempdir().unwrap();
+        let mut opts = LsmOptions::default().path(&dir);
+        opts.num_levels = num_levels;
+        opts.memtable_size = 1024;
+        opts.target_file_size_base = 4096;
+        opts.max_bytes_for_level_base = 4096;
+        opts.level0_file_num_compaction_trigger = 2;
+        let storage = LsmStorage::open(opts.clone()).unwrap();
+        for idx in 0..2000 {
+            storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+        }
+        storage.sync().unwrap();
+        storage
+            .compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        let stats = storage.stats();
+        assert_eq!(stats.levels.len(), num_levels);
+        assert!(stats.levels[num_levels - 1].num_tables > 0);
+        drop(storage);
+
+        let storage = LsmStorage::open(opts.clone()).unwrap();
+        for idx in 0..2000 {
+            assert_eq!(
+                storage.get(&key_of(idx)).unwrap().unwrap(),
+                value_of(idx, "")
+            );
+        }
+        drop(storage);
+
+        // tables of the last level don't fit in fewer levels
+        opts.num_levels = 2;
+        assert!(LsmStorage::open(opts.clone()).is_err());
+        opts.num_levels = 1;
+        assert!(LsmStorage::open(opts).is_err());
+    }
+}
+
+#[test]
+fn test_storage_column_families() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 1024;
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    let users = storage.create_cf("users").unwrap();
+    let orders = storage.create_cf("orders").unwrap();
+    assert!(storage.create_cf("users").is_err());
+    assert!(storage.cf("missing").is_none());
+    for idx in 0..200 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+        users.put(&key_of(idx), &value_of(idx, "users")).unwrap();
+        if idx % 2 == 0 {
+            orders.put(&key_of(idx), &value_of(idx, "orders")).unwrap();
+        }
+    }
+    users.delete(&key_of(0)).unwrap();
+
+    let check = |storage: &LsmStorage| {
+        let users = storage.cf("users").unwrap();
+        let orders = storage.cf("orders").unwrap();
+        let default = storage.cf("default").unwrap();
+        assert_eq!(users.name(), "users");
+        assert_eq!(storage.get(&key_of(0)).unwrap().unwrap(), value_of(0, ""));
+        assert_eq!(users.get(&key_of(0)).unwrap(), None);
+        for idx in 1..200 {
+            assert_eq!(
+                default.get(&key_of(idx)).unwrap().unwrap(),
+                value_of(idx, "")
+            );
+            assert_eq!(
+                users.get(&key_of(idx)).unwrap().unwrap(),
+                value_of(idx, "users")
+            );
+            let order = orders.get(&key_of(idx)).unwrap();
+            match idx % 2 {
+                0 => assert_eq!(order.unwrap(), value_of(idx, "orders")),
+                _ => assert_eq!(order, None),
+            }
+        }
+        let mut iter = orders.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(count, 100);
+    };
+    check(&storage);
+    users.sync().unwrap();
+    check(&storage);
+    drop(users);
+    drop(orders);
+    drop(storage);
+
+    let storage = LsmStorage::open(opts).unwrap();
+    check(&storage);
+    assert!(storage.create_cf("orders").is_err());
+}
+
+#[test]
+fn test_storage_ingest_sst() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::table::SsTableBuilder;
+    use std::sync::Arc;
+    let dir = tempdir().unwrap();
+    let external = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir);
+    let build = |name: &str, keys: std::ops::Range<usize>, info: &str| {
+        let path = external.path().join(name);
+        let mut builder = SsTableBuilder::new(Arc::new(opts.clone()));
+        for idx in keys {
+            builder.add(&key_of(idx), &value_of(idx, info)).unwrap();
+        }
+        builder.build(0, None, &path).unwrap().mark_save();
+        path
+    };
+
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.put(&key_of(50), &value_of(50, "mem")).unwrap();
+
+    // overlapping with an l0 table and the memtable
+    let path = build("1.sst", 40..60, "ingested");
+    assert_eq!(storage.ingest_sst(&path).unwrap(), 0);
+    assert!(path.exists());
+    // not overlapping with any table
+    let path = build("2.sst", 200..300, "ingested");
+    assert_eq!(storage.ingest_sst(&path).unwrap(), 5);
+    assert_eq!(storage.stats().levels[5].num_tables, 1);
+    // a write still in the channel is older than the ingested keys
+    storage
+        .put_to_channel_not_msg(vec![(
+            Bytes::from(key_of(310)),
+            Bytes::from(value_of(310, "channel")),
+        )])
+        .unwrap();
+    let path = build("3.sst", 300..320, "ingested");
+    assert_eq!(storage.ingest_sst(&path).unwrap(), 0);
+
+    let check = |storage: &LsmStorage| {
+        for idx in (0..100).chain(200..320) {
+            let info = match idx {
+                40..60 | 200..320 => "ingested",
+                _ => "",
+            };
+            assert_eq!(
+                storage.get(&key_of(idx)).unwrap(),
+                Some(as_bytes(&value_of(idx, info)))
+            );
+        }
+    };
+    check(&storage);
+
+    // a corrupted file is rejected
+    let path = external.path().join("4.sst");
+    std::fs::write(&path, b"not a sstable").unwrap();
+    assert!(storage.ingest_sst(&path).is_err());
+
+    storage.close().unwrap();
+    let storage = LsmStorage::open(opts).unwrap();
+    check(&storage);
+}
+
+#[test]
+fn test_storage_checkpoint() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let backup = tempdir().unwrap();
+    let dest = backup.path().join("checkpoint");
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+        if idx % 30 == 0 {
+            storage.sync().unwrap();
+        }
+    }
+    storage.checkpoint(&dest).unwrap();
+    assert!(storage.checkpoint(&dest).is_err());
+
+    // tables of the checkpoint are compacted and deleted from the source
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    storage.delete(&key_of(0)).unwrap();
+    storage.sync().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(storage.get(&key_of(0)).unwrap(), None);
+
+    let checkpoint = LsmStorage::open(LsmOptions::default().path(&dest)).unwrap();
+    for idx in 0..100 {
+        assert_eq!(
+            checkpoint.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+    checkpoint.put(&key_of(1), b"checkpoint").unwrap();
+    assert_eq!(
+        storage.get(&key_of(1)).unwrap(),
+        Some(as_bytes(&value_of(1, "new")))
+    );
+}
+
+#[test]
+fn test_storage_put_with_ttl() {
+    use crate::level::LevelController;
+    use crate::lsm_storage::LsmStorage;
+    use std::sync::Arc;
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    let count = |storage: &LsmStorage| {
+        let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+        let mut num = 0;
+        while iter.is_valid() {
+            num += 1;
+            iter.next().unwrap();
+        }
+        num
+    };
+
+    // expired in the memtable
+    storage
+        .put_with_ttl(b"tmp", b"1", Duration::from_millis(1))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    assert_eq!(storage.get(b"tmp").unwrap(), None);
+
+    for idx in 0..100 {
+        storage
+            .put_with_ttl(&key_of(idx), &value_of(idx, ""), Duration::from_millis(500))
+            .unwrap();
+    }
+    for idx in 100..200 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    assert_eq!(
+        storage.get(&key_of(1)).unwrap(),
+        Some(as_bytes(&value_of(1, "")))
+    );
+    assert_eq!(count(&storage), 200);
+
+    // expired in sstables
+    std::thread::sleep(Duration::from_millis(600));
+    assert_eq!(storage.get(&key_of(1)).unwrap(), None);
+    assert_eq!(
+        storage.get(&key_of(101)).unwrap(),
+        Some(as_bytes(&value_of(101, "")))
+    );
+    assert_eq!(count(&storage), 100);
+
+    let result = storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    // with "tmp" expired in the memtable
+    assert_eq!(result.dropped_expired, 101);
+    assert_eq!(result.output_entries, 100);
+    assert_eq!(count(&storage), 100);
+    drop(storage);
+
+    // expired entries are dropped by compaction to the last level
+    let lvctl = LevelController::open(Arc::new(opts)).unwrap();
+    let num_entries = lvctl
+        .snapshot_levels()
+        .iter()
+        .flatten()
+        .map(|table| table.num_entries().unwrap())
+        .sum::<u64>();
+    assert_eq!(num_entries, 100);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_storage_options_serde() {
+    use crate::block::CompressOptions;
+    use crate::opt::WalSync;
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let config = format!(
+        r#"{{
+            "dir": {:?},
+            "memtable_size": 1024,
+            "compress_option": "lz4",
+            "wal_sync": {{ "every_n": 8 }}
+        }}"#,
+        dir.path()
+    );
+    let opts: LsmOptions = serde_json::from_str(&config).unwrap();
+    assert_eq!(opts.dir, dir.path());
+    assert_eq!(opts.memtable_size, 1024);
+    assert_eq!(opts.compress_option, CompressOptions::Lz4);
+    assert_eq!(opts.wal_sync, WalSync::EveryN(8));
+    // missing fields are defaults
+    let default = LsmOptions::default();
+    assert_eq!(opts.block_size, default.block_size);
+    assert_eq!(opts.max_value_size, default.max_value_size);
+
+    let mut opts2 = opts.clone();
+    opts2.wal_sync = WalSync::Interval(Duration::from_millis(10));
+    let json = serde_json::to_string(&opts2).unwrap();
+    let opts3: LsmOptions = serde_json::from_str(&json).unwrap();
+    assert_eq!(opts3.wal_sync, opts2.wal_sync);
+    assert_eq!(opts3.dir, opts2.dir);
+
+    let storage = opts.open().unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    drop(storage);
+    let storage = opts3.open().unwrap();
+    for idx in 0..100 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().unwrap(),
+            value_of(idx, "")
+        );
+    }
+}
+
+#[test]
+fn test_storage_scan_into_items() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for i in 0..100 {
+        storage.put(&key_of(i), &value_of(i, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    for i in (0..100).step_by(3) {
+        storage.delete(&key_of(i)).unwrap();
+    }
+
+    let items: Vec<(Bytes, Bytes)> = storage
+        .scan(Bound::Included(&key_of(10)), Bound::Unbounded)
+        .unwrap()
+        .into_items()
+        .collect::<anyhow::Result<_>>()
+        .unwrap();
+    let expected: Vec<_> = (10..100)
+        .filter(|i| i % 3 != 0)
+        .map(|i| (Bytes::from(key_of(i)), Bytes::from(value_of(i, ""))))
+        .collect();
+    assert_eq!(items, expected);
+
+    let mut cnt = 0;
+    for kv in storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap() {
+        let (key, value) = kv.unwrap();
+        assert_eq!(key[4..], value[6..10]);
+        cnt += 1;
+    }
+    assert_eq!(cnt, 66);
+
+    // the items end after the error
+    let limit = key_of(0).len() + value_of(0, "").len();
+    let mut items = storage
+        .scan_with_byte_limit(Bound::Unbounded, Bound::Unbounded, limit)
+        .unwrap()
+        .into_items();
+    assert!(items.next().unwrap().is_ok());
+    assert!(items.next().unwrap().is_err());
+    assert!(items.next().is_none());
+}
+
+#[test]
+fn test_storage_scan_prefix_bloom() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    // l0 isn't compacted until it has 100 tables
+    opts.max_bytes_for_level_base = opts.target_file_size_base * 100;
+    opts.prefix_bloom_len = Some(4);
+    let storage = LsmStorage::open(opts).unwrap();
+    // every table covers the whole key space, but holds a single prefix
+    for table in 0..10 {
+        storage.put(b"aaa", &value_of(table, "first")).unwrap();
+        for idx in 0..100 {
+            let key = format!("p{table:02}_{idx:03}");
+            storage.put(key.as_bytes(), &value_of(idx, "v")).unwrap();
+        }
+        storage.put(b"zzz", &value_of(table, "last")).unwrap();
+        storage.sync().unwrap();
+    }
+    assert_eq!(storage.stats().levels[0].num_tables, 10);
+
+    let reads = || {
+        let stats = storage.stats();
+        stats.block_cache_hits + stats.block_cache_misses
+    };
+    let before = reads();
+    let expected = (0..100)
+        .map(|idx| {
+            let key = format!("p07_{idx:03}");
+            (Bytes::from(key), as_bytes(&value_of(idx, "v")))
+        })
+        .collect();
+    check_iter_result(storage.scan_prefix(b"p07_").unwrap(), expected);
+    let prefix_reads = reads() - before;
+
+    let before = reads();
+    let mut iter = storage
+        .scan(Bound::Included(b"p07_"), Bound::Excluded(b"p07`"))
+        .unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    // the range scan seeks every table, the prefix scan skips most of them
+    assert!(reads() - before >= 10);
+    assert!(prefix_reads < 5, "{prefix_reads} blocks read");
+
+    // longer prefixes are checked by their first 4 bytes, shorter ones read all tables
+    assert_eq!(
+        storage.scan_prefix(b"p07_05").unwrap().into_items().count(),
+        10
+    );
+    assert_eq!(
+        storage.scan_prefix(b"p09_1").unwrap().into_items().count(),
+        0
+    );
+    assert_eq!(
+        storage.scan_prefix(b"p0").unwrap().into_items().count(),
+        1000
+    );
+    assert_eq!(
+        storage.scan_prefix(b"q00_").unwrap().into_items().count(),
+        0
+    );
+}
+
+#[test]
+fn test_storage_scan_limit() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir).block_size(256);
+    // l0 isn't compacted until it has 100 tables
+    opts.max_bytes_for_level_base = opts.target_file_size_base * 100;
+    let storage = LsmStorage::open(opts).unwrap();
+    // newer tables have smaller keys, so the tables are a sorted run
+    for chunk in (0..10).rev() {
+        for idx in chunk * 200..chunk * 200 + 200 {
+            storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+        }
+        storage.sync().unwrap();
+    }
+    assert_eq!(storage.stats().levels[0].num_tables, 10);
+    for idx in (0..2000).step_by(7) {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    for idx in (0..2000).step_by(5) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+
+    let collect = |iter: &mut dyn StorageIterator| {
+        let mut entries = vec![];
+        while iter.is_valid() {
+            entries.push((as_bytes(iter.key()), as_bytes(iter.value())));
+            iter.next().unwrap();
+        }
+        entries
+    };
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Excluded(key_of(500)), Bound::Included(key_of(1500))),
+    ];
+    for (lower, upper) in bounds {
+        let (lower, upper) = (
+            lower.as_ref().map(Vec::as_slice),
+            upper.as_ref().map(Vec::as_slice),
+        );
+        let all = collect(&mut storage.scan(lower, upper).unwrap());
+        for limit in [0, 1, 10, 333, all.len(), all.len() + 1] {
+            let entries = collect(&mut storage.scan_limit(lower, upper, limit).unwrap());
+            assert_eq!(entries.len(), limit.min(all.len()));
+            assert_eq!(entries, all[..entries.len()]);
+        }
+    }
+
+    // tables after the last key returned are not read
+    let stats = storage.stats();
+    let reads = stats.block_cache_hits + stats.block_cache_misses;
+    let mut iter = storage
+        .scan_limit(Bound::Unbounded, Bound::Unbounded, 3)
+        .unwrap();
+    collect(&mut iter);
+    let stats = storage.stats();
+    assert!(stats.block_cache_hits + stats.block_cache_misses - reads <= 2);
+
+    // seek across the tables of a level
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    iter.seek(&key_of(1800)).unwrap();
+    assert_eq!(iter.key(), key_of(1801));
+    iter.seek(&key_of(99)).unwrap();
+    assert_eq!(iter.key(), key_of(99));
+    assert_eq!(iter.value(), value_of(99, "old"));
+    iter.seek(&key_of(1999)).unwrap();
+    assert_eq!(iter.key(), key_of(1999));
+    iter.next().unwrap();
+    assert!(!iter.is_valid());
+
+    let all = collect(&mut storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap());
+    check_iter_result_rev(
+        storage
+            .scan_rev(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        all,
+    );
+}
+
+#[test]
+fn test_storage_compact_range_result() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in (0..1000).step_by(2) {
+        storage.delete(&key_of(idx)).unwrap();
+    }
+    storage.sync().unwrap();
+    let input_bytes = storage.stats().levels[0].size as u64;
+
+    let result = storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    // deleted values and their tombstones are dropped in the last level
+    assert_eq!(result.input_entries, 1500);
+    assert_eq!(result.output_entries, 500);
+    assert_eq!(result.dropped_tombstones, 500);
+    assert_eq!(result.dropped_expired, 0);
+    assert_eq!(result.input_bytes, input_bytes);
+    let stats = storage.stats();
+    assert_eq!(
+        result.output_bytes,
+        stats.levels.last().unwrap().size as u64
+    );
+
+    // nothing left to compact
+    let result = storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(result, Default::default());
+}
+
+#[test]
+fn test_storage_warmup() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir).block_size(256);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, "old")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    for idx in (0..1000).step_by(3) {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.close().unwrap();
+
+    // the cache is cold after reopening
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    let loaded = storage.warmup().unwrap();
+    assert!(loaded > 0);
+    let misses = storage.stats().block_cache_misses;
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, 1000);
+    assert_eq!(storage.stats().block_cache_misses, misses);
+    drop(iter);
+    storage.close().unwrap();
+
+    // stops once the cache is full
+    let capacity = loaded / 4;
+    let mut opts = opts;
+    opts.block_cache_size = capacity;
+    let storage = LsmStorage::open(opts).unwrap();
+    let partial = storage.warmup().unwrap();
+    assert!(partial > 0 && partial <= capacity);
+}
+
+/// Adds the operands to the value as decimal integers.
+fn add_operator(_key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Bytes> {
+    let parse = |x: &[u8]| std::str::from_utf8(x).unwrap().parse::<u64>().unwrap();
+    let sum = existing.map_or(0, parse) + operands.iter().map(|x| parse(x)).sum::<u64>();
+    Some(Bytes::from(sum.to_string()))
+}
+
+#[test]
+fn test_storage_merge() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::merge::encode_operand;
+    use crate::opt::WalSync;
+    use crate::table::SsTableIterator;
+    use crate::util::memtable_file_path;
+    use crate::wal::Wal;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert!(storage.merge(b"key", b"1").is_err());
+    storage.close().unwrap();
+
+    let opts = LsmOptions::default()
+        .path(&dir)
+        .merge_operator(add_operator);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..10 {
+        storage.merge(&key_of(idx), b"1").unwrap();
+    }
+    // merged into the value or the tombstone in the memtable
+    storage.put(&key_of(0), b"100").unwrap();
+    storage.merge(&key_of(0), b"5").unwrap();
+    storage.delete(&key_of(1)).unwrap();
+    storage.merge(&key_of(1), b"5").unwrap();
+    storage.sync().unwrap();
+
+    // merged into the records of the sstable by reads
+    for idx in 0..10 {
+        storage.merge(&key_of(idx), b"2").unwrap();
+    }
+    let expected = |idx: usize| match idx {
+        0 => Bytes::from("107"),
+        1 => Bytes::from("7"),
+        _ => Bytes::from("3"),
+    };
+    let keys: Vec<_> = (0..10).map(key_of).collect();
+    let values: Vec<_> = (0..10).map(|idx| Some(expected(idx))).collect();
+    for (key, value) in keys.iter().zip(&values) {
+        assert_eq!(&storage.get(key).unwrap(), value);
+    }
+    let keys: Vec<_> = keys.iter().map(|key| &key[..]).collect();
+    assert_eq!(storage.multi_get(&keys).unwrap(), values);
+    let entries: Vec<_> = (0..10)
+        .map(|idx| (as_bytes(&key_of(idx)), expected(idx)))
+        .collect();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        entries.clone(),
+    );
+    let mut iter = storage
+        .scan_rev(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    for (key, value) in entries.iter().rev() {
+        assert_eq!((iter.key(), iter.value()), (&key[..], &value[..]));
+        iter.prev().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let snapshot = storage.snapshot().unwrap();
+    assert_eq!(snapshot.get(&key_of(0)).unwrap(), Some(expected(0)));
+    drop((iter, snapshot));
+
+    // merged into values by compaction into the last level
+    storage.sync().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    for table in storage.snapshot().unwrap().levels().iter().flatten() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+        while iter.is_valid() {
+            assert!(!iter.is_merge());
+            iter.next().unwrap();
+        }
+    }
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        entries,
+    );
+    storage.close().unwrap();
+
+    // merges not flushed before a crash are replayed
+    let wal = Wal::create(memtable_file_path(&dir, 1), WalSync::Always).unwrap();
+    wal.merge(&key_of(0), &encode_operand(b"3")).unwrap();
+    wal.save_file();
+    drop(wal);
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert!(storage.get(&key_of(0)).is_err());
+    storage.close().unwrap();
+    let storage = LsmStorage::open(opts).unwrap();
+    assert_eq!(storage.get(&key_of(0)).unwrap(), Some(Bytes::from("110")));
+}
+
+/// Places sstables under the `sst` subdirectory.
+#[derive(Debug)]
+struct SubdirLayout;
+
+impl crate::opt::PathLayout for SubdirLayout {
+    fn sstable_path(&self, dir: &std::path::Path, id: u64) -> std::path::PathBuf {
+        crate::util::sstable_file_path(&dir.join("sst"), id)
+    }
+}
+
+#[test]
+fn test_storage_path_layout() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::util::parse_sstable_id;
+    let sst_ids = |dir: &std::path::Path| {
+        let mut ids: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| parse_sstable_id(entry.unwrap().file_name().to_str()?))
+            .collect();
+        ids.sort_unstable();
+        ids
+    };
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir).path_layout(SubdirLayout);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in 100..200 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    assert!(sst_ids(dir.path()).is_empty());
+    assert_eq!(sst_ids(&dir.path().join("sst")).len(), 1);
+    assert!(dir.path().join("MANIFEST").exists());
+
+    let checkpoint = dir.path().join("checkpoint");
+    storage.checkpoint(&checkpoint).unwrap();
+    assert_eq!(
+        sst_ids(&checkpoint.join("sst")),
+        sst_ids(&dir.path().join("sst"))
+    );
+    assert!(checkpoint.join("MANIFEST").exists());
+    storage.close().unwrap();
+
+    // orphan files are found in the subdirectory
+    std::fs::write(dir.path().join("sst").join("999.sst"), b"orphan").unwrap();
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    assert!(!dir.path().join("sst").join("999.sst").exists());
+    let entries: Vec<_> = (0..200)
+        .map(|idx| (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, ""))))
+        .collect();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        entries.clone(),
+    );
+    storage.close().unwrap();
+
+    let storage = LsmStorage::open(opts.path(&checkpoint)).unwrap();
+    check_iter_result(
+        storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        entries,
+    );
+}
+
+#[test]
+fn test_storage_verify_checksums() {
+    use crate::checksum::ChecksumType;
+    use crate::level::LevelController;
+    use crate::lsm_storage::LsmStorage;
+    use std::sync::Arc;
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir).block_size(256);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    storage.verify_checksums().unwrap();
+    storage.close().unwrap();
+
+    // flip a byte of the 3rd block of a table, and fix the checksum of the file so it's still
+    // opened
+    let lvctl = LevelController::open(Arc::new(opts.clone())).unwrap();
+    let table = lvctl.snapshot_levels().concat()[0].clone();
+    assert!(table.num_of_blocks() > 3);
+    let (path, offset) = (opts.sstable_path(table.id), table.block_offset(2) + 1);
+    lvctl.mark_save();
+    drop((table, lvctl));
+    let mut data = std::fs::read(&path).unwrap();
+    data[offset] ^= 0xff;
+    // |data|checksum(u32)|checksum type(u8)|magic(u32)|
+    let len = data.len() - 9;
+    let checksum = ChecksumType::Crc32.calculate(&data[..len]);
+    data[len..len + 4].copy_from_slice(&checksum.to_be_bytes());
+    std::fs::write(&path, data).unwrap();
+
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    let err = storage.verify_checksums().unwrap_err().to_string();
+    let id = path.file_stem().unwrap().to_str().unwrap();
+    assert!(err.contains(&format!("table {id} block 2")), "{err}");
+    storage.close().unwrap();
+
+    let mut opts = opts;
+    opts.paranoid_checks = true;
+    assert!(LsmStorage::open(opts).is_err());
+}
+
+#[test]
+fn test_storage_describe_levels() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let storage = LsmStorage::open(LsmOptions::default().path(&dir)).unwrap();
+    assert_eq!(
+        storage.describe_levels(),
+        "level 0:\nlevel 1:\nlevel 2:\nlevel 3:\nlevel 4:\nlevel 5:\n"
+    );
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "1")).unwrap();
+    }
+    storage.sync().unwrap();
+    for idx in 50..150 {
+        storage.put(&key_of(idx), &value_of(idx, "2")).unwrap();
+    }
+    storage.sync().unwrap();
+    let levels = storage.snapshot().unwrap().levels().to_vec();
+    let (newer, older) = (&levels[0][1], &levels[0][0]);
+    assert_eq!(
+        storage.describe_levels(),
+        format!(
+            "level 0:\n  {}: b\"key_0000\"..=b\"key_0099\" {}B\n  {}: b\"key_0050\"..=b\"key_0149\" {}B\nlevel 1:\nlevel 2:\nlevel 3:\nlevel 4:\nlevel 5:\n",
+            older.id, older.size, newer.id, newer.size
+        )
+    );
+
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let levels = storage.snapshot().unwrap().levels().to_vec();
+    let table = &levels[5][0];
+    assert!(table.id > newer.id);
+    assert_eq!(
+        storage.describe_levels(),
+        format!(
+            "level 0:\nlevel 1:\nlevel 2:\nlevel 3:\nlevel 4:\nlevel 5:\n  {}: b\"key_0000\"..=b\"key_0149\" {}B\n",
+            table.id, table.size
+        )
+    );
+}
+
+#[test]
+fn test_storage_flush() {
+    use crate::lsm_storage::LsmStorage;
+    use std::time::{Duration, Instant};
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.min_memtable_to_merge = 1;
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.flush().unwrap();
+    assert_eq!(storage.stats().memtable_size, 0);
+
+    // flushed by the flush thread
+    let start = Instant::now();
+    while storage.stats().num_imm_memtables > 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(storage.stats().levels[0].num_tables, 1);
+    // an empty memtable is not frozen
+    storage.flush().unwrap();
+    assert_eq!(storage.stats().num_imm_memtables, 0);
+
+    // read from the sstable
+    for idx in 0..100 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+    assert!(storage.stats().block_cache_misses > 0);
+}
+
+#[test]
+fn test_storage_wait_for_compaction() {
+    use crate::lsm_storage::LsmStorage;
+    use std::time::Duration;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.memtable_size = 1024;
+    opts.target_file_size_base = 1024;
+    opts.max_bytes_for_level_base = 4096;
+    opts.max_bytes_for_level_multiplier = 4;
+    let storage = LsmStorage::open(opts).unwrap();
+    for idx in 0..5000 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+
+    storage
+        .wait_for_compaction(Duration::from_secs(30))
+        .unwrap();
+    let levels = storage.stats().levels;
+    assert!(levels[1..].iter().any(|level| level.num_tables > 0));
+    // a level is compacted once its score reaches 1
+    assert!(levels.iter().all(|level| level.score() < 1.0), "{levels:?}");
+    for idx in 0..5000 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+}
+
+#[test]
+fn test_storage_scan_during_compaction() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir).block_size(256);
+    opts.block_cache_size = 0;
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..1000 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    let tables = storage.snapshot().unwrap().levels().concat();
+    assert_eq!(tables.len(), 1);
+    let (id, path) = (tables[0].id, opts.sstable_path(tables[0].id));
+    drop(tables);
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for _ in 0..10 {
+        iter.next().unwrap();
+    }
+    // the table is compacted away, but its file is kept until the scan is dropped
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let tables = storage.snapshot().unwrap().levels().concat();
+    assert!(tables.iter().all(|table| table.id != id));
+    assert!(path.exists());
+    let mut cnt = 10;
+    while iter.is_valid() {
+        assert_eq!(iter.key(), key_of(cnt));
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, 1000);
+    drop(iter);
+    assert!(!path.exists());
+}
+
+/// Orders keys by their bytes in reverse.
+#[derive(Debug)]
+struct ReverseComparator;
+
+impl crate::opt::Comparator for ReverseComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        b.cmp(a)
+    }
+
+    fn name(&self) -> &str {
+        "reverse"
+    }
+}
+
+#[test]
+fn test_storage_comparator() {
+    use crate::lsm_storage::LsmStorage;
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default()
+        .path(&dir)
+        .block_size(64)
+        .comparator(ReverseComparator);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    let entry = |idx: usize| {
+        let info = if idx >= 50 { "new" } else { "" };
+        (as_bytes(&key_of(idx)), as_bytes(&value_of(idx, info)))
+    };
+    // keys of the memtable come in the order of the comparator
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in (0..100).rev() {
+        assert_eq!(iter.key(), key_of(idx));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    drop(iter);
+
+    storage.sync().unwrap();
+    for idx in 50..150 {
+        storage.put(&key_of(idx), &value_of(idx, "new")).unwrap();
+    }
+    let check = |storage: &LsmStorage| {
+        check_iter_result(
+            storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+            (0..150).rev().map(entry).collect(),
+        );
+        check_iter_result(
+            storage
+                .scan(Bound::Included(&key_of(120)), Bound::Excluded(&key_of(30)))
+                .unwrap(),
+            (31..=120).rev().map(entry).collect(),
+        );
+        check_iter_result_rev(
+            storage
+                .scan_rev(Bound::Excluded(&key_of(120)), Bound::Unbounded)
+                .unwrap(),
+            (0..120).rev().map(entry).collect(),
+        );
+        let mut iter = storage
+            .scan(Bound::Included(&key_of(120)), Bound::Unbounded)
+            .unwrap();
+        iter.seek(&key_of(60)).unwrap();
+        assert_eq!(iter.key(), key_of(60));
+        for idx in [0, 49, 50, 149] {
+            assert_eq!(storage.get(&key_of(idx)).unwrap(), Some(entry(idx).1));
+        }
+        let keys = [key_of(10), key_of(140), key_of(200)];
+        let keys: Vec<_> = keys.iter().map(|key| &key[..]).collect();
+        assert_eq!(
+            storage.multi_get(&keys).unwrap(),
+            vec![Some(entry(10).1), Some(entry(140).1), None]
+        );
+    };
+    check(&storage);
+    storage.sync().unwrap();
+    check(&storage);
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    let tables = storage.snapshot().unwrap().levels().concat();
+    assert!(tables.len() > 1);
+    check(&storage);
+    // range tombstones are bytewise
+    assert!(storage
+        .delete_range(Bound::Unbounded, Bound::Unbounded)
+        .is_err());
+    assert!(storage.scan_prefix(b"key_").is_err());
+    storage.close().unwrap();
+
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    check(&storage);
+    storage.close().unwrap();
+
+    // the comparator is recorded in the manifest
+    let err = LsmStorage::open(LsmOptions::default().path(&dir))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("comparator"), "{err}");
+    // the memtable index must be ordered by the comparator
+    let opts = opts.memtable_index(|| {
+        std::sync::Arc::new(crossbeam::skiplist::SkipMap::<Bytes, Value>::new())
+    });
+    let err = LsmStorage::open(opts).err().unwrap().to_string();
+    assert!(err.contains("memtable index"), "{err}");
+}
+
+/// A skiplist whose full scans, which flushes do, are slow, counting the full scans of each index
+/// and the most scans running at once.
+#[derive(Default)]
+struct SlowFlushIndex {
+    map: crossbeam::skiplist::SkipMap<Bytes, Value>,
+    scans: std::sync::atomic::AtomicUsize,
+    running: std::sync::Arc<(
+        std::sync::atomic::AtomicUsize,
+        std::sync::atomic::AtomicUsize,
+    )>,
+}
+
+impl MemTableIndex for SlowFlushIndex {
+    fn insert(&self, key: Bytes, value: Value) -> bool {
+        MemTableIndex::insert(&self.map, key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        MemTableIndex::get(&self.map, key)
+    }
+
+    fn range(&self, lower: Bound<Bytes>, upper: Bound<Bytes>) -> MemTableRange<'_> {
+        use std::sync::atomic::Ordering;
+        if matches!((&lower, &upper), (Bound::Unbounded, Bound::Unbounded)) {
+            self.scans.fetch_add(1, Ordering::SeqCst);
+            let (running, max_running) = &*self.running;
+            let num = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(num, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            running.fetch_sub(1, Ordering::SeqCst);
+        }
+        MemTableIndex::range(&self.map, lower, upper)
+    }
+
+    fn comparator(&self) -> &dyn crate::opt::Comparator {
+        &crate::opt::BytewiseComparator
+    }
+}
+
+#[test]
+fn test_storage_parallel_flush() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::table::SsTableIterator;
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let indexes = Arc::new(Mutex::new(Vec::<Arc<SlowFlushIndex>>::new()));
+    // the running full scans and the most of them
+    let running = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.max_memtable_num = 16;
+    opts.flush_num = 4;
+    // l0 isn't compacted until it has 100 tables
+    opts.max_bytes_for_level_base = opts.target_file_size_base * 100;
+    let new_indexes = indexes.clone();
+    let new_running = running.clone();
+    let opts = opts.memtable_index(move || {
+        let index = Arc::new(SlowFlushIndex {
+            running: new_running.clone(),
+            ..Default::default()
+        });
+        new_indexes.lock().push(index.clone());
+        index
+    });
+    let storage = LsmStorage::open(opts).unwrap();
+
+    // 12 immutable memtables of 10 keys each
+    let paused = storage.pause_flush();
+    for idx in 0..120 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+        if idx % 10 == 9 {
+            storage.flush().unwrap();
+        }
+    }
+    assert_eq!(storage.stats().num_imm_memtables, 12);
+    drop(paused);
+
+    let start = Instant::now();
+    while storage.stats().num_imm_memtables > 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // 4 groups of 3 memtables are flushed concurrently
+    let levels = storage.snapshot().unwrap().levels().to_vec();
+    assert_eq!(levels[0].len(), 4);
+    assert!(running.1.load(Ordering::SeqCst) > 1);
+    // each memtable is flushed once, and the 13th is still mutable
+    let scans = indexes
+        .lock()
+        .iter()
+        .map(|index| index.scans.load(Ordering::SeqCst))
+        .collect::<Vec<_>>();
+    assert_eq!(scans, [vec![1; 12], vec![0]].concat());
+
+    // all keys are in l0 once, newer tables are pushed later
+    let mut keys = vec![];
+    for table in &levels[0] {
+        let mut iter = SsTableIterator::create_and_seek_to_first(table.clone()).unwrap();
+        let first = keys.len();
+        while iter.is_valid() {
+            keys.push(as_bytes(iter.key()));
+            iter.next().unwrap();
+        }
+        assert_eq!(keys.len() - first, 30);
+    }
+    let expected = (0..120)
+        .map(|idx| as_bytes(&key_of(idx)))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+    for idx in 0..120 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+}
+
+#[test]
+fn test_storage_estimate_range_size() {
+    use crate::block::CompressOptions;
+    use crate::lsm_storage::LsmStorage;
+    use std::ops::RangeBounds;
+    let dir = tempdir().unwrap();
+    let mut opts = LsmOptions::default().path(&dir);
+    opts.compress_option = CompressOptions::Uncompress;
+    let storage = LsmStorage::open(opts).unwrap();
+    let value = |idx| format!("{:0>100}", idx).into_bytes();
+    // two tables of 1000 entries
+    for idx in 0..2000 {
+        storage.put(&key_of(idx), &value(idx)).unwrap();
+        if idx % 1000 == 999 {
+            storage.sync().unwrap();
+        }
+    }
+    assert_eq!(storage.snapshot().unwrap().levels()[0].len(), 2);
+
+    let check = |lower: Bound<usize>, upper: Bound<usize>| {
+        let (lower_key, upper_key) = (lower.map(key_of), upper.map(key_of));
+        let lower_key = lower_key.as_ref().map(|key| &key[..]);
+        let upper_key = upper_key.as_ref().map(|key| &key[..]);
+        let actual: usize = (0..2000)
+            .filter(|idx| (lower, upper).contains(idx))
+            .map(|idx| key_of(idx).len() + value(idx).len())
+            .sum();
+        let estimate = storage.estimate_range_size(lower_key, upper_key);
+        assert!(
+            estimate >= actual / 2 && estimate <= actual * 2,
+            "estimate {estimate}, actual {actual}"
+        );
+    };
+    check(Bound::Unbounded, Bound::Unbounded);
+    check(Bound::Unbounded, Bound::Excluded(1000));
+    check(Bound::Included(500), Bound::Excluded(1500));
+    check(Bound::Included(100), Bound::Included(300));
+    check(Bound::Excluded(1800), Bound::Unbounded);
+
+    let total = storage.estimate_range_size(Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(
+        storage.estimate_range_size(Bound::Included(b"z"), Bound::Unbounded),
+        0
+    );
+    // memtables are not counted
+    for idx in 2000..3000 {
+        storage.put(&key_of(idx), &value(idx)).unwrap();
+    }
+    assert_eq!(
+        storage.estimate_range_size(Bound::Unbounded, Bound::Unbounded),
+        total
+    );
+}
+
+#[test]
+fn test_storage_interrupted_sstable_write() {
+    use crate::lsm_storage::LsmStorage;
+    use crate::util::{sstable_file_path, sstable_tmp_file_path};
+    let dir = tempdir().unwrap();
+    let opts = LsmOptions::default().path(&dir);
+    let storage = LsmStorage::open(opts.clone()).unwrap();
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    // sstables are renamed from temporary files once they are written
+    let ids = storage.snapshot().unwrap().levels()[0]
+        .iter()
+        .map(|table| table.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids.len(), 1);
+    assert!(sstable_file_path(dir.path(), ids[0]).exists());
+    assert!(!sstable_tmp_file_path(dir.path(), ids[0]).exists());
+    storage.close().unwrap();
+
+    // a crash in the middle of writing the next sstable
+    let id = ids[0] + 1;
+    let tmp_path = sstable_tmp_file_path(dir.path(), id);
+    std::fs::write(&tmp_path, b"partial").unwrap();
+    assert!(!sstable_file_path(dir.path(), id).exists());
+
+    let storage = LsmStorage::open(opts).unwrap();
+    assert!(!tmp_path.exists());
+    for idx in 0..100 {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap(),
+            Some(as_bytes(&value_of(idx, "")))
+        );
+    }
+    for idx in 100..200 {
+        storage.put(&key_of(idx), &value_of(idx, "")).unwrap();
+    }
+    storage.sync().unwrap();
+    assert_eq!(storage.snapshot().unwrap().levels()[0].len(), 2);
+}
@@ -1,9 +1,65 @@
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn sstable_file_path(dir: &Path, id: u64) -> PathBuf {
     dir.join(format!("{id}.sst"))
 }
 
+pub const SSTABLE_FILE_EXT: &str = ".sst";
+pub const TMP_FILE_EXT: &str = ".tmp";
+
+pub fn sstable_tmp_file_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}{SSTABLE_FILE_EXT}{TMP_FILE_EXT}"))
+}
+
+/// Parse the id of a sstable file or a temporary sstable file.
+pub fn parse_sstable_id(file_name: &str) -> Option<u64> {
+    let name = file_name.strip_suffix(TMP_FILE_EXT).unwrap_or(file_name);
+    name.strip_suffix(SSTABLE_FILE_EXT)?.parse().ok()
+}
+
+/// Get the temporary file written before it's renamed to `path`.
+pub fn tmp_file_path(path: &Path) -> PathBuf {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(TMP_FILE_EXT);
+    tmp.into()
+}
+
+pub fn manifest_file_path(dir: &Path) -> PathBuf {
+    dir.join("MANIFEST")
+}
+
+/// Get the smallest key bigger than all keys starting with `prefix`.
+///
+/// Return None if there is no such key, i.e. `prefix` only contains 0xff.
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last != u8::MAX {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+/// Get the current time in milliseconds since the UNIX epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Check if a value expiring at `expire_at` milliseconds since the UNIX epoch has expired.
+pub fn is_expired(expire_at: u64) -> bool {
+    expire_at <= now_millis()
+}
+
+/// Get the directory of WALs of column family `cf`.
+pub fn cf_dir(dir: &Path, cf: u32) -> PathBuf {
+    dir.join(format!("cf_{cf}"))
+}
+
 pub fn path_mem(dir: &Path, id: u64) -> PathBuf {
     dir.join(format!("{:05}.mem", id))
 }
@@ -14,11 +70,18 @@ pub fn memtable_file_path(dir: impl AsRef<Path>, id: usize) -> PathBuf {
     dir.as_ref().join(format!("{:05}{}", id, MEMTABLE_FILE_EXT))
 }
 
+/// Parse the id of a WAL file.
+pub fn parse_memtable_id(file_name: &str) -> Option<usize> {
+    file_name.strip_suffix(MEMTABLE_FILE_EXT)?.parse().ok()
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
 
-    use super::{path_mem, sstable_file_path};
+    use super::{
+        parse_sstable_id, path_mem, prefix_upper_bound, sstable_file_path, sstable_tmp_file_path,
+    };
     #[test]
     fn test_path_sst() {
         let path = sstable_file_path(Path::new("./"), 1);
@@ -26,10 +89,29 @@ mod test {
         assert_eq!(path, buf)
     }
 
+    #[test]
+    fn test_parse_sstable_id() {
+        let path = sstable_tmp_file_path(Path::new("./"), 12);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name, "12.sst.tmp");
+        assert_eq!(parse_sstable_id(name), Some(12));
+        assert_eq!(parse_sstable_id("12.sst"), Some(12));
+        assert_eq!(parse_sstable_id("00012.mem"), None);
+        assert_eq!(parse_sstable_id("MANIFEST"), None);
+    }
+
     #[test]
     fn test_path_mem() {
         let path = path_mem(Path::new("./"), 1);
         let buf = Path::new("./00001.mem").to_path_buf();
         assert_eq!(path, buf)
     }
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+    }
 }
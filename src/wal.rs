@@ -2,41 +2,68 @@ mod iterator;
 use anyhow::Result;
 
 use bytes::{BufMut, Bytes, BytesMut};
-use parking_lot::Mutex;
+use log::error;
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use std::{
     fs::{remove_file, File},
-    io::{BufReader, BufWriter, Read, Seek, Write},
+    io::{Seek, Write},
     path::{Path, PathBuf},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
-use crate::block::Entry;
+use crate::{block::Entry, checksum, opt::WalSync, range_tombstone::RangeTombstone};
 
 use self::iterator::WalIterator;
 
+// |MAGIC|version(u32)|frames|
+//
+// A frame is |len(u32)|records|crc32(u32)|, and each of its records starts with the version of the
+// entry, see `encode_record`. A file without the header is a WAL written before frames, which is
+// records of |key_len(u16)|key|value_len(u16)|value| without checksums or versions, an empty
+// value being a tombstone. Its records are replayed with the versions they were appended with,
+// counting from 1.
+const MAGIC: &[u8; 8] = b"TOPAZWAL";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 4;
+
+/// Appended frames that are being written or waiting for a leader to write them.
+struct WalWriter {
+    file: Arc<File>,
+    buf: Vec<u8>,
+    /// Version of the last appended record.
+    version: u64,
+    /// Version of the last record written to the file.
+    written: u64,
+    /// Version of the last record synced to the disk.
+    synced: u64,
+    last_sync: Instant,
+    /// A leader is writing the buffered frames.
+    writing: bool,
+    /// The last write failed, no more writes are accepted.
+    failed: bool,
+}
+
 enum WalInner {
-    WalWriter((BufWriter<File>, u64)),
-    WalReader(BufReader<File>),
+    WalWriter(WalWriter),
+    WalReader(File),
 }
 
 impl WalInner {
-    fn append(&mut self, buf: &[u8]) -> Result<u64> {
-        if let WalInner::WalWriter((writer, id)) = self {
-            writer.write_all(buf)?;
-            writer.flush()?;
-            *id += 1;
-            Ok(*id)
+    fn writer(&mut self) -> Result<&mut WalWriter> {
+        if let WalInner::WalWriter(writer) = self {
+            Ok(writer)
         } else {
             Err(anyhow::anyhow!("only write"))
         }
     }
 
-    fn read_all(&mut self) -> Result<Vec<u8>> {
-        if let WalInner::WalReader(reader) = self {
-            let mut buf = vec![];
-            reader.rewind()?;
-            reader.read_to_end(&mut buf)?;
-            Ok(buf)
+    fn reader(&mut self) -> Result<&File> {
+        if let WalInner::WalReader(file) = self {
+            Ok(file)
         } else {
             Err(anyhow::anyhow!("only read"))
         }
@@ -45,6 +72,9 @@ impl WalInner {
 
 pub struct Wal {
     inner: Mutex<WalInner>,
+    // notified when a leader finishes writing
+    written: Condvar,
+    sync: WalSync,
     path: PathBuf,
     remove_file: AtomicBool,
 }
@@ -54,7 +84,9 @@ impl Wal {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let file = File::options().read(true).open(&path)?;
         Ok(Wal {
-            inner: Mutex::new(WalInner::WalReader(BufReader::new(file))),
+            inner: Mutex::new(WalInner::WalReader(file)),
+            written: Condvar::new(),
+            sync: WalSync::Always,
             path: path.as_ref().to_path_buf(),
             remove_file: AtomicBool::new(true),
         })
@@ -64,41 +96,211 @@ impl Wal {
         self.remove_file.store(false, Ordering::Relaxed)
     }
 
-    /// create a file(only-write)
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    /// create a file(only-write), which is synced to the disk according to `sync`
+    pub fn create(path: impl AsRef<Path>, sync: WalSync) -> Result<Self> {
         if path.as_ref().exists() {
             remove_file(&path)?;
         }
-        let file = match File::options().create_new(true).append(true).open(&path) {
-            Ok(file) => file,
-            Err(e) => panic!("{e}: {:?}", path.as_ref()),
+        let file = File::options()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("{e}: {:?}", path.as_ref()))?;
+        // synced with the first frames
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.put_slice(MAGIC);
+        header.put_u32(VERSION);
+        (&file).write_all(&header)?;
+        let writer = WalWriter {
+            file: Arc::new(file),
+            buf: vec![],
+            version: 0,
+            written: 0,
+            synced: 0,
+            last_sync: Instant::now(),
+            writing: false,
+            failed: false,
         };
         Ok(Wal {
-            inner: Mutex::new(WalInner::WalWriter((BufWriter::new(file), 0))),
+            inner: Mutex::new(WalInner::WalWriter(writer)),
+            written: Condvar::new(),
+            sync,
             path: path.as_ref().to_path_buf(),
             remove_file: AtomicBool::new(true),
         })
     }
 
+    // |version(u64)|key_len(u16)|key|value_type(u8) | LONG_VALUE|value_len(u32)|value|
+    fn encode_record(
+        buf: &mut BytesMut,
+        version: u64,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expire_at: Option<u64>,
+    ) -> Result<()> {
+        buf.put_u64(version);
+        let entry = match (value, expire_at) {
+            (Some(value), Some(expire_at)) => Entry::with_expiry(key, value, expire_at),
+            (Some(value), None) => Entry::new(key, value),
+            (None, _) => Entry::tombstone(key),
+        };
+        buf.put(entry.encode()?);
+        Ok(())
+    }
+
     pub fn add(&self, key: &[u8], value: &[u8]) -> Result<u64> {
-        let entry = Entry::new(key, value);
-        let buf = entry.encode();
-        self.inner.lock().append(&buf)
+        self.add_record(key, Some(value), None)
+    }
+
+    /// Append a key-value pair which expires at `expire_at` milliseconds since the UNIX epoch.
+    pub fn add_with_expiry(&self, key: &[u8], value: &[u8], expire_at: u64) -> Result<u64> {
+        self.add_record(key, Some(value), Some(expire_at))
+    }
+
+    /// Append a tombstone of a deleted key.
+    pub fn delete(&self, key: &[u8]) -> Result<u64> {
+        self.add_record(key, None, None)
     }
 
-    pub fn add_entries(&self, entries: &[(Bytes, Bytes)]) -> Result<u64> {
+    /// Append encoded operands merged into the value of `key`, see [`crate::merge`].
+    pub fn merge(&self, key: &[u8], operands: &[u8]) -> Result<u64> {
+        let mut inner = self.inner.lock();
+        let writer = inner.writer()?;
+        let mut buf = BytesMut::new();
+        buf.put_u64(writer.version + 1);
+        buf.put(Entry::merge(key, operands).encode()?);
+        self.append(inner, &buf)
+    }
+
+    fn add_record(&self, key: &[u8], value: Option<&[u8]>, expire_at: Option<u64>) -> Result<u64> {
+        let mut inner = self.inner.lock();
+        let writer = inner.writer()?;
+        let mut buf = BytesMut::new();
+        Self::encode_record(&mut buf, writer.version + 1, key, value, expire_at)?;
+        self.append(inner, &buf)
+    }
+
+    /// Append a range deletion, which is a record with an empty key.
+    pub fn add_range_tombstone(&self, tombstone: &RangeTombstone) -> Result<u64> {
+        let mut value = vec![];
+        RangeTombstone::encode(std::slice::from_ref(tombstone), &mut value);
+        self.add(b"", &value)
+    }
+
+    /// Append entries with the same version, a None value is a tombstone. They are written in one
+    /// frame so either all or none of them are replayed.
+    pub fn add_entries(&self, entries: &[(Bytes, Option<Bytes>)]) -> Result<u64> {
+        let mut inner = self.inner.lock();
+        let version = inner.writer()?.version + 1;
         let mut buf = BytesMut::new();
         for (key, value) in entries {
-            let entry = Entry::new(key, value);
-            buf.put(entry.encode());
+            Self::encode_record(&mut buf, version, key, value.as_deref(), None)?;
         }
-        self.inner.lock().append(&buf.freeze())
+        self.append(inner, &buf)
     }
 
-    pub fn iter(&self) -> Result<WalIterator> {
-        let buf = self.inner.lock().read_all()?;
+    /// Append `buf` as one frame: |len(u32)|buf|crc32(u32)|, and wait until it's written to the
+    /// file, or synced to the disk if the policy asks for it. A frame longer than `u32::MAX` is
+    /// an error.
+    ///
+    /// Frames appended while a leader is writing are batched, the first waiting writer becomes
+    /// the next leader and writes all of them with one write (and one sync).
+    fn append(&self, mut inner: MutexGuard<WalInner>, buf: &[u8]) -> Result<u64> {
+        let len = u32::try_from(buf.len()).map_err(|_| {
+            anyhow::anyhow!(
+                "wal record of {} bytes exceeds {} bytes",
+                buf.len(),
+                u32::MAX
+            )
+        })?;
+        let writer = inner.writer()?;
+        if writer.failed {
+            return Err(anyhow::anyhow!("wal {:?} failed to write", self.path));
+        }
+        writer.buf.put_u32(len);
+        writer.buf.put_slice(buf);
+        writer.buf.put_u32(checksum::calculate_checksum(buf));
+        writer.version += 1;
+        let version = writer.version;
+
+        loop {
+            let writer = inner.writer()?;
+            if writer.failed {
+                return Err(anyhow::anyhow!("wal {:?} failed to write", self.path));
+            }
+            let done = match self.sync {
+                WalSync::Always => writer.synced >= version,
+                _ => writer.written >= version,
+            };
+            if done {
+                return Ok(version);
+            }
+            if writer.writing {
+                self.written.wait(&mut inner);
+                continue;
+            }
+
+            // become the leader
+            let data = std::mem::take(&mut writer.buf);
+            let last = writer.version;
+            let sync = match self.sync {
+                WalSync::Always => true,
+                WalSync::EveryN(n) => last - writer.synced >= n as u64,
+                WalSync::Interval(interval) => writer.last_sync.elapsed() >= interval,
+            };
+            let file = writer.file.clone();
+            writer.writing = true;
+            let res = MutexGuard::unlocked(&mut inner, || -> Result<()> {
+                (&*file).write_all(&data)?;
+                if sync {
+                    file.sync_data()?;
+                }
+                Ok(())
+            });
+
+            let writer = inner.writer()?;
+            writer.writing = false;
+            match res {
+                Ok(()) => {
+                    writer.written = last;
+                    if sync {
+                        writer.synced = last;
+                        writer.last_sync = Instant::now();
+                    }
+                }
+                Err(_) => writer.failed = true,
+            }
+            self.written.notify_all();
+            res?;
+        }
+    }
+
+    /// Get the version of the last appended record, 0 if none is appended.
+    pub fn last_version(&self) -> Result<u64> {
+        Ok(self.inner.lock().writer()?.version)
+    }
 
-        Ok(WalIterator::create(&buf))
+    /// Sync all written records to the disk.
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        while inner.writer()?.writing {
+            self.written.wait(&mut inner);
+        }
+        let writer = inner.writer()?;
+        if writer.synced < writer.written {
+            writer.file.sync_data()?;
+            writer.synced = writer.written;
+            writer.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Iterate over the records from the start of the file, which are read incrementally. The
+    /// file position is shared by iterators of the same WAL, so only one can be used at a time.
+    pub fn iter(&self) -> Result<WalIterator> {
+        let mut file = self.inner.lock().reader()?.try_clone()?;
+        file.rewind()?;
+        WalIterator::create(file)
     }
 }
 
@@ -108,6 +310,10 @@ impl Drop for Wal {
             if let Err(e) = remove_file(&self.path) {
                 panic!("{e}: {:?}", self.path);
             }
+        } else if matches!(*self.inner.get_mut(), WalInner::WalWriter(_)) {
+            if let Err(e) = self.sync() {
+                error!("failed to sync wal {:?}: {e}", self.path);
+            }
         }
     }
 }
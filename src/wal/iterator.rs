@@ -1,20 +1,79 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek},
+};
+
+use anyhow::Result;
 use bytes::{Buf, Bytes};
+use log::warn;
+
+use crate::{
+    block::{split_expire_at, ValueType, LONG_VALUE},
+    checksum::{self, CHECKSUM_SIZE},
+    range_tombstone::RangeTombstone,
+};
+
+use super::{HEADER_SIZE, MAGIC, VERSION};
 
 pub struct WalIterator {
-    data: Bytes,
+    /// None once the end of the file, a torn write or a corrupted frame is reached.
+    reader: Option<BufReader<File>>,
+    /// The file has no header, its records are not framed, see `Wal`.
+    legacy: bool,
+    /// Bytes of the frames read so far.
+    offset: u64,
+    /// Records left in the current frame.
+    frame: Bytes,
     key: Vec<u8>,
     value: Vec<u8>,
+    tombstone: bool,
+    merge: bool,
+    expire_at: Option<u64>,
+    version: u64,
+    range_tombstone: Option<RangeTombstone>,
 }
 
 impl WalIterator {
-    pub fn create(buf: &[u8]) -> Self {
+    /// Iterate over the records of `file` from its start, which is its current position. Frames
+    /// are read one at a time, so the whole file is never in memory.
+    ///
+    /// A file without the header is read in the format before frames, and a file of an unknown
+    /// version is an error.
+    pub fn create(file: File) -> Result<Self> {
+        let mut reader = BufReader::new(file);
+        let header = read_up_to(&mut reader, HEADER_SIZE)?;
+        let legacy = match header.get(..MAGIC.len()) {
+            Some(magic) if magic == MAGIC && header.len() == HEADER_SIZE => {
+                let version = (&header[MAGIC.len()..]).get_u32();
+                if version != VERSION {
+                    return Err(anyhow::anyhow!(
+                        "unsupported wal version {version}, expected {VERSION}"
+                    ));
+                }
+                false
+            }
+            // the header is torn, no frame is written after it
+            _ if header.len() < HEADER_SIZE && MAGIC.starts_with(&header) => false,
+            _ => {
+                reader.rewind()?;
+                true
+            }
+        };
         let mut iter = WalIterator {
-            data: Bytes::copy_from_slice(buf),
+            reader: Some(reader),
+            legacy,
+            offset: 0,
+            frame: Bytes::new(),
             key: vec![],
             value: vec![],
+            tombstone: false,
+            merge: false,
+            expire_at: None,
+            version: 0,
+            range_tombstone: None,
         };
         iter.next();
-        iter
+        Ok(iter)
     }
 
     pub fn key(&self) -> &[u8] {
@@ -26,21 +85,191 @@ impl WalIterator {
         &self.value
     }
 
+    /// Returns the version of the current entry.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns true if the current record is a tombstone of a deleted key.
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    /// Returns true if the current record is a merge, whose value is a list of operands.
+    pub fn is_merge(&self) -> bool {
+        self.merge
+    }
+
+    /// Returns the expiry time of the value in milliseconds since the UNIX epoch, None if it never
+    /// expires.
+    pub fn expire_at(&self) -> Option<u64> {
+        self.expire_at
+    }
+
+    /// Returns the range tombstone if the current record is a range deletion, whose key is empty.
+    pub fn range_tombstone(&self) -> Option<&RangeTombstone> {
+        self.range_tombstone.as_ref()
+    }
+
     /// Returns true if the iterator is valid.
     pub fn is_valid(&self) -> bool {
-        !self.key.is_empty()
+        !self.key.is_empty() || self.range_tombstone.is_some()
     }
 
     pub fn next(&mut self) {
-        if self.data.is_empty() {
-            self.key.clear();
+        if self.legacy {
+            if let Err(e) = self.next_legacy() {
+                warn!("wal has a torn write at offset {}: {e}", self.offset);
+                self.reader = None;
+                self.key.clear();
+            }
             return;
         }
-        let klen = self.data.get_u16() as usize;
-        self.key = self.data[..klen].to_vec();
-        self.data.advance(klen);
-        let vlen = self.data.get_u16() as usize;
-        self.value = self.data[..vlen].to_vec();
-        self.data.advance(vlen);
+        if self.frame.is_empty() {
+            match self.next_frame() {
+                Ok(Some(frame)) => self.frame = frame,
+                end => {
+                    // the rest is a torn or corrupted write, which is the truncation point
+                    if let Err(e) = end {
+                        warn!("wal has a torn write at offset {}: {e}", self.offset);
+                    }
+                    self.reader = None;
+                    self.key.clear();
+                    self.range_tombstone = None;
+                    return;
+                }
+            }
+        }
+        if let Err(e) = self.decode_record() {
+            warn!("wal has a bad record: {e}");
+            self.reader = None;
+            self.frame.clear();
+            self.key.clear();
+            self.range_tombstone = None;
+        }
+    }
+
+    /// Read the next frame |len(u32)|records|crc32(u32)|, return None at the end of the file,
+    /// or an error if it's incomplete or fails its checksum.
+    fn next_frame(&mut self) -> Result<Option<Bytes>> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(None);
+        };
+        let header = read_up_to(reader, 4)?;
+        match header.len() {
+            0 => return Ok(None),
+            4 => {}
+            n => return Err(anyhow::anyhow!("length is truncated to {n} bytes")),
+        }
+        let len = (&header[..]).get_u32() as usize;
+        // read up to the end of the file instead of allocating a corrupted length
+        let mut frame = read_up_to(reader, len + CHECKSUM_SIZE)?;
+        if frame.len() < len + CHECKSUM_SIZE {
+            return Err(anyhow::anyhow!(
+                "frame of {len} bytes is truncated to {} bytes",
+                frame.len()
+            ));
+        }
+        let crc = (&frame[len..]).get_u32();
+        frame.truncate(len);
+        checksum::verify_checksum(&frame, crc)?;
+        self.offset += (4 + len + CHECKSUM_SIZE) as u64;
+        Ok(Some(frame.into()))
+    }
+
+    /// Read the next record |key_len(u16)|key|value_len(u16)|value| of a file without the
+    /// header, an empty value is a tombstone. Records have no versions, each one was appended with
+    /// the next version. The key is cleared at the end of the file.
+    fn next_legacy(&mut self) -> Result<()> {
+        self.key.clear();
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(());
+        };
+        let klen = read_up_to(reader, 2)?;
+        match klen.len() {
+            0 => return Ok(()),
+            2 => {}
+            _ => return Err(anyhow::anyhow!("key length is truncated")),
+        }
+        let mut read = |len: usize, what: &str| -> Result<Vec<u8>> {
+            let buf = read_up_to(reader, len)?;
+            match buf.len() == len {
+                true => Ok(buf),
+                false => Err(anyhow::anyhow!("{what} is truncated")),
+            }
+        };
+        let klen = (&klen[..]).get_u16() as usize;
+        let key = read(klen, "key")?;
+        let vlen = (&read(2, "value length")?[..]).get_u16() as usize;
+        let value = read(vlen, "value")?;
+        self.offset += (4 + klen + vlen) as u64;
+        self.version += 1;
+        self.tombstone = value.is_empty();
+        self.merge = false;
+        self.expire_at = None;
+        self.key = key;
+        self.value = value;
+        Ok(())
+    }
+
+    /// Bytes of the frames read so far.
+    #[cfg(test)]
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // |version(u64)|key_len(u16)|key|value_type(u8)|value_len(u16)|value|, the value length is a
+    // u32 if `LONG_VALUE` is set in the value type
+    // the value of a range deletion is an encoded range tombstone, and its key is empty
+    fn decode_record(&mut self) -> Result<()> {
+        let frame = &mut self.frame;
+        if frame.len() < 10 {
+            return Err(anyhow::anyhow!("record header is truncated"));
+        }
+        self.version = frame.get_u64();
+        let klen = frame.get_u16() as usize;
+        if frame.len() < klen + 3 {
+            return Err(anyhow::anyhow!("key is truncated"));
+        }
+        self.key = frame[..klen].to_vec();
+        frame.advance(klen);
+        let tag = frame.get_u8();
+        let value_type = ValueType::try_from(tag & !LONG_VALUE)?;
+        let vlen = match tag & LONG_VALUE {
+            0 => frame.get_u16() as usize,
+            _ if frame.len() < 4 => return Err(anyhow::anyhow!("value length is truncated")),
+            _ => frame.get_u32() as usize,
+        };
+        if frame.len() < vlen {
+            return Err(anyhow::anyhow!("value is truncated"));
+        }
+        let value = frame.split_to(vlen);
+        self.tombstone = value_type == ValueType::Delete;
+        self.merge = value_type == ValueType::Merge;
+        self.expire_at = None;
+        match value_type {
+            ValueType::PutWithExpiry => {
+                let (value, expire_at) = split_expire_at(&value);
+                self.value = value.to_vec();
+                self.expire_at = Some(expire_at);
+            }
+            _ => self.value = value.to_vec(),
+        }
+        self.range_tombstone = None;
+        if self.key.is_empty() {
+            let mut tombstones = RangeTombstone::decode(&self.value[..])?;
+            if tombstones.len() != 1 {
+                return Err(anyhow::anyhow!("invalid range deletion"));
+            }
+            self.range_tombstone = tombstones.pop();
+        }
+        Ok(())
     }
 }
+
+/// Read `len` bytes, or less if the file ends before them.
+fn read_up_to(reader: &mut impl Read, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
@@ -1,14 +1,16 @@
-use bytes::Bytes;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes};
 use tempfile::TempDir;
 
-use crate::util::memtable_file_path;
+use crate::{block::ValueType, checksum, opt::WalSync, util::memtable_file_path};
 
-use super::Wal;
+use super::{Wal, HEADER_SIZE, MAGIC, VERSION};
 
 #[test]
 fn test_replay() {
     let dir = TempDir::new().unwrap();
-    let wal = Wal::create(memtable_file_path(dir.path(), 0)).unwrap();
+    let wal = Wal::create(memtable_file_path(dir.path(), 0), WalSync::Always).unwrap();
     let input = vec![
         (&b"aaa"[..], &b"bbb"[..]),
         (&b"aaa"[..], &b"bbb"[..]),
@@ -31,14 +33,67 @@ fn test_replay() {
     }
 }
 
+#[test]
+fn test_replay_large_value() {
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    let value = vec![b'v'; 100 * 1024];
+    let wal = Wal::create(&path, WalSync::Always).unwrap();
+    wal.add(b"a", &value).unwrap();
+    wal.add(b"b", b"1").unwrap();
+    wal.save_file();
+    drop(wal);
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    assert_eq!(iter.key(), b"a");
+    assert_eq!(iter.value(), value);
+    iter.next();
+    assert_eq!(iter.key(), b"b");
+    assert_eq!(iter.value(), b"1");
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_replay_u16_value_len() {
+    // |version|key_len|key|value_type|value_len(u16)|value| written before values may be long
+    let mut record = vec![];
+    record.put_u64(1);
+    record.put_u16(1);
+    record.put_slice(b"a");
+    record.put_u8(ValueType::Put as u8);
+    record.put_u16(3);
+    record.put_slice(b"123");
+    let mut data = vec![];
+    data.put_slice(MAGIC);
+    data.put_u32(VERSION);
+    data.put_u32(record.len() as u32);
+    data.put_slice(&record);
+    data.put_u32(checksum::calculate_checksum(&record));
+
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    std::fs::write(&path, data).unwrap();
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    assert_eq!(iter.key(), b"a");
+    assert_eq!(iter.value(), b"123");
+    assert_eq!(iter.version(), 1);
+    iter.next();
+    assert!(!iter.is_valid());
+}
+
 #[test]
 fn test_replay_add_entries() {
     let dir = TempDir::new().unwrap();
-    let wal = Wal::create(memtable_file_path(dir.path(), 0)).unwrap();
+    let wal = Wal::create(memtable_file_path(dir.path(), 0), WalSync::Always).unwrap();
     let input = vec![
-        (Bytes::from_static(b"key1"), Bytes::from_static(b"value1")),
-        (Bytes::from_static(b"key2"), Bytes::from_static(b"value2")),
-        (Bytes::from_static(b"key3"), Bytes::from_static(b"value3")),
+        (
+            Bytes::from_static(b"key1"),
+            Some(Bytes::from_static(b"value1")),
+        ),
+        (Bytes::from_static(b"key2"), None),
+        (Bytes::from_static(b"key3"), Some(Bytes::new())),
     ];
     wal.add_entries(&input).unwrap();
     wal.save_file();
@@ -46,9 +101,196 @@ fn test_replay_add_entries() {
     let r_wal = Wal::open(memtable_file_path(dir.path(), 0)).unwrap();
     let mut iter = r_wal.iter().unwrap();
     for (key, value) in input {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.is_tombstone(), value.is_none());
+        assert_eq!(iter.value(), value.unwrap_or_default());
+        iter.next();
+    }
+}
+
+#[test]
+fn test_replay_version() {
+    let dir = TempDir::new().unwrap();
+    let wal = Wal::create(memtable_file_path(dir.path(), 0), WalSync::Always).unwrap();
+    assert_eq!(wal.add(b"a", b"1").unwrap(), 1);
+    let input = vec![
+        (Bytes::from_static(b"b"), Some(Bytes::from_static(b"2"))),
+        (Bytes::from_static(b"c"), Some(Bytes::from_static(b"3"))),
+    ];
+    assert_eq!(wal.add_entries(&input).unwrap(), 2);
+    assert_eq!(wal.add(b"a", b"4").unwrap(), 3);
+    wal.save_file();
+    drop(wal);
+    let r_wal = Wal::open(memtable_file_path(dir.path(), 0)).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    for (key, version) in [(b"a", 1), (b"b", 2), (b"c", 2), (b"a", 3)] {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.version(), version);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_replay_torn_write() {
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    let wal = Wal::create(&path, WalSync::Always).unwrap();
+    for i in 0..10u8 {
+        wal.add(&[b'k', i], &[b'v', i]).unwrap();
+    }
+    wal.save_file();
+    drop(wal);
+    let size = std::fs::metadata(&path).unwrap().len();
+    let record_size = (size - HEADER_SIZE as u64) / 10;
+
+    // truncate the file in the middle of the last record
+    let file = std::fs::File::options().write(true).open(&path).unwrap();
+    file.set_len(size - record_size / 2).unwrap();
+    drop(file);
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    for i in 0..9u8 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), [b'k', i]);
+        assert_eq!(iter.value(), [b'v', i]);
+        assert_eq!(iter.version(), i as u64 + 1);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    r_wal.save_file();
+    drop(r_wal);
+
+    // corrupt the 5th record
+    let mut data = std::fs::read(&path).unwrap();
+    data[HEADER_SIZE + (record_size * 4 + 6) as usize] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    for i in 0..4u8 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), [b'k', i]);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_replay_concurrent_every_n() {
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    let wal = std::sync::Arc::new(Wal::create(&path, WalSync::EveryN(8)).unwrap());
+    let handles = (0..4u8)
+        .map(|t| {
+            let wal = wal.clone();
+            std::thread::spawn(move || {
+                for i in 0..100u8 {
+                    wal.add(&[t, i], &[i]).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    wal.save_file();
+    drop(wal);
+
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    let mut keys = vec![];
+    let mut version = 0;
+    while iter.is_valid() {
+        // records are written in the order of their versions
+        assert_eq!(iter.version(), version + 1);
+        version = iter.version();
+        assert_eq!(iter.value(), &iter.key()[1..]);
+        keys.push(iter.key().to_vec());
+        iter.next();
+    }
+    keys.sort();
+    let expected = (0..4u8)
+        .flat_map(|t| (0..100u8).map(move |i| vec![t, i]))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn test_replay_streaming() {
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    let wal = Wal::create(&path, WalSync::Interval(Duration::from_secs(60))).unwrap();
+    let value = vec![b'v'; 1000];
+    for i in 0..10000u32 {
+        wal.add(&i.to_be_bytes(), &value).unwrap();
+    }
+    wal.save_file();
+    drop(wal);
+    assert!(std::fs::metadata(&path).unwrap().len() > 10 * 1000 * 1000);
+
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    // only the first frame is read
+    assert!(iter.offset() < 2000);
+    for i in 0..10000u32 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key(), i.to_be_bytes());
+        assert_eq!(iter.value(), value);
+        assert_eq!(iter.version(), i as u64 + 1);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_replay_legacy() {
+    // |key_len(u16)|key|value_len(u16)|value| without the header, an empty value is a tombstone
+    let mut data = vec![];
+    for (key, value) in [
+        (&b"a"[..], &b"1"[..]),
+        (b"b", b"22"),
+        (b"a", b"333"),
+        (b"b", b""),
+    ] {
+        data.put_u16(key.len() as u16);
+        data.put_slice(key);
+        data.put_u16(value.len() as u16);
+        data.put_slice(value);
+    }
+    // a torn record
+    data.put_u16(1);
+    data.put_slice(b"c");
+    data.put_u16(3);
+
+    let dir = TempDir::new().unwrap();
+    let path = memtable_file_path(dir.path(), 0);
+    std::fs::write(&path, data).unwrap();
+    let r_wal = Wal::open(&path).unwrap();
+    let mut iter = r_wal.iter().unwrap();
+    let expected = [
+        (b"a", &b"1"[..]),
+        (b"b", b"22"),
+        (b"a", b"333"),
+        (b"b", b""),
+    ];
+    for (idx, (key, value)) in expected.into_iter().enumerate() {
         assert!(iter.is_valid());
         assert_eq!(iter.key(), key);
         assert_eq!(iter.value(), value);
+        assert_eq!(iter.is_tombstone(), value.is_empty());
+        // not read from the record
+        assert_eq!(iter.version(), idx as u64 + 1);
         iter.next();
     }
+    assert!(!iter.is_valid());
+    drop(iter);
+
+    // an unknown version is an error instead of being replayed as records
+    let mut data = vec![];
+    data.put_slice(MAGIC);
+    data.put_u32(VERSION + 1);
+    std::fs::write(&path, data).unwrap();
+    assert!(r_wal.iter().is_err());
 }